@@ -68,10 +68,6 @@ export async function run(): Promise<void> {
     default: "../keys/validator_list.json"
   });
 
-  parser.add_argument("--credit-list", {
-    default: "../keys/credit_list.json"
-  });
-
   parser.add_argument("--tSOL", {
     default: "../keys/tSOL_token.json"
   });
@@ -163,7 +159,6 @@ export async function run(): Promise<void> {
     await readAccount(args["stake_pool"]),
     await readAccount(args["owner"]),
     await readAccount(args["validator_list"]),
-    await readAccount(args['credit_list']),
     (await readAccount(args['tSOL'])).publicKey,
     (await readAccount(args['owners_fee'])).publicKey,
     (await readAccount(args['credit_reserve'])).publicKey
@@ -182,7 +177,12 @@ export async function run(): Promise<void> {
     console.log('\n ...Create stake pool...');
     await tenderize.createStakePool({
       feeDenominator: args["fee_denominator"],
-      feeNumerator: args["fee_numerator"]
+      feeNumerator: args["fee_numerator"],
+      withdrawalFeeDenominator: 0,
+      withdrawalFeeNumerator: 0,
+      performanceFeeDenominator: 0,
+      performanceFeeNumerator: 0,
+      freshnessGraceSlots: BigInt(0),
     });
     state = await tenderize.readState();
   } else {
@@ -221,6 +221,10 @@ export async function run(): Promise<void> {
 
   await tenderize.payAllCreditors();
 
+  console.log('\n ...Expire credits...');
+
+  await tenderize.expireCredits();
+
   reserve = await connection.getAccountInfo(await tenderize.getReserveAddress());
 
   console.log(`Tenderize ${tenderize.stakePool.publicKey.toBase58()} with reserve ${await tenderize.getReserveAddress()} ${reserve!.lamports / LAMPORTS_PER_SOL}`);
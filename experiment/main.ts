@@ -74,16 +74,12 @@ async function main() {
   }
 
   if (true) {
-    console.log('\n ...Approving token...');
-    await execShellCommand(
-      `spl-token approve ${tester.userTokenAccount.publicKey} 1 ${await (
-        await tester.tenderize!.getWithdrawAuthority()
-      ).toBase58()}`
-    );
-
     const cancelAuthority = new Account();
 
     console.log('\n ...Calling credit function...');
+    const ticket = await tester.tenderize!.getCreditTicketAddress(
+      state!.creditQueueHead
+    );
     await tester.tenderize!.credit({
       userTokenSource: tester.userTokenAccount.publicKey,
       amount: 10000000,
@@ -96,7 +92,8 @@ async function main() {
       userTokenSource: tester.userTokenAccount.publicKey,
       amount: -2000000,
       userSolTarget: tester.payerAccount.publicKey,
-      cancelAuthority
+      cancelAuthority,
+      ticket
     });
 
     state = await tester.tenderize!.readState();
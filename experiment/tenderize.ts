@@ -17,18 +17,34 @@ const _ = require("lodash");
 const SPL_TOKEN_PROGRAM_ID = new PublicKey(
   'TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA'
 );
+const SYSVAR_EPOCH_SCHEDULE_PUBKEY = new PublicKey(
+  'SysvarEpochSchedu1e111111111111111111111111'
+);
 const MIN_STAKE_ACCOUNT_BALANCE = 1000000000;
+// CreditRecord::EXPIRY_EPOCHS
+const CREDIT_EXPIRY_EPOCHS = BigInt(10);
 
 export interface ValidatorInfo {
   votePubkey: PublicKey;
   balance: number;
   lastUpdateEpoch: number;
   stakeCount: number;
+  targetWeight: number;
+  activatingLamports: number; // at lastUpdateEpoch
+  deactivatingLamports: number;
+  activeLamports: number;
 }
 
 export interface CreateStakePoolParams {
   feeDenominator: number;
   feeNumerator: number;
+  withdrawalFeeDenominator: number;
+  withdrawalFeeNumerator: number;
+  performanceFeeDenominator: number;
+  performanceFeeNumerator: number;
+  freshnessGraceSlots: bigint; // 0 is strict, 2^64-1 is permissive
+  // List capacity comes from the account size. ReallocList moves a full list
+  validatorCapacity?: number; // 100 by default
 }
 
 export interface CreateValidatorStakeParams {
@@ -60,6 +76,8 @@ export interface CreditParams {
   amount: number;
   userSolTarget: PublicKey;
   cancelAuthority: PublicKey | Account;
+  // Ticket to return the tokens from. Uncredit only
+  ticket?: PublicKey;
 }
 
 export interface TestDepositParams {
@@ -82,6 +100,13 @@ export interface DepositReserveValidatorParam {
 
 export interface DepositReserveParams {
   validators: DepositReserveValidatorParam[];
+  // Delegate without the owner, limited by the validator target weights
+  permissionless?: boolean;
+}
+
+export interface SetValidatorTargetWeightParams {
+  validator: PublicKey;
+  targetWeight: number;
 }
 
 export interface StakePair {
@@ -103,10 +128,16 @@ export interface Unstake {
 
 export interface UnstakeParams {
   unstakes: Unstake[];
+  // Unstake without the owner to cover the queued credits
+  permissionless?: boolean;
 }
 
 export interface UpdateListBalanceParams {
   validators: ValidatorInfo[];
+  // UpdateListBalancePage continuing from State.listUpdate* cursor fields.
+  // The first validator's stakes start at startStakeIndex
+  paged?: boolean;
+  startStakeIndex?: number;
 }
 
 export interface State {
@@ -123,12 +154,49 @@ export interface State {
   lastEpochUpdate: bigint;
   feeDenominator: bigint;
   feeNumerator: bigint;
+  lastUpdateSlot: bigint;
+  freshnessGraceSlots: bigint;
+  withdrawalFeeDenominator: bigint;
+  withdrawalFeeNumerator: bigint;
+  performanceFeeDenominator: bigint;
+  performanceFeeNumerator: bigint;
+  pendingDepositFeeDenominator: bigint;
+  pendingDepositFeeNumerator: bigint;
+  pendingDepositFeeEpoch: bigint; // 0 if nothing is pending
+  pendingWithdrawalFeeDenominator: bigint;
+  pendingWithdrawalFeeNumerator: bigint;
+  pendingWithdrawalFeeEpoch: bigint;
+  pendingPerformanceFeeDenominator: bigint;
+  pendingPerformanceFeeNumerator: bigint;
+  pendingPerformanceFeeEpoch: bigint;
+  pendingOwner: PublicKey; // default pubkey if there is no proposal
+  pendingOwnerFeeAccount: PublicKey;
+  liquidityTargetBps: number;
+  deactivatingLamports: bigint; // cooling down in deactivatingEpoch
+  deactivatingEpoch: bigint;
+  activeLamports: bigint; // at lastEpochUpdate
+  activatingLamports: bigint;
+  listUpdateEpoch: bigint; // paged UpdateListBalance cursor
+  listUpdateValidatorIndex: number;
+  listUpdateStakeIndex: number;
+  listUpdateStakeCount: number;
+  creditQueueHead: bigint; // sequence of the next credit ticket
+  creditQueueTail: bigint; // sequence of the first ticket FIFO payments start at
+  creditLamportsTotal: bigint; // owed to credit tickets, not in stakeTotal
+  creditOrdering: number; // 0 FIFO with partial payments, 1 skip-ahead
+  performanceHighWater: bigint; // highest lamports value of 10^9 pool tokens, the performance fee applies above it
 }
 
 export interface Creditor {
+  ticket: PublicKey;
+  sequence: bigint;
   target: PublicKey;
   cancelAuthority: PublicKey;
-  amount: number;
+  amount: number; // pool tokens
+  createdEpoch: bigint;
+  lamportsOwed: number;
+  refundAccount: PublicKey;
+  payer: PublicKey; // gets the ticket rent back when the ticket is closed
 }
 
 export class TenderizeProgram {
@@ -138,7 +206,6 @@ export class TenderizeProgram {
   stakePool: Account;
   owner: Account;
   validatorStakeListAccount: Account;
-  creditListAccount: Account;
   poolMintToken: PublicKey;
   ownersFee: PublicKey;
   creditReserve: PublicKey;
@@ -150,7 +217,6 @@ export class TenderizeProgram {
     stakePool: Account,
     owner: Account,
     validatorStakeListAccount: Account,
-    creditListAccount: Account,
     poolMintToken: PublicKey,
     ownersFee: PublicKey,
     creditReserve: PublicKey
@@ -161,7 +227,6 @@ export class TenderizeProgram {
     this.stakePool = stakePool;
     this.owner = owner;
     this.validatorStakeListAccount = validatorStakeListAccount;
-    this.creditListAccount = creditListAccount;
     this.poolMintToken = poolMintToken;
     this.ownersFee = ownersFee;
     this.creditReserve = creditReserve;
@@ -198,6 +263,21 @@ export class TenderizeProgram {
     )[0];
   }
 
+  async getCreditTicketAddress(sequence: bigint): Promise<PublicKey> {
+    const sequenceBuffer = Buffer.alloc(8);
+    sequenceBuffer.writeBigUInt64LE(sequence, 0);
+    return (
+      await PublicKey.findProgramAddress(
+        [
+          this.stakePool.publicKey.toBuffer(),
+          Buffer.from('credit'),
+          sequenceBuffer,
+        ],
+        this.programId
+      )
+    )[0];
+  }
+
   async getStakeForValidator(validator: PublicKey, index: number) {
     const indexBuffer = Buffer.alloc(4);
     indexBuffer.writeUInt32LE(index, 0);
@@ -217,10 +297,10 @@ export class TenderizeProgram {
     console.log(
       `Creating stake pool ${this.stakePool.publicKey} with owners fee ${this.ownersFee}`
     );
-    const stakePoolLength =
-      1000 + 4 + 32 + 4 + 4 + 32 + 32 + 32 + 8 + 8 + 8 + 2 * 8;
-    const validatorStakeListLength = 60000 + 4 + 4 + 1000 * (32 + 8 + 8);
-    const creditListLength = 5 + 10000 * (32 + 32 + 32);
+    // StakePool::LEN: packed fields and reserved space
+    const stakePoolLength = 1024;
+    // Header: version u8, count u16. ValidatorStakeInfo is 80 bytes
+    const validatorStakeListLength = 3 + 80 * (params.validatorCapacity || 100);
 
     const transaction = new Transaction();
     transaction.add(
@@ -245,17 +325,6 @@ export class TenderizeProgram {
         programId: this.programId,
       })
     );
-    transaction.add(
-      SystemProgram.createAccount({
-        fromPubkey: this.payerAccount.publicKey,
-        newAccountPubkey: this.creditListAccount.publicKey,
-        lamports: await this.connection.getMinimumBalanceForRentExemption(
-          creditListLength
-        ),
-        space: creditListLength,
-        programId: this.programId,
-      })
-    );
     transaction.add(this.createStakePoolInstruction(params));
 
     await sendAndConfirmTransaction(
@@ -266,7 +335,6 @@ export class TenderizeProgram {
         this.owner,
         this.stakePool,
         this.validatorStakeListAccount,
-        this.creditListAccount,
       ],
       {
         commitment: 'singleGossip',
@@ -276,10 +344,15 @@ export class TenderizeProgram {
   }
 
   createStakePoolInstruction(params: CreateStakePoolParams) {
-    const data = Buffer.alloc(1 + 8 + 8);
+    const data = Buffer.alloc(1 + (8 + 8) * 3 + 8);
     let p = data.writeUInt8(0, 0);
     p = data.writeBigUInt64LE(BigInt(params.feeDenominator), p);
     p = data.writeBigUInt64LE(BigInt(params.feeNumerator), p);
+    p = data.writeBigUInt64LE(BigInt(params.withdrawalFeeDenominator), p);
+    p = data.writeBigUInt64LE(BigInt(params.withdrawalFeeNumerator), p);
+    p = data.writeBigUInt64LE(BigInt(params.performanceFeeDenominator), p);
+    p = data.writeBigUInt64LE(BigInt(params.performanceFeeNumerator), p);
+    p = data.writeBigUInt64LE(params.freshnessGraceSlots, p);
 
     return new TransactionInstruction({
       keys: [
@@ -290,11 +363,6 @@ export class TenderizeProgram {
           isSigner: false,
           isWritable: true,
         },
-        {
-          pubkey: this.creditListAccount.publicKey,
-          isSigner: false,
-          isWritable: true,
-        },
         { pubkey: this.poolMintToken, isSigner: false, isWritable: false },
         { pubkey: this.ownersFee, isSigner: false, isWritable: false },
         { pubkey: this.creditReserve, isSigner: false, isWritable: true },
@@ -308,6 +376,7 @@ export class TenderizeProgram {
   }
 
   parseState(data: Buffer): State {
+    // Packed little-endian layout without padding (version 2)
     return {
       version: data.readUInt8(0),
       owner: new PublicKey(data.slice(1, 33)),
@@ -318,14 +387,45 @@ export class TenderizeProgram {
       ownerFeeAccount: new PublicKey(data.slice(131, 163)),
       creditReserve: new PublicKey(data.slice(163, 195)),
       tokenProgram: new PublicKey(data.slice(195, 227)),
-      // Padding
-      stakeTotal: data.readBigUInt64LE(232),
-      poolTotal: data.readBigUInt64LE(240),
-      lastEpochUpdate: data.readBigUInt64LE(248),
-      feeDenominator: data.readBigUInt64LE(256),
-      feeNumerator: data.readBigUInt64LE(264),
+      stakeTotal: data.readBigUInt64LE(227),
+      poolTotal: data.readBigUInt64LE(235),
+      lastEpochUpdate: data.readBigUInt64LE(243),
+      feeDenominator: data.readBigUInt64LE(251),
+      feeNumerator: data.readBigUInt64LE(259),
+      lastUpdateSlot: data.readBigUInt64LE(267),
+      freshnessGraceSlots: data.readBigUInt64LE(275),
+      withdrawalFeeDenominator: data.readBigUInt64LE(283),
+      withdrawalFeeNumerator: data.readBigUInt64LE(291),
+      performanceFeeDenominator: data.readBigUInt64LE(299),
+      performanceFeeNumerator: data.readBigUInt64LE(307),
+      pendingDepositFeeDenominator: data.readBigUInt64LE(315),
+      pendingDepositFeeNumerator: data.readBigUInt64LE(323),
+      pendingDepositFeeEpoch: data.readBigUInt64LE(331),
+      pendingWithdrawalFeeDenominator: data.readBigUInt64LE(339),
+      pendingWithdrawalFeeNumerator: data.readBigUInt64LE(347),
+      pendingWithdrawalFeeEpoch: data.readBigUInt64LE(355),
+      pendingPerformanceFeeDenominator: data.readBigUInt64LE(363),
+      pendingPerformanceFeeNumerator: data.readBigUInt64LE(371),
+      pendingPerformanceFeeEpoch: data.readBigUInt64LE(379),
+      pendingOwner: new PublicKey(data.slice(387, 419)),
+      pendingOwnerFeeAccount: new PublicKey(data.slice(419, 451)),
+      liquidityTargetBps: data.readUInt16LE(451),
+      deactivatingLamports: data.readBigUInt64LE(453),
+      deactivatingEpoch: data.readBigUInt64LE(461),
+      activeLamports: data.readBigUInt64LE(469),
+      activatingLamports: data.readBigUInt64LE(477),
+      listUpdateEpoch: data.readBigUInt64LE(485),
+      listUpdateValidatorIndex: data.readUInt32LE(493),
+      listUpdateStakeIndex: data.readUInt32LE(497),
+      listUpdateStakeCount: data.readUInt32LE(501),
+      creditQueueHead: data.readBigUInt64LE(505),
+      creditQueueTail: data.readBigUInt64LE(513),
+      creditLamportsTotal: data.readBigUInt64LE(521),
+      creditOrdering: data.readUInt8(529),
+      performanceHighWater: data.readBigUInt64LE(530),
     }
   }
+  }
 
   async readState(setState?: (state: State) => void): Promise<State | null> {
     const stateAccount = await this.connection.getAccountInfo(
@@ -351,29 +451,25 @@ export class TenderizeProgram {
       this.validatorStakeListAccount.publicKey,
       'singleGossip'
     );
-    const validatorCount = validatorListAccount!.data.readUInt16LE(1);
+    const data = validatorListAccount!.data;
+    const validatorCount = data.readUInt16LE(1);
+    // Fields appended by later list versions read as zero
+    const recordLength = [0, 56, 72][data.readUInt8(0)] || 80;
     const validators: ValidatorInfo[] = [];
     for (let i = 0; i < validatorCount; ++i) {
+      const offset = 3 + recordLength * i;
       validators.push({
-        votePubkey: new PublicKey(
-          validatorListAccount!.data.slice(
-            3 + (32 + 8 + 8 + 8) * i,
-            3 + (32 + 8 + 8 + 8) * i + 32
-          )
-        ),
-        balance: Number(
-          validatorListAccount!.data.readBigUInt64LE(
-            3 + (32 + 8 + 8 + 8) * i + 32
-          )
-        ),
-        lastUpdateEpoch: Number(
-          validatorListAccount!.data.readBigUInt64LE(
-            3 + (32 + 8 + 8 + 8) * i + 32 + 8
-          )
-        ),
-        stakeCount: validatorListAccount!.data.readUInt32LE(
-          3 + (32 + 8 + 8 + 8) * i + 32 + 8 + 8
-        ),
+        votePubkey: new PublicKey(data.slice(offset, offset + 32)),
+        balance: Number(data.readBigUInt64LE(offset + 32)),
+        lastUpdateEpoch: Number(data.readBigUInt64LE(offset + 40)),
+        stakeCount: data.readUInt32LE(offset + 48),
+        targetWeight: data.readUInt32LE(offset + 52),
+        activatingLamports:
+          recordLength > 56 ? Number(data.readBigUInt64LE(offset + 56)) : 0,
+        deactivatingLamports:
+          recordLength > 56 ? Number(data.readBigUInt64LE(offset + 64)) : 0,
+        activeLamports:
+          recordLength > 72 ? Number(data.readBigUInt64LE(offset + 72)) : 0,
       });
     }
     return validators;
@@ -434,6 +530,50 @@ export class TenderizeProgram {
     );
   }
 
+  async setValidatorTargetWeight(params: SetValidatorTargetWeightParams) {
+    console.log(
+      `Set validator ${params.validator} target weight to ${params.targetWeight}`
+    );
+    const transaction = new Transaction();
+    transaction.add(await this.setValidatorTargetWeightInstruction(params));
+    await sendAndConfirmTransaction(
+      this.connection,
+      transaction,
+      [this.payerAccount, this.owner],
+      {
+        commitment: 'singleGossip',
+        preflightCommitment: 'singleGossip',
+      }
+    );
+  }
+
+  async setValidatorTargetWeightInstruction(
+    params: SetValidatorTargetWeightParams
+  ) {
+    const data = Buffer.alloc(1 + 4);
+    let p = data.writeUInt8(23, 0);
+    p = data.writeUInt32LE(params.targetWeight, p);
+
+    return new TransactionInstruction({
+      keys: [
+        {
+          pubkey: this.stakePool.publicKey,
+          isSigner: false,
+          isWritable: false,
+        },
+        { pubkey: this.owner.publicKey, isSigner: true, isWritable: false },
+        {
+          pubkey: this.validatorStakeListAccount.publicKey,
+          isSigner: false,
+          isWritable: true,
+        },
+        { pubkey: params.validator, isSigner: false, isWritable: false },
+      ],
+      programId: this.programId,
+      data,
+    });
+  }
+
   async removeValidatorInstruction(params: RemoveValidatorParams) {
     const data = Buffer.alloc(1);
     let p = data.writeUInt8(3, 0);
@@ -501,6 +641,8 @@ export class TenderizeProgram {
         { pubkey: this.ownersFee, isSigner: false, isWritable: true },
         { pubkey: this.poolMintToken, isSigner: false, isWritable: true },
         { pubkey: SYSVAR_RENT_PUBKEY, isSigner: false, isWritable: false },
+        { pubkey: SYSVAR_CLOCK_PUBKEY, isSigner: false, isWritable: false },
+        { pubkey: SYSVAR_EPOCH_SCHEDULE_PUBKEY, isSigner: false, isWritable: false },
         { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
         { pubkey: SPL_TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
       ],
@@ -543,9 +685,12 @@ export class TenderizeProgram {
           isWritable: true,
         },
         { pubkey: params.userTokenSource, isSigner: false, isWritable: true },
+        { pubkey: this.ownersFee, isSigner: false, isWritable: true },
         { pubkey: this.poolMintToken, isSigner: false, isWritable: true },
         { pubkey: params.userSolTarget, isSigner: false, isWritable: true },
         { pubkey: SYSVAR_RENT_PUBKEY, isSigner: false, isWritable: false },
+        { pubkey: SYSVAR_CLOCK_PUBKEY, isSigner: false, isWritable: false },
+        { pubkey: SYSVAR_EPOCH_SCHEDULE_PUBKEY, isSigner: false, isWritable: false },
         { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
         { pubkey: SPL_TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
       ],
@@ -582,19 +727,49 @@ export class TenderizeProgram {
     let p = data.writeUInt8(params.amount >= 0 ? 10 : 11, 0);
     p = data.writeBigInt64LE(BigInt(Math.abs(params.amount)), p);
 
+    if (params.amount >= 0) {
+      // Opens the ticket at the queue head and burns the tokens.
+      // The ticket owes their SOL value at the current ratio
+      const ticket = await this.getCreditTicketAddress(
+        (await this.readState())!.creditQueueHead
+      );
+      return new TransactionInstruction({
+        keys: [
+          { pubkey: this.stakePool.publicKey, isSigner: false, isWritable: true },
+          { pubkey: ticket, isSigner: false, isWritable: true },
+          { pubkey: params.userTokenSource, isSigner: false, isWritable: true },
+          // The owner of the token source signs the burn
+          { pubkey: this.payerAccount.publicKey, isSigner: true, isWritable: false },
+          { pubkey: this.poolMintToken, isSigner: false, isWritable: true },
+          { pubkey: params.userSolTarget, isSigner: false, isWritable: false },
+          { pubkey: params.cancelAuthority as PublicKey, isSigner: false, isWritable: false },
+          { pubkey: this.payerAccount.publicKey, isSigner: true, isWritable: true },
+          { pubkey: SYSVAR_RENT_PUBKEY, isSigner: false, isWritable: false },
+          { pubkey: SYSVAR_CLOCK_PUBKEY, isSigner: false, isWritable: false },
+          { pubkey: SYSVAR_EPOCH_SCHEDULE_PUBKEY, isSigner: false, isWritable: false },
+          { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
+          { pubkey: SPL_TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+        ],
+        programId: this.programId,
+        data,
+      });
+    }
+
+    // Uncredit returns lamports of the ticket as pool tokens
     return new TransactionInstruction({
       keys: [
         { pubkey: this.stakePool.publicKey, isSigner: false, isWritable: true },
-        { pubkey: this.creditListAccount.publicKey, isSigner: false, isWritable: true },
-        { pubkey: this.creditReserve, isSigner: false, isWritable: true },
-        {
-          pubkey: await this.getWithdrawAuthority(),
-          isSigner: false,
-          isWritable: false,
-        },
+        { pubkey: params.ticket!, isSigner: false, isWritable: true },
+        { pubkey: await this.getWithdrawAuthority(), isSigner: false, isWritable: false },
+        // Must be the refund account recorded at Credit
         { pubkey: params.userTokenSource, isSigner: false, isWritable: true },
         { pubkey: params.userSolTarget, isSigner: false, isWritable: false },
-        { pubkey: (params.amount >= 0) ? params.cancelAuthority as PublicKey : (params.cancelAuthority as Account).publicKey, isSigner: (params.amount < 0), isWritable: false },
+        // Paid the ticket rent at Credit
+        { pubkey: this.payerAccount.publicKey, isSigner: false, isWritable: true },
+        { pubkey: (params.cancelAuthority as Account).publicKey, isSigner: true, isWritable: false },
+        { pubkey: this.poolMintToken, isSigner: false, isWritable: true },
+        { pubkey: SYSVAR_CLOCK_PUBKEY, isSigner: false, isWritable: false },
+        { pubkey: SYSVAR_EPOCH_SCHEDULE_PUBKEY, isSigner: false, isWritable: false },
         { pubkey: SPL_TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
       ],
       programId: this.programId,
@@ -608,7 +783,9 @@ export class TenderizeProgram {
     await sendAndConfirmTransaction(
       this.connection,
       transaction,
-      [this.payerAccount, this.owner],
+      params.permissionless
+        ? [this.payerAccount]
+        : [this.payerAccount, this.owner],
       {
         commitment: 'singleGossip',
         preflightCommitment: 'singleGossip',
@@ -618,12 +795,14 @@ export class TenderizeProgram {
 
   async delegateReserveInstruction(params: DepositReserveParams) {
     const data = Buffer.alloc(1 + 4 + (8 + 4) * params.validators.length);
-    let p = data.writeUInt8(12, 0);
+    let p = data.writeUInt8(params.permissionless ? 24 : 12, 0);
     p = data.writeUInt32LE(params.validators.length, p);
 
     const keys = [
       { pubkey: this.stakePool.publicKey, isSigner: false, isWritable: false },
-      { pubkey: this.owner.publicKey, isSigner: true, isWritable: false },
+      ...(params.permissionless
+        ? []
+        : [{ pubkey: this.owner.publicKey, isSigner: true, isWritable: false }]),
       {
         pubkey: this.validatorStakeListAccount.publicKey,
         isSigner: false,
@@ -991,12 +1170,14 @@ export class TenderizeProgram {
 
   async unstakeInstruction(params: UnstakeParams): Promise<TransactionInstruction> {
     const data = Buffer.alloc(1 + 4 + (32 + 4 + 4 + 8) * params.unstakes.length);
-    let p = data.writeUInt8(14, 0);
+    let p = data.writeUInt8(params.permissionless ? 26 : 14, 0);
     p = data.writeUInt32LE(params.unstakes.length, p);
 
     const keys = [
       { pubkey: this.stakePool.publicKey, isSigner: false, isWritable: true },
-      { pubkey: this.owner.publicKey, isSigner: true, isWritable: true },
+      ...(params.permissionless
+        ? []
+        : [{ pubkey: this.owner.publicKey, isSigner: true, isWritable: true }]),
       {
         pubkey: this.validatorStakeListAccount.publicKey,
         isSigner: false,
@@ -1007,11 +1188,19 @@ export class TenderizeProgram {
         isSigner: false,
         isWritable: false,
       },
+      ...(params.permissionless
+        ? [
+            {
+              pubkey: await this.getReserveAddress(),
+              isSigner: false,
+              isWritable: true,
+            },
+          ]
+        : []),
       { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
       { pubkey: StakeProgram.programId, isSigner: false, isWritable: false },
       { pubkey: SYSVAR_RENT_PUBKEY, isSigner: false, isWritable: false },
       { pubkey: SYSVAR_CLOCK_PUBKEY, isSigner: false, isWritable: false },
-      { pubkey: SYSVAR_STAKE_HISTORY_PUBKEY, isSigner: false, isWritable: false },
     ];
 
     console.log(`Unstake`);
@@ -1087,18 +1276,12 @@ export class TenderizeProgram {
     const data = Buffer.alloc(1);
     let p = data.writeUInt8(15, 0);
 
-    const state = await this.readState();
-
     const keys = [
       { pubkey: this.stakePool.publicKey, isSigner: false, isWritable: true },
-      { pubkey: this.creditListAccount.publicKey, isSigner: false, isWritable: true },
-      {
-        pubkey: await this.getWithdrawAuthority(),
-        isSigner: false,
-        isWritable: false,
-      },
       { pubkey: await this.getReserveAddress(), isSigner: false, isWritable: true },
-      { pubkey: state!.creditReserve, isSigner: false, isWritable: true },
+      { pubkey: await this.getWithdrawAuthority(), isSigner: false, isWritable: false },
+      // Gets the withdrawal fee of the payments in pool tokens
+      { pubkey: this.ownersFee, isSigner: false, isWritable: true },
       { pubkey: this.poolMintToken, isSigner: false, isWritable: true },
       { pubkey: SYSVAR_RENT_PUBKEY, isSigner: false, isWritable: false },
       { pubkey: SYSVAR_CLOCK_PUBKEY, isSigner: false, isWritable: false },
@@ -1106,11 +1289,31 @@ export class TenderizeProgram {
       { pubkey: SPL_TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
     ];
 
+    const state = (await this.readState())!;
     const creditors = await this.readCreditors();
-    for (let i = 0; ((maxCount <= 0) || (i < maxCount)) && (i < creditors.length); ++i) {
-      keys.push(
-        { pubkey: creditors[i].target, isSigner: false, isWritable: true },
-      )
+    if (state.creditOrdering == 0) {
+      // FIFO walks the queue from its tail. Tickets closed by Uncredit or ExpireCredit
+      // are passed at their address with any target and payer
+      const bySequence = new Map(creditors.map(creditor => [creditor.sequence, creditor]));
+      for (let sequence = state.creditQueueTail, i = 0;
+        ((maxCount <= 0) || (i < maxCount)) && (sequence < state.creditQueueHead);
+        ++sequence, ++i) {
+        const creditor = bySequence.get(sequence);
+        const ticket = creditor ? creditor.ticket : await this.getCreditTicketAddress(sequence);
+        keys.push(
+          { pubkey: ticket, isSigner: false, isWritable: true },
+          { pubkey: creditor ? creditor.target : ticket, isSigner: false, isWritable: true },
+          { pubkey: creditor ? creditor.payer : ticket, isSigner: false, isWritable: true },
+        )
+      }
+    } else {
+      for (let i = 0; ((maxCount <= 0) || (i < maxCount)) && (i < creditors.length); ++i) {
+        keys.push(
+          { pubkey: creditors[i].ticket, isSigner: false, isWritable: true },
+          { pubkey: creditors[i].target, isSigner: false, isWritable: true },
+          { pubkey: creditors[i].payer, isSigner: false, isWritable: true },
+        )
+      }
     }
 
     return new TransactionInstruction({
@@ -1120,43 +1323,103 @@ export class TenderizeProgram {
     });
   }
 
+  // Open credit tickets of the pool in the queue order
   async readCreditors(): Promise<Creditor[]> {
-    const state = await this.readState();
-    const creditorsAccount = await this.connection.getAccountInfo(state!.creditList);
-    const data = creditorsAccount!.data;
-    const count = data.readUInt16LE(1);
-    const creditors: Creditor[] = [];
-
-    let p = 3;
-    for (let i = 0; i < count; ++i) {
-      const target = new PublicKey(data.slice(p, p + 32));
-      p += 32;
-      const cancelAuthority = new PublicKey(data.slice(p, p + 32));
-      p += 32;
-      const amount = Number(data.readBigUInt64LE(p));
-      p += 8;
-      creditors.push({
-        target,
-        cancelAuthority,
-        amount
-      })
-    }
+    const accounts = await this.connection.getProgramAccounts(
+      this.programId,
+      'singleGossip'
+    );
+    // CreditRecord::LEN is 200 bytes, the stake pool follows the version byte
+    const creditors: Creditor[] = accounts
+      .filter(({ account }) =>
+        account.data.length == 200 &&
+        new PublicKey(account.data.slice(1, 33)).equals(this.stakePool.publicKey)
+      )
+      .map(({ pubkey, account }) => {
+        const data = account.data;
+        return {
+          ticket: pubkey,
+          sequence: data.readBigUInt64LE(33),
+          target: new PublicKey(data.slice(41, 73)),
+          cancelAuthority: new PublicKey(data.slice(73, 105)),
+          amount: Number(data.readBigUInt64LE(105)),
+          createdEpoch: data.readBigUInt64LE(113),
+          lamportsOwed: Number(data.readBigUInt64LE(121)),
+          refundAccount: new PublicKey(data.slice(129, 161)),
+          payer: new PublicKey(data.slice(161, 193)),
+        };
+      });
+    creditors.sort((a, b) => (a.sequence < b.sequence ? -1 : 1));
 
     return creditors;
   }
 
   async payAllCreditors() {
     let creditors = await this.readCreditors();
+    let tail = (await this.readState())!.creditQueueTail;
     while (creditors.length > 0) {
       const oldLength = creditors.length;
+      const oldTail = tail;
       await this.payCreditors(30);
       creditors = await this.readCreditors();
-      if (creditors.length == oldLength) {
+      tail = (await this.readState())!.creditQueueTail;
+      // Skipped and closed tickets only move the FIFO tail
+      if (creditors.length == oldLength && tail == oldTail) {
         return;
       }
     }
   }
 
+  // Returns the tokens of the tickets older than CreditRecord::EXPIRY_EPOCHS
+  // to their refund accounts. Anybody may do it unless PayCreditors can pay the target
+  async expireCredits() {
+    const { epoch } = await this.connection.getEpochInfo('singleGossip');
+    const creditors = (await this.readCreditors()).filter(
+      creditor =>
+        creditor.createdEpoch + CREDIT_EXPIRY_EPOCHS <= BigInt(epoch)
+    );
+    for (const creditor of creditors) {
+      const target = await this.connection.getAccountInfo(creditor.target, 'singleGossip');
+      if (target === null || target.owner.equals(SystemProgram.programId)) {
+        continue;
+      }
+      console.log(`Expire credit ticket #${creditor.sequence}`);
+      const transaction = new Transaction();
+      transaction.add(await this.expireCreditInstruction(creditor));
+      await sendAndConfirmTransaction(
+        this.connection,
+        transaction,
+        [this.payerAccount],
+        {
+          commitment: 'singleGossip',
+          preflightCommitment: 'singleGossip',
+        }
+      );
+    }
+  }
+
+  async expireCreditInstruction(creditor: Creditor) {
+    const data = Buffer.alloc(1);
+    data.writeUInt8(29, 0);
+
+    return new TransactionInstruction({
+      keys: [
+        { pubkey: this.stakePool.publicKey, isSigner: false, isWritable: true },
+        { pubkey: creditor.ticket, isSigner: false, isWritable: true },
+        { pubkey: await this.getWithdrawAuthority(), isSigner: false, isWritable: false },
+        { pubkey: creditor.refundAccount, isSigner: false, isWritable: true },
+        { pubkey: creditor.target, isSigner: false, isWritable: false },
+        { pubkey: creditor.payer, isSigner: false, isWritable: true },
+        { pubkey: this.poolMintToken, isSigner: false, isWritable: true },
+        { pubkey: SYSVAR_CLOCK_PUBKEY, isSigner: false, isWritable: false },
+        { pubkey: SYSVAR_EPOCH_SCHEDULE_PUBKEY, isSigner: false, isWritable: false },
+        { pubkey: SPL_TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
+      ],
+      programId: this.programId,
+      data,
+    });
+  }
+
   async updateListBalance(
     params: UpdateListBalanceParams,
   ): Promise<void> {
@@ -1177,10 +1440,14 @@ export class TenderizeProgram {
     params: UpdateListBalanceParams,
   ): Promise<TransactionInstruction> {
     const data = Buffer.alloc(1);
-    let p = data.writeUInt8(4, 0);
+    let p = data.writeUInt8(params.paged ? 27 : 4, 0);
 
     const keys = [
-      { pubkey: this.stakePool.publicKey, isSigner: false, isWritable: false },
+      {
+        pubkey: this.stakePool.publicKey,
+        isSigner: false,
+        isWritable: !!params.paged,
+      },
       {
         pubkey: this.validatorStakeListAccount.publicKey,
         isSigner: false,
@@ -1201,6 +1468,7 @@ export class TenderizeProgram {
       { pubkey: SYSVAR_CLOCK_PUBKEY, isSigner: false, isWritable: false },
       { pubkey: SYSVAR_STAKE_HISTORY_PUBKEY, isSigner: false, isWritable: false },
     ];
+    let startStakeIndex = params.startStakeIndex || 0;
     for (const validator of params.validators) {
       keys.push({
         pubkey: validator.votePubkey,
@@ -1208,7 +1476,7 @@ export class TenderizeProgram {
         isWritable: false,
       });
 
-      for (let i = 0; i < validator.stakeCount; ++i) {
+      for (let i = startStakeIndex; i < validator.stakeCount; ++i) {
         const stake = await this.getStakeForValidator(validator.votePubkey, i);
         keys.push({
           pubkey: stake,
@@ -1216,6 +1484,7 @@ export class TenderizeProgram {
           isWritable: true,
         });
       }
+      startStakeIndex = 0;
     }
 
     return new TransactionInstruction({
@@ -1258,6 +1527,14 @@ export class TenderizeProgram {
           isWritable: false,
         },
         { pubkey: SYSVAR_CLOCK_PUBKEY, isSigner: false, isWritable: false },
+        {
+          pubkey: await this.getWithdrawAuthority(),
+          isSigner: false,
+          isWritable: false,
+        },
+        { pubkey: this.ownersFee, isSigner: false, isWritable: true },
+        { pubkey: this.poolMintToken, isSigner: false, isWritable: true },
+        { pubkey: SPL_TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
       ],
       programId: this.programId,
       data,
@@ -35,7 +35,6 @@ export class Tester {
       await Tester.loadAccount("stake_pool"),
       this.payerAccount, // owner
       await Tester.loadAccount("validator_list"),
-      await Tester.loadAccount('credit_list'),
       (await Tester.loadAccount('tSOL_token')).publicKey,
       (await Tester.loadAccount('owners_fee')).publicKey,
       (await Tester.loadAccount('credit_reserve')).publicKey);
@@ -117,7 +116,12 @@ export class Tester {
   async createStakePool(): Promise<void> {
     await this.tenderize!.createStakePool({
       feeDenominator: 100,
-      feeNumerator: 3
+      feeNumerator: 3,
+      withdrawalFeeDenominator: 0,
+      withdrawalFeeNumerator: 0,
+      performanceFeeDenominator: 100,
+      performanceFeeNumerator: 5,
+      freshnessGraceSlots: BigInt(0),
     });
   }
 
@@ -19,7 +19,7 @@ pub enum StakePoolError {
     /// The calculation failed.
     #[error("CalculationFailure")]
     CalculationFailure,
-    /// Stake pool fee > 1.
+    /// Stake pool fee is above its maximum.
     #[error("FeeTooHigh")]
     FeeTooHigh,
     /// Token account is associated with the wrong mint.
@@ -100,6 +100,34 @@ pub enum StakePoolError {
     /// UnknownCreditor
     #[error("UnknownCreditor")]
     UnknownCreditor,
+    /// Pool balance is older than the freshness policy allows
+    #[error("PoolBalanceOutOfDate")]
+    PoolBalanceOutOfDate,
+    /// Stake pool account must be upgraded by MigrateState
+    #[error("OutdatedStateVersion")]
+    OutdatedStateVersion,
+    /// Delegation moves the validator stake above its target weight
+    #[error("DelegationAboveTarget")]
+    DelegationAboveTarget,
+    /// Unstake is larger than queued credits need
+    #[error("UnstakeAboveRequired")]
+    UnstakeAboveRequired,
+    /// Unstake takes from a validator that is not the most over its target weight
+    #[error("ValidatorNotOverTarget")]
+    ValidatorNotOverTarget,
+    /// Paged validator stake list update must be finished first
+    #[error("ListUpdateInProgress")]
+    ListUpdateInProgress,
+    /// Credit ticket is payable or can't expire yet
+    #[error("CreditNotExpired")]
+    CreditNotExpired,
+    /// Deposit or withdrawal gives less than the minimum the user accepts
+    #[error("SlippageExceeded")]
+    SlippageExceeded,
+    /// v1 validator stake list doesn't fit its validators in the current layout.
+    /// Move it to a larger account with ReallocList
+    #[error("ValidatorListNeedsRealloc")]
+    ValidatorListNeedsRealloc,
 }
 impl From<StakePoolError> for ProgramError {
     fn from(e: StakePoolError) -> Self {
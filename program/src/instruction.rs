@@ -1,17 +1,45 @@
 //! Instruction types
+//!
+//! # Wire format
+//!
+//! Instruction data starts with a one-byte tag, the index of the
+//! [StakePoolInstruction](enum.StakePoolInstruction.html) variant. Tags never change
+//! once assigned, tag 1 is unused. The payload follows the tag without padding:
+//!
+//! * integers are little-endian, pubkeys are 32 raw bytes
+//! * `Fee` is `denominator: u64, numerator: u64`
+//! * `FreshnessPolicy` is `grace_slots: u64`
+//! * `CreditOrdering` is a `u8`: 0 for FIFO, 1 for skip-ahead
+//! * `InitArgs` is `deposit_fee, withdrawal_fee, performance_fee, freshness_policy` (56 bytes)
+//! * `WithdrawStakeArgs` is `amount: u64, stake_index: u32` (12 bytes)
+//! * `SlippageArgs` is `amount: u64, minimum_out: u64` (16 bytes)
+//! * batched ops carry a `u32` item count followed by the items:
+//!   * `DelegateReserveInstruction`: `amount: u64, stake_index: u32` (12 bytes)
+//!   * `MergeStakesInstruction`: `validator_address, main_index: u32, additional_index: u32` (40 bytes)
+//!   * `UnstakeInstruction`: `validator_address, source_index: u32, split_index: u32, amount: u64` (48 bytes)
+//!
+//! Bytes after the payload are ignored.
 
 #![allow(clippy::too_many_arguments)]
 
+use crate::{
+    processor::Processor,
+    stake,
+    state::{ListUpdateCursor, ValidatorStakeInfo},
+};
+use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
 use solana_program::instruction::Instruction;
 use solana_program::program_error::ProgramError;
+use solana_program::program_pack::{Pack, Sealed};
 use solana_program::pubkey::Pubkey;
-use solana_program::sysvar;
 use solana_program::{instruction::AccountMeta, msg};
-use std::mem::{self, size_of};
+use solana_program::{system_program, sysvar};
+use std::convert::TryFrom;
+use std::mem::size_of;
+use std::str::FromStr;
 
 /// Fee rate as a ratio
-/// Fee is minted on deposit
-#[repr(C)]
+/// Zero denominator means no fee
 #[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct Fee {
     /// denominator of the fee ratio
@@ -20,15 +48,246 @@ pub struct Fee {
     pub numerator: u64,
 }
 
+impl Fee {
+    /// Fee part of the amount
+    pub fn apply(&self, amount: u64) -> Option<u64> {
+        if self.denominator == 0 {
+            return Some(0);
+        }
+        u64::try_from(
+            (amount as u128)
+                .checked_mul(self.numerator as u128)?
+                .checked_div(self.denominator as u128)?,
+        )
+        .ok()
+    }
+
+    /// Check that the fee is not above `max`
+    pub fn is_within(&self, max: &Fee) -> bool {
+        if self.numerator > self.denominator {
+            return false;
+        }
+        if self.denominator == 0 || self.numerator == 0 {
+            return true;
+        }
+        if max.denominator == 0 {
+            return false;
+        }
+        self.numerator as u128 * max.denominator as u128
+            <= max.numerator as u128 * self.denominator as u128
+    }
+}
+
+impl Sealed for Fee {}
+
+impl Pack for Fee {
+    const LEN: usize = 16;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, Fee::LEN];
+        let (denominator, numerator) = mut_array_refs![dst, 8, 8];
+        *denominator = self.denominator.to_le_bytes();
+        *numerator = self.numerator.to_le_bytes();
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() < Self::LEN {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        let (denominator, numerator) = array_refs![array_ref![src, 0, Fee::LEN], 8, 8];
+        Ok(Fee {
+            denominator: u64::from_le_bytes(*denominator),
+            numerator: u64::from_le_bytes(*numerator),
+        })
+    }
+}
+
+/// How stale the pool balance may be when pricing deposits and withdrawals
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FreshnessPolicy {
+    /// Slots at the start of an epoch, its first slot included, during which a balance
+    /// from a previous epoch is still accepted. `0` is strict, `u64::MAX` is permissive
+    pub grace_slots: u64,
+}
+
+impl FreshnessPolicy {
+    /// Reject until the pool balance is updated in the current epoch
+    pub const STRICT: Self = Self { grace_slots: 0 };
+    /// Never reject because of a stale pool balance
+    pub const PERMISSIVE: Self = Self {
+        grace_slots: u64::MAX,
+    };
+
+    /// Accept a balance from a previous epoch for `grace_slots` slots into the new epoch
+    pub fn grace(grace_slots: u64) -> Self {
+        Self { grace_slots }
+    }
+}
+
+/// How PayCreditors handles a credit ticket the reserve can't pay in full
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CreditOrdering {
+    /// Tickets are paid in queue order. The first one the reserve can't pay
+    /// in full gets what is left and the payment stops there
+    Fifo,
+    /// Tickets the reserve can't pay in full are skipped, so smaller
+    /// later ones can be paid
+    SkipAhead,
+}
+
+// `#[default]` on enum variants needs a newer compiler than the program builds with,
+// and older clippy doesn't know the lint
+#[allow(unknown_lints, clippy::derivable_impls)]
+impl Default for CreditOrdering {
+    fn default() -> Self {
+        Self::Fifo
+    }
+}
+
+impl TryFrom<u8> for CreditOrdering {
+    type Error = ProgramError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Fifo),
+            1 => Ok(Self::SkipAhead),
+            _ => Err(ProgramError::InvalidArgument),
+        }
+    }
+}
+
+impl Sealed for FreshnessPolicy {}
+
+impl Pack for FreshnessPolicy {
+    const LEN: usize = 8;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        *array_mut_ref![dst, 0, FreshnessPolicy::LEN] = self.grace_slots.to_le_bytes();
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() < Self::LEN {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        Ok(Self::grace(u64::from_le_bytes(*array_ref![
+            src,
+            0,
+            FreshnessPolicy::LEN
+        ])))
+    }
+}
+
 /// Inital values for the Stake Pool
-#[repr(C)]
 #[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct InitArgs {
-    /// Fee paid to the owner in pool tokens
-    pub fee: Fee,
+    /// Fee minted to the owner on deposits
+    pub deposit_fee: Fee,
+    /// Fee taken by the owner on withdrawals and credit payouts
+    pub withdrawal_fee: Fee,
+    /// Fee minted to the owner from the epoch rewards
+    pub performance_fee: Fee,
+    /// Freshness required from the pool balance on deposits and withdrawals
+    pub freshness_policy: FreshnessPolicy,
+}
+
+impl Sealed for InitArgs {}
+
+impl Pack for InitArgs {
+    const LEN: usize = 56;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, InitArgs::LEN];
+        let (deposit_fee, withdrawal_fee, performance_fee, freshness_policy) =
+            mut_array_refs![dst, 16, 16, 16, 8];
+        self.deposit_fee.pack_into_slice(deposit_fee);
+        self.withdrawal_fee.pack_into_slice(withdrawal_fee);
+        self.performance_fee.pack_into_slice(performance_fee);
+        self.freshness_policy.pack_into_slice(freshness_policy);
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() < Self::LEN {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        let (deposit_fee, withdrawal_fee, performance_fee, freshness_policy) =
+            array_refs![array_ref![src, 0, InitArgs::LEN], 16, 16, 16, 8];
+        Ok(InitArgs {
+            deposit_fee: Fee::unpack_from_slice(deposit_fee)?,
+            withdrawal_fee: Fee::unpack_from_slice(withdrawal_fee)?,
+            performance_fee: Fee::unpack_from_slice(performance_fee)?,
+            freshness_policy: FreshnessPolicy::unpack_from_slice(freshness_policy)?,
+        })
+    }
+}
+/// Amount of a deposit or withdrawal with the least output the user accepts
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SlippageArgs {
+    /// Lamports to deposit or pool tokens to withdraw
+    pub amount: u64,
+    /// Minimum pool tokens or lamports the user receives
+    pub minimum_out: u64,
+}
+
+impl Sealed for SlippageArgs {}
+
+impl Pack for SlippageArgs {
+    const LEN: usize = 16;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, SlippageArgs::LEN];
+        let (amount, minimum_out) = mut_array_refs![dst, 8, 8];
+        *amount = self.amount.to_le_bytes();
+        *minimum_out = self.minimum_out.to_le_bytes();
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() < Self::LEN {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        let src = array_ref![src, 0, SlippageArgs::LEN];
+        let (amount, minimum_out) = array_refs![src, 8, 8];
+        Ok(SlippageArgs {
+            amount: u64::from_le_bytes(*amount),
+            minimum_out: u64::from_le_bytes(*minimum_out),
+        })
+    }
+}
+
+/// WithdrawStake arguments
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct WithdrawStakeArgs {
+    /// Pool tokens to withdraw, the withdrawal fee included
+    pub amount: u64,
+    /// Index of the validator stake account to split from
+    pub stake_index: u32,
+}
+
+impl Sealed for WithdrawStakeArgs {}
+
+impl Pack for WithdrawStakeArgs {
+    const LEN: usize = 12;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, WithdrawStakeArgs::LEN];
+        let (amount, stake_index) = mut_array_refs![dst, 8, 4];
+        *amount = self.amount.to_le_bytes();
+        *stake_index = self.stake_index.to_le_bytes();
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() < Self::LEN {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        let src = array_ref![src, 0, WithdrawStakeArgs::LEN];
+        let (amount, stake_index) = array_refs![src, 8, 4];
+        Ok(WithdrawStakeArgs {
+            amount: u64::from_le_bytes(*amount),
+            stake_index: u32::from_le_bytes(*stake_index),
+        })
+    }
 }
+
 /// Delegate Reserve Instruction
-#[repr(C, packed)]
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct DelegateReserveInstruction {
     /// amount to delegate
@@ -37,8 +296,32 @@ pub struct DelegateReserveInstruction {
     pub stake_index: u32,
 }
 
+impl Sealed for DelegateReserveInstruction {}
+
+impl Pack for DelegateReserveInstruction {
+    const LEN: usize = 12;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, DelegateReserveInstruction::LEN];
+        let (amount, stake_index) = mut_array_refs![dst, 8, 4];
+        *amount = self.amount.to_le_bytes();
+        *stake_index = self.stake_index.to_le_bytes();
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() < Self::LEN {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        let src = array_ref![src, 0, DelegateReserveInstruction::LEN];
+        let (amount, stake_index) = array_refs![src, 8, 4];
+        Ok(DelegateReserveInstruction {
+            amount: u64::from_le_bytes(*amount),
+            stake_index: u32::from_le_bytes(*stake_index),
+        })
+    }
+}
+
 /// Merge stakes Instruction
-#[repr(C, packed)]
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct MergeStakesInstruction {
     /// Validator vote pubkey
@@ -49,8 +332,34 @@ pub struct MergeStakesInstruction {
     pub additional_index: u32,
 }
 
+impl Sealed for MergeStakesInstruction {}
+
+impl Pack for MergeStakesInstruction {
+    const LEN: usize = 40;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, MergeStakesInstruction::LEN];
+        let (validator_address, main_index, additional_index) = mut_array_refs![dst, 32, 4, 4];
+        validator_address.copy_from_slice(self.validator_address.as_ref());
+        *main_index = self.main_index.to_le_bytes();
+        *additional_index = self.additional_index.to_le_bytes();
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() < Self::LEN {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        let src = array_ref![src, 0, MergeStakesInstruction::LEN];
+        let (validator_address, main_index, additional_index) = array_refs![src, 32, 4, 4];
+        Ok(MergeStakesInstruction {
+            validator_address: Pubkey::new(validator_address),
+            main_index: u32::from_le_bytes(*main_index),
+            additional_index: u32::from_le_bytes(*additional_index),
+        })
+    }
+}
+
 /// Unstake
-#[repr(C, packed)]
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct UnstakeInstruction {
     /// Validator vote pubkey
@@ -63,8 +372,37 @@ pub struct UnstakeInstruction {
     pub amount: u64,
 }
 
+impl Sealed for UnstakeInstruction {}
+
+impl Pack for UnstakeInstruction {
+    const LEN: usize = 48;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, UnstakeInstruction::LEN];
+        let (validator_address, source_index, split_index, amount) =
+            mut_array_refs![dst, 32, 4, 4, 8];
+        validator_address.copy_from_slice(self.validator_address.as_ref());
+        *source_index = self.source_index.to_le_bytes();
+        *split_index = self.split_index.to_le_bytes();
+        *amount = self.amount.to_le_bytes();
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() < Self::LEN {
+            return Err(ProgramError::InvalidInstructionData);
+        }
+        let src = array_ref![src, 0, UnstakeInstruction::LEN];
+        let (validator_address, source_index, split_index, amount) = array_refs![src, 32, 4, 4, 8];
+        Ok(UnstakeInstruction {
+            validator_address: Pubkey::new(validator_address),
+            source_index: u32::from_le_bytes(*source_index),
+            split_index: u32::from_le_bytes(*split_index),
+            amount: u64::from_le_bytes(*amount),
+        })
+    }
+}
+
 /// Instructions supported by the StakePool program.
-#[repr(C)]
 #[derive(Clone, Debug, PartialEq)]
 pub enum StakePoolInstruction {
     ///   0) Initializes a new StakePool.
@@ -72,13 +410,12 @@ pub enum StakePoolInstruction {
     ///   0. `[w]` New StakePool to create.
     ///   1. `[s]` Owner
     ///   2. `[w]` Uninitialized validator stake list storage account
-    ///   3. `[w]` Uninitialized credit list storage account
-    ///   4. `[]` pool token Mint. Must be non zero, owned by withdraw authority.
-    ///   5. `[]` Pool Account to deposit the generated fee for owner.
-    ///   6. `[w]` Credit reserve token account
-    ///   7. `[]` Clock sysvar
-    ///   8. `[]` Rent sysvar
-    ///   9. `[]` Token program id
+    ///   3. `[]` pool token Mint. Must be non zero, owned by withdraw authority.
+    ///   4. `[]` Pool Account to deposit the generated fee for owner.
+    ///   5. `[w]` Credit reserve token account
+    ///   6. `[]` Clock sysvar
+    ///   7. `[]` Rent sysvar
+    ///   8. `[]` Token program id
     Initialize(InitArgs),
 
     ///   2) Adds validator stake account to the pool
@@ -113,15 +450,20 @@ pub enum StakePoolInstruction {
     UpdateListBalance,
 
     ///   5) Updates total pool balance based on balances in validator stake account list storage
+    ///      and mints the performance fee from the balance increase
     ///
     ///   0. `[w]` Stake pool
     ///   1. `[]` Validator stake list storage account
     ///   2. `[]` Reserve account PDA
     ///   3. `[]` Sysvar clock account
+    ///   4. `[]` Stake pool withdraw authority
+    ///   5. `[w]` Account to receive pool fee tokens
+    ///   6. `[w]` Pool token mint account
+    ///   7. `[]` Pool token program id
     UpdatePoolBalance,
 
     ///   6) Deposit some stake into the pool.  The output is a "pool" token representing ownership
-    ///   into the pool. Inputs are converted to the current ratio.
+    ///      into the pool. Inputs are converted to the current ratio.
     ///
     ///   0. `[w]` Stake pool
     ///   1. `[]` Stake pool withdraw authority
@@ -131,11 +473,15 @@ pub enum StakePoolInstruction {
     ///   5. `[w]` Account to receive pool fee tokens
     ///   6. `[w]` Pool token mint account
     ///   7. `[]` Rent sysvar
-    ///   8. `[]` System program
-    ///   9. `[]` Pool token program id,
+    ///   8. `[]` Clock sysvar
+    ///   9. `[]` Epoch schedule sysvar
+    ///   10. `[]` System program
+    ///   11. `[]` Pool token program id,
+    ///
     ///   in case of wrapped SOLs:
-    ///   10. `[w]` Temp account (PDA)
-    ///   11. `[w]` native token mint ("So11111111111111111111111111111111111111112")
+    ///
+    ///   12. `[w]` Temp account (PDA)
+    ///   13. `[]` native token mint ("So11111111111111111111111111111111111111112")
     Deposit(u64),
 
     ///   7) Withdraw the token from the pool at the current ratio.
@@ -144,63 +490,87 @@ pub enum StakePoolInstruction {
     ///   1. `[]` Stake pool withdraw authority
     ///   2. `[w]` Reserve account (PDA)
     ///   3. `[w]` User account with pool tokens to burn from
-    ///   4. `[w]` Pool token mint account
-    ///   5. `[w]` Target to SOL transfer
-    ///   6. `[]` Rent sysvar
-    ///   7. `[]` System program
-    ///   8. `[]` Pool token program id
+    ///   4. `[w]` Account to receive pool fee tokens
+    ///   5. `[w]` Pool token mint account
+    ///   6. `[w]` Target to SOL transfer
+    ///   7. `[]` Rent sysvar
+    ///   8. `[]` Clock sysvar
+    ///   9. `[]` Epoch schedule sysvar
+    ///   10. `[]` System program
+    ///   11. `[]` Pool token program id
+    ///
     ///   userdata: amount to withdraw
     Withdraw(u64),
 
-    ///   8) Update the staking pubkey for a stake
+    ///   8) Hand a stake over to a new staker and withdrawer
+    ///      The stake account must be the last one of its validator and is removed from the pool
+    ///      and its totals. No pool tokens are burned, the holders bear the stake that leaves.
+    ///      Requires the validator and the pool balance to be updated in the current epoch
     ///
     ///   0. `[w]` StakePool
     ///   1. `[s]` Owner
     ///   2. `[]` withdraw authority
-    ///   3. `[w]` Stake to update the staking pubkey
-    ///   4. '[]` Staking pubkey.
-    ///   5. '[]' Sysvar clock account (reserved for future use)
-    ///   6. `[]` Stake program id,
+    ///   3. `[w]` Validator stake list storage account
+    ///   4. `[w]` Stake to hand over
+    ///   5. `[]` New staker and withdrawer
+    ///   6. `[]` Clock sysvar
+    ///   7. `[]` Stake history sysvar that carries stake warmup/cooldown history
+    ///   8. `[]` Stake program id
     SetStakingAuthority,
 
-    ///   9) Update owner
+    ///   9) Propose new owner. The owner is changed by AcceptOwner
     ///
     ///   0. `[w]` StakePool
     ///   1. `[s]` Owner
     ///   2. '[]` New owner pubkey
     ///   3. '[]` New owner fee account
-    SetOwner,
+    ProposeOwner,
 
-    ///   10) Credit
+    ///   10) Credit. Opens the credit ticket at `StakePool::credit_queue_head`
+    ///       and moves the queue head on. The pool tokens are burned right away
+    ///       and the ticket owes their SOL value at the current ratio.
+    ///       PayCreditors takes the withdrawal fee from the payments
     ///
-    ///   0. `[]` Stake pool
-    ///   1. `[w]` Credit list account
-    ///   2. `[w]` Credit resrve
-    ///   3. `[]` Stake pool withdraw authority
-    ///   4. `[w]` User account with pool tokens to burn from
+    ///   0. `[w]` Stake pool
+    ///   1. `[w]` Credit ticket (PDA of the queue head sequence number)
+    ///   2. `[w]` User account with pool tokens to burn from. Becomes the refund account
+    ///   3. `[s]` User transfer authority: owner or delegate of the pool tokens
+    ///   4. `[w]` Pool token mint account
     ///   5. `[]` Target to SOL transfer
     ///   6. `[]` Cancel authority
-    ///   7. `[]` Pool token program id
+    ///   7. `[ws]` Payer of the ticket rent. Gets it back when the ticket is closed
+    ///   8. `[]` Rent sysvar
+    ///   9. `[]` Clock sysvar
+    ///   10. `[]` Epoch schedule sysvar
+    ///   11. `[]` System program
+    ///   12. `[]` Pool token program id
+    ///
     ///   userdata: amount to withdraw
     Credit(u64),
 
-    ///   11) Uncredit
+    ///   11) Uncredit. Returns lamports of the credit ticket as pool tokens minted
+    ///       at the current ratio, but no more than their share of the tokens burned at Credit.
+    ///       Closes the ticket when all of it is returned
     ///
-    ///   0. `[]` Stake pool
-    ///   1. `[w]` Credit list account
-    ///   2. `[w]` Credit resrve
-    ///   3. `[]` Stake pool withdraw authority
-    ///   4. `[w]` User account with pool tokens for returning
-    ///   5. `[]` Target to SOL transfer for canceling
+    ///   0. `[w]` Stake pool
+    ///   1. `[w]` Credit ticket
+    ///   2. `[]` Stake pool withdraw authority
+    ///   3. `[w]` Refund account of the ticket
+    ///   4. `[]` Target to SOL transfer
+    ///   5. `[w]` Payer of the ticket rent. Gets the rent of a closed ticket
     ///   6. `[s]` Cancel authority
-    ///   6. `[]` Pool token program id
-    ///   userdata: amount to withdraw
+    ///   7. `[w]` Pool token mint account
+    ///   8. `[]` Clock sysvar
+    ///   9. `[]` Epoch schedule sysvar
+    ///   10. `[]` Pool token program id
+    ///
+    ///   userdata: lamports to return
     Uncredit(u64),
 
     ///   12) Delegate reserve to stake account
     ///
     ///   0.  `[]` StakePool
-    ///   1.  `[s]` Owner signature // TODO: maybe non owner can do it if properly check
+    ///   1.  `[s]` Owner signature
     ///   2.  `[w]` Validator stake list storage account
     ///   3.  `[]` Stake pool withdraw authority
     ///   4.  `[]` Stake pool deposit authority
@@ -219,212 +589,554 @@ pub enum StakePoolInstruction {
     ///   0.  `[]` StakePool
     ///   1.  `[w]` Validator stake list storage account
     ///   2.  `[]` Stake pool deposit authority
-    ///   4.  `[]` Stake program
-    ///   5.  `[]` Clock sysvar
-    ///   6.  `[]` Stake history sysvar that carries stake warmup/cooldown history
-    ///   7. ..7+2N `[w]` stake A `[w]` stake B
+    ///   3.  `[]` Stake program
+    ///   4.  `[]` Clock sysvar
+    ///   5.  `[]` Stake history sysvar that carries stake warmup/cooldown history
+    ///   6. ..6+2N `[w]` stake A `[w]` stake B
     MergeStakes(Vec<MergeStakesInstruction>),
 
     ///  14. Unstake
     ///
+    ///   0.  `[w]` StakePool
+    ///   1.  `[ws]` Owner signature (pays rent for split targets)
+    ///   2.  `[w]` Validator stake list storage account
+    ///   3.  `[]` Stake pool deposit authority
+    ///   4.  `[]` System program
+    ///   5.  `[]` Stake program
+    ///   6.  `[]` Rent sysvar
+    ///   7.  `[]` Clock sysvar
+    ///
+    ///   8..8+? `[w]` stake source `[w]` stake split target (optional)
+    Unstake(Vec<UnstakeInstruction>),
+
+    /// 15. Delayed withdraw. Pays the passed credit tickets in the given order
+    ///     while the reserve has enough. A ticket the reserve can't pay in full is handled
+    ///     by `StakePool::credit_ordering`: with FIFO the tickets must come in queue order
+    ///     starting at `StakePool::credit_queue_tail` and that ticket is paid partially,
+    ///     with skip-ahead it is skipped. FIFO passes the tickets closed by Uncredit or
+    ///     ExpireCredit at their address, with any target and payer accounts.
+    ///     Tickets of targets that are not system accounts are skipped and logged
+    ///     until ExpireCredit returns them. A paid ticket is closed and its rent goes to its payer.
+    ///     The withdrawal fee of every payment stays in the reserve and the owner fee account
+    ///     gets the pool tokens it is worth
+    ///
+    ///   0. `[w]` Stake pool
+    ///   1. `[w]` Reserve account (PDA)
+    ///   2. `[]` Stake pool withdraw authority
+    ///   3. `[w]` Account to receive pool fee tokens
+    ///   4. `[w]` Pool token mint account
+    ///   5. `[]` Rent sysvar
+    ///   6. `[]` Clock sysvar
+    ///   7. `[]` System program
+    ///   8. `[]` Pool token program id
+    ///
+    ///   9..9+3N `[w]` credit ticket `[w]` its user target account `[w]` its rent payer
+    PayCreditors,
+
+    /// 16. Set the freshness policy for deposits and withdrawals
+    ///
+    ///   0. `[w]` Stake pool
+    ///   1. `[s]` Owner
+    SetFreshnessPolicy(FreshnessPolicy),
+
+    /// 17. Set the fee minted on deposits.
+    ///     Decreases apply immediately, increases are applied by UpdatePoolBalance
+    ///     `StakePool::FEE_INCREASE_DELAY_EPOCHS` epochs later
+    ///
+    ///   0. `[w]` Stake pool
+    ///   1. `[s]` Owner
+    ///   2. `[]` Clock sysvar
+    SetDepositFee(Fee),
+
+    /// 18. Set the fee taken on withdrawals and credit payouts.
+    ///     Timelocked like SetDepositFee
+    ///
+    ///   0. `[w]` Stake pool
+    ///   1. `[s]` Owner
+    ///   2. `[]` Clock sysvar
+    SetWithdrawalFee(Fee),
+
+    /// 19. Set the fee minted from the epoch rewards.
+    ///     Timelocked like SetDepositFee
+    ///
+    ///   0. `[w]` Stake pool
+    ///   1. `[s]` Owner
+    ///   2. `[]` Clock sysvar
+    SetPerformanceFee(Fee),
+
+    /// 20. Accept the ownership proposed by ProposeOwner
+    ///
+    ///   0. `[w]` Stake pool
+    ///   1. `[s]` Proposed owner
+    AcceptOwner,
+
+    /// 21. Cancel the pending ownership proposal
+    ///
+    ///   0. `[w]` Stake pool
+    ///   1. `[s]` Owner
+    CancelOwnerProposal,
+
+    /// 22. Rewrite a stake pool account from the v1 layout into the current one.
+    ///     The account must be at least `StakePool::PACKED_LEN` bytes long,
+    ///     which holds for the `StakePool::V1_CLIENT_LEN` accounts of the pool client
+    ///
+    ///   0. `[w]` Stake pool
+    MigrateState,
+
+    /// 23. Set the share of the delegated stake the validator should get
+    ///     relative to the other validators' weights. Used by DelegateReserveToTargets
+    ///
+    ///   0. `[]` Stake pool
+    ///   1. `[s]` Owner
+    ///   2. `[w]` Validator stake list storage account
+    ///   3. `[]` Validator vote account
+    SetValidatorTargetWeight(u32),
+
+    /// 24. Permissionless DelegateReserve.
+    ///     Each validator may receive at most the lamports it is missing to reach its
+    ///     `target_weight` share of the validator stakes plus the reserve above its minimum,
+    ///     the lamports owed to credit tickets and the liquidity target. Amounts must not be zero and existing stake
+    ///     accounts must be fully inactive, so deactivations can't be undone.
+    ///     Requires the validator stake list to be updated in the current epoch
+    ///
     ///   0.  `[]` StakePool
-    ///   1.  `[s]` Owner signature
+    ///   1.  `[w]` Validator stake list storage account
+    ///   2.  `[]` Stake pool withdraw authority
+    ///   3.  `[]` Stake pool deposit authority
+    ///   4.  `[w]` SOL reserve account (PDA)
+    ///   5.  `[]` System program
+    ///   6.  `[]` Stake program
+    ///   7.  `[]` Clock sysvar
+    ///   8.  `[]` Stake history sysvar that carries stake warmup/cooldown history
+    ///   9.  `[]` Address of config account that carries stake config
+    ///   10. `[]` Rent sysvar
+    ///   11. ..11+2N `[]` validator `[w]` stake
+    DelegateReserveToTargets(Vec<DelegateReserveInstruction>),
+
+    /// 25. Set the reserve balance kept for instant withdrawals,
+    ///     in basis points of the pool stake
+    ///
+    ///   0. `[w]` Stake pool
+    ///   1. `[s]` Owner
+    SetLiquidityTarget(u16),
+
+    /// 26. Permissionless Unstake that covers the queued credits.
+    ///     The total may not exceed `StakePool::required_unstake` and each validator
+    ///     gives at most its stake above the `target_weight` share of what stays staked.
+    ///     The batch must take from the validators most over their targets.
+    ///     A full unstake counts the whole source account.
+    ///     Requires the pool and the validator stake list to be updated in the current epoch
+    ///
+    ///   0.  `[w]` StakePool
     ///   1.  `[w]` Validator stake list storage account
     ///   2.  `[]` Stake pool deposit authority
-    ///   3.  `[]` System program
-    ///   4.  `[]` Stake program
-    ///   5.  `[]` Clock sysvar
-    ///   6.  `[]` Stake history sysvar that carries stake warmup/cooldown history
-    ///   7..7+? `[w]` stake source `[w]` stake split target (optional)
-    Unstake(Vec<UnstakeInstruction>),
+    ///   3.  `[w]` SOL reserve account (PDA). Pays rent for split targets
+    ///   4.  `[]` System program
+    ///   5.  `[]` Stake program
+    ///   6.  `[]` Rent sysvar
+    ///   7.  `[]` Clock sysvar
+    ///
+    ///   8..8+? `[w]` stake source `[w]` stake split target (optional)
+    UnstakeForCredits(Vec<UnstakeInstruction>),
 
-    /// 15. Delayed withdraw
+    /// 27. Paged `UpdateListBalance`. Continues from `StakePool::list_update_cursor`,
+    ///     which restarts at the first validator in a new epoch or after a finished round.
+    ///     A page may end inside the stake accounts of a validator. That validator
+    ///     is out of date from its first page until the page with its last stake
+    ///     account is processed, also in a second round of the same epoch
     ///
     ///   0. `[w]` Stake pool
-    ///   1. `[w]` Credit list account
+    ///   1. `[w]` Validator stake list storage account
     ///   2. `[]` Stake pool withdraw authority
     ///   3. `[w]` Reserve account (PDA)
-    ///   4. `[w]` Credit resrve
-    ///   5. `[w]` Pool token mint account
-    ///   6. `[]` Rent sysvar
-    ///   7.  `[]` Clock sysvar
-    ///   8. `[]` System program
+    ///   4. `[]` System program
+    ///   5. `[]` Stake program
+    ///   6. `[]` Clock sysvar
+    ///   7. `[]` Stake history sysvar that carries stake warmup/cooldown history
+    ///   8. `[]` Cursor validator + `[w]` its stakes from the cursor stake index
+    ///
+    ///   9..9+N `[]` next validators + `[w]` their stakes, in the list order
+    UpdateListBalancePage,
+
+    /// 28. Moves the validator stake list into another account,
+    ///     usually a bigger one. The list capacity comes from the account size.
+    ///     The new account must be owned by the program, rent-exempt, zeroed
+    ///     and fit every validator. The old account is zeroed and its lamports move out
+    ///
+    ///   0. `[w]` Stake pool
+    ///   1. `[s]` Owner
+    ///   2. `[w]` Validator stake list storage account of the pool
+    ///   3. `[w]` New list storage account
+    ///   4. `[w]` Account receiving the lamports of the old list account
+    ///   5. `[]` Rent sysvar
+    ReallocList,
+
+    /// 29. Permissionless Uncredit of the whole ticket once
+    ///     `CreditRecord::EXPIRY_EPOCHS` passed since Credit. The pool tokens go back
+    ///     to the refund account recorded at Credit, so tickets that PayCreditors
+    ///     keeps skipping don't stay in the queue forever.
+    ///     Tickets of system account targets are payable and never expire
+    ///
+    ///   0. `[w]` Stake pool
+    ///   1. `[w]` Credit ticket
+    ///   2. `[]` Stake pool withdraw authority
+    ///   3. `[w]` Refund account of the ticket
+    ///   4. `[]` Target to SOL transfer
+    ///   5. `[w]` Payer of the ticket rent. Gets the ticket rent
+    ///   6. `[w]` Pool token mint account
+    ///   7. `[]` Clock sysvar
+    ///   8. `[]` Epoch schedule sysvar
     ///   9. `[]` Pool token program id
-    ///  10..10+N `[w]` user target account
-    PayCreditors,
+    ExpireCredit,
+
+    /// 30. Set how PayCreditors handles the tickets the reserve can't pay in full
+    ///
+    ///   0. `[w]` Stake pool
+    ///   1. `[s]` Owner
+    SetCreditOrdering(CreditOrdering),
+
+    /// 31. Deposit a fully active stake account delegated to a listed validator.
+    ///     Both of its authorities move to the pool, then it is merged into the validator
+    ///     stake account at the given index or becomes the next one when the index equals
+    ///     the validator stake count. Mints pool tokens for the delegated stake less the
+    ///     deposit fee, the rent exempt reserve goes to the holders. The stake must not have
+    ///     a lockup or lamports beyond the stake and the reserve. Fails while a paged update
+    ///     is inside the validator
+    ///
+    ///   0. `[w]` Stake pool
+    ///   1. `[w]` Validator stake list storage account
+    ///   2. `[]` Stake pool deposit authority
+    ///   3. `[]` Stake pool withdraw authority
+    ///   4. `[w]` User stake account to deposit
+    ///   5. `[s]` Withdraw authority of the user stake account
+    ///   6. `[w]` Validator stake account (PDA) to merge into or to create
+    ///   7. `[w]` User account to receive pool tokens
+    ///   8. `[w]` Account to receive pool fee tokens
+    ///   9. `[w]` Pool token mint account
+    ///   10. `[]` Clock sysvar
+    ///   11. `[]` Epoch schedule sysvar
+    ///   12. `[]` Stake history sysvar
+    ///   13. `[]` System program
+    ///   14. `[]` Stake program
+    ///   15. `[]` Pool token program id
+    ///
+    ///   userdata: validator stake index
+    DepositStake(u32),
+
+    /// 32. Withdraw the token from the pool as a stake account, bypassing the reserve.
+    ///     The lamports the tokens are worth are split off the validator stake account at the
+    ///     given index into the uninitialized stake account, then both of its authorities
+    ///     move to the user. The source must keep at least `MIN_STAKE_ACCOUNT_BALANCE`
+    ///     and must not be deactivating. Fails while a paged update is inside the validator
+    ///
+    ///   0. `[w]` Stake pool
+    ///   1. `[w]` Validator stake list storage account
+    ///   2. `[]` Stake pool deposit authority
+    ///   3. `[]` Stake pool withdraw authority
+    ///   4. `[w]` Validator stake account (PDA) to split from
+    ///   5. `[w]` Uninitialized stake account to receive the split
+    ///   6. `[]` New staker and withdrawer of the split stake
+    ///   7. `[w]` User account with pool tokens to burn from
+    ///   8. `[s]` User transfer authority: owner or delegate of the pool tokens
+    ///   9. `[w]` Account to receive pool fee tokens
+    ///   10. `[w]` Pool token mint account
+    ///   11. `[]` Clock sysvar
+    ///   12. `[]` Epoch schedule sysvar
+    ///   13. `[]` Stake program
+    ///   14. `[]` Pool token program id
+    WithdrawStake(WithdrawStakeArgs),
+
+    /// 33. Deposit that fails with `SlippageExceeded` when the user would get
+    ///     less than `minimum_out` pool tokens. Accounts are the same as in Deposit
+    DepositWithSlippage(SlippageArgs),
+
+    /// 34. Withdraw that fails with `SlippageExceeded` when the user would get
+    ///     less than `minimum_out` lamports. Accounts are the same as in Withdraw
+    WithdrawWithSlippage(SlippageArgs),
+
+    /// 35. Return the pool tokens of the credit list left by version 1 pools.
+    ///     Pops the list entries from its end and transfers their pool tokens
+    ///     from the credit reserve to the token accounts of their SOL targets.
+    ///     The list is closed once it is empty
+    ///
+    ///   0. `[w]` Stake pool
+    ///   1. `[ws]` Owner. Gets the rent of the closed list
+    ///   2. `[w]` Credit list
+    ///   3. `[w]` Credit resrve
+    ///   4. `[]` Stake pool withdraw authority
+    ///   5. `[]` Pool token program id
+    ///
+    ///   6.. `[w]` Pool token accounts owned by the SOL targets of the last entries, last first
+    RefundCreditList,
 }
 
 impl StakePoolInstruction {
     /// Deserializes a byte buffer into an [StakePoolInstruction](enum.StakePoolInstruction.html).
-    /// TODO efficient unpacking here
+    /// See the [module documentation](index.html#wire-format) for the format
     pub fn deserialize(input: &[u8]) -> Result<Self, ProgramError> {
         if input.len() < size_of::<u8>() {
             return Err(ProgramError::InvalidAccountData);
         }
         Ok(match input[0] {
             0 => {
-                let val: &InitArgs = unpack(input)?;
-                Self::Initialize(*val)
+                let val: InitArgs = unpack(input)?;
+                Self::Initialize(val)
             }
             2 => Self::AddValidator,
             3 => Self::RemoveValidator,
             4 => Self::UpdateListBalance,
             5 => Self::UpdatePoolBalance,
             6 => {
-                let val: &u64 = unpack(input)?;
-                Self::Deposit(*val)
+                let val = unpack_u64(input)?;
+                Self::Deposit(val)
             }
             7 => {
-                let val: &u64 = unpack(input)?;
-                Self::Withdraw(*val)
+                let val = unpack_u64(input)?;
+                Self::Withdraw(val)
             }
             8 => Self::SetStakingAuthority,
-            9 => Self::SetOwner,
+            9 => Self::ProposeOwner,
             10 => {
-                let val: &u64 = unpack(input)?;
-                Self::Credit(*val)
+                let val = unpack_u64(input)?;
+                Self::Credit(val)
             }
             11 => {
-                let val: &u64 = unpack(input)?;
-                Self::Uncredit(*val)
-            }
-            12 => {
-                let count: &u32 = unpack(input)?;
-                if input.len()
-                    < 1 + 4 + *count as usize * mem::size_of::<DelegateReserveInstruction>()
-                {
-                    msg!(
-                        "Expeced size to be {} but got {} sizeof = {}",
-                        1 + 4 + *count as usize * mem::size_of::<DelegateReserveInstruction>(),
-                        input.len(),
-                        mem::size_of::<DelegateReserveInstruction>()
-                    );
-                    return Err(ProgramError::InvalidArgument);
-                }
-                let instructions = unsafe {
-                    std::slice::from_raw_parts(
-                        &input[1 + 4] as *const u8 as *const DelegateReserveInstruction,
-                        *count as usize,
-                    )
-                };
-                Self::DelegateReserve(instructions.to_vec())
-            }
-            13 => {
-                let count: &u32 = unpack(input)?;
-                if input.len() < 1 + 4 + *count as usize * mem::size_of::<MergeStakesInstruction>()
-                {
-                    msg!(
-                        "Expeced size to be {} but got {} sizeof = {}",
-                        1 + 4 + *count as usize * mem::size_of::<MergeStakesInstruction>(),
-                        input.len(),
-                        mem::size_of::<MergeStakesInstruction>()
-                    );
-                    return Err(ProgramError::InvalidArgument);
-                }
-                let instructions = unsafe {
-                    std::slice::from_raw_parts(
-                        &input[1 + 4] as *const u8 as *const MergeStakesInstruction,
-                        *count as usize,
-                    )
-                };
-                Self::MergeStakes(instructions.to_vec())
-            }
-            14 => {
-                let count: &u32 = unpack(input)?;
-                if input.len() < 1 + 4 + *count as usize * mem::size_of::<UnstakeInstruction>() {
-                    msg!(
-                        "Expeced size to be {} but got {} sizeof = {}",
-                        1 + 4 + *count as usize * mem::size_of::<UnstakeInstruction>(),
-                        input.len(),
-                        mem::size_of::<UnstakeInstruction>()
-                    );
-                    return Err(ProgramError::InvalidArgument);
-                }
-                let instructions = unsafe {
-                    std::slice::from_raw_parts(
-                        &input[1 + 4] as *const u8 as *const UnstakeInstruction,
-                        *count as usize,
-                    )
-                };
-                Self::Unstake(instructions.to_vec())
+                let val = unpack_u64(input)?;
+                Self::Uncredit(val)
             }
+            12 => Self::DelegateReserve(unpack_vec(input)?),
+            13 => Self::MergeStakes(unpack_vec(input)?),
+            14 => Self::Unstake(unpack_vec(input)?),
             15 => Self::PayCreditors,
+            16 => {
+                let val: FreshnessPolicy = unpack(input)?;
+                Self::SetFreshnessPolicy(val)
+            }
+            17 => {
+                let val: Fee = unpack(input)?;
+                Self::SetDepositFee(val)
+            }
+            18 => {
+                let val: Fee = unpack(input)?;
+                Self::SetWithdrawalFee(val)
+            }
+            19 => {
+                let val: Fee = unpack(input)?;
+                Self::SetPerformanceFee(val)
+            }
+            20 => Self::AcceptOwner,
+            21 => Self::CancelOwnerProposal,
+            22 => Self::MigrateState,
+            23 => Self::SetValidatorTargetWeight(unpack_u32(input)?),
+            24 => Self::DelegateReserveToTargets(unpack_vec(input)?),
+            25 => Self::SetLiquidityTarget(unpack_u16(input)?),
+            26 => Self::UnstakeForCredits(unpack_vec(input)?),
+            27 => Self::UpdateListBalancePage,
+            28 => Self::ReallocList,
+            29 => Self::ExpireCredit,
+            30 => Self::SetCreditOrdering(CreditOrdering::try_from(unpack_u8(input)?)?),
+            31 => Self::DepositStake(unpack_u32(input)?),
+            32 => {
+                let val: WithdrawStakeArgs = unpack(input)?;
+                Self::WithdrawStake(val)
+            }
+            33 => {
+                let val: SlippageArgs = unpack(input)?;
+                Self::DepositWithSlippage(val)
+            }
+            34 => {
+                let val: SlippageArgs = unpack(input)?;
+                Self::WithdrawWithSlippage(val)
+            }
+            35 => Self::RefundCreditList,
             _ => return Err(ProgramError::InvalidAccountData),
         })
     }
 
-    /*
     /// Serializes an [StakePoolInstruction](enum.StakePoolInstruction.html) into a byte buffer.
-    /// TODO efficient packing here
     pub fn serialize(&self) -> Result<Vec<u8>, ProgramError> {
-        let mut output = vec![0u8; size_of::<StakePoolInstruction>()];
+        let mut output = Vec::with_capacity(size_of::<u8>() + InitArgs::LEN);
         match self {
             Self::Initialize(init) => {
-                output[0] = 0;
-                #[allow(clippy::cast_ptr_alignment)]
-                let value = unsafe { &mut *(&mut output[1] as *mut u8 as *mut InitArgs) };
-                *value = *init;
+                output.push(0);
+                append(init, &mut output);
             }
-            Self::AddValidator => {
-                output[0] = 2;
+            Self::AddValidator => output.push(2),
+            Self::RemoveValidator => output.push(3),
+            Self::UpdateListBalance => output.push(4),
+            Self::UpdatePoolBalance => output.push(5),
+            Self::Deposit(val) => {
+                output.push(6);
+                output.extend_from_slice(&val.to_le_bytes());
             }
-            Self::RemoveValidatorStakeAccount => {
-                output[0] = 3;
+            Self::Withdraw(val) => {
+                output.push(7);
+                output.extend_from_slice(&val.to_le_bytes());
             }
-            Self::UpdateListBalance => {
-                output[0] = 4;
+            Self::SetStakingAuthority => output.push(8),
+            Self::ProposeOwner => output.push(9),
+            Self::Credit(val) => {
+                output.push(10);
+                output.extend_from_slice(&val.to_le_bytes());
             }
-            Self::UpdatePoolBalance => {
-                output[0] = 5;
+            Self::Uncredit(val) => {
+                output.push(11);
+                output.extend_from_slice(&val.to_le_bytes());
             }
-            Self::Deposit(val) => {
-                output[0] = 6;
-                let value = unsafe { &mut *(&mut output[1] as *mut u8 as *mut u64) };
-                *value = *val;
+            Self::DelegateReserve(instructions) => {
+                output.push(12);
+                append_vec(instructions, &mut output);
             }
-            Self::Withdraw(val) => {
-                output[0] = 7;
-                #[allow(clippy::cast_ptr_alignment)]
-                let value = unsafe { &mut *(&mut output[1] as *mut u8 as *mut u64) };
-                *value = *val;
+            Self::MergeStakes(instructions) => {
+                output.push(13);
+                append_vec(instructions, &mut output);
             }
-            Self::SetStakingAuthority => {
-                output[0] = 8;
+            Self::Unstake(instructions) => {
+                output.push(14);
+                append_vec(instructions, &mut output);
             }
-            Self::SetOwner => {
-                output[0] = 9;
+            Self::PayCreditors => output.push(15),
+            Self::SetFreshnessPolicy(policy) => {
+                output.push(16);
+                append(policy, &mut output);
             }
-            Self::TestDeposit(val) => {
-                output[0] = 10;
-                let value = unsafe { &mut *(&mut output[1] as *mut u8 as *mut u64) };
-                *value = *val;
+            Self::SetDepositFee(fee) => {
+                output.push(17);
+                append(fee, &mut output);
             }
-            Self::TestWithdraw(val) => {
-                output[0] = 11;
-                let value = unsafe { &mut *(&mut output[1] as *mut u8 as *mut u64) };
-                *value = *val;
+            Self::SetWithdrawalFee(fee) => {
+                output.push(18);
+                append(fee, &mut output);
             }
-            Self::DelegateReserve(val) => {
-                output[0] = 11;
-                let value = unsafe { &mut *(&mut output[1] as *mut u8 as *mut u64) };
-                *value = *val;
+            Self::SetPerformanceFee(fee) => {
+                output.push(19);
+                append(fee, &mut output);
             }
-        }
-        Ok(output)
-    }*/
-}
-
-/// Unpacks a reference from a bytes buffer.
-pub fn unpack<T>(input: &[u8]) -> Result<&T, ProgramError> {
-    if input.len() < size_of::<u8>() + size_of::<T>() {
-        return Err(ProgramError::InvalidAccountData);
-    }
-    #[allow(clippy::cast_ptr_alignment)]
-    let val: &T = unsafe { &*(&input[1] as *const u8 as *const T) };
-    Ok(val)
-}
-/*
-/// Creates an 'initialize' instruction.
+            Self::AcceptOwner => output.push(20),
+            Self::CancelOwnerProposal => output.push(21),
+            Self::MigrateState => output.push(22),
+            Self::SetValidatorTargetWeight(weight) => {
+                output.push(23);
+                output.extend_from_slice(&weight.to_le_bytes());
+            }
+            Self::DelegateReserveToTargets(instructions) => {
+                output.push(24);
+                append_vec(instructions, &mut output);
+            }
+            Self::SetLiquidityTarget(bps) => {
+                output.push(25);
+                output.extend_from_slice(&bps.to_le_bytes());
+            }
+            Self::UnstakeForCredits(instructions) => {
+                output.push(26);
+                append_vec(instructions, &mut output);
+            }
+            Self::UpdateListBalancePage => output.push(27),
+            Self::ReallocList => output.push(28),
+            Self::ExpireCredit => output.push(29),
+            Self::SetCreditOrdering(ordering) => {
+                output.push(30);
+                output.push(*ordering as u8);
+            }
+            Self::DepositStake(stake_index) => {
+                output.push(31);
+                output.extend_from_slice(&stake_index.to_le_bytes());
+            }
+            Self::WithdrawStake(args) => {
+                output.push(32);
+                append(args, &mut output);
+            }
+            Self::DepositWithSlippage(args) => {
+                output.push(33);
+                append(args, &mut output);
+            }
+            Self::WithdrawWithSlippage(args) => {
+                output.push(34);
+                append(args, &mut output);
+            }
+            Self::RefundCreditList => output.push(35),
+        }
+        Ok(output)
+    }
+}
+
+/// Unpacks the payload following the instruction tag
+pub fn unpack<T: Pack>(input: &[u8]) -> Result<T, ProgramError> {
+    if input.len() < size_of::<u8>() + T::LEN {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    T::unpack_from_slice(&input[1..1 + T::LEN])
+}
+
+/// Unpacks a little-endian u64 following the instruction tag
+pub fn unpack_u64(input: &[u8]) -> Result<u64, ProgramError> {
+    input
+        .get(1..9)
+        .map(|bytes| u64::from_le_bytes(*array_ref![bytes, 0, 8]))
+        .ok_or(ProgramError::InvalidAccountData)
+}
+
+/// Unpacks the byte following the instruction tag
+pub fn unpack_u8(input: &[u8]) -> Result<u8, ProgramError> {
+    input
+        .get(1)
+        .copied()
+        .ok_or(ProgramError::InvalidAccountData)
+}
+
+/// Unpacks a little-endian u16 following the instruction tag
+pub fn unpack_u16(input: &[u8]) -> Result<u16, ProgramError> {
+    input
+        .get(1..3)
+        .map(|bytes| u16::from_le_bytes(*array_ref![bytes, 0, 2]))
+        .ok_or(ProgramError::InvalidAccountData)
+}
+
+/// Unpacks a little-endian u32 following the instruction tag
+pub fn unpack_u32(input: &[u8]) -> Result<u32, ProgramError> {
+    input
+        .get(1..5)
+        .map(|bytes| u32::from_le_bytes(*array_ref![bytes, 0, 4]))
+        .ok_or(ProgramError::InvalidAccountData)
+}
+
+/// Unpacks a u32 item count following the instruction tag and the items after it
+pub fn unpack_vec<T: Pack>(input: &[u8]) -> Result<Vec<T>, ProgramError> {
+    let count = unpack_u32(input)? as usize;
+    let items = &input[1 + 4..];
+    if count
+        .checked_mul(T::LEN)
+        .map_or(true, |len| items.len() < len)
+    {
+        msg!(
+            "Expected {} items of {} bytes but got {} bytes",
+            count,
+            T::LEN,
+            items.len()
+        );
+        return Err(ProgramError::InvalidArgument);
+    }
+    items
+        .chunks_exact(T::LEN)
+        .take(count)
+        .map(T::unpack_from_slice)
+        .collect()
+}
+
+fn append<T: Pack>(value: &T, output: &mut Vec<u8>) {
+    let start = output.len();
+    output.resize(start + T::LEN, 0);
+    value.pack_into_slice(&mut output[start..]);
+}
+
+fn append_vec<T: Pack>(values: &[T], output: &mut Vec<u8>) {
+    output.extend_from_slice(&(values.len() as u32).to_le_bytes());
+    for value in values {
+        append(value, output);
+    }
+}
+
+/// Creates an 'initialize' instruction.
 pub fn initialize(
     program_id: &Pubkey,
     stake_pool: &Pubkey,
@@ -432,17 +1144,19 @@ pub fn initialize(
     validator_stake_list: &Pubkey,
     pool_mint: &Pubkey,
     owner_pool_account: &Pubkey,
+    credit_reserve: &Pubkey,
     token_program_id: &Pubkey,
     init_args: InitArgs,
 ) -> Result<Instruction, ProgramError> {
     let init_data = StakePoolInstruction::Initialize(init_args);
     let data = init_data.serialize()?;
     let accounts = vec![
-        AccountMeta::new(*stake_pool, true),
+        AccountMeta::new(*stake_pool, false),
         AccountMeta::new_readonly(*owner, true),
         AccountMeta::new(*validator_stake_list, false),
         AccountMeta::new_readonly(*pool_mint, false),
         AccountMeta::new_readonly(*owner_pool_account, false),
+        AccountMeta::new(*credit_reserve, false),
         AccountMeta::new_readonly(sysvar::clock::id(), false),
         AccountMeta::new_readonly(sysvar::rent::id(), false),
         AccountMeta::new_readonly(*token_program_id, false),
@@ -454,87 +1168,78 @@ pub fn initialize(
     })
 }
 
-/// Creates `AddValidatorStakeAccount` instruction (add new validator stake account to the pool)
+/// Creates `AddValidator` instruction (add new validator to the pool)
 pub fn add_validator(
     program_id: &Pubkey,
     stake_pool: &Pubkey,
     owner: &Pubkey,
-    stake_pool_deposit: &Pubkey,
-    stake_pool_withdraw: &Pubkey,
     validator_stake_list: &Pubkey,
-    stake_account: &Pubkey,
-    pool_tokens_to: &Pubkey,
-    pool_mint: &Pubkey,
-    token_program_id: &Pubkey,
-    stake_program_id: &Pubkey,
+    validator: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
-        AccountMeta::new(*stake_pool, false),
+        AccountMeta::new_readonly(*stake_pool, false),
         AccountMeta::new_readonly(*owner, true),
-        AccountMeta::new_readonly(*stake_pool_deposit, false),
-        AccountMeta::new_readonly(*stake_pool_withdraw, false),
         AccountMeta::new(*validator_stake_list, false),
-        AccountMeta::new(*stake_account, false),
-        AccountMeta::new(*pool_tokens_to, false),
-        AccountMeta::new(*pool_mint, false),
+        AccountMeta::new_readonly(*validator, false),
         AccountMeta::new_readonly(sysvar::clock::id(), false),
-        AccountMeta::new_readonly(sysvar::stake_history::id(), false),
-        AccountMeta::new_readonly(*token_program_id, false),
-        AccountMeta::new_readonly(*stake_program_id, false),
     ];
     Ok(Instruction {
         program_id: *program_id,
         accounts,
-        data: StakePoolInstruction::AddValidatorStakeAccount.serialize()?,
+        data: StakePoolInstruction::AddValidator.serialize()?,
     })
 }
 
-/// Creates `RemoveValidatorStakeAccount` instruction (remove validator stake account from the pool)
-pub fn remove_validator_stake_account(
+/// Creates `RemoveValidator` instruction (remove validator without stakes from the pool)
+pub fn remove_validator(
     program_id: &Pubkey,
     stake_pool: &Pubkey,
     owner: &Pubkey,
-    stake_pool_withdraw: &Pubkey,
-    new_stake_authority: &Pubkey,
     validator_stake_list: &Pubkey,
-    stake_account: &Pubkey,
-    burn_from: &Pubkey,
-    pool_mint: &Pubkey,
-    token_program_id: &Pubkey,
-    stake_program_id: &Pubkey,
+    validator: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
-        AccountMeta::new(*stake_pool, false),
+        AccountMeta::new_readonly(*stake_pool, false),
         AccountMeta::new_readonly(*owner, true),
-        AccountMeta::new_readonly(*stake_pool_withdraw, false),
-        AccountMeta::new_readonly(*new_stake_authority, false),
         AccountMeta::new(*validator_stake_list, false),
-        AccountMeta::new(*stake_account, false),
-        AccountMeta::new(*burn_from, false),
-        AccountMeta::new(*pool_mint, false),
+        AccountMeta::new_readonly(*validator, false),
         AccountMeta::new_readonly(sysvar::clock::id(), false),
-        AccountMeta::new_readonly(*token_program_id, false),
-        AccountMeta::new_readonly(*stake_program_id, false),
     ];
     Ok(Instruction {
         program_id: *program_id,
         accounts,
-        data: StakePoolInstruction::RemoveValidatorStakeAccount.serialize()?,
+        data: StakePoolInstruction::RemoveValidator.serialize()?,
     })
 }
 
 /// Creates `UpdateListBalance` instruction (update validator stake account balances)
+/// Every validator is followed by all its stake accounts
 pub fn update_list_balance(
     program_id: &Pubkey,
+    stake_pool: &Pubkey,
     validator_stake_list_storage: &Pubkey,
-    validator_stake_list: &[&Pubkey],
+    validators: &[ValidatorStakeInfo],
 ) -> Result<Instruction, ProgramError> {
-    let mut accounts: Vec<AccountMeta> = validator_stake_list
-        .iter()
-        .map(|pubkey| AccountMeta::new_readonly(**pubkey, false))
-        .collect();
-    accounts.insert(0, AccountMeta::new(*validator_stake_list_storage, false));
-    accounts.insert(1, AccountMeta::new_readonly(sysvar::clock::id(), false));
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*stake_pool, false),
+        AccountMeta::new(*validator_stake_list_storage, false),
+        AccountMeta::new_readonly(withdraw_authority(program_id, stake_pool), false),
+        AccountMeta::new(reserve_address(program_id, stake_pool), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(stake::id(), false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(sysvar::stake_history::id(), false),
+    ];
+    for validator in validators {
+        accounts.push(AccountMeta::new_readonly(
+            validator.validator_account,
+            false,
+        ));
+        for index in 0..validator.stake_count {
+            let (stake_account, _) = validator.stake_address(program_id, stake_pool, index);
+            accounts.push(AccountMeta::new(stake_account, false));
+        }
+    }
     Ok(Instruction {
         program_id: *program_id,
         accounts,
@@ -542,16 +1247,78 @@ pub fn update_list_balance(
     })
 }
 
+/// Creates `UpdateListBalancePage` instruction starting at the `start` cursor
+/// read from the stake pool. Takes at most `max_accounts` validator and stake accounts,
+/// and never ends a page right after a validator that still has stakes to process
+pub fn update_list_balance_page(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    validator_stake_list_storage: &Pubkey,
+    validators: &[ValidatorStakeInfo],
+    start: &ListUpdateCursor,
+    max_accounts: usize,
+) -> Result<Instruction, ProgramError> {
+    let mut accounts = vec![
+        AccountMeta::new(*stake_pool, false),
+        AccountMeta::new(*validator_stake_list_storage, false),
+        AccountMeta::new_readonly(withdraw_authority(program_id, stake_pool), false),
+        AccountMeta::new(reserve_address(program_id, stake_pool), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(stake::id(), false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(sysvar::stake_history::id(), false),
+    ];
+    let mut remaining = max_accounts;
+    let mut stake_index = start.stake_index;
+    'validators: for validator in validators.iter().skip(start.validator_index as usize) {
+        let needed = if stake_index < validator.stake_count {
+            2
+        } else {
+            1
+        };
+        if remaining < needed {
+            break;
+        }
+        accounts.push(AccountMeta::new_readonly(
+            validator.validator_account,
+            false,
+        ));
+        remaining -= 1;
+        for index in stake_index..validator.stake_count {
+            if remaining == 0 {
+                break 'validators;
+            }
+            let (stake_account, _) = validator.stake_address(program_id, stake_pool, index);
+            accounts.push(AccountMeta::new(stake_account, false));
+            remaining -= 1;
+        }
+        stake_index = 0;
+    }
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data: StakePoolInstruction::UpdateListBalancePage.serialize()?,
+    })
+}
+
 /// Creates `UpdatePoolBalance` instruction (pool balance from the stake account list balances)
 pub fn update_pool_balance(
     program_id: &Pubkey,
     stake_pool: &Pubkey,
     validator_stake_list_storage: &Pubkey,
+    pool_fee_to: &Pubkey,
+    pool_mint: &Pubkey,
+    token_program_id: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let accounts = vec![
         AccountMeta::new(*stake_pool, false),
-        AccountMeta::new(*validator_stake_list_storage, false),
+        AccountMeta::new_readonly(*validator_stake_list_storage, false),
+        AccountMeta::new_readonly(reserve_address(program_id, stake_pool), false),
         AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(withdraw_authority(program_id, stake_pool), false),
+        AccountMeta::new(*pool_fee_to, false),
+        AccountMeta::new(*pool_mint, false),
+        AccountMeta::new_readonly(*token_program_id, false),
     ];
     Ok(Instruction {
         program_id: *program_id,
@@ -559,38 +1326,70 @@ pub fn update_pool_balance(
         data: StakePoolInstruction::UpdatePoolBalance.serialize()?,
     })
 }
-/*
-/// Creates a 'Deposit' instruction.
+
+/// Creates a 'deposit' instruction taking native SOLs from a system account.
 pub fn deposit(
     program_id: &Pubkey,
     stake_pool: &Pubkey,
-    validator_stake_list_storage: &Pubkey,
-    stake_pool_deposit: &Pubkey,
-    stake_pool_withdraw: &Pubkey,
-    stake_to_join: &Pubkey,
-    validator_stake_accont: &Pubkey,
+    user_sol_account: &Pubkey,
     pool_tokens_to: &Pubkey,
     pool_fee_to: &Pubkey,
     pool_mint: &Pubkey,
     token_program_id: &Pubkey,
-    stake_program_id: &Pubkey,
+    amount: u64,
 ) -> Result<Instruction, ProgramError> {
-    let args = StakePoolInstruction::Deposit;
+    let args = StakePoolInstruction::Deposit(amount);
     let data = args.serialize()?;
     let accounts = vec![
         AccountMeta::new(*stake_pool, false),
-        AccountMeta::new(*validator_stake_list_storage, false),
-        AccountMeta::new_readonly(*stake_pool_deposit, false),
-        AccountMeta::new_readonly(*stake_pool_withdraw, false),
-        AccountMeta::new(*stake_to_join, false),
-        AccountMeta::new(*validator_stake_accont, false),
+        AccountMeta::new_readonly(withdraw_authority(program_id, stake_pool), false),
+        AccountMeta::new(reserve_address(program_id, stake_pool), false),
+        AccountMeta::new(*user_sol_account, true),
         AccountMeta::new(*pool_tokens_to, false),
         AccountMeta::new(*pool_fee_to, false),
         AccountMeta::new(*pool_mint, false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
         AccountMeta::new_readonly(sysvar::clock::id(), false),
-        AccountMeta::new_readonly(sysvar::stake_history::id(), false),
+        AccountMeta::new_readonly(sysvar::epoch_schedule::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(*token_program_id, false),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'deposit' instruction taking wrapped SOLs from a token account.
+/// The withdraw authority must be approved as a delegate of the wrapped SOL account.
+pub fn deposit_wrapped(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    user_wrapped_sol_account: &Pubkey,
+    pool_tokens_to: &Pubkey,
+    pool_fee_to: &Pubkey,
+    pool_mint: &Pubkey,
+    token_program_id: &Pubkey,
+    amount: u64,
+) -> Result<Instruction, ProgramError> {
+    let args = StakePoolInstruction::Deposit(amount);
+    let data = args.serialize()?;
+    let accounts = vec![
+        AccountMeta::new(*stake_pool, false),
+        AccountMeta::new_readonly(withdraw_authority(program_id, stake_pool), false),
+        AccountMeta::new(reserve_address(program_id, stake_pool), false),
+        AccountMeta::new(*user_wrapped_sol_account, false),
+        AccountMeta::new(*pool_tokens_to, false),
+        AccountMeta::new(*pool_fee_to, false),
+        AccountMeta::new(*pool_mint, false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(sysvar::epoch_schedule::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
         AccountMeta::new_readonly(*token_program_id, false),
-        AccountMeta::new_readonly(*stake_program_id, false),
+        AccountMeta::new(temp_address(program_id, stake_pool), false),
+        AccountMeta::new_readonly(spl_token::native_mint::id(), false),
     ];
     Ok(Instruction {
         program_id: *program_id,
@@ -598,36 +1397,34 @@ pub fn deposit(
         data,
     })
 }
-*/
+
 /// Creates a 'withdraw' instruction.
+/// The withdraw authority must be approved as a delegate of the pool token account.
 pub fn withdraw(
     program_id: &Pubkey,
     stake_pool: &Pubkey,
-    validator_stake_list_storage: &Pubkey,
-    stake_pool_withdraw: &Pubkey,
-    stake_to_split: &Pubkey,
-    stake_to_receive: &Pubkey,
-    user_withdrawer: &Pubkey,
     burn_from: &Pubkey,
+    pool_fee_to: &Pubkey,
     pool_mint: &Pubkey,
+    sol_target: &Pubkey,
     token_program_id: &Pubkey,
-    stake_program_id: &Pubkey,
     amount: u64,
 ) -> Result<Instruction, ProgramError> {
     let args = StakePoolInstruction::Withdraw(amount);
     let data = args.serialize()?;
     let accounts = vec![
         AccountMeta::new(*stake_pool, false),
-        AccountMeta::new(*validator_stake_list_storage, false),
-        AccountMeta::new_readonly(*stake_pool_withdraw, false),
-        AccountMeta::new(*stake_to_split, false),
-        AccountMeta::new(*stake_to_receive, false),
-        AccountMeta::new_readonly(*user_withdrawer, false),
+        AccountMeta::new_readonly(withdraw_authority(program_id, stake_pool), false),
+        AccountMeta::new(reserve_address(program_id, stake_pool), false),
         AccountMeta::new(*burn_from, false),
+        AccountMeta::new(*pool_fee_to, false),
         AccountMeta::new(*pool_mint, false),
+        AccountMeta::new(*sol_target, false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
         AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(sysvar::epoch_schedule::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
         AccountMeta::new_readonly(*token_program_id, false),
-        AccountMeta::new_readonly(*stake_program_id, false),
     ];
     Ok(Instruction {
         program_id: *program_id,
@@ -636,26 +1433,120 @@ pub fn withdraw(
     })
 }
 
+/// Creates a 'deposit' instruction from a system account that fails when
+/// the user would get less than `min_pool_tokens_out` pool tokens.
+pub fn deposit_with_slippage(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    user_sol_account: &Pubkey,
+    pool_tokens_to: &Pubkey,
+    pool_fee_to: &Pubkey,
+    pool_mint: &Pubkey,
+    token_program_id: &Pubkey,
+    amount: u64,
+    min_pool_tokens_out: u64,
+) -> Result<Instruction, ProgramError> {
+    let mut instruction = deposit(
+        program_id,
+        stake_pool,
+        user_sol_account,
+        pool_tokens_to,
+        pool_fee_to,
+        pool_mint,
+        token_program_id,
+        amount,
+    )?;
+    instruction.data = StakePoolInstruction::DepositWithSlippage(SlippageArgs {
+        amount,
+        minimum_out: min_pool_tokens_out,
+    })
+    .serialize()?;
+    Ok(instruction)
+}
+
+/// Creates a 'deposit' instruction from a wrapped SOL account that fails when
+/// the user would get less than `min_pool_tokens_out` pool tokens.
+pub fn deposit_wrapped_with_slippage(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    user_wrapped_sol_account: &Pubkey,
+    pool_tokens_to: &Pubkey,
+    pool_fee_to: &Pubkey,
+    pool_mint: &Pubkey,
+    token_program_id: &Pubkey,
+    amount: u64,
+    min_pool_tokens_out: u64,
+) -> Result<Instruction, ProgramError> {
+    let mut instruction = deposit_wrapped(
+        program_id,
+        stake_pool,
+        user_wrapped_sol_account,
+        pool_tokens_to,
+        pool_fee_to,
+        pool_mint,
+        token_program_id,
+        amount,
+    )?;
+    instruction.data = StakePoolInstruction::DepositWithSlippage(SlippageArgs {
+        amount,
+        minimum_out: min_pool_tokens_out,
+    })
+    .serialize()?;
+    Ok(instruction)
+}
+
+/// Creates a 'withdraw' instruction that fails when the user would get
+/// less than `min_lamports_out` lamports.
+pub fn withdraw_with_slippage(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    burn_from: &Pubkey,
+    pool_fee_to: &Pubkey,
+    pool_mint: &Pubkey,
+    sol_target: &Pubkey,
+    token_program_id: &Pubkey,
+    amount: u64,
+    min_lamports_out: u64,
+) -> Result<Instruction, ProgramError> {
+    let mut instruction = withdraw(
+        program_id,
+        stake_pool,
+        burn_from,
+        pool_fee_to,
+        pool_mint,
+        sol_target,
+        token_program_id,
+        amount,
+    )?;
+    instruction.data = StakePoolInstruction::WithdrawWithSlippage(SlippageArgs {
+        amount,
+        minimum_out: min_lamports_out,
+    })
+    .serialize()?;
+    Ok(instruction)
+}
+
 /// Creates a 'set staking authority' instruction.
 pub fn set_staking_authority(
     program_id: &Pubkey,
     stake_pool: &Pubkey,
     stake_pool_owner: &Pubkey,
-    stake_pool_withdraw: &Pubkey,
+    validator_stake_list_storage: &Pubkey,
     stake_account_to_update: &Pubkey,
     stake_account_new_authority: &Pubkey,
-    stake_program_id: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
     let args = StakePoolInstruction::SetStakingAuthority;
     let data = args.serialize()?;
     let accounts = vec![
         AccountMeta::new(*stake_pool, false),
         AccountMeta::new_readonly(*stake_pool_owner, true),
-        AccountMeta::new_readonly(*stake_pool_withdraw, false),
+        AccountMeta::new_readonly(withdraw_authority(program_id, stake_pool), false),
+        AccountMeta::new(*validator_stake_list_storage, false),
         AccountMeta::new(*stake_account_to_update, false),
         AccountMeta::new_readonly(*stake_account_new_authority, false),
         AccountMeta::new_readonly(sysvar::clock::id(), false),
-        AccountMeta::new_readonly(*stake_program_id, false),
+        AccountMeta::new_readonly(sysvar::stake_history::id(), false),
+        AccountMeta::new_readonly(stake::id(), false),
     ];
     Ok(Instruction {
         program_id: *program_id,
@@ -664,15 +1555,15 @@ pub fn set_staking_authority(
     })
 }
 
-/// Creates a 'set owner' instruction.
-pub fn set_owner(
+/// Creates a 'propose owner' instruction.
+pub fn propose_owner(
     program_id: &Pubkey,
     stake_pool: &Pubkey,
     stake_pool_owner: &Pubkey,
     stake_pool_new_owner: &Pubkey,
     stake_pool_new_fee_receiver: &Pubkey,
 ) -> Result<Instruction, ProgramError> {
-    let args = StakePoolInstruction::SetOwner;
+    let args = StakePoolInstruction::ProposeOwner;
     let data = args.serialize()?;
     let accounts = vec![
         AccountMeta::new(*stake_pool, false),
@@ -686,4 +1577,1375 @@ pub fn set_owner(
         data,
     })
 }
-*/
+
+/// Creates an 'accept owner' instruction.
+pub fn accept_owner(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    stake_pool_new_owner: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let args = StakePoolInstruction::AcceptOwner;
+    let data = args.serialize()?;
+    let accounts = vec![
+        AccountMeta::new(*stake_pool, false),
+        AccountMeta::new_readonly(*stake_pool_new_owner, true),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'cancel owner proposal' instruction.
+pub fn cancel_owner_proposal(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    stake_pool_owner: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let args = StakePoolInstruction::CancelOwnerProposal;
+    let data = args.serialize()?;
+    let accounts = vec![
+        AccountMeta::new(*stake_pool, false),
+        AccountMeta::new_readonly(*stake_pool_owner, true),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'migrate state' instruction.
+pub fn migrate_state(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let args = StakePoolInstruction::MigrateState;
+    let data = args.serialize()?;
+    let accounts = vec![AccountMeta::new(*stake_pool, false)];
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'set validator target weight' instruction.
+pub fn set_validator_target_weight(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    owner: &Pubkey,
+    validator_stake_list: &Pubkey,
+    validator: &Pubkey,
+    target_weight: u32,
+) -> Result<Instruction, ProgramError> {
+    let args = StakePoolInstruction::SetValidatorTargetWeight(target_weight);
+    let data = args.serialize()?;
+    let accounts = vec![
+        AccountMeta::new_readonly(*stake_pool, false),
+        AccountMeta::new_readonly(*owner, true),
+        AccountMeta::new(*validator_stake_list, false),
+        AccountMeta::new_readonly(*validator, false),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'set liquidity target' instruction.
+pub fn set_liquidity_target(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    owner: &Pubkey,
+    liquidity_target_bps: u16,
+) -> Result<Instruction, ProgramError> {
+    let args = StakePoolInstruction::SetLiquidityTarget(liquidity_target_bps);
+    let data = args.serialize()?;
+    let accounts = vec![
+        AccountMeta::new(*stake_pool, false),
+        AccountMeta::new_readonly(*owner, true),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'set credit ordering' instruction.
+pub fn set_credit_ordering(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    owner: &Pubkey,
+    credit_ordering: CreditOrdering,
+) -> Result<Instruction, ProgramError> {
+    let args = StakePoolInstruction::SetCreditOrdering(credit_ordering);
+    let data = args.serialize()?;
+    let accounts = vec![
+        AccountMeta::new(*stake_pool, false),
+        AccountMeta::new_readonly(*owner, true),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'realloc list' instruction.
+pub fn realloc_list(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    owner: &Pubkey,
+    list: &Pubkey,
+    new_list: &Pubkey,
+    lamports_to: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let args = StakePoolInstruction::ReallocList;
+    let data = args.serialize()?;
+    let accounts = vec![
+        AccountMeta::new(*stake_pool, false),
+        AccountMeta::new_readonly(*owner, true),
+        AccountMeta::new(*list, false),
+        AccountMeta::new(*new_list, false),
+        AccountMeta::new(*lamports_to, false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'credit' instruction.
+/// `sequence` is the current `StakePool::credit_queue_head`
+pub fn credit(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    sequence: u64,
+    burn_from: &Pubkey,
+    user_transfer_authority: &Pubkey,
+    pool_mint: &Pubkey,
+    sol_target: &Pubkey,
+    cancel_authority: &Pubkey,
+    payer: &Pubkey,
+    token_program_id: &Pubkey,
+    amount: u64,
+) -> Result<Instruction, ProgramError> {
+    let args = StakePoolInstruction::Credit(amount);
+    let data = args.serialize()?;
+    let accounts = vec![
+        AccountMeta::new(*stake_pool, false),
+        AccountMeta::new(
+            credit_ticket_address(program_id, stake_pool, sequence),
+            false,
+        ),
+        AccountMeta::new(*burn_from, false),
+        AccountMeta::new_readonly(*user_transfer_authority, true),
+        AccountMeta::new(*pool_mint, false),
+        AccountMeta::new_readonly(*sol_target, false),
+        AccountMeta::new_readonly(*cancel_authority, false),
+        AccountMeta::new(*payer, true),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(sysvar::epoch_schedule::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(*token_program_id, false),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates an 'uncredit' instruction.
+pub fn uncredit(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    credit_ticket: &Pubkey,
+    return_to: &Pubkey,
+    sol_target: &Pubkey,
+    payer: &Pubkey,
+    cancel_authority: &Pubkey,
+    pool_mint: &Pubkey,
+    token_program_id: &Pubkey,
+    amount: u64,
+) -> Result<Instruction, ProgramError> {
+    let args = StakePoolInstruction::Uncredit(amount);
+    let data = args.serialize()?;
+    let accounts = vec![
+        AccountMeta::new(*stake_pool, false),
+        AccountMeta::new(*credit_ticket, false),
+        AccountMeta::new_readonly(withdraw_authority(program_id, stake_pool), false),
+        AccountMeta::new(*return_to, false),
+        AccountMeta::new_readonly(*sol_target, false),
+        AccountMeta::new(*payer, false),
+        AccountMeta::new_readonly(*cancel_authority, true),
+        AccountMeta::new(*pool_mint, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(sysvar::epoch_schedule::id(), false),
+        AccountMeta::new_readonly(*token_program_id, false),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates an 'expire credit' instruction.
+pub fn expire_credit(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    credit_ticket: &Pubkey,
+    refund_account: &Pubkey,
+    sol_target: &Pubkey,
+    payer: &Pubkey,
+    pool_mint: &Pubkey,
+    token_program_id: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let args = StakePoolInstruction::ExpireCredit;
+    let data = args.serialize()?;
+    let accounts = vec![
+        AccountMeta::new(*stake_pool, false),
+        AccountMeta::new(*credit_ticket, false),
+        AccountMeta::new_readonly(withdraw_authority(program_id, stake_pool), false),
+        AccountMeta::new(*refund_account, false),
+        AccountMeta::new_readonly(*sol_target, false),
+        AccountMeta::new(*payer, false),
+        AccountMeta::new(*pool_mint, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(sysvar::epoch_schedule::id(), false),
+        AccountMeta::new_readonly(*token_program_id, false),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'deposit stake' instruction.
+/// `stake_authority` must be the withdraw authority of `user_stake`.
+pub fn deposit_stake(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    validator_stake_list_storage: &Pubkey,
+    user_stake: &Pubkey,
+    stake_authority: &Pubkey,
+    validator: &Pubkey,
+    stake_index: u32,
+    pool_tokens_to: &Pubkey,
+    pool_fee_to: &Pubkey,
+    pool_mint: &Pubkey,
+    token_program_id: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let args = StakePoolInstruction::DepositStake(stake_index);
+    let data = args.serialize()?;
+    let (validator_stake, _) =
+        ValidatorStakeInfo::find_stake_address(program_id, validator, stake_pool, stake_index);
+    let accounts = vec![
+        AccountMeta::new(*stake_pool, false),
+        AccountMeta::new(*validator_stake_list_storage, false),
+        AccountMeta::new_readonly(deposit_authority(program_id, stake_pool), false),
+        AccountMeta::new_readonly(withdraw_authority(program_id, stake_pool), false),
+        AccountMeta::new(*user_stake, false),
+        AccountMeta::new_readonly(*stake_authority, true),
+        AccountMeta::new(validator_stake, false),
+        AccountMeta::new(*pool_tokens_to, false),
+        AccountMeta::new(*pool_fee_to, false),
+        AccountMeta::new(*pool_mint, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(sysvar::epoch_schedule::id(), false),
+        AccountMeta::new_readonly(sysvar::stake_history::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(stake::id(), false),
+        AccountMeta::new_readonly(*token_program_id, false),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'withdraw stake' instruction.
+/// The withdraw authority must be approved as a delegate of the pool token account.
+pub fn withdraw_stake(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    validator_stake_list_storage: &Pubkey,
+    validator: &Pubkey,
+    stake_index: u32,
+    stake_to: &Pubkey,
+    user_stake_authority: &Pubkey,
+    burn_from: &Pubkey,
+    user_transfer_authority: &Pubkey,
+    pool_fee_to: &Pubkey,
+    pool_mint: &Pubkey,
+    token_program_id: &Pubkey,
+    amount: u64,
+) -> Result<Instruction, ProgramError> {
+    let args = StakePoolInstruction::WithdrawStake(WithdrawStakeArgs {
+        amount,
+        stake_index,
+    });
+    let data = args.serialize()?;
+    let (validator_stake, _) =
+        ValidatorStakeInfo::find_stake_address(program_id, validator, stake_pool, stake_index);
+    let accounts = vec![
+        AccountMeta::new(*stake_pool, false),
+        AccountMeta::new(*validator_stake_list_storage, false),
+        AccountMeta::new_readonly(deposit_authority(program_id, stake_pool), false),
+        AccountMeta::new_readonly(withdraw_authority(program_id, stake_pool), false),
+        AccountMeta::new(validator_stake, false),
+        AccountMeta::new(*stake_to, false),
+        AccountMeta::new_readonly(*user_stake_authority, false),
+        AccountMeta::new(*burn_from, false),
+        AccountMeta::new_readonly(*user_transfer_authority, true),
+        AccountMeta::new(*pool_fee_to, false),
+        AccountMeta::new(*pool_mint, false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(sysvar::epoch_schedule::id(), false),
+        AccountMeta::new_readonly(stake::id(), false),
+        AccountMeta::new_readonly(*token_program_id, false),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'delegate reserve' instruction.
+/// Every delegation is paired with the vote account of its validator.
+pub fn delegate_reserve(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    owner: &Pubkey,
+    validator_stake_list_storage: &Pubkey,
+    delegations: &[(Pubkey, DelegateReserveInstruction)],
+) -> Result<Instruction, ProgramError> {
+    let args = StakePoolInstruction::DelegateReserve(
+        delegations
+            .iter()
+            .map(|(_, delegation)| *delegation)
+            .collect(),
+    );
+    let data = args.serialize()?;
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*stake_pool, false),
+        AccountMeta::new_readonly(*owner, true),
+        AccountMeta::new(*validator_stake_list_storage, false),
+        AccountMeta::new_readonly(withdraw_authority(program_id, stake_pool), false),
+        AccountMeta::new_readonly(deposit_authority(program_id, stake_pool), false),
+        AccountMeta::new(reserve_address(program_id, stake_pool), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(stake::id(), false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(sysvar::stake_history::id(), false),
+        AccountMeta::new_readonly(stake_config_id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+    ];
+    for (validator, delegation) in delegations {
+        let (stake_account, _) = ValidatorStakeInfo::find_stake_address(
+            program_id,
+            validator,
+            stake_pool,
+            delegation.stake_index,
+        );
+        accounts.push(AccountMeta::new_readonly(*validator, false));
+        accounts.push(AccountMeta::new(stake_account, false));
+    }
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'delegate reserve to targets' instruction. Doesn't need the owner
+pub fn delegate_reserve_to_targets(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    validator_stake_list_storage: &Pubkey,
+    delegations: &[(Pubkey, DelegateReserveInstruction)],
+) -> Result<Instruction, ProgramError> {
+    let args = StakePoolInstruction::DelegateReserveToTargets(
+        delegations
+            .iter()
+            .map(|(_, delegation)| *delegation)
+            .collect(),
+    );
+    let data = args.serialize()?;
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*stake_pool, false),
+        AccountMeta::new(*validator_stake_list_storage, false),
+        AccountMeta::new_readonly(withdraw_authority(program_id, stake_pool), false),
+        AccountMeta::new_readonly(deposit_authority(program_id, stake_pool), false),
+        AccountMeta::new(reserve_address(program_id, stake_pool), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(stake::id(), false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(sysvar::stake_history::id(), false),
+        AccountMeta::new_readonly(stake_config_id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+    ];
+    for (validator, delegation) in delegations {
+        let (stake_account, _) = ValidatorStakeInfo::find_stake_address(
+            program_id,
+            validator,
+            stake_pool,
+            delegation.stake_index,
+        );
+        accounts.push(AccountMeta::new_readonly(*validator, false));
+        accounts.push(AccountMeta::new(stake_account, false));
+    }
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'merge stakes' instruction.
+pub fn merge_stakes(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    validator_stake_list_storage: &Pubkey,
+    merges: &[MergeStakesInstruction],
+) -> Result<Instruction, ProgramError> {
+    let args = StakePoolInstruction::MergeStakes(merges.to_vec());
+    let data = args.serialize()?;
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*stake_pool, false),
+        AccountMeta::new(*validator_stake_list_storage, false),
+        AccountMeta::new_readonly(deposit_authority(program_id, stake_pool), false),
+        AccountMeta::new_readonly(stake::id(), false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(sysvar::stake_history::id(), false),
+    ];
+    for merge in merges {
+        let validator = merge.validator_address;
+        let (main_stake, _) = ValidatorStakeInfo::find_stake_address(
+            program_id,
+            &validator,
+            stake_pool,
+            merge.main_index,
+        );
+        let (additional_stake, _) = ValidatorStakeInfo::find_stake_address(
+            program_id,
+            &validator,
+            stake_pool,
+            merge.additional_index,
+        );
+        accounts.push(AccountMeta::new(main_stake, false));
+        accounts.push(AccountMeta::new(additional_stake, false));
+    }
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates an 'unstake' instruction.
+pub fn unstake(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    owner: &Pubkey,
+    validator_stake_list_storage: &Pubkey,
+    unstakes: &[UnstakeInstruction],
+) -> Result<Instruction, ProgramError> {
+    let args = StakePoolInstruction::Unstake(unstakes.to_vec());
+    let data = args.serialize()?;
+    let mut accounts = vec![
+        AccountMeta::new(*stake_pool, false),
+        AccountMeta::new(*owner, true),
+        AccountMeta::new(*validator_stake_list_storage, false),
+        AccountMeta::new_readonly(deposit_authority(program_id, stake_pool), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(stake::id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+    ];
+    append_unstake_accounts(program_id, stake_pool, unstakes, &mut accounts);
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates an 'unstake for credits' instruction. Doesn't need the owner
+pub fn unstake_for_credits(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    validator_stake_list_storage: &Pubkey,
+    unstakes: &[UnstakeInstruction],
+) -> Result<Instruction, ProgramError> {
+    let args = StakePoolInstruction::UnstakeForCredits(unstakes.to_vec());
+    let data = args.serialize()?;
+    let mut accounts = vec![
+        AccountMeta::new(*stake_pool, false),
+        AccountMeta::new(*validator_stake_list_storage, false),
+        AccountMeta::new_readonly(deposit_authority(program_id, stake_pool), false),
+        AccountMeta::new(reserve_address(program_id, stake_pool), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(stake::id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+    ];
+    append_unstake_accounts(program_id, stake_pool, unstakes, &mut accounts);
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+fn append_unstake_accounts(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    unstakes: &[UnstakeInstruction],
+    accounts: &mut Vec<AccountMeta>,
+) {
+    for unstake in unstakes {
+        let validator = unstake.validator_address;
+        let (source_stake, _) = ValidatorStakeInfo::find_stake_address(
+            program_id,
+            &validator,
+            stake_pool,
+            unstake.source_index,
+        );
+        accounts.push(AccountMeta::new(source_stake, false));
+        if unstake.split_index != unstake.source_index {
+            let (split_stake, _) = ValidatorStakeInfo::find_stake_address(
+                program_id,
+                &validator,
+                stake_pool,
+                unstake.split_index,
+            );
+            accounts.push(AccountMeta::new(split_stake, false));
+        }
+    }
+}
+
+/// Creates a 'pay creditors' instruction.
+/// `tickets` are every credit ticket with its SOL target and rent payer
+pub fn pay_creditors(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    pool_fee_to: &Pubkey,
+    pool_mint: &Pubkey,
+    token_program_id: &Pubkey,
+    tickets: &[(Pubkey, Pubkey, Pubkey)],
+) -> Result<Instruction, ProgramError> {
+    let args = StakePoolInstruction::PayCreditors;
+    let data = args.serialize()?;
+    let mut accounts = vec![
+        AccountMeta::new(*stake_pool, false),
+        AccountMeta::new(reserve_address(program_id, stake_pool), false),
+        AccountMeta::new_readonly(withdraw_authority(program_id, stake_pool), false),
+        AccountMeta::new(*pool_fee_to, false),
+        AccountMeta::new(*pool_mint, false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(*token_program_id, false),
+    ];
+    for (ticket, sol_target, payer) in tickets {
+        accounts.push(AccountMeta::new(*ticket, false));
+        accounts.push(AccountMeta::new(*sol_target, false));
+        accounts.push(AccountMeta::new(*payer, false));
+    }
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'refund credit list' instruction.
+/// `refund_to` are the pool token accounts of the last list entries, last first
+pub fn refund_credit_list(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    owner: &Pubkey,
+    credit_list: &Pubkey,
+    credit_reserve: &Pubkey,
+    token_program_id: &Pubkey,
+    refund_to: &[Pubkey],
+) -> Result<Instruction, ProgramError> {
+    let args = StakePoolInstruction::RefundCreditList;
+    let data = args.serialize()?;
+    let mut accounts = vec![
+        AccountMeta::new(*stake_pool, false),
+        AccountMeta::new(*owner, true),
+        AccountMeta::new(*credit_list, false),
+        AccountMeta::new(*credit_reserve, false),
+        AccountMeta::new_readonly(withdraw_authority(program_id, stake_pool), false),
+        AccountMeta::new_readonly(*token_program_id, false),
+    ];
+    for token_account in refund_to {
+        accounts.push(AccountMeta::new(*token_account, false));
+    }
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'set freshness policy' instruction.
+pub fn set_freshness_policy(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    stake_pool_owner: &Pubkey,
+    policy: FreshnessPolicy,
+) -> Result<Instruction, ProgramError> {
+    let args = StakePoolInstruction::SetFreshnessPolicy(policy);
+    let data = args.serialize()?;
+    let accounts = vec![
+        AccountMeta::new(*stake_pool, false),
+        AccountMeta::new_readonly(*stake_pool_owner, true),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'set deposit fee' instruction.
+pub fn set_deposit_fee(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    stake_pool_owner: &Pubkey,
+    fee: Fee,
+) -> Result<Instruction, ProgramError> {
+    set_fee(
+        program_id,
+        stake_pool,
+        stake_pool_owner,
+        StakePoolInstruction::SetDepositFee(fee),
+    )
+}
+
+/// Creates a 'set withdrawal fee' instruction.
+pub fn set_withdrawal_fee(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    stake_pool_owner: &Pubkey,
+    fee: Fee,
+) -> Result<Instruction, ProgramError> {
+    set_fee(
+        program_id,
+        stake_pool,
+        stake_pool_owner,
+        StakePoolInstruction::SetWithdrawalFee(fee),
+    )
+}
+
+/// Creates a 'set performance fee' instruction.
+pub fn set_performance_fee(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    stake_pool_owner: &Pubkey,
+    fee: Fee,
+) -> Result<Instruction, ProgramError> {
+    set_fee(
+        program_id,
+        stake_pool,
+        stake_pool_owner,
+        StakePoolInstruction::SetPerformanceFee(fee),
+    )
+}
+
+fn set_fee(
+    program_id: &Pubkey,
+    stake_pool: &Pubkey,
+    stake_pool_owner: &Pubkey,
+    args: StakePoolInstruction,
+) -> Result<Instruction, ProgramError> {
+    let data = args.serialize()?;
+    let accounts = vec![
+        AccountMeta::new(*stake_pool, false),
+        AccountMeta::new_readonly(*stake_pool_owner, true),
+        AccountMeta::new_readonly(sysvar::clock::id(), false),
+    ];
+    Ok(Instruction {
+        program_id: *program_id,
+        accounts,
+        data,
+    })
+}
+
+fn withdraw_authority(program_id: &Pubkey, stake_pool: &Pubkey) -> Pubkey {
+    Processor::find_authority_bump_seed(program_id, stake_pool, Processor::AUTHORITY_WITHDRAW).0
+}
+
+fn deposit_authority(program_id: &Pubkey, stake_pool: &Pubkey) -> Pubkey {
+    Processor::find_authority_bump_seed(program_id, stake_pool, Processor::AUTHORITY_DEPOSIT).0
+}
+
+fn reserve_address(program_id: &Pubkey, stake_pool: &Pubkey) -> Pubkey {
+    Processor::get_reserve_adderess(program_id, stake_pool).0
+}
+
+fn credit_ticket_address(program_id: &Pubkey, stake_pool: &Pubkey, sequence: u64) -> Pubkey {
+    Processor::get_credit_ticket_address(program_id, stake_pool, sequence).0
+}
+
+fn temp_address(program_id: &Pubkey, stake_pool: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[&stake_pool.to_bytes()[..32], Processor::TEMP_ACCOUNT],
+        program_id,
+    )
+    .0
+}
+
+fn stake_config_id() -> Pubkey {
+    Pubkey::from_str(stake::STAKE_CONFIG).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::{collection::vec, prelude::*};
+
+    fn check_round_trip(instruction: &Instruction, expected: StakePoolInstruction) {
+        assert_eq!(instruction.program_id, crate::id());
+        assert_eq!(expected.serialize().unwrap(), instruction.data);
+        assert_eq!(
+            StakePoolInstruction::deserialize(&instruction.data).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_credit_round_trip() {
+        let stake_pool = Pubkey::new_unique();
+        let user_transfer_authority = Pubkey::new_unique();
+        let payer = Pubkey::new_unique();
+        let instruction = credit(
+            &crate::id(),
+            &stake_pool,
+            7,
+            &Pubkey::new_unique(),
+            &user_transfer_authority,
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &payer,
+            &spl_token::id(),
+            42,
+        )
+        .unwrap();
+        check_round_trip(&instruction, StakePoolInstruction::Credit(42));
+        assert_eq!(instruction.accounts.len(), 13);
+        assert_eq!(
+            instruction.accounts[1].pubkey,
+            Processor::get_credit_ticket_address(&crate::id(), &stake_pool, 7).0
+        );
+        assert_eq!(instruction.accounts[3].pubkey, user_transfer_authority);
+        assert!(instruction.accounts[3].is_signer);
+        assert_eq!(instruction.accounts[7].pubkey, payer);
+        assert!(instruction.accounts[7].is_signer);
+    }
+
+    #[test]
+    fn test_uncredit_round_trip() {
+        let payer = Pubkey::new_unique();
+        let cancel_authority = Pubkey::new_unique();
+        let instruction = uncredit(
+            &crate::id(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &payer,
+            &cancel_authority,
+            &Pubkey::new_unique(),
+            &spl_token::id(),
+            u64::MAX,
+        )
+        .unwrap();
+        check_round_trip(&instruction, StakePoolInstruction::Uncredit(u64::MAX));
+        assert_eq!(instruction.accounts[5].pubkey, payer);
+        assert!(instruction.accounts[5].is_writable);
+        assert_eq!(instruction.accounts[6].pubkey, cancel_authority);
+        assert!(instruction.accounts[6].is_signer);
+    }
+
+    #[test]
+    fn test_expire_credit_round_trip() {
+        let refund_account = Pubkey::new_unique();
+        let payer = Pubkey::new_unique();
+        let instruction = expire_credit(
+            &crate::id(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &refund_account,
+            &Pubkey::new_unique(),
+            &payer,
+            &Pubkey::new_unique(),
+            &spl_token::id(),
+        )
+        .unwrap();
+        check_round_trip(&instruction, StakePoolInstruction::ExpireCredit);
+        assert_eq!(instruction.accounts[3].pubkey, refund_account);
+        assert_eq!(instruction.accounts[5].pubkey, payer);
+        assert!(instruction
+            .accounts
+            .iter()
+            .all(|account| !account.is_signer));
+    }
+
+    #[test]
+    fn test_deposit_stake_round_trip() {
+        let stake_pool = Pubkey::new_unique();
+        let validator = Pubkey::new_unique();
+        let stake_authority = Pubkey::new_unique();
+        let instruction = deposit_stake(
+            &crate::id(),
+            &stake_pool,
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &stake_authority,
+            &validator,
+            3,
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &spl_token::id(),
+        )
+        .unwrap();
+        check_round_trip(&instruction, StakePoolInstruction::DepositStake(3));
+        assert_eq!(instruction.accounts[5].pubkey, stake_authority);
+        assert!(instruction.accounts[5].is_signer);
+        assert_eq!(
+            instruction.accounts[6].pubkey,
+            ValidatorStakeInfo::find_stake_address(&crate::id(), &validator, &stake_pool, 3).0
+        );
+    }
+
+    #[test]
+    fn test_withdraw_stake_round_trip() {
+        let stake_pool = Pubkey::new_unique();
+        let validator = Pubkey::new_unique();
+        let stake_to = Pubkey::new_unique();
+        let user_transfer_authority = Pubkey::new_unique();
+        let instruction = withdraw_stake(
+            &crate::id(),
+            &stake_pool,
+            &Pubkey::new_unique(),
+            &validator,
+            2,
+            &stake_to,
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &user_transfer_authority,
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &spl_token::id(),
+            1_000,
+        )
+        .unwrap();
+        check_round_trip(
+            &instruction,
+            StakePoolInstruction::WithdrawStake(WithdrawStakeArgs {
+                amount: 1_000,
+                stake_index: 2,
+            }),
+        );
+        assert_eq!(
+            instruction.accounts[4].pubkey,
+            ValidatorStakeInfo::find_stake_address(&crate::id(), &validator, &stake_pool, 2).0
+        );
+        assert_eq!(instruction.accounts[5].pubkey, stake_to);
+        let signers: Vec<_> = instruction
+            .accounts
+            .iter()
+            .filter(|account| account.is_signer)
+            .map(|account| account.pubkey)
+            .collect();
+        assert_eq!(signers, vec![user_transfer_authority]);
+    }
+
+    #[test]
+    fn test_slippage_round_trip() {
+        let stake_pool = Pubkey::new_unique();
+        let user = Pubkey::new_unique();
+        let args = SlippageArgs {
+            amount: 10,
+            minimum_out: 9,
+        };
+        let accounts = |instruction: &Instruction| {
+            instruction
+                .accounts
+                .iter()
+                .map(|account| (account.pubkey, account.is_signer, account.is_writable))
+                .collect::<Vec<_>>()
+        };
+
+        let plain = deposit(
+            &crate::id(),
+            &stake_pool,
+            &user,
+            &user,
+            &user,
+            &user,
+            &spl_token::id(),
+            10,
+        )
+        .unwrap();
+        let instruction = deposit_with_slippage(
+            &crate::id(),
+            &stake_pool,
+            &user,
+            &user,
+            &user,
+            &user,
+            &spl_token::id(),
+            10,
+            9,
+        )
+        .unwrap();
+        check_round_trip(
+            &instruction,
+            StakePoolInstruction::DepositWithSlippage(args),
+        );
+        assert_eq!(accounts(&instruction), accounts(&plain));
+
+        let instruction = deposit_wrapped_with_slippage(
+            &crate::id(),
+            &stake_pool,
+            &user,
+            &user,
+            &user,
+            &user,
+            &spl_token::id(),
+            10,
+            9,
+        )
+        .unwrap();
+        check_round_trip(
+            &instruction,
+            StakePoolInstruction::DepositWithSlippage(args),
+        );
+        assert_eq!(instruction.accounts.len(), 14);
+
+        let plain = withdraw(
+            &crate::id(),
+            &stake_pool,
+            &user,
+            &user,
+            &user,
+            &user,
+            &spl_token::id(),
+            10,
+        )
+        .unwrap();
+        let instruction = withdraw_with_slippage(
+            &crate::id(),
+            &stake_pool,
+            &user,
+            &user,
+            &user,
+            &user,
+            &spl_token::id(),
+            10,
+            9,
+        )
+        .unwrap();
+        check_round_trip(
+            &instruction,
+            StakePoolInstruction::WithdrawWithSlippage(args),
+        );
+        assert_eq!(accounts(&instruction), accounts(&plain));
+    }
+
+    #[test]
+    fn test_delegate_reserve_round_trip() {
+        let stake_pool = Pubkey::new_unique();
+        let validator = Pubkey::new_unique();
+        let delegations = vec![
+            (
+                validator,
+                DelegateReserveInstruction {
+                    amount: 1_000_000_000,
+                    stake_index: 0,
+                },
+            ),
+            (
+                Pubkey::new_unique(),
+                DelegateReserveInstruction {
+                    amount: 5,
+                    stake_index: 3,
+                },
+            ),
+        ];
+        let instruction = delegate_reserve(
+            &crate::id(),
+            &stake_pool,
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &delegations,
+        )
+        .unwrap();
+        check_round_trip(
+            &instruction,
+            StakePoolInstruction::DelegateReserve(
+                delegations.iter().map(|(_, item)| *item).collect(),
+            ),
+        );
+        assert_eq!(instruction.accounts.len(), 12 + 2 * delegations.len());
+        assert_eq!(
+            instruction.accounts[5].pubkey,
+            reserve_address(&crate::id(), &stake_pool)
+        );
+        assert_eq!(instruction.accounts[12].pubkey, validator);
+        assert_eq!(
+            instruction.accounts[13].pubkey,
+            ValidatorStakeInfo::find_stake_address(&crate::id(), &validator, &stake_pool, 0).0
+        );
+    }
+
+    #[test]
+    fn test_merge_stakes_round_trip() {
+        let merges = vec![MergeStakesInstruction {
+            validator_address: Pubkey::new_unique(),
+            main_index: 1,
+            additional_index: 7,
+        }];
+        let instruction = merge_stakes(
+            &crate::id(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &merges,
+        )
+        .unwrap();
+        check_round_trip(&instruction, StakePoolInstruction::MergeStakes(merges));
+        assert_eq!(instruction.accounts.len(), 6 + 2);
+    }
+
+    #[test]
+    fn test_unstake_round_trip() {
+        let validator_address = Pubkey::new_unique();
+        let unstakes = vec![
+            UnstakeInstruction {
+                validator_address,
+                source_index: 0,
+                split_index: 0,
+                amount: 0,
+            },
+            UnstakeInstruction {
+                validator_address,
+                source_index: 0,
+                split_index: 2,
+                amount: 123_456_789,
+            },
+        ];
+        let instruction = unstake(
+            &crate::id(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &unstakes,
+        )
+        .unwrap();
+        check_round_trip(&instruction, StakePoolInstruction::Unstake(unstakes));
+        // Full unstake takes one account, split takes two
+        assert_eq!(instruction.accounts.len(), 8 + 1 + 2);
+    }
+
+    #[test]
+    fn test_pay_creditors_round_trip() {
+        let tickets = vec![
+            (
+                Pubkey::new_unique(),
+                Pubkey::new_unique(),
+                Pubkey::new_unique(),
+            ),
+            (
+                Pubkey::new_unique(),
+                Pubkey::new_unique(),
+                Pubkey::new_unique(),
+            ),
+        ];
+        let instruction = pay_creditors(
+            &crate::id(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &spl_token::id(),
+            &tickets,
+        )
+        .unwrap();
+        check_round_trip(&instruction, StakePoolInstruction::PayCreditors);
+        assert_eq!(instruction.accounts.len(), 15);
+        assert_eq!(instruction.accounts[9].pubkey, tickets[0].0);
+        assert_eq!(instruction.accounts[10].pubkey, tickets[0].1);
+        assert_eq!(instruction.accounts[11].pubkey, tickets[0].2);
+        assert_eq!(instruction.accounts[13].pubkey, tickets[1].1);
+        assert_eq!(instruction.accounts[14].pubkey, tickets[1].2);
+    }
+
+    #[test]
+    fn test_refund_credit_list_round_trip() {
+        let owner = Pubkey::new_unique();
+        let refund_to = vec![Pubkey::new_unique(), Pubkey::new_unique()];
+        let instruction = refund_credit_list(
+            &crate::id(),
+            &Pubkey::new_unique(),
+            &owner,
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &spl_token::id(),
+            &refund_to,
+        )
+        .unwrap();
+        check_round_trip(&instruction, StakePoolInstruction::RefundCreditList);
+        assert_eq!(instruction.accounts.len(), 8);
+        assert_eq!(instruction.accounts[1].pubkey, owner);
+        assert!(instruction.accounts[1].is_signer);
+        assert_eq!(instruction.accounts[6].pubkey, refund_to[0]);
+        assert_eq!(instruction.accounts[7].pubkey, refund_to[1]);
+    }
+
+    #[test]
+    fn test_set_fee_round_trip() {
+        let fee = Fee {
+            denominator: 100,
+            numerator: 3,
+        };
+        let instruction = set_withdrawal_fee(
+            &crate::id(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            fee,
+        )
+        .unwrap();
+        check_round_trip(&instruction, StakePoolInstruction::SetWithdrawalFee(fee));
+        assert!(instruction.accounts[1].is_signer);
+    }
+
+    #[test]
+    fn test_wire_format_vectors() {
+        #[rustfmt::skip]
+        let vectors: Vec<(StakePoolInstruction, Vec<u8>)> = vec![
+            (
+                StakePoolInstruction::Initialize(InitArgs {
+                    deposit_fee: Fee { denominator: 100, numerator: 1 },
+                    withdrawal_fee: Fee { denominator: 1_000, numerator: 5 },
+                    performance_fee: Fee::default(),
+                    freshness_policy: FreshnessPolicy::grace(0x0102),
+                }),
+                vec![
+                    0,
+                    100, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0,
+                    0xe8, 3, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0,
+                    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                    2, 1, 0, 0, 0, 0, 0, 0,
+                ],
+            ),
+            (StakePoolInstruction::AddValidator, vec![2]),
+            (StakePoolInstruction::RemoveValidator, vec![3]),
+            (StakePoolInstruction::UpdateListBalance, vec![4]),
+            (StakePoolInstruction::UpdatePoolBalance, vec![5]),
+            (
+                StakePoolInstruction::Deposit(0x0102_0304_0506_0708),
+                vec![6, 8, 7, 6, 5, 4, 3, 2, 1],
+            ),
+            (
+                StakePoolInstruction::Withdraw(1_000_000_000),
+                vec![7, 0, 0xca, 0x9a, 0x3b, 0, 0, 0, 0],
+            ),
+            (StakePoolInstruction::SetStakingAuthority, vec![8]),
+            (StakePoolInstruction::ProposeOwner, vec![9]),
+            (StakePoolInstruction::Credit(1), vec![10, 1, 0, 0, 0, 0, 0, 0, 0]),
+            (StakePoolInstruction::Uncredit(u64::MAX), vec![11, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]),
+            (
+                StakePoolInstruction::DelegateReserve(vec![
+                    DelegateReserveInstruction { amount: 0x0100, stake_index: 2 },
+                    DelegateReserveInstruction { amount: 3, stake_index: 0x0400 },
+                ]),
+                vec![
+                    12,
+                    2, 0, 0, 0,
+                    0, 1, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0,
+                    3, 0, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0,
+                ],
+            ),
+            (
+                StakePoolInstruction::MergeStakes(vec![MergeStakesInstruction {
+                    validator_address: Pubkey::new(&[7; 32]),
+                    main_index: 1,
+                    additional_index: 2,
+                }]),
+                [&[13, 1, 0, 0, 0][..], &[7; 32], &[1, 0, 0, 0, 2, 0, 0, 0]].concat(),
+            ),
+            (
+                StakePoolInstruction::Unstake(vec![UnstakeInstruction {
+                    validator_address: Pubkey::new(&[9; 32]),
+                    source_index: 3,
+                    split_index: 4,
+                    amount: 5,
+                }]),
+                [&[14, 1, 0, 0, 0][..], &[9; 32], &[3, 0, 0, 0, 4, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0]].concat(),
+            ),
+            (StakePoolInstruction::Unstake(vec![]), vec![14, 0, 0, 0, 0]),
+            (StakePoolInstruction::PayCreditors, vec![15]),
+            (
+                StakePoolInstruction::SetFreshnessPolicy(FreshnessPolicy::PERMISSIVE),
+                vec![16, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            ),
+            (
+                StakePoolInstruction::SetDepositFee(Fee { denominator: 100, numerator: 2 }),
+                vec![17, 100, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0],
+            ),
+            (
+                StakePoolInstruction::SetWithdrawalFee(Fee { denominator: 10, numerator: 1 }),
+                vec![18, 10, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0],
+            ),
+            (
+                StakePoolInstruction::SetPerformanceFee(Fee { denominator: 4, numerator: 1 }),
+                vec![19, 4, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0],
+            ),
+            (StakePoolInstruction::AcceptOwner, vec![20]),
+            (StakePoolInstruction::CancelOwnerProposal, vec![21]),
+            (StakePoolInstruction::MigrateState, vec![22]),
+            (
+                StakePoolInstruction::SetValidatorTargetWeight(0x0102),
+                vec![23, 2, 1, 0, 0],
+            ),
+            (
+                StakePoolInstruction::DelegateReserveToTargets(vec![
+                    DelegateReserveInstruction { amount: 5, stake_index: 1 },
+                ]),
+                vec![24, 1, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0],
+            ),
+            (StakePoolInstruction::SetLiquidityTarget(500), vec![25, 0xf4, 1]),
+            (
+                StakePoolInstruction::UnstakeForCredits(vec![UnstakeInstruction {
+                    validator_address: Pubkey::new(&[6; 32]),
+                    source_index: 0,
+                    split_index: 0,
+                    amount: 0x0100,
+                }]),
+                [&[26, 1, 0, 0, 0][..], &[6; 32], &[0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]].concat(),
+            ),
+            (StakePoolInstruction::UpdateListBalancePage, vec![27]),
+            (StakePoolInstruction::ReallocList, vec![28]),
+            (StakePoolInstruction::ExpireCredit, vec![29]),
+            (
+                StakePoolInstruction::SetCreditOrdering(CreditOrdering::SkipAhead),
+                vec![30, 1],
+            ),
+            (StakePoolInstruction::DepositStake(0x0102), vec![31, 2, 1, 0, 0]),
+            (
+                StakePoolInstruction::WithdrawStake(WithdrawStakeArgs { amount: 0x0100, stake_index: 3 }),
+                vec![32, 0, 1, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0],
+            ),
+            (
+                StakePoolInstruction::DepositWithSlippage(SlippageArgs { amount: 5, minimum_out: 0x0100 }),
+                vec![33, 5, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0],
+            ),
+            (
+                StakePoolInstruction::WithdrawWithSlippage(SlippageArgs { amount: 0x0100, minimum_out: 5 }),
+                vec![34, 0, 1, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0],
+            ),
+            (StakePoolInstruction::RefundCreditList, vec![35]),
+        ];
+        for (instruction, data) in vectors {
+            assert_eq!(instruction.serialize().unwrap(), data, "{:?}", instruction);
+            assert_eq!(StakePoolInstruction::deserialize(&data), Ok(instruction));
+        }
+
+        // Tag 1 is not assigned
+        assert!(StakePoolInstruction::deserialize(&[1]).is_err());
+        // Truncated payloads
+        assert!(StakePoolInstruction::deserialize(&[6, 1, 0, 0]).is_err());
+        assert_eq!(
+            StakePoolInstruction::deserialize(&[
+                12, 2, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
+            ]),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert!(StakePoolInstruction::deserialize(&[12, 0xff, 0xff, 0xff, 0xff]).is_err());
+        // Unknown enum values
+        assert_eq!(
+            StakePoolInstruction::deserialize(&[30, 2]),
+            Err(ProgramError::InvalidArgument)
+        );
+        assert!(StakePoolInstruction::deserialize(&[30]).is_err());
+    }
+
+    #[test]
+    fn test_fee_cap() {
+        let max = Fee {
+            denominator: 10,
+            numerator: 1,
+        };
+        let fee = |numerator, denominator| Fee {
+            denominator,
+            numerator,
+        };
+        assert!(fee(0, 0).is_within(&max));
+        assert!(fee(1, 10).is_within(&max));
+        assert!(fee(9, 100).is_within(&max));
+        assert!(!fee(11, 100).is_within(&max));
+        assert!(!fee(1, 0).is_within(&max));
+        assert!(!fee(1, 1).is_within(&fee(0, 0)));
+        assert_eq!(fee(3, 100).apply(1_000), Some(30));
+        assert_eq!(fee(0, 0).apply(1_000), Some(0));
+    }
+
+    prop_compose! {
+        fn fee()(denominator in any::<u64>(), numerator in any::<u64>()) -> Fee {
+            Fee { denominator, numerator }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_init_args_pack_round_trip(
+            deposit_fee in fee(),
+            withdrawal_fee in fee(),
+            performance_fee in fee(),
+            grace_slots in any::<u64>(),
+        ) {
+            let init_args = InitArgs {
+                deposit_fee,
+                withdrawal_fee,
+                performance_fee,
+                freshness_policy: FreshnessPolicy::grace(grace_slots),
+            };
+            let mut data = [0; InitArgs::LEN];
+            InitArgs::pack(init_args, &mut data).unwrap();
+            prop_assert_eq!(InitArgs::unpack_unchecked(&data), Ok(init_args));
+            prop_assert_eq!(&data[..8], &deposit_fee.denominator.to_le_bytes()[..]);
+            prop_assert_eq!(&data[48..], &grace_slots.to_le_bytes()[..]);
+
+            let mut data = [0; Fee::LEN];
+            Fee::pack(performance_fee, &mut data).unwrap();
+            prop_assert_eq!(Fee::unpack_unchecked(&data), Ok(performance_fee));
+        }
+
+        #[test]
+        fn test_deserialize_arbitrary(tag in 0u8..36, data in vec(any::<u8>(), 0..128)) {
+            let mut input = vec![tag];
+            input.extend_from_slice(&data);
+            if let Ok(instruction) = StakePoolInstruction::deserialize(&input) {
+                let output = instruction.serialize().unwrap();
+                prop_assert_eq!(&output[..], &input[..output.len()]);
+            }
+        }
+    }
+}
@@ -8,8 +8,8 @@ pub mod processor;
 pub mod stake;
 pub mod state;
 
-/// Current program version
-pub const PROGRAM_VERSION: u8 = 1;
+/// Current program version. Also the version of the StakePool layout
+pub const PROGRAM_VERSION: u8 = 2;
 
 #[cfg(not(feature = "no-entrypoint"))]
 pub mod entrypoint;
@@ -1,17 +1,15 @@
 //! Program state processor
 
-use std::mem::swap;
-
 use crate::{
     error::StakePoolError,
     instruction::{
-        DelegateReserveInstruction, InitArgs, MergeStakesInstruction, StakePoolInstruction,
-        UnstakeInstruction,
+        CreditOrdering, DelegateReserveInstruction, Fee, FreshnessPolicy, InitArgs,
+        MergeStakesInstruction, StakePoolInstruction, UnstakeInstruction, WithdrawStakeArgs,
     },
-    stake::{self, delegate_stake, split_only, StakeState},
+    stake::{self, StakeState},
     state::{
-        self, CreditList, CreditRecord, StakePool, ValidatorStakeInfo, ValidatorStakeList,
-        MAX_CREDIT_RECORDS, MIN_STAKE_ACCOUNT_BALANCE,
+        CreditList, CreditQueueItem, CreditRecord, FeeType, StakePool, ValidatorStakeInfo,
+        ValidatorStakeList, MIN_STAKE_ACCOUNT_BALANCE,
     },
     PROGRAM_VERSION,
 };
@@ -23,13 +21,15 @@ use solana_program::{
     clock::Clock,
     decode_error::DecodeError,
     entrypoint::ProgramResult,
+    epoch_schedule::EpochSchedule,
     msg,
     program::{invoke, invoke_signed},
     program_error::PrintProgramError,
     program_error::ProgramError,
-    program_pack::Pack,
+    program_pack::{IsInitialized, Pack},
     pubkey::Pubkey,
     rent::Rent,
+    stake_history::StakeHistory,
     system_instruction, system_program,
     sysvar::Sysvar,
 };
@@ -46,6 +46,8 @@ impl Processor {
     pub const AUTHORITY_RESERVE: &'static [u8] = b"reserve";
     /// Suffix for temp account
     pub const TEMP_ACCOUNT: &'static [u8] = b"temp";
+    /// Suffix for credit ticket seed, followed by the ticket sequence number
+    pub const CREDIT_TICKET: &'static [u8] = b"credit";
     /// Calculates the authority id by generating a program address.
     pub fn authority_id(
         program_id: &Pubkey,
@@ -97,12 +99,81 @@ impl Processor {
         )
     }
 
+    /// Get address for the credit ticket with `sequence` number
+    pub fn get_credit_ticket_address(
+        program_id: &Pubkey,
+        stake_pool: &Pubkey,
+        sequence: u64,
+    ) -> (Pubkey, u8) {
+        Pubkey::find_program_address(
+            &[
+                &stake_pool.to_bytes()[..32],
+                &Self::CREDIT_TICKET,
+                &sequence.to_le_bytes(),
+            ],
+            program_id,
+        )
+    }
+
+    /// Zeroes the data of a program account and moves all its lamports out
+    pub fn close_account(account: &AccountInfo, lamports_to: &AccountInfo) -> ProgramResult {
+        for byte in account.data.borrow_mut().iter_mut() {
+            *byte = 0;
+        }
+        **lamports_to.lamports.borrow_mut() = lamports_to
+            .lamports()
+            .checked_add(account.lamports())
+            .ok_or(StakePoolError::CalculationFailure)?;
+        **account.lamports.borrow_mut() = 0;
+        Ok(())
+    }
+
+    /// Reads an open credit ticket of the stake pool
+    pub fn read_credit_ticket(
+        program_id: &Pubkey,
+        stake_pool: &Pubkey,
+        ticket_info: &AccountInfo,
+    ) -> Result<CreditRecord, ProgramError> {
+        if ticket_info.owner != program_id {
+            msg!(
+                "Wrong owner {} for the credit ticket {}. Expected {}",
+                ticket_info.owner,
+                ticket_info.key,
+                program_id
+            );
+            return Err(StakePoolError::UnknownCreditor.into());
+        }
+        let ticket = CreditRecord::unpack_from_slice(&ticket_info.data.borrow())?;
+        if !ticket.is_initialized() || ticket.stake_pool != *stake_pool {
+            msg!(
+                "Account {} is not an open credit ticket of the pool",
+                ticket_info.key
+            );
+            return Err(StakePoolError::UnknownCreditor.into());
+        }
+        Ok(ticket)
+    }
+
+    /// Checks that the account is the payer of the ticket rent
+    pub fn check_ticket_payer(ticket: &CreditRecord, payer_info: &AccountInfo) -> ProgramResult {
+        if *payer_info.key != ticket.payer {
+            msg!(
+                "Credit ticket #{} rent goes to {} but got {}",
+                ticket.sequence,
+                &ticket.payer,
+                payer_info.key
+            );
+            return Err(ProgramError::InvalidArgument);
+        }
+        Ok(())
+    }
+
     /// Returns validator address for a particular stake account
     pub fn get_validator(stake_account_info: &AccountInfo) -> Result<Pubkey, ProgramError> {
         let stake_state: stake::StakeState = deserialize(&stake_account_info.data.borrow())
-            .or_else(|_| {
+            .map_err(|_| {
                 msg!("Error reading stake {} state", stake_account_info.key);
-                Err(ProgramError::InvalidAccountData)
+                ProgramError::InvalidAccountData
             })?;
         match stake_state {
             stake::StakeState::Stake(_, stake) => Ok(stake.delegation.voter_pubkey),
@@ -142,6 +213,46 @@ impl Processor {
         )
     }
 
+    /// Issue a spl_token `Burn` instruction signed by the token owner or delegate.
+    pub fn user_token_burn<'a>(
+        token_program: AccountInfo<'a>,
+        burn_account: AccountInfo<'a>,
+        mint: AccountInfo<'a>,
+        user_authority: AccountInfo<'a>,
+        amount: u64,
+    ) -> Result<(), ProgramError> {
+        let ix = spl_token::instruction::burn(
+            token_program.key,
+            burn_account.key,
+            mint.key,
+            user_authority.key,
+            &[],
+            amount,
+        )?;
+
+        invoke(&ix, &[burn_account, mint, user_authority, token_program])
+    }
+
+    /// Issue a spl_token `Transfer` instruction signed by the token owner or delegate.
+    pub fn user_token_transfer<'a>(
+        token_program: AccountInfo<'a>,
+        source: AccountInfo<'a>,
+        destination: AccountInfo<'a>,
+        user_authority: AccountInfo<'a>,
+        amount: u64,
+    ) -> Result<(), ProgramError> {
+        let ix = spl_token::instruction::transfer(
+            token_program.key,
+            source.key,
+            destination.key,
+            user_authority.key,
+            &[],
+            amount,
+        )?;
+
+        invoke(&ix, &[source, destination, user_authority, token_program])
+    }
+
     /// Issue a spl_token `MintTo` instruction.
     #[allow(clippy::too_many_arguments)]
     pub fn token_mint_to<'a>(
@@ -170,6 +281,38 @@ impl Processor {
         invoke_signed(&ix, &[mint, destination, authority, token_program], signers)
     }
 
+    /// Issue a spl_token `Transfer` instruction.
+    #[allow(clippy::too_many_arguments)]
+    pub fn token_transfer<'a>(
+        stake_pool: &Pubkey,
+        token_program: AccountInfo<'a>,
+        source: AccountInfo<'a>,
+        destination: AccountInfo<'a>,
+        authority: AccountInfo<'a>,
+        authority_type: &[u8],
+        bump_seed: u8,
+        amount: u64,
+    ) -> Result<(), ProgramError> {
+        let me_bytes = stake_pool.to_bytes();
+        let authority_signature_seeds = [&me_bytes[..32], authority_type, &[bump_seed]];
+        let signers = &[&authority_signature_seeds[..]];
+
+        let ix = spl_token::instruction::transfer(
+            token_program.key,
+            source.key,
+            destination.key,
+            authority.key,
+            &[],
+            amount,
+        )?;
+
+        invoke_signed(
+            &ix,
+            &[source, destination, authority, token_program],
+            signers,
+        )
+    }
+
     /// Processes `Initialize` instruction.
     pub fn process_initialize(
         program_id: &Pubkey,
@@ -180,7 +323,6 @@ impl Processor {
         let stake_pool_info = next_account_info(account_info_iter)?;
         let owner_info = next_account_info(account_info_iter)?;
         let validator_stake_list_info = next_account_info(account_info_iter)?;
-        let credit_list_info = next_account_info(account_info_iter)?;
         let pool_mint_info = next_account_info(account_info_iter)?;
         let owner_fee_info = next_account_info(account_info_iter)?;
         let credit_reserve_info = next_account_info(account_info_iter)?;
@@ -221,14 +363,10 @@ impl Processor {
         }
         validator_stake_list.version = ValidatorStakeList::VALIDATOR_STAKE_LIST_VERSION;
         validator_stake_list.validators.clear();
-
-        // Check if credit list storage is unitialized
-        let mut credit_list = CreditList::deserialize(&credit_list_info.data.borrow())?;
-        if credit_list.is_initialized() {
-            return Err(StakePoolError::AlreadyInUse.into());
+        if ValidatorStakeList::capacity(validator_stake_list_info.data_len()) == 0 {
+            msg!("Validator stake list account can't fit a validator");
+            return Err(ProgramError::AccountDataTooSmall);
         }
-        credit_list.version = CreditList::VERSION;
-        credit_list.credits.clear();
 
         // Check if stake pool account is rent-exempt
         if !rent.is_exempt(stake_pool_info.lamports(), stake_pool_info.data_len()) {
@@ -243,11 +381,6 @@ impl Processor {
             return Err(StakePoolError::AccountNotRentExempt.into());
         }
 
-        // Check if credit list account is rent-exempt
-        if !rent.is_exempt(credit_list_info.lamports(), credit_list_info.data_len()) {
-            return Err(StakePoolError::AccountNotRentExempt.into());
-        }
-
         let (_, deposit_bump_seed) = Self::find_authority_bump_seed(
             program_id,
             stake_pool_info.key,
@@ -259,8 +392,15 @@ impl Processor {
             Self::AUTHORITY_WITHDRAW,
         );
 
-        // Numerator should be smaller than or equal to denominator (fee <= 1)
-        if init.fee.numerator > init.fee.denominator {
+        // Every fee must be within its cap
+        if !init.deposit_fee.is_within(&StakePool::MAX_DEPOSIT_FEE)
+            || !init
+                .withdrawal_fee
+                .is_within(&StakePool::MAX_WITHDRAWAL_FEE)
+            || !init
+                .performance_fee
+                .is_within(&StakePool::MAX_PERFORMANCE_FEE)
+        {
             return Err(StakePoolError::FeeTooHigh.into());
         }
 
@@ -343,14 +483,13 @@ impl Processor {
                 &[],
             )?,
             &[
-                token_program_info.clone(),
                 owner_info.clone(),
                 credit_reserve_info.clone(),
+                token_program_info.clone(),
             ],
         )?;
 
         validator_stake_list.serialize(&mut validator_stake_list_info.data.borrow_mut())?;
-        credit_list.serialize(&mut credit_list_info.data.borrow_mut())?;
 
         msg!("Clock data: {:?}", clock_info.data.borrow());
         msg!("Epoch: {}", clock.epoch);
@@ -360,13 +499,16 @@ impl Processor {
         stake_pool.deposit_bump_seed = deposit_bump_seed;
         stake_pool.withdraw_bump_seed = withdraw_bump_seed;
         stake_pool.validator_stake_list = *validator_stake_list_info.key;
-        stake_pool.credit_list = *credit_list_info.key;
         stake_pool.pool_mint = *pool_mint_info.key;
         stake_pool.owner_fee_account = *owner_fee_info.key;
         stake_pool.credit_reserve = *credit_reserve_info.key;
         stake_pool.token_program_id = *token_program_info.key;
         stake_pool.last_update_epoch = clock.epoch;
-        stake_pool.fee = init.fee;
+        stake_pool.last_update_slot = clock.slot;
+        stake_pool.deposit_fee = init.deposit_fee;
+        stake_pool.withdrawal_fee = init.withdrawal_fee;
+        stake_pool.performance_fee = init.performance_fee;
+        stake_pool.freshness_policy = init.freshness_policy;
 
         stake_pool.serialize(&mut stake_pool_info.data.borrow_mut())
     }
@@ -421,7 +563,10 @@ impl Processor {
             return Err(StakePoolError::InvalidState.into());
         }
 
-        if validator_stake_list.validators.len() >= crate::state::MAX_VALIDATORS {
+        if validator_stake_list.validators.len()
+            >= ValidatorStakeList::capacity(validator_stake_list_info.data_len())
+        {
+            msg!("Validator stake list is full. Move it to a bigger account with ReallocList");
             return Err(StakePoolError::ValidatorListOverflow.into());
         }
 
@@ -431,10 +576,14 @@ impl Processor {
 
         // Add validator to the list and save
         validator_stake_list.validators.push(ValidatorStakeInfo {
-            validator_account: validator_info.key.clone(),
+            validator_account: *validator_info.key,
             balance: 0,
             last_update_epoch: clock.epoch,
             stake_count: 0,
+            target_weight: 0,
+            activating_lamports: 0,
+            deactivating_lamports: 0,
+            active_lamports: 0,
         });
         validator_stake_list.serialize(&mut validator_stake_list_info.data.borrow_mut())?;
 
@@ -494,6 +643,18 @@ impl Processor {
             return Err(StakePoolError::InvalidState.into());
         }
 
+        // Removing shifts the validator indices the paged update walks
+        let cursor = stake_pool
+            .list_update_cursor
+            .start(clock.epoch, validator_stake_list.validators.len());
+        if cursor.validator_index > 0 || cursor.stake_index > 0 {
+            msg!(
+                "Paged list update stopped at validator {}. Finish it with UpdateListBalancePage",
+                cursor.validator_index
+            );
+            return Err(StakePoolError::ListUpdateInProgress.into());
+        }
+
         if let Some(index) = validator_stake_list
             .validators
             .iter()
@@ -512,10 +673,11 @@ impl Processor {
         Ok(())
     }
 
-    /// Processes `UpdateListBalance` instruction.
+    /// Processes `UpdateListBalance` and `UpdateListBalancePage` instructions.
     pub fn process_update_list_balance(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
+        paged: bool,
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
         // Stake pool account
@@ -530,6 +692,7 @@ impl Processor {
         let clock_info = next_account_info(account_info_iter)?;
         let clock = &Clock::from_account_info(clock_info)?;
         let stake_history_info = next_account_info(account_info_iter)?;
+        let stake_history = &StakeHistory::from_account_info(stake_history_info)?;
 
         if stake_pool_info.owner != program_id {
             msg!(
@@ -541,7 +704,7 @@ impl Processor {
             return Err(StakePoolError::WrongOwner.into());
         }
         // Get stake pool stake (and check if it is initialized)
-        let stake_pool = StakePool::deserialize(&stake_pool_info.data.borrow())?;
+        let mut stake_pool = StakePool::deserialize(&stake_pool_info.data.borrow())?;
         if !stake_pool.is_initialized() {
             return Err(StakePoolError::InvalidState.into());
         }
@@ -570,126 +733,128 @@ impl Processor {
             return Err(ProgramError::InvalidArgument);
         }
 
+        if paged {
+            let mut cursor = stake_pool
+                .list_update_cursor
+                .start(clock.epoch, validator_stake_list.validators.len());
+            while let Some(validator_vote_info) = account_info_iter.next() {
+                let validator_stake_record = validator_stake_list
+                    .validators
+                    .get_mut(cursor.validator_index as usize)
+                    .ok_or_else(|| {
+                        msg!("Every validator is updated in this round");
+                        StakePoolError::ValidatorNotFound
+                    })?;
+                if validator_stake_record.validator_account != *validator_vote_info.key {
+                    msg!(
+                        "Expected validator {} but got {}",
+                        validator_stake_record.validator_account,
+                        validator_vote_info.key
+                    );
+                    return Err(StakePoolError::ValidatorNotFound.into());
+                }
+
+                if cursor.stake_index == 0 {
+                    // The record is stale until its last stake account is counted,
+                    // even when a round already finished in this epoch
+                    validator_stake_record.last_update_epoch = clock.epoch.saturating_sub(1);
+                    validator_stake_record.balance = 0;
+                    validator_stake_record.activating_lamports = 0;
+                    validator_stake_record.deactivating_lamports = 0;
+                    validator_stake_record.active_lamports = 0;
+                    cursor.stake_count = 0;
+                }
+                while cursor.stake_index < validator_stake_record.stake_count {
+                    let stake_account_info = match account_info_iter.next() {
+                        Some(stake_account_info) => stake_account_info,
+                        None => break,
+                    };
+                    if Self::update_validator_stake(
+                        program_id,
+                        stake_pool_info,
+                        &stake_pool,
+                        validator_stake_record,
+                        cursor.stake_index,
+                        stake_account_info,
+                        withdraw_info,
+                        reserve_account_info,
+                        system_program_info,
+                        stake_program_info,
+                        clock_info,
+                        stake_history_info,
+                        clock,
+                        stake_history,
+                    )? {
+                        cursor.stake_count = cursor.stake_index + 1;
+                    }
+                    cursor.stake_index += 1;
+                }
+                if cursor.stake_index < validator_stake_record.stake_count {
+                    // The page ends inside the validator stake accounts
+                    break;
+                }
+
+                validator_stake_record.stake_count = cursor.stake_count;
+                validator_stake_record.last_update_epoch = clock.epoch;
+                cursor = cursor.next_validator();
+            }
+            msg!(
+                "Next page starts at validator {} stake {} of {} validators",
+                cursor.validator_index,
+                cursor.stake_index,
+                validator_stake_list.validators.len()
+            );
+
+            stake_pool.list_update_cursor = cursor;
+            stake_pool.serialize(&mut stake_pool_info.data.borrow_mut())?;
+            validator_stake_list.serialize(&mut validator_stake_list_info.data.borrow_mut())?;
+            return Ok(());
+        }
+
         let mut changes = false;
         while let Some(validator_vote_info) = account_info_iter.next() {
-            if let Some(validator_stake_record) = validator_stake_list
+            if let Some((validator_index, validator_stake_record)) = validator_stake_list
                 .validators
                 .iter_mut()
-                .find(|v| v.validator_account == *validator_vote_info.key)
+                .enumerate()
+                .find(|(_, v)| v.validator_account == *validator_vote_info.key)
             {
+                if stake_pool
+                    .list_update_cursor
+                    .is_inside(clock.epoch, validator_index)
+                {
+                    msg!(
+                        "Validator {} is in the middle of a paged update. Continue UpdateListBalancePage",
+                        validator_vote_info.key
+                    );
+                    return Err(StakePoolError::ListUpdateInProgress.into());
+                }
+
                 // ? if validator_stake_record.last_update_epoch >= clock.epoch
                 validator_stake_record.balance = 0;
+                validator_stake_record.activating_lamports = 0;
+                validator_stake_record.deactivating_lamports = 0;
+                validator_stake_record.active_lamports = 0;
                 let mut new_stake_count = 0u32;
                 for index in 0..validator_stake_record.stake_count {
                     let stake_account_info = next_account_info(account_info_iter)?;
-
-                    let stake_bump_seed = validator_stake_record.check_validator_stake_address(
+                    if Self::update_validator_stake(
                         program_id,
-                        stake_pool_info.key,
+                        stake_pool_info,
+                        &stake_pool,
+                        validator_stake_record,
                         index,
-                        stake_account_info.key,
-                    )?;
-
-                    let stake_signer_seeds = &[
-                        &validator_vote_info.key.to_bytes()[..32],
-                        &stake_pool_info.key.to_bytes()[..32],
-                        &unsafe { std::mem::transmute::<u32, [u8; 4]>(index) },
-                        &[stake_bump_seed],
-                    ];
-
-                    // ? check stake_account_owner
-                    let mut balance = **stake_account_info.lamports.borrow();
-                    if balance > 0 {
-                        if *stake_account_info.owner == stake::id() {
-                            // return money back if there are some free
-                            let stake_state: stake::StakeState =
-                                deserialize(&stake_account_info.data.borrow()).or_else(|_| {
-                                    msg!("Error reading stake {} state", stake_account_info.key);
-                                    Err(ProgramError::InvalidAccountData)
-                                })?;
-
-                            let mut available_lamports = balance;
-                            match stake_state {
-                                StakeState::Uninitialized => {}
-                                StakeState::Initialized(meta) => {
-                                    available_lamports -= meta.rent_exempt_reserve;
-                                }
-                                StakeState::Stake(
-                                    meta,
-                                    stake::Stake {
-                                        delegation,
-                                        credits_observed,
-                                    },
-                                ) => {
-                                    available_lamports -= meta.rent_exempt_reserve;
-                                    available_lamports -= delegation.stake;
-                                    // TODO: fix available calculation
-                                }
-                                StakeState::RewardsPool => {
-                                    msg!(
-                                        "Stake account {} is rewards pool",
-                                        stake_account_info.key
-                                    );
-                                    return Err(StakePoolError::WrongStakeState.into());
-                                }
-                            }
-
-                            if available_lamports > 0 {
-                                let withdraw_signer_seeds: &[&[_]] = &[
-                                    &stake_pool_info.key.to_bytes()[..32],
-                                    Self::AUTHORITY_WITHDRAW,
-                                    &[stake_pool.withdraw_bump_seed],
-                                ];
-
-                                invoke_signed(
-                                    &stake::withdraw(
-                                        stake_account_info.key,
-                                        withdraw_info.key,
-                                        reserve_account_info.key,
-                                        available_lamports,
-                                        None,
-                                    ),
-                                    &[
-                                        stake_account_info.clone(),
-                                        reserve_account_info.clone(),
-                                        clock_info.clone(),
-                                        stake_history_info.clone(),
-                                        withdraw_info.clone(),
-                                        stake_program_info.clone(),
-                                    ],
-                                    &[withdraw_signer_seeds],
-                                )?;
-
-                                balance -= available_lamports;
-                            }
-
-                            validator_stake_record.balance += balance;
-                            new_stake_count = index + 1;
-                        } else {
-                            if *stake_account_info.owner == system_program::id() {
-                                invoke_signed(
-                                    &system_instruction::transfer(
-                                        stake_account_info.key,
-                                        reserve_account_info.key,
-                                        balance,
-                                    ),
-                                    &[
-                                        system_program_info.clone(),
-                                        stake_account_info.clone(),
-                                        reserve_account_info.clone(),
-                                    ],
-                                    &[stake_signer_seeds],
-                                )?;
-                                continue;
-                            }
-
-                            msg!(
-                                "Invalid stake {} owner {}",
-                                stake_account_info.key,
-                                stake_account_info.owner
-                            );
-                            return Err(StakePoolError::WrongStakeState.into());
-                        }
+                        stake_account_info,
+                        withdraw_info,
+                        reserve_account_info,
+                        system_program_info,
+                        stake_program_info,
+                        clock_info,
+                        stake_history_info,
+                        clock,
+                        stake_history,
+                    )? {
+                        new_stake_count = index + 1;
                     }
                 }
 
@@ -709,6 +874,154 @@ impl Processor {
         Ok(())
     }
 
+    /// Sweeps what the pool can take out of the validator stake account at `index`
+    /// to the reserve and adds the rest to the validator record.
+    /// Returns true if the account keeps some lamports
+    #[allow(clippy::too_many_arguments)]
+    fn update_validator_stake<'a>(
+        program_id: &Pubkey,
+        stake_pool_info: &AccountInfo<'a>,
+        stake_pool: &StakePool,
+        validator_stake_record: &mut ValidatorStakeInfo,
+        index: u32,
+        stake_account_info: &AccountInfo<'a>,
+        withdraw_info: &AccountInfo<'a>,
+        reserve_account_info: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
+        stake_program_info: &AccountInfo<'a>,
+        clock_info: &AccountInfo<'a>,
+        stake_history_info: &AccountInfo<'a>,
+        clock: &Clock,
+        stake_history: &StakeHistory,
+    ) -> Result<bool, ProgramError> {
+        let stake_bump_seed = validator_stake_record.check_validator_stake_address(
+            program_id,
+            stake_pool_info.key,
+            index,
+            stake_account_info.key,
+        )?;
+
+        let stake_signer_seeds = &[
+            &validator_stake_record.validator_account.to_bytes()[..32],
+            &stake_pool_info.key.to_bytes()[..32],
+            &index.to_le_bytes(),
+            &[stake_bump_seed],
+        ];
+
+        // ? check stake_account_owner
+        let mut balance = **stake_account_info.lamports.borrow();
+        if balance > 0 {
+            if *stake_account_info.owner == stake::id() {
+                // return money back if there are some free
+                let stake_state: stake::StakeState = deserialize(&stake_account_info.data.borrow())
+                    .map_err(|_| {
+                        msg!("Error reading stake {} state", stake_account_info.key);
+                        ProgramError::InvalidAccountData
+                    })?;
+
+                // Lamports the stake program lets the withdraw authority take.
+                // An account without stake is withdrawn completely
+                let available_lamports = match stake_state {
+                    StakeState::Uninitialized => balance,
+                    StakeState::Initialized(meta) => {
+                        balance.saturating_sub(meta.rent_exempt_reserve)
+                    }
+                    StakeState::Stake(
+                        meta,
+                        stake::Stake {
+                            delegation,
+                            credits_observed: _,
+                        },
+                    ) => {
+                        let (effective, activating, deactivating) = delegation
+                            .stake_activating_and_deactivating(
+                                clock.epoch,
+                                Some(stake_history),
+                                true,
+                            );
+                        // Same rule as the stake program withdraw
+                        let staked = if clock.epoch >= delegation.deactivation_epoch {
+                            effective
+                        } else {
+                            delegation.stake
+                        };
+                        if staked == 0 {
+                            msg!("Stake {} is inactive", stake_account_info.key);
+                            balance
+                        } else {
+                            validator_stake_record.active_lamports +=
+                                effective.saturating_sub(deactivating);
+                            validator_stake_record.activating_lamports += activating;
+                            validator_stake_record.deactivating_lamports += deactivating;
+                            balance.saturating_sub(staked + meta.rent_exempt_reserve)
+                        }
+                    }
+                    StakeState::RewardsPool => {
+                        msg!("Stake account {} is rewards pool", stake_account_info.key);
+                        return Err(StakePoolError::WrongStakeState.into());
+                    }
+                };
+
+                if available_lamports > 0 {
+                    let withdraw_signer_seeds: &[&[_]] = &[
+                        &stake_pool_info.key.to_bytes()[..32],
+                        Self::AUTHORITY_WITHDRAW,
+                        &[stake_pool.withdraw_bump_seed],
+                    ];
+
+                    invoke_signed(
+                        &stake::withdraw(
+                            stake_account_info.key,
+                            withdraw_info.key,
+                            reserve_account_info.key,
+                            available_lamports,
+                            None,
+                        ),
+                        &[
+                            stake_account_info.clone(),
+                            reserve_account_info.clone(),
+                            clock_info.clone(),
+                            stake_history_info.clone(),
+                            withdraw_info.clone(),
+                            stake_program_info.clone(),
+                        ],
+                        &[withdraw_signer_seeds],
+                    )?;
+
+                    balance -= available_lamports;
+                }
+
+                validator_stake_record.balance += balance;
+                return Ok(balance > 0);
+            } else {
+                if *stake_account_info.owner == system_program::id() {
+                    invoke_signed(
+                        &system_instruction::transfer(
+                            stake_account_info.key,
+                            reserve_account_info.key,
+                            balance,
+                        ),
+                        &[
+                            stake_account_info.clone(),
+                            reserve_account_info.clone(),
+                            system_program_info.clone(),
+                        ],
+                        &[stake_signer_seeds],
+                    )?;
+                    return Ok(false);
+                }
+
+                msg!(
+                    "Invalid stake {} owner {}",
+                    stake_account_info.key,
+                    stake_account_info.owner
+                );
+                return Err(StakePoolError::WrongStakeState.into());
+            }
+        }
+        Ok(false)
+    }
+
     /// Processes `UpdatePoolBalance` instruction.
     pub fn process_update_pool_balance(
         program_id: &Pubkey,
@@ -724,6 +1037,14 @@ impl Processor {
         // Clock sysvar account
         let clock_info = next_account_info(account_info_iter)?;
         let clock = &Clock::from_account_info(clock_info)?;
+        // Stake pool withdraw authority
+        let withdraw_info = next_account_info(account_info_iter)?;
+        // Account to receive pool fee tokens
+        let owner_fee_info = next_account_info(account_info_iter)?;
+        // Pool token mint account
+        let pool_mint_info = next_account_info(account_info_iter)?;
+        // Pool token program id
+        let token_program_info = next_account_info(account_info_iter)?;
 
         if stake_pool_info.owner != program_id {
             msg!(
@@ -740,6 +1061,18 @@ impl Processor {
             return Err(StakePoolError::InvalidState.into());
         }
 
+        stake_pool.check_authority_withdraw(withdraw_info.key, program_id, stake_pool_info.key)?;
+
+        if stake_pool.owner_fee_account != *owner_fee_info.key {
+            return Err(StakePoolError::InvalidFeeAccount.into());
+        }
+        if stake_pool.pool_mint != *pool_mint_info.key {
+            return Err(StakePoolError::WrongPoolMint.into());
+        }
+        if stake_pool.token_program_id != *token_program_info.key {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
         // Check validator stake account list storage
         if *validator_stake_list_info.key != stake_pool.validator_stake_list {
             return Err(StakePoolError::InvalidValidatorStakeList.into());
@@ -763,31 +1096,92 @@ impl Processor {
         }
 
         let mut total_balance: u64 = **reserve_account_info.lamports.borrow();
-        for validator_stake_record in validator_stake_list.validators {
+        for validator_stake_record in &validator_stake_list.validators {
             if validator_stake_record.last_update_epoch < clock.epoch {
+                msg!(
+                    "Validator {} balance was updated in epoch {}",
+                    validator_stake_record.validator_account,
+                    validator_stake_record.last_update_epoch
+                );
                 return Err(StakePoolError::StakeListOutOfDate.into());
             }
             total_balance += validator_stake_record.balance;
         }
+        let cursor = stake_pool.list_update_cursor;
+        if cursor.in_progress(clock.epoch) {
+            msg!(
+                "Paged list update stopped inside validator {}. Finish it with UpdateListBalancePage",
+                cursor.validator_index
+            );
+            return Err(StakePoolError::ListUpdateInProgress.into());
+        }
+        // Lamports owed to the locked credit tickets are not the holders' anymore
+        let total_balance = total_balance.saturating_sub(stake_pool.credit_lamports_total);
 
-        stake_pool.stake_total = total_balance;
-        stake_pool.last_update_epoch = clock.epoch;
-        stake_pool.serialize(&mut stake_pool_info.data.borrow_mut())?;
-
-        Ok(())
-    }
-
-    const MIN_RESERVE_BALANCE: u64 = 1000000;
-
-    fn min_reserve_balance(rent: &Rent) -> u64 {
-        Self::MIN_RESERVE_BALANCE
+        let fee_amount = stake_pool
+            .calc_performance_fee_amount(total_balance)
+            .ok_or(StakePoolError::CalculationFailure)?;
+        if fee_amount > 0 {
+            Self::token_mint_to(
+                stake_pool_info.key,
+                token_program_info.clone(),
+                pool_mint_info.clone(),
+                owner_fee_info.clone(),
+                withdraw_info.clone(),
+                Self::AUTHORITY_WITHDRAW,
+                stake_pool.withdraw_bump_seed,
+                fee_amount,
+            )?;
+            stake_pool.pool_total += fee_amount;
+        }
+
+        let (active_lamports, activating_lamports, deactivating_lamports) = validator_stake_list
+            .activation_totals()
+            .ok_or(StakePoolError::CalculationFailure)?;
+        stake_pool.active_lamports = active_lamports;
+        stake_pool.activating_lamports = activating_lamports;
+        stake_pool.deactivating_lamports = deactivating_lamports;
+        stake_pool.deactivating_epoch = clock.epoch;
+
+        // Rewards collected so far are charged with the old performance fee
+        stake_pool.promote_pending_fees(clock.epoch);
+
+        stake_pool.stake_total = total_balance;
+        stake_pool
+            .update_performance_high_water()
+            .ok_or(StakePoolError::CalculationFailure)?;
+        stake_pool.last_update_epoch = clock.epoch;
+        stake_pool.last_update_slot = clock.slot;
+        stake_pool.serialize(&mut stake_pool_info.data.borrow_mut())?;
+
+        let reserve_balance = **reserve_account_info.lamports.borrow();
+        let liquidity_target = stake_pool
+            .calc_liquidity_target()
+            .ok_or(StakePoolError::CalculationFailure)?;
+        if reserve_balance < liquidity_target {
+            msg!(
+                "Reserve balance {} is below the liquidity target {}. Unstake {} lamports",
+                reserve_balance,
+                liquidity_target,
+                liquidity_target - reserve_balance
+            );
+        }
+
+        Ok(())
+    }
+
+    const MIN_RESERVE_BALANCE: u64 = 1000000;
+
+    fn min_reserve_balance(rent: &Rent) -> u64 {
+        Self::MIN_RESERVE_BALANCE
             .max(rent.minimum_balance(0) + rent.minimum_balance(spl_token::state::Account::LEN))
     }
 
-    /// Processes [Deposit](enum.Instruction.html).
+    /// Processes [Deposit](enum.Instruction.html) and DepositWithSlippage.
     pub fn process_deposit(
         program_id: &Pubkey,
         amount: u64,
+        min_pool_tokens_out: u64,
         accounts: &[AccountInfo],
     ) -> ProgramResult {
         if amount == 0 {
@@ -813,6 +1207,12 @@ impl Processor {
         // Rent sysvar account
         let rent_info = next_account_info(account_info_iter)?;
         let rent = &Rent::from_account_info(rent_info)?;
+        // Clock sysvar account
+        let clock_info = next_account_info(account_info_iter)?;
+        let clock = &Clock::from_account_info(clock_info)?;
+        // Epoch schedule sysvar account
+        let epoch_schedule =
+            &EpochSchedule::from_account_info(next_account_info(account_info_iter)?)?;
         // System program id
         let system_program_info = next_account_info(account_info_iter)?;
         // Pool token program id
@@ -864,10 +1264,7 @@ impl Processor {
         }
 
         // Check stake pool last update epoch
-        /*
-        if stake_pool.last_update_epoch < clock.epoch {
-            return Err(StakePoolError::StakeListAndPoolOutOfDate.into());
-        }*/
+        stake_pool.check_freshness(clock, epoch_schedule)?;
 
         let target_balance = **reserve_account_info.lamports.borrow() + amount;
         if target_balance < Self::min_reserve_balance(&rent) {
@@ -879,12 +1276,20 @@ impl Processor {
             .ok_or(StakePoolError::CalculationFailure)?;
 
         let fee_amount = stake_pool
-            .calc_fee_amount(pool_amount)
+            .calc_deposit_fee_amount(pool_amount)
             .ok_or(StakePoolError::CalculationFailure)?;
 
         let user_amount = pool_amount
             .checked_sub(fee_amount)
             .ok_or(StakePoolError::CalculationFailure)?;
+        if user_amount < min_pool_tokens_out {
+            msg!(
+                "Deposit gives {} pool tokens, below the minimum {}",
+                user_amount,
+                min_pool_tokens_out
+            );
+            return Err(StakePoolError::SlippageExceeded.into());
+        }
 
         let withdraw_signer_seeds: &[&[_]] = &[
             &stake_pool_info.key.to_bytes()[..32],
@@ -951,11 +1356,11 @@ impl Processor {
                     withdraw_info.key,
                 )?,
                 &[
-                    token_program_info.clone(),
                     temp_account_info.clone(),
                     native_mint_info.clone(),
                     withdraw_info.clone(),
                     rent_info.clone(),
+                    token_program_info.clone(),
                 ],
             )?;
 
@@ -969,10 +1374,10 @@ impl Processor {
                     amount,
                 )?,
                 &[
-                    token_program_info.clone(),
                     source_user_info.clone(),
                     temp_account_info.clone(),
                     withdraw_info.clone(),
+                    token_program_info.clone(),
                 ],
                 &[withdraw_signer_seeds],
             )?;
@@ -986,10 +1391,10 @@ impl Processor {
                     &[],
                 )?,
                 &[
-                    token_program_info.clone(),
                     temp_account_info.clone(),
                     reserve_account_info.clone(),
                     withdraw_info.clone(),
+                    token_program_info.clone(),
                 ],
                 &[withdraw_signer_seeds],
             )?;
@@ -1040,10 +1445,342 @@ impl Processor {
         Ok(())
     }
 
-    /// Processes [Withdraw](enum.Instruction.html).
+    /// Processes [DepositStake](enum.Instruction.html).
+    pub fn process_deposit_stake(
+        program_id: &Pubkey,
+        stake_index: u32,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        // Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // Account storing validator stake list
+        let validator_stake_list_info = next_account_info(account_info_iter)?;
+        // Stake pool deposit authority
+        let deposit_info = next_account_info(account_info_iter)?;
+        // Stake pool withdraw authority
+        let withdraw_info = next_account_info(account_info_iter)?;
+        // User stake account to deposit
+        let user_stake_info = next_account_info(account_info_iter)?;
+        // Withdraw authority of the user stake
+        let user_stake_authority_info = next_account_info(account_info_iter)?;
+        // Validator stake account to merge into or to create
+        let validator_stake_info = next_account_info(account_info_iter)?;
+        // User account to receive pool tokens
+        let dest_user_info = next_account_info(account_info_iter)?;
+        // Account to receive pool fee tokens
+        let owner_fee_info = next_account_info(account_info_iter)?;
+        // Pool token mint account
+        let pool_mint_info = next_account_info(account_info_iter)?;
+        // Clock sysvar account
+        let clock_info = next_account_info(account_info_iter)?;
+        let clock = &Clock::from_account_info(clock_info)?;
+        // Epoch schedule sysvar account
+        let epoch_schedule =
+            &EpochSchedule::from_account_info(next_account_info(account_info_iter)?)?;
+        // Stake history sysvar account
+        let stake_history_info = next_account_info(account_info_iter)?;
+        let stake_history = &StakeHistory::from_account_info(stake_history_info)?;
+        // System program id
+        let system_program_info = next_account_info(account_info_iter)?;
+        // Staking program id
+        let stake_program_info = next_account_info(account_info_iter)?;
+        // Pool token program id
+        let token_program_info = next_account_info(account_info_iter)?;
+
+        if stake_pool_info.owner != program_id {
+            msg!(
+                "Wrong owner {} for the stake pool {}. Expected {}",
+                stake_pool_info.owner,
+                stake_pool_info.key,
+                program_id
+            );
+            return Err(StakePoolError::WrongOwner.into());
+        }
+        let mut stake_pool = StakePool::deserialize(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            return Err(StakePoolError::InvalidState.into());
+        }
+
+        stake_pool.check_authority_deposit(deposit_info.key, program_id, stake_pool_info.key)?;
+        stake_pool.check_authority_withdraw(withdraw_info.key, program_id, stake_pool_info.key)?;
+
+        if stake_pool.owner_fee_account != *owner_fee_info.key {
+            return Err(StakePoolError::InvalidFeeAccount.into());
+        }
+        if stake_pool.pool_mint != *pool_mint_info.key {
+            return Err(StakePoolError::WrongPoolMint.into());
+        }
+        if stake_pool.token_program_id != *token_program_info.key {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        // Check validator stake account list storage
+        if *validator_stake_list_info.key != stake_pool.validator_stake_list {
+            return Err(StakePoolError::InvalidValidatorStakeList.into());
+        }
+
+        // Read validator stake list account and check if it is valid
+        let mut validator_stake_list =
+            ValidatorStakeList::deserialize(&validator_stake_list_info.data.borrow())?;
+        if !validator_stake_list.is_initialized() {
+            return Err(StakePoolError::InvalidState.into());
+        }
+
+        // Check stake pool last update epoch
+        stake_pool.check_freshness(clock, epoch_schedule)?;
+
+        if *user_stake_info.owner != stake::id() {
+            msg!(
+                "Invalid stake {} owner {}",
+                user_stake_info.key,
+                user_stake_info.owner
+            );
+            return Err(StakePoolError::WrongStakeState.into());
+        }
+        let user_stake_state: stake::StakeState = deserialize(&user_stake_info.data.borrow())
+            .map_err(|_| {
+                msg!("Error reading stake {} state", user_stake_info.key);
+                ProgramError::InvalidAccountData
+            })?;
+        let (meta, user_stake) = match user_stake_state {
+            StakeState::Stake(meta, stake) => (meta, stake),
+            _ => {
+                msg!("Stake {} is not delegated", user_stake_info.key);
+                return Err(StakePoolError::WrongStakeState.into());
+            }
+        };
+        if meta.lockup != stake::Lockup::default() {
+            msg!("Stake {} has a lockup", user_stake_info.key);
+            return Err(StakePoolError::WrongStakeState.into());
+        }
+
+        // Only fully active stake is priced at the pool ratio
+        let delegation = user_stake.delegation;
+        let (effective, activating, deactivating) =
+            delegation.stake_activating_and_deactivating(clock.epoch, Some(stake_history), true);
+        if delegation.deactivation_epoch != u64::MAX
+            || activating > 0
+            || deactivating > 0
+            || effective != delegation.stake
+        {
+            msg!("Stake {} is not fully active", user_stake_info.key);
+            return Err(StakePoolError::WrongStakeState.into());
+        }
+        let stake_amount = delegation.stake;
+        let lamports = **user_stake_info.lamports.borrow();
+        // Only the rent exempt reserve may come along with the stake
+        let undelegated = lamports
+            .checked_sub(stake_amount)
+            .and_then(|rest| rest.checked_sub(meta.rent_exempt_reserve))
+            .ok_or(StakePoolError::CalculationFailure)?;
+        if undelegated > 0 {
+            msg!(
+                "Stake {} holds {} undelegated lamports. Withdraw them first",
+                user_stake_info.key,
+                undelegated
+            );
+            return Err(StakePoolError::WrongStakeState.into());
+        }
+
+        let (validator_index, validator) = validator_stake_list
+            .validators
+            .iter_mut()
+            .enumerate()
+            .find(|(_, validator)| validator.validator_account == delegation.voter_pubkey)
+            .ok_or_else(|| {
+                msg!("Unexpected validator account {}", delegation.voter_pubkey);
+                StakePoolError::ValidatorNotFound
+            })?;
+        // The record is zeroed until the paged update counts its last stake account
+        if stake_pool
+            .list_update_cursor
+            .is_inside(clock.epoch, validator_index)
+            && validator.last_update_epoch < clock.epoch
+        {
+            msg!(
+                "Validator {} is in the middle of a paged update. Continue UpdateListBalancePage",
+                validator.validator_account
+            );
+            return Err(StakePoolError::ListUpdateInProgress.into());
+        }
+        if stake_index > validator.stake_count {
+            return Err(StakePoolError::InvalidStakeIndex.into());
+        }
+        let validator_stake_bump_seed = validator.check_validator_stake_address(
+            program_id,
+            stake_pool_info.key,
+            stake_index,
+            validator_stake_info.key,
+        )?;
+
+        // Only the delegated stake is minted for. The rent exempt reserve
+        // goes to the holders
+        let pool_amount = stake_pool
+            .calc_pool_deposit_amount(stake_amount)
+            .ok_or(StakePoolError::CalculationFailure)?;
+
+        let fee_amount = stake_pool
+            .calc_deposit_fee_amount(pool_amount)
+            .ok_or(StakePoolError::CalculationFailure)?;
+
+        let user_amount = pool_amount
+            .checked_sub(fee_amount)
+            .ok_or(StakePoolError::CalculationFailure)?;
+
+        // Hand the user stake over to the pool
+        for (new_authority, stake_authorize) in &[
+            (deposit_info.key, stake::StakeAuthorize::Staker),
+            (withdraw_info.key, stake::StakeAuthorize::Withdrawer),
+        ] {
+            invoke(
+                &stake::authorize(
+                    user_stake_info.key,
+                    user_stake_authority_info.key,
+                    new_authority,
+                    *stake_authorize,
+                ),
+                &[
+                    user_stake_info.clone(),
+                    clock_info.clone(),
+                    user_stake_authority_info.clone(),
+                    stake_program_info.clone(),
+                ],
+            )?;
+        }
+
+        let deposit_signer_seeds: &[&[_]] = &[
+            &stake_pool_info.key.to_bytes()[..32],
+            Self::AUTHORITY_DEPOSIT,
+            &[stake_pool.deposit_bump_seed],
+        ];
+
+        if stake_index < validator.stake_count {
+            msg!(
+                "Merge stake {} into {}",
+                user_stake_info.key,
+                validator_stake_info.key
+            );
+
+            // Active stakes merge only with the same credits observed
+            if let Ok(StakeState::Stake(_, validator_stake)) =
+                deserialize::<StakeState>(&validator_stake_info.data.borrow())
+            {
+                if validator_stake.credits_observed != user_stake.credits_observed {
+                    msg!(
+                        "Stake {} observed {} credits. Deposit into the next stake index",
+                        validator_stake_info.key,
+                        validator_stake.credits_observed
+                    );
+                    return Err(StakePoolError::WrongStakeState.into());
+                }
+            }
+
+            invoke_signed(
+                &stake::merge(
+                    validator_stake_info.key,
+                    user_stake_info.key,
+                    deposit_info.key,
+                ),
+                &[
+                    validator_stake_info.clone(),
+                    user_stake_info.clone(),
+                    clock_info.clone(),
+                    stake_history_info.clone(),
+                    deposit_info.clone(),
+                    stake_program_info.clone(),
+                ],
+                &[deposit_signer_seeds],
+            )?;
+        } else {
+            msg!(
+                "Register stake {} as {}",
+                user_stake_info.key,
+                validator_stake_info.key
+            );
+
+            let validator_stake_signer_seeds: &[&[u8]] = &[
+                &delegation.voter_pubkey.to_bytes()[..32],
+                &stake_pool_info.key.to_bytes()[..32],
+                &stake_index.to_le_bytes(),
+                &[validator_stake_bump_seed],
+            ];
+
+            // The split of the whole balance carries the rent exempt reserve along
+            invoke_signed(
+                &system_instruction::create_account(
+                    user_stake_authority_info.key, // Sending 0, so any signer will suffice
+                    validator_stake_info.key,
+                    0,
+                    std::mem::size_of::<StakeState>() as u64,
+                    &stake::id(),
+                ),
+                &[
+                    user_stake_authority_info.clone(),
+                    validator_stake_info.clone(),
+                    system_program_info.clone(),
+                ],
+                &[validator_stake_signer_seeds],
+            )?;
+
+            invoke_signed(
+                &stake::split_only(
+                    user_stake_info.key,
+                    deposit_info.key,
+                    lamports,
+                    validator_stake_info.key,
+                ),
+                &[
+                    user_stake_info.clone(),
+                    deposit_info.clone(),
+                    validator_stake_info.clone(),
+                    stake_program_info.clone(),
+                ],
+                &[deposit_signer_seeds],
+            )?;
+
+            validator.stake_count += 1;
+        }
+
+        validator.balance += lamports;
+        validator.active_lamports += stake_amount;
+        validator_stake_list.serialize(&mut validator_stake_list_info.data.borrow_mut())?;
+
+        Self::token_mint_to(
+            stake_pool_info.key,
+            token_program_info.clone(),
+            pool_mint_info.clone(),
+            dest_user_info.clone(),
+            withdraw_info.clone(),
+            Self::AUTHORITY_WITHDRAW,
+            stake_pool.withdraw_bump_seed,
+            user_amount,
+        )?;
+
+        Self::token_mint_to(
+            stake_pool_info.key,
+            token_program_info.clone(),
+            pool_mint_info.clone(),
+            owner_fee_info.clone(),
+            withdraw_info.clone(),
+            Self::AUTHORITY_WITHDRAW,
+            stake_pool.withdraw_bump_seed,
+            fee_amount,
+        )?;
+        // stake_total takes the reserve too, so UpdatePoolBalance doesn't
+        // count it as rewards
+        stake_pool.pool_total += pool_amount;
+        stake_pool.stake_total += lamports;
+        stake_pool.serialize(&mut stake_pool_info.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    /// Processes [Withdraw](enum.Instruction.html) and WithdrawWithSlippage.
     pub fn process_withdraw(
         program_id: &Pubkey,
         pool_amount: u64,
+        min_lamports_out: u64,
         accounts: &[AccountInfo],
     ) -> ProgramResult {
         if pool_amount == 0 {
@@ -1060,6 +1797,8 @@ impl Processor {
         let reserve_account_info = next_account_info(account_info_iter)?;
         // User account with pool tokens to burn from
         let burn_from_info = next_account_info(account_info_iter)?;
+        // Account to receive pool fee tokens
+        let owner_fee_info = next_account_info(account_info_iter)?;
         // Pool token mint account
         let pool_mint_info = next_account_info(account_info_iter)?;
         // Target user account with SOLs
@@ -1067,6 +1806,12 @@ impl Processor {
         // Rent sysvar account
         let rent_info = next_account_info(account_info_iter)?;
         let rent = &Rent::from_account_info(rent_info)?;
+        // Clock sysvar account
+        let clock_info = next_account_info(account_info_iter)?;
+        let clock = &Clock::from_account_info(clock_info)?;
+        // Epoch schedule sysvar account
+        let epoch_schedule =
+            &EpochSchedule::from_account_info(next_account_info(account_info_iter)?)?;
         // System program id
         let system_program_info = next_account_info(account_info_iter)?;
         // Pool token program id
@@ -1090,6 +1835,10 @@ impl Processor {
             return Err(ProgramError::IncorrectProgramId);
         }
 
+        if stake_pool.owner_fee_account != *owner_fee_info.key {
+            return Err(StakePoolError::InvalidFeeAccount.into());
+        }
+
         stake_pool.check_authority_withdraw(withdraw_info.key, program_id, stake_pool_info.key)?;
 
         let (expected_reserve, reserve_bump) =
@@ -1103,15 +1852,28 @@ impl Processor {
             return Err(ProgramError::IncorrectProgramId);
         }
 
-        /*
         // Check stake pool last update epoch
-        if stake_pool.last_update_epoch < clock.epoch {
-            return Err(StakePoolError::StakeListAndPoolOutOfDate.into());
-        }*/
+        stake_pool.check_freshness(clock, epoch_schedule)?;
+
+        let fee_amount = stake_pool
+            .calc_withdrawal_fee_amount(pool_amount)
+            .ok_or(StakePoolError::CalculationFailure)?;
+
+        let burn_amount = pool_amount
+            .checked_sub(fee_amount)
+            .ok_or(StakePoolError::CalculationFailure)?;
 
         let stake_amount = stake_pool
-            .calc_lamports_amount(pool_amount)
+            .calc_lamports_amount(burn_amount)
             .ok_or(StakePoolError::CalculationFailure)?;
+        if stake_amount < min_lamports_out {
+            msg!(
+                "Withdrawal gives {} lamports, below the minimum {}",
+                stake_amount,
+                min_lamports_out
+            );
+            return Err(StakePoolError::SlippageExceeded.into());
+        }
 
         let reserve_balance = **reserve_account_info.lamports.borrow();
         if stake_amount > reserve_balance
@@ -1126,15 +1888,28 @@ impl Processor {
             return Err(ProgramError::InsufficientFunds);
         }
 
-        Self::token_burn(
-            stake_pool_info.key,
-            token_program_info.clone(),
-            burn_from_info.clone(),
+        if fee_amount > 0 {
+            Self::token_transfer(
+                stake_pool_info.key,
+                token_program_info.clone(),
+                burn_from_info.clone(),
+                owner_fee_info.clone(),
+                withdraw_info.clone(),
+                Self::AUTHORITY_WITHDRAW,
+                stake_pool.withdraw_bump_seed,
+                fee_amount,
+            )?;
+        }
+
+        Self::token_burn(
+            stake_pool_info.key,
+            token_program_info.clone(),
+            burn_from_info.clone(),
             pool_mint_info.clone(),
             withdraw_info.clone(),
             Self::AUTHORITY_WITHDRAW,
             stake_pool.withdraw_bump_seed,
-            pool_amount,
+            burn_amount,
         )?;
 
         let reserve_signer_seeds: &[&[u8]] = &[
@@ -1150,27 +1925,27 @@ impl Processor {
                 stake_amount,
             ),
             &[
-                system_program_info.clone(),
                 reserve_account_info.clone(),
                 target_account_info.clone(),
+                system_program_info.clone(),
             ],
             &[reserve_signer_seeds],
         )?;
 
-        stake_pool.pool_total -= pool_amount;
+        stake_pool.pool_total -= burn_amount;
         stake_pool.stake_total -= stake_amount;
         stake_pool.serialize(&mut stake_pool_info.data.borrow_mut())?;
 
         Ok(())
     }
 
-    /// Processes [Credit](enum.Instruction.html).
-    pub fn process_credit(
+    /// Processes [WithdrawStake](enum.Instruction.html).
+    pub fn process_withdraw_stake(
         program_id: &Pubkey,
-        amount: u64,
+        args: &WithdrawStakeArgs,
         accounts: &[AccountInfo],
     ) -> ProgramResult {
-        if amount == 0 {
+        if args.amount == 0 {
             msg!("Amount must not be zero");
             return Err(ProgramError::InvalidArgument);
         }
@@ -1178,19 +1953,41 @@ impl Processor {
         let account_info_iter = &mut accounts.iter();
         // Stake pool
         let stake_pool_info = next_account_info(account_info_iter)?;
-        let credit_list_info = next_account_info(account_info_iter)?;
-        let credit_reserve_info = next_account_info(account_info_iter)?;
+        // Account storing validator stake list
+        let validator_stake_list_info = next_account_info(account_info_iter)?;
         // Stake pool deposit authority
+        let deposit_info = next_account_info(account_info_iter)?;
+        // Stake pool withdraw authority
         let withdraw_info = next_account_info(account_info_iter)?;
+        // Validator stake account to split from
+        let validator_stake_info = next_account_info(account_info_iter)?;
+        // Uninitialized stake account to split into
+        let split_stake_info = next_account_info(account_info_iter)?;
+        // New authority of the split stake
+        let user_stake_authority_info = next_account_info(account_info_iter)?;
         // User account with pool tokens to burn from
         let burn_from_info = next_account_info(account_info_iter)?;
-        // Target user account with SOLs
-        let target_account_info = next_account_info(account_info_iter)?;
-        // Cancel authority
-        let cancel_authority_info = next_account_info(account_info_iter)?;
+        // Owner or delegate of the pool tokens
+        let user_transfer_authority_info = next_account_info(account_info_iter)?;
+        // Account to receive pool fee tokens
+        let owner_fee_info = next_account_info(account_info_iter)?;
+        // Pool token mint account
+        let pool_mint_info = next_account_info(account_info_iter)?;
+        // Clock sysvar account
+        let clock_info = next_account_info(account_info_iter)?;
+        let clock = &Clock::from_account_info(clock_info)?;
+        // Epoch schedule sysvar account
+        let epoch_schedule =
+            &EpochSchedule::from_account_info(next_account_info(account_info_iter)?)?;
+        // Staking program id
+        let stake_program_info = next_account_info(account_info_iter)?;
         // Pool token program id
         let token_program_info = next_account_info(account_info_iter)?;
 
+        if !user_transfer_authority_info.is_signer {
+            return Err(StakePoolError::SignatureMissing.into());
+        }
+
         if stake_pool_info.owner != program_id {
             msg!(
                 "Wrong owner {} for the stake pool {}. Expected {}",
@@ -1200,7 +1997,7 @@ impl Processor {
             );
             return Err(StakePoolError::WrongOwner.into());
         }
-        let stake_pool = StakePool::deserialize(&stake_pool_info.data.borrow())?;
+        let mut stake_pool = StakePool::deserialize(&stake_pool_info.data.borrow())?;
         if !stake_pool.is_initialized() {
             return Err(StakePoolError::InvalidState.into());
         }
@@ -1209,64 +2006,135 @@ impl Processor {
             return Err(ProgramError::IncorrectProgramId);
         }
 
-        if *credit_list_info.key != stake_pool.credit_list {
-            msg!(
-                "Expected credit list to be {} but got {}",
-                &stake_pool.credit_list,
-                credit_list_info.key
-            );
-            return Err(ProgramError::InvalidArgument.into());
+        if stake_pool.owner_fee_account != *owner_fee_info.key {
+            return Err(StakePoolError::InvalidFeeAccount.into());
         }
-        let mut credit_list = CreditList::deserialize(&credit_list_info.data.borrow())?;
-        if !credit_list.is_initialized() {
+
+        stake_pool.check_authority_deposit(deposit_info.key, program_id, stake_pool_info.key)?;
+        stake_pool.check_authority_withdraw(withdraw_info.key, program_id, stake_pool_info.key)?;
+
+        // Check validator stake account list storage
+        if *validator_stake_list_info.key != stake_pool.validator_stake_list {
+            return Err(StakePoolError::InvalidValidatorStakeList.into());
+        }
+
+        // Read validator stake list account and check if it is valid
+        let mut validator_stake_list =
+            ValidatorStakeList::deserialize(&validator_stake_list_info.data.borrow())?;
+        if !validator_stake_list.is_initialized() {
             return Err(StakePoolError::InvalidState.into());
         }
 
-        if *credit_reserve_info.key != stake_pool.credit_reserve {
+        // Check stake pool last update epoch
+        stake_pool.check_freshness(clock, epoch_schedule)?;
+
+        let validator_stake_state: stake::StakeState =
+            deserialize(&validator_stake_info.data.borrow()).map_err(|_| {
+                msg!("Error reading stake {} state", validator_stake_info.key);
+                ProgramError::InvalidAccountData
+            })?;
+        let delegation = match validator_stake_state.delegation() {
+            Some(delegation) if delegation.deactivation_epoch == u64::MAX => delegation,
+            _ => {
+                msg!("Stake {} is not delegated", validator_stake_info.key);
+                return Err(StakePoolError::WrongStakeState.into());
+            }
+        };
+
+        let (validator_index, validator) = validator_stake_list
+            .validators
+            .iter_mut()
+            .enumerate()
+            .find(|(_, validator)| validator.validator_account == delegation.voter_pubkey)
+            .ok_or_else(|| {
+                msg!("Unexpected validator account {}", delegation.voter_pubkey);
+                StakePoolError::ValidatorNotFound
+            })?;
+        // The record is zeroed until the paged update counts its last stake account
+        if stake_pool
+            .list_update_cursor
+            .is_inside(clock.epoch, validator_index)
+            && validator.last_update_epoch < clock.epoch
+        {
             msg!(
-                "Expected credit reserve to be {} but got {}",
-                &stake_pool.credit_reserve,
-                credit_reserve_info.key
+                "Validator {} is in the middle of a paged update. Continue UpdateListBalancePage",
+                validator.validator_account
             );
-            return Err(ProgramError::InvalidArgument.into());
+            return Err(StakePoolError::ListUpdateInProgress.into());
+        }
+        if args.stake_index >= validator.stake_count {
+            return Err(StakePoolError::InvalidStakeIndex.into());
         }
+        validator.check_validator_stake_address(
+            program_id,
+            stake_pool_info.key,
+            args.stake_index,
+            validator_stake_info.key,
+        )?;
 
-        if *target_account_info.owner != system_program::id() {
+        if *split_stake_info.owner != stake::id() {
             msg!(
-                "User account {} must by system one but owner is {}",
-                target_account_info.key,
-                target_account_info.owner
+                "Invalid stake {} owner {}",
+                split_stake_info.key,
+                split_stake_info.owner
             );
-            return Err(ProgramError::IncorrectProgramId);
+            return Err(StakePoolError::WrongStakeState.into());
         }
 
-        if let Some(credit_record) = credit_list
-            .credits
-            .iter_mut()
-            .find(|record| record.sol_target == *target_account_info.key)
-        {
-            if *cancel_authority_info.key != credit_record.cancel_authority {
-                msg!(
-                    "User {} waits for authority {} but got {}",
-                    target_account_info.key,
-                    &credit_record.cancel_authority,
-                    cancel_authority_info.key
-                );
-                return Err(ProgramError::InvalidArgument);
-            }
+        let fee_amount = stake_pool
+            .calc_withdrawal_fee_amount(args.amount)
+            .ok_or(StakePoolError::CalculationFailure)?;
 
-            credit_record.token_amount += amount;
-        } else {
-            if credit_list.credits.len() >= MAX_CREDIT_RECORDS {
-                return Err(StakePoolError::CreditListOverfow.into());
-            }
-            credit_list.credits.push(CreditRecord {
-                sol_target: target_account_info.key.clone(),
-                cancel_authority: cancel_authority_info.key.clone(),
-                token_amount: amount,
-            })
+        let burn_amount = args
+            .amount
+            .checked_sub(fee_amount)
+            .ok_or(StakePoolError::CalculationFailure)?;
+
+        let stake_amount = stake_pool
+            .calc_lamports_amount(burn_amount)
+            .ok_or(StakePoolError::CalculationFailure)?;
+
+        let validator_stake_balance = **validator_stake_info.lamports.borrow();
+        if stake_amount.saturating_add(MIN_STAKE_ACCOUNT_BALANCE) > validator_stake_balance {
+            msg!(
+                "Requested to withdraw {} but stake {} contains only {}",
+                stake_amount,
+                validator_stake_info.key,
+                validator_stake_balance
+            );
+            return Err(ProgramError::InsufficientFunds);
+        }
+
+        if fee_amount > 0 {
+            Self::user_token_transfer(
+                token_program_info.clone(),
+                burn_from_info.clone(),
+                owner_fee_info.clone(),
+                user_transfer_authority_info.clone(),
+                fee_amount,
+            )?;
         }
 
+        Self::user_token_burn(
+            token_program_info.clone(),
+            burn_from_info.clone(),
+            pool_mint_info.clone(),
+            user_transfer_authority_info.clone(),
+            burn_amount,
+        )?;
+
+        msg!(
+            "Split {} lamports off {} into {}",
+            stake_amount,
+            validator_stake_info.key,
+            split_stake_info.key
+        );
+
+        let deposit_signer_seeds: &[&[_]] = &[
+            &stake_pool_info.key.to_bytes()[..32],
+            Self::AUTHORITY_DEPOSIT,
+            &[stake_pool.deposit_bump_seed],
+        ];
         let withdraw_signer_seeds: &[&[_]] = &[
             &stake_pool_info.key.to_bytes()[..32],
             Self::AUTHORITY_WITHDRAW,
@@ -1274,46 +2142,113 @@ impl Processor {
         ];
 
         invoke_signed(
-            &spl_token::instruction::transfer(
-                token_program_info.key,
-                burn_from_info.key,
-                credit_reserve_info.key,
-                withdraw_info.key,
-                &[],
-                amount,
-            )?,
+            &stake::split_only(
+                validator_stake_info.key,
+                deposit_info.key,
+                stake_amount,
+                split_stake_info.key,
+            ),
             &[
-                burn_from_info.clone(),
-                credit_reserve_info.clone(),
-                withdraw_info.clone(),
+                validator_stake_info.clone(),
+                deposit_info.clone(),
+                split_stake_info.clone(),
+                stake_program_info.clone(),
             ],
-            &[withdraw_signer_seeds],
+            &[deposit_signer_seeds],
         )?;
 
-        credit_list.serialize(&mut credit_list_info.data.borrow_mut())?;
+        // Hand the split stake over to the user
+        for (authority_info, stake_authorize, signer_seeds) in &[
+            (
+                deposit_info,
+                stake::StakeAuthorize::Staker,
+                deposit_signer_seeds,
+            ),
+            (
+                withdraw_info,
+                stake::StakeAuthorize::Withdrawer,
+                withdraw_signer_seeds,
+            ),
+        ] {
+            invoke_signed(
+                &stake::authorize(
+                    split_stake_info.key,
+                    authority_info.key,
+                    user_stake_authority_info.key,
+                    *stake_authorize,
+                ),
+                &[
+                    split_stake_info.clone(),
+                    clock_info.clone(),
+                    (*authority_info).clone(),
+                    stake_program_info.clone(),
+                ],
+                &[signer_seeds],
+            )?;
+        }
+
+        validator.balance = validator
+            .balance
+            .checked_sub(stake_amount)
+            .ok_or(StakePoolError::CalculationFailure)?;
+        validator.active_lamports = validator
+            .active_lamports
+            .checked_sub(stake_amount)
+            .ok_or(StakePoolError::CalculationFailure)?;
+        validator_stake_list.serialize(&mut validator_stake_list_info.data.borrow_mut())?;
+
+        stake_pool.pool_total = stake_pool
+            .pool_total
+            .checked_sub(burn_amount)
+            .ok_or(StakePoolError::CalculationFailure)?;
+        stake_pool.stake_total = stake_pool
+            .stake_total
+            .checked_sub(stake_amount)
+            .ok_or(StakePoolError::CalculationFailure)?;
+        stake_pool.serialize(&mut stake_pool_info.data.borrow_mut())?;
 
         Ok(())
     }
 
-    /// Processes [Uncredit](enum.Instruction.html).
-    pub fn process_uncredit(
+    /// Processes [Credit](enum.Instruction.html).
+    pub fn process_credit(
         program_id: &Pubkey,
         amount: u64,
         accounts: &[AccountInfo],
     ) -> ProgramResult {
+        if amount == 0 {
+            msg!("Amount must not be zero");
+            return Err(ProgramError::InvalidArgument);
+        }
+
         let account_info_iter = &mut accounts.iter();
         // Stake pool
         let stake_pool_info = next_account_info(account_info_iter)?;
-        let credit_list_info = next_account_info(account_info_iter)?;
-        let credit_reserve_info = next_account_info(account_info_iter)?;
-        // Stake pool deposit authority
-        let withdraw_info = next_account_info(account_info_iter)?;
-        // User account with pool tokens to burn from
+        // Credit ticket to open at the queue head
+        let ticket_info = next_account_info(account_info_iter)?;
+        // User account with pool tokens to burn from. Uncredit returns the tokens here
         let burn_from_info = next_account_info(account_info_iter)?;
+        // Owner or delegate of the pool tokens
+        let user_transfer_authority_info = next_account_info(account_info_iter)?;
+        // Pool token mint account
+        let pool_mint_info = next_account_info(account_info_iter)?;
         // Target user account with SOLs
         let target_account_info = next_account_info(account_info_iter)?;
         // Cancel authority
         let cancel_authority_info = next_account_info(account_info_iter)?;
+        // Pays rent for the ticket
+        let payer_info = next_account_info(account_info_iter)?;
+        // Rent sysvar account
+        let rent_info = next_account_info(account_info_iter)?;
+        let rent = &Rent::from_account_info(rent_info)?;
+        // Clock sysvar account
+        let clock_info = next_account_info(account_info_iter)?;
+        let clock = &Clock::from_account_info(clock_info)?;
+        // Epoch schedule sysvar account
+        let epoch_schedule =
+            &EpochSchedule::from_account_info(next_account_info(account_info_iter)?)?;
+        // System program id
+        let system_program_info = next_account_info(account_info_iter)?;
         // Pool token program id
         let token_program_info = next_account_info(account_info_iter)?;
 
@@ -1326,7 +2261,7 @@ impl Processor {
             );
             return Err(StakePoolError::WrongOwner.into());
         }
-        let stake_pool = StakePool::deserialize(&stake_pool_info.data.borrow())?;
+        let mut stake_pool = StakePool::deserialize(&stake_pool_info.data.borrow())?;
         if !stake_pool.is_initialized() {
             return Err(StakePoolError::InvalidState.into());
         }
@@ -1335,99 +2270,1101 @@ impl Processor {
             return Err(ProgramError::IncorrectProgramId);
         }
 
-        if *credit_list_info.key != stake_pool.credit_list {
-            msg!(
-                "Expected credit list to be {} but got {}",
-                &stake_pool.credit_list,
-                credit_list_info.key
-            );
-            return Err(ProgramError::InvalidArgument.into());
-        }
-        let mut credit_list = CreditList::deserialize(&credit_list_info.data.borrow())?;
-        if !credit_list.is_initialized() {
-            return Err(StakePoolError::InvalidState.into());
+        if stake_pool.pool_mint != *pool_mint_info.key {
+            return Err(StakePoolError::WrongPoolMint.into());
         }
 
-        if *credit_reserve_info.key != stake_pool.credit_reserve {
+        // The ticket is priced with the current pool balance. PayCreditors takes the withdrawal fee
+        stake_pool.check_freshness(clock, epoch_schedule)?;
+
+        let lamports_owed = stake_pool
+            .calc_lamports_amount(amount)
+            .ok_or(StakePoolError::CalculationFailure)?;
+
+        if *target_account_info.owner != system_program::id() {
             msg!(
-                "Expected credit reserve to be {} but got {}",
-                &stake_pool.credit_reserve,
-                credit_reserve_info.key
+                "User account {} must by system one but owner is {}",
+                target_account_info.key,
+                target_account_info.owner
             );
-            return Err(ProgramError::InvalidArgument.into());
+            return Err(ProgramError::IncorrectProgramId);
         }
 
-        if let Some(credit_record) = credit_list
-            .credits
-            .iter_mut()
-            .find(|record| record.sol_target == *target_account_info.key)
-        {
-            if *cancel_authority_info.key != credit_record.cancel_authority {
-                msg!(
-                    "User {} waits for authority {} but got {}",
-                    target_account_info.key,
-                    &credit_record.cancel_authority,
-                    cancel_authority_info.key
-                );
-                return Err(ProgramError::InvalidArgument);
-            }
-            if !cancel_authority_info.is_signer {
-                return Err(ProgramError::MissingRequiredSignature);
-            }
-            if amount > credit_record.token_amount {
-                return Err(ProgramError::InsufficientFunds);
-            }
-            if amount == credit_record.token_amount {
-                credit_list
-                    .credits
-                    .retain(|record| record.sol_target != *target_account_info.key);
-            } else {
-                credit_record.token_amount -= amount;
-            }
-        } else {
+        if !user_transfer_authority_info.is_signer || !payer_info.is_signer {
+            return Err(StakePoolError::SignatureMissing.into());
+        }
+
+        let sequence = stake_pool.credit_queue_head;
+        let (ticket_address, ticket_bump) =
+            Self::get_credit_ticket_address(program_id, stake_pool_info.key, sequence);
+        if *ticket_info.key != ticket_address {
             msg!(
-                "Unknown creditor {} from {} creditors",
-                target_account_info.key,
-                credit_list.credits.len()
+                "Expected credit ticket #{} to be {} but got {}",
+                sequence,
+                &ticket_address,
+                ticket_info.key
             );
-            return Err(StakePoolError::UnknownCreditor.into());
+            return Err(ProgramError::InvalidArgument);
         }
-
-        let withdraw_signer_seeds: &[&[_]] = &[
+        let ticket_signer_seeds: &[&[u8]] = &[
             &stake_pool_info.key.to_bytes()[..32],
-            Self::AUTHORITY_WITHDRAW,
-            &[stake_pool.withdraw_bump_seed],
+            Self::CREDIT_TICKET,
+            &sequence.to_le_bytes(),
+            &[ticket_bump],
         ];
 
+        // The ticket address is known in advance so it may hold lamports already
+        let rent_lamports = rent
+            .minimum_balance(CreditRecord::LEN)
+            .saturating_sub(ticket_info.lamports());
+        if rent_lamports > 0 {
+            invoke(
+                &system_instruction::transfer(payer_info.key, ticket_info.key, rent_lamports),
+                &[
+                    payer_info.clone(),
+                    ticket_info.clone(),
+                    system_program_info.clone(),
+                ],
+            )?;
+        }
         invoke_signed(
-            &spl_token::instruction::transfer(
-                token_program_info.key,
-                credit_reserve_info.key,
-                burn_from_info.key,
-                withdraw_info.key,
-                &[],
-                amount,
-            )?,
-            &[
-                burn_from_info.clone(),
-                credit_reserve_info.clone(),
-                withdraw_info.clone(),
-            ],
-            &[withdraw_signer_seeds],
+            &system_instruction::allocate(ticket_info.key, CreditRecord::LEN as u64),
+            &[ticket_info.clone(), system_program_info.clone()],
+            &[ticket_signer_seeds],
+        )?;
+        invoke_signed(
+            &system_instruction::assign(ticket_info.key, program_id),
+            &[ticket_info.clone(), system_program_info.clone()],
+            &[ticket_signer_seeds],
+        )?;
+
+        Self::user_token_burn(
+            token_program_info.clone(),
+            burn_from_info.clone(),
+            pool_mint_info.clone(),
+            user_transfer_authority_info.clone(),
+            amount,
         )?;
 
-        credit_list.serialize(&mut credit_list_info.data.borrow_mut())?;
+        let ticket = CreditRecord {
+            version: CreditRecord::VERSION,
+            stake_pool: *stake_pool_info.key,
+            sequence,
+            sol_target: *target_account_info.key,
+            cancel_authority: *cancel_authority_info.key,
+            token_amount: amount,
+            created_epoch: clock.epoch,
+            lamports_owed,
+            refund_account: *burn_from_info.key,
+            payer: *payer_info.key,
+        };
+        ticket.pack_into_slice(&mut ticket_info.data.borrow_mut());
+        msg!(
+            "Credit ticket #{} {} for {} lamports",
+            sequence,
+            ticket_info.key,
+            lamports_owed
+        );
+
+        stake_pool.credit_queue_head = sequence
+            .checked_add(1)
+            .ok_or(StakePoolError::CalculationFailure)?;
+        // The owed lamports stay in the pool until paid but don't belong to the holders anymore
+        stake_pool
+            .book_credit(&ticket)
+            .ok_or(StakePoolError::CalculationFailure)?;
+        stake_pool.serialize(&mut stake_pool_info.data.borrow_mut())?;
 
         Ok(())
     }
 
-    /// Processes [SetOwner](enum.Instruction.html).
-    pub fn process_set_owner(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    /// Processes [Uncredit](enum.Instruction.html).
+    pub fn process_uncredit(
+        program_id: &Pubkey,
+        amount: u64,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        // Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // Credit ticket
+        let ticket_info = next_account_info(account_info_iter)?;
+        // Stake pool withdraw authority
+        let withdraw_info = next_account_info(account_info_iter)?;
+        // Refund account of the ticket to return the pool tokens to
+        let return_to_info = next_account_info(account_info_iter)?;
+        // Target user account with SOLs
+        let target_account_info = next_account_info(account_info_iter)?;
+        // Payer of the ticket rent. Gets it back when the ticket is closed
+        let payer_info = next_account_info(account_info_iter)?;
+        // Cancel authority
+        let cancel_authority_info = next_account_info(account_info_iter)?;
+        // Pool token mint account
+        let pool_mint_info = next_account_info(account_info_iter)?;
+        // Clock sysvar account
+        let clock_info = next_account_info(account_info_iter)?;
+        let clock = &Clock::from_account_info(clock_info)?;
+        // Epoch schedule sysvar account
+        let epoch_schedule =
+            &EpochSchedule::from_account_info(next_account_info(account_info_iter)?)?;
+        // Pool token program id
+        let token_program_info = next_account_info(account_info_iter)?;
+
+        if stake_pool_info.owner != program_id {
+            msg!(
+                "Wrong owner {} for the stake pool {}. Expected {}",
+                stake_pool_info.owner,
+                stake_pool_info.key,
+                program_id
+            );
+            return Err(StakePoolError::WrongOwner.into());
+        }
+        let mut stake_pool = StakePool::deserialize(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            return Err(StakePoolError::InvalidState.into());
+        }
+
+        if stake_pool.token_program_id != *token_program_info.key {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let mut ticket = Self::read_credit_ticket(program_id, stake_pool_info.key, ticket_info)?;
+        if *target_account_info.key != ticket.sol_target {
+            msg!(
+                "Credit ticket #{} is for user {} but got {}",
+                ticket.sequence,
+                &ticket.sol_target,
+                target_account_info.key
+            );
+            return Err(ProgramError::InvalidArgument);
+        }
+        if *cancel_authority_info.key != ticket.cancel_authority {
+            msg!(
+                "User {} waits for authority {} but got {}",
+                target_account_info.key,
+                &ticket.cancel_authority,
+                cancel_authority_info.key
+            );
+            return Err(ProgramError::InvalidArgument);
+        }
+        if !cancel_authority_info.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        Self::check_ticket_payer(&ticket, payer_info)?;
+        if *return_to_info.key != ticket.refund_account {
+            msg!(
+                "Credit ticket #{} refunds to {} but got {}",
+                ticket.sequence,
+                &ticket.refund_account,
+                return_to_info.key
+            );
+            return Err(ProgramError::InvalidArgument);
+        }
+        Self::uncredit_ticket(
+            stake_pool_info,
+            &mut stake_pool,
+            ticket_info,
+            &mut ticket,
+            return_to_info,
+            payer_info,
+            pool_mint_info,
+            withdraw_info,
+            token_program_info,
+            clock,
+            epoch_schedule,
+            amount,
+        )?;
+        stake_pool.serialize(&mut stake_pool_info.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    /// Returns `amount` lamports of a ticket as pool tokens minted at the current ratio,
+    /// but no more than their share of the tokens burned at Credit
+    #[allow(clippy::too_many_arguments)]
+    fn uncredit_ticket<'a>(
+        stake_pool_info: &AccountInfo<'a>,
+        stake_pool: &mut StakePool,
+        ticket_info: &AccountInfo<'a>,
+        ticket: &mut CreditRecord,
+        return_to_info: &AccountInfo<'a>,
+        payer_info: &AccountInfo<'a>,
+        pool_mint_info: &AccountInfo<'a>,
+        withdraw_info: &AccountInfo<'a>,
+        token_program_info: &AccountInfo<'a>,
+        clock: &Clock,
+        epoch_schedule: &EpochSchedule,
+        amount: u64,
+    ) -> ProgramResult {
+        if stake_pool.pool_mint != *pool_mint_info.key {
+            return Err(StakePoolError::WrongPoolMint.into());
+        }
+        if amount > ticket.lamports_owed {
+            return Err(ProgramError::InsufficientFunds);
+        }
+        stake_pool.check_freshness(clock, epoch_schedule)?;
+
+        let pool_amount = stake_pool
+            .book_uncredit(ticket, amount)
+            .ok_or(StakePoolError::CalculationFailure)?;
+        Self::token_mint_to(
+            stake_pool_info.key,
+            token_program_info.clone(),
+            pool_mint_info.clone(),
+            return_to_info.clone(),
+            withdraw_info.clone(),
+            Self::AUTHORITY_WITHDRAW,
+            stake_pool.withdraw_bump_seed,
+            pool_amount,
+        )?;
+
+        if ticket.lamports_owed == 0 {
+            msg!("Credit ticket #{} is canceled", ticket.sequence);
+            Self::close_account(ticket_info, payer_info)?;
+        } else {
+            ticket.pack_into_slice(&mut ticket_info.data.borrow_mut());
+        }
+        Ok(())
+    }
+
+    /// Processes [ExpireCredit](enum.Instruction.html).
+    pub fn process_expire_credit(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        // Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // Credit ticket
+        let ticket_info = next_account_info(account_info_iter)?;
+        // Stake pool withdraw authority
+        let withdraw_info = next_account_info(account_info_iter)?;
+        // Refund account of the ticket to return the pool tokens to
+        let refund_info = next_account_info(account_info_iter)?;
+        // Target user account with SOLs
+        let target_account_info = next_account_info(account_info_iter)?;
+        // Payer of the ticket rent
+        let payer_info = next_account_info(account_info_iter)?;
+        // Pool token mint account
+        let pool_mint_info = next_account_info(account_info_iter)?;
+        // Clock sysvar account
+        let clock_info = next_account_info(account_info_iter)?;
+        let clock = &Clock::from_account_info(clock_info)?;
+        // Epoch schedule sysvar account
+        let epoch_schedule =
+            &EpochSchedule::from_account_info(next_account_info(account_info_iter)?)?;
+        // Pool token program id
+        let token_program_info = next_account_info(account_info_iter)?;
+
+        if stake_pool_info.owner != program_id {
+            msg!(
+                "Wrong owner {} for the stake pool {}. Expected {}",
+                stake_pool_info.owner,
+                stake_pool_info.key,
+                program_id
+            );
+            return Err(StakePoolError::WrongOwner.into());
+        }
+        let mut stake_pool = StakePool::deserialize(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            return Err(StakePoolError::InvalidState.into());
+        }
+
+        if stake_pool.token_program_id != *token_program_info.key {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let mut ticket = Self::read_credit_ticket(program_id, stake_pool_info.key, ticket_info)?;
+        if *target_account_info.key != ticket.sol_target {
+            msg!(
+                "Credit ticket #{} is for user {} but got {}",
+                ticket.sequence,
+                &ticket.sol_target,
+                target_account_info.key
+            );
+            return Err(ProgramError::InvalidArgument);
+        }
+        Self::check_ticket_payer(&ticket, payer_info)?;
+        // PayCreditors pays system accounts, so only its cancel authority may return such a ticket
+        if *target_account_info.owner == system_program::id() {
+            msg!(
+                "Credit ticket #{} is payable to {}",
+                ticket.sequence,
+                target_account_info.key
+            );
+            return Err(StakePoolError::CreditNotExpired.into());
+        }
+        if clock.epoch < ticket.expiry_epoch() {
+            msg!(
+                "Credit ticket #{} expires at epoch {}",
+                ticket.sequence,
+                ticket.expiry_epoch()
+            );
+            return Err(StakePoolError::CreditNotExpired.into());
+        }
+        if *refund_info.key != ticket.refund_account {
+            msg!(
+                "Credit ticket #{} refunds to {} but got {}",
+                ticket.sequence,
+                &ticket.refund_account,
+                refund_info.key
+            );
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        msg!("Credit ticket #{} is expired", ticket.sequence);
+        let amount = ticket.lamports_owed;
+        Self::uncredit_ticket(
+            stake_pool_info,
+            &mut stake_pool,
+            ticket_info,
+            &mut ticket,
+            refund_info,
+            payer_info,
+            pool_mint_info,
+            withdraw_info,
+            token_program_info,
+            clock,
+            epoch_schedule,
+            amount,
+        )?;
+        stake_pool.serialize(&mut stake_pool_info.data.borrow_mut())?;
+
+        Ok(())
+    }
+
+    /// Processes [RefundCreditList](enum.Instruction.html).
+    pub fn process_refund_credit_list(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        // Stake pool
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        // Pool owner. Gets the list rent when the list is closed
+        let owner_info = next_account_info(account_info_iter)?;
+        // Credit list of the pool
+        let credit_list_info = next_account_info(account_info_iter)?;
+        let credit_reserve_info = next_account_info(account_info_iter)?;
+        // Stake pool withdraw authority
+        let withdraw_info = next_account_info(account_info_iter)?;
+        // Pool token program id
+        let token_program_info = next_account_info(account_info_iter)?;
+
+        if stake_pool_info.owner != program_id {
+            msg!(
+                "Wrong owner {} for the stake pool {}. Expected {}",
+                stake_pool_info.owner,
+                stake_pool_info.key,
+                program_id
+            );
+            return Err(StakePoolError::WrongOwner.into());
+        }
+        let mut stake_pool = StakePool::deserialize(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            return Err(StakePoolError::InvalidState.into());
+        }
+
+        stake_pool.check_owner(owner_info)?;
+        stake_pool.check_authority_withdraw(withdraw_info.key, program_id, stake_pool_info.key)?;
+
+        if stake_pool.token_program_id != *token_program_info.key {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        if *credit_reserve_info.key != stake_pool.credit_reserve {
+            msg!(
+                "Expected credit reserve to be {} but got {}",
+                &stake_pool.credit_reserve,
+                credit_reserve_info.key
+            );
+            return Err(ProgramError::InvalidArgument);
+        }
+        if stake_pool.credit_list == Pubkey::default()
+            || *credit_list_info.key != stake_pool.credit_list
+        {
+            msg!(
+                "Expected credit list to be {} but got {}",
+                &stake_pool.credit_list,
+                credit_list_info.key
+            );
+            return Err(ProgramError::InvalidArgument);
+        }
+        if credit_list_info.owner != program_id {
+            msg!(
+                "Wrong owner {} for the credit list {}. Expected {}",
+                credit_list_info.owner,
+                credit_list_info.key,
+                program_id
+            );
+            return Err(StakePoolError::WrongOwner.into());
+        }
+
+        let mut credit_list = CreditList::deserialize(&credit_list_info.data.borrow())?;
+        for refund_info in account_info_iter {
+            let entry = credit_list.credits.pop().ok_or_else(|| {
+                msg!("Every credit of the list is refunded");
+                StakePoolError::UnknownCreditor
+            })?;
+            if refund_info.owner != token_program_info.key {
+                msg!(
+                    "Refund account {} must be a token account but owner is {}",
+                    refund_info.key,
+                    refund_info.owner
+                );
+                return Err(ProgramError::IncorrectProgramId);
+            }
+            let refund_account =
+                spl_token::state::Account::unpack_from_slice(&refund_info.data.borrow())?;
+            if refund_account.mint != stake_pool.pool_mint {
+                return Err(StakePoolError::WrongAccountMint.into());
+            }
+            if refund_account.owner != entry.sol_target {
+                msg!(
+                    "Credit of {} can't be refunded to account {} of {}",
+                    &entry.sol_target,
+                    refund_info.key,
+                    &refund_account.owner
+                );
+                return Err(StakePoolError::WrongCreditOwner.into());
+            }
+
+            if entry.token_amount > 0 {
+                Self::token_transfer(
+                    stake_pool_info.key,
+                    token_program_info.clone(),
+                    credit_reserve_info.clone(),
+                    refund_info.clone(),
+                    withdraw_info.clone(),
+                    Self::AUTHORITY_WITHDRAW,
+                    stake_pool.withdraw_bump_seed,
+                    entry.token_amount,
+                )?;
+            }
+            msg!(
+                "Refunded {} pool tokens to {}",
+                entry.token_amount,
+                &entry.sol_target
+            );
+        }
+
+        if credit_list.credits.is_empty() {
+            msg!("Credit list {} is closed", credit_list_info.key);
+            Self::close_account(credit_list_info, owner_info)?;
+            stake_pool.credit_list = Pubkey::default();
+            stake_pool.serialize(&mut stake_pool_info.data.borrow_mut())?;
+        } else {
+            credit_list.serialize(&mut credit_list_info.data.borrow_mut())?;
+        }
+
+        Ok(())
+    }
+
+    /// Processes [SetStakingAuthority](enum.Instruction.html).
+    pub fn process_set_staking_authority(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        let owner_info = next_account_info(account_info_iter)?;
+        let withdraw_info = next_account_info(account_info_iter)?;
+        let validator_stake_list_info = next_account_info(account_info_iter)?;
+        let stake_account_info = next_account_info(account_info_iter)?;
+        let new_authority_info = next_account_info(account_info_iter)?;
+        // Clock sysvar account
+        let clock_info = next_account_info(account_info_iter)?;
+        let clock = &Clock::from_account_info(clock_info)?;
+        // Stake history sysvar account
+        let stake_history =
+            &StakeHistory::from_account_info(next_account_info(account_info_iter)?)?;
+        // Staking program id
+        let stake_program_info = next_account_info(account_info_iter)?;
+
+        if stake_pool_info.owner != program_id {
+            msg!(
+                "Wrong owner {} for the stake pool {}. Expected {}",
+                stake_pool_info.owner,
+                stake_pool_info.key,
+                program_id
+            );
+            return Err(StakePoolError::WrongOwner.into());
+        }
+        let mut stake_pool = StakePool::deserialize(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            return Err(StakePoolError::InvalidState.into());
+        }
+
+        // Check owner validity and signature
+        stake_pool.check_owner(owner_info)?;
+
+        stake_pool.check_authority_withdraw(withdraw_info.key, program_id, stake_pool_info.key)?;
+
+        if *stake_program_info.key != stake::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        // Check validator stake account list storage
+        if *validator_stake_list_info.key != stake_pool.validator_stake_list {
+            return Err(StakePoolError::InvalidValidatorStakeList.into());
+        }
+
+        // Read validator stake list account and check if it is valid
+        let mut validator_stake_list =
+            ValidatorStakeList::deserialize(&validator_stake_list_info.data.borrow())?;
+        if !validator_stake_list.is_initialized() {
+            return Err(StakePoolError::InvalidState.into());
+        }
+
+        let stake_state: stake::StakeState = deserialize(&stake_account_info.data.borrow())
+            .map_err(|_| {
+                msg!("Error reading stake {} state", stake_account_info.key);
+                ProgramError::InvalidAccountData
+            })?;
+        let delegation = stake_state.delegation().ok_or_else(|| {
+            msg!("Stake {} is not delegated", stake_account_info.key);
+            StakePoolError::WrongStakeState
+        })?;
+        let (validator_index, validator) = validator_stake_list
+            .validators
+            .iter_mut()
+            .enumerate()
+            .find(|(_, validator)| validator.validator_account == delegation.voter_pubkey)
+            .ok_or_else(|| {
+                msg!("Unexpected validator account {}", delegation.voter_pubkey);
+                StakePoolError::ValidatorNotFound
+            })?;
+
+        // Removing the stake changes the stake count the paged update walks
+        if stake_pool
+            .list_update_cursor
+            .is_inside(clock.epoch, validator_index)
+        {
+            msg!(
+                "Validator {} is in the middle of a paged update. Continue UpdateListBalancePage",
+                validator.validator_account
+            );
+            return Err(StakePoolError::ListUpdateInProgress.into());
+        }
+
+        // The stake is taken off the totals counted in this epoch
+        if validator.last_update_epoch < clock.epoch || stake_pool.last_update_epoch < clock.epoch {
+            return Err(StakePoolError::StakeListAndPoolOutOfDate.into());
+        }
+
+        // Only the last stake account can leave the pool without breaking the index sequence
+        if validator.stake_count == 0 {
+            return Err(StakePoolError::InvalidStakeIndex.into());
+        }
+        let index = validator.stake_count - 1;
+        validator.check_validator_stake_address(
+            program_id,
+            stake_pool_info.key,
+            index,
+            stake_account_info.key,
+        )?;
+
+        // Same split as UpdateListBalance
+        let (effective, activating, deactivating) =
+            delegation.stake_activating_and_deactivating(clock.epoch, Some(stake_history), true);
+        let active = effective.saturating_sub(deactivating);
+        let lamports = stake_account_info.lamports();
+        let out_of_date = || {
+            msg!(
+                "Validator {} totals don't hold stake {}. Run UpdateListBalance first",
+                delegation.voter_pubkey,
+                stake_account_info.key
+            );
+            StakePoolError::StakeListOutOfDate
+        };
+        validator.balance = validator
+            .balance
+            .checked_sub(lamports)
+            .ok_or_else(out_of_date)?;
+        validator.active_lamports = validator
+            .active_lamports
+            .checked_sub(active)
+            .ok_or_else(out_of_date)?;
+        validator.activating_lamports = validator
+            .activating_lamports
+            .checked_sub(activating)
+            .ok_or_else(out_of_date)?;
+        validator.deactivating_lamports = validator
+            .deactivating_lamports
+            .checked_sub(deactivating)
+            .ok_or_else(out_of_date)?;
+        validator.stake_count = index;
+
+        // No pool tokens are burned: the holders bear the stake that leaves
+        stake_pool.stake_total = stake_pool
+            .stake_total
+            .checked_sub(lamports)
+            .ok_or(StakePoolError::CalculationFailure)?;
+        stake_pool.active_lamports = stake_pool
+            .active_lamports
+            .checked_sub(active)
+            .ok_or(StakePoolError::CalculationFailure)?;
+        stake_pool.activating_lamports = stake_pool
+            .activating_lamports
+            .checked_sub(activating)
+            .ok_or(StakePoolError::CalculationFailure)?;
+        stake_pool.deactivating_lamports = stake_pool
+            .deactivating_lamports_at(clock.epoch)
+            .checked_sub(deactivating)
+            .ok_or(StakePoolError::CalculationFailure)?;
+        stake_pool.deactivating_epoch = clock.epoch;
+
+        let withdraw_signer_seeds: &[&[_]] = &[
+            &stake_pool_info.key.to_bytes()[..32],
+            Self::AUTHORITY_WITHDRAW,
+            &[stake_pool.withdraw_bump_seed],
+        ];
+
+        msg!(
+            "Set staker and withdrawer {} for stake #{} {}",
+            new_authority_info.key,
+            index,
+            stake_account_info.key
+        );
+
+        // The withdrawer may change both authorities. It goes last since it signs both
+        for stake_authorize in &[
+            stake::StakeAuthorize::Staker,
+            stake::StakeAuthorize::Withdrawer,
+        ] {
+            invoke_signed(
+                &stake::authorize(
+                    stake_account_info.key,
+                    withdraw_info.key,
+                    new_authority_info.key,
+                    *stake_authorize,
+                ),
+                &[
+                    stake_account_info.clone(),
+                    clock_info.clone(),
+                    withdraw_info.clone(),
+                    stake_program_info.clone(),
+                ],
+                &[withdraw_signer_seeds],
+            )?;
+        }
+
+        validator_stake_list.serialize(&mut validator_stake_list_info.data.borrow_mut())?;
+        stake_pool.serialize(&mut stake_pool_info.data.borrow_mut())?;
+        Ok(())
+    }
+
+    /// Processes [ProposeOwner](enum.Instruction.html).
+    pub fn process_propose_owner(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        let owner_info = next_account_info(account_info_iter)?;
+        let new_owner_info = next_account_info(account_info_iter)?;
+        let new_owner_fee_info = next_account_info(account_info_iter)?;
+
+        if stake_pool_info.owner != program_id {
+            msg!(
+                "Wrong owner {} for the stake pool {}. Expected {}",
+                stake_pool_info.owner,
+                stake_pool_info.key,
+                program_id
+            );
+            return Err(StakePoolError::WrongOwner.into());
+        }
+        let mut stake_pool = StakePool::deserialize(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            return Err(StakePoolError::InvalidState.into());
+        }
+
+        // Check owner validity and signature
+        stake_pool.check_owner(owner_info)?;
+
+        // Check if fee account's owner the same as token program id
+        if *new_owner_fee_info.owner != stake_pool.token_program_id {
+            msg!(
+                "Expexted owner fee's account {} to have {} owner but it has {}",
+                new_owner_fee_info.key,
+                &stake_pool.token_program_id,
+                new_owner_fee_info.owner
+            );
+            return Err(StakePoolError::InvalidFeeAccount.into());
+        }
+
+        // Check for owner fee account to have proper mint assigned
+        if stake_pool.pool_mint
+            != spl_token::state::Account::unpack_from_slice(&new_owner_fee_info.data.borrow())?.mint
+        {
+            return Err(StakePoolError::WrongAccountMint.into());
+        }
+
+        stake_pool.pending_owner = *new_owner_info.key;
+        stake_pool.pending_owner_fee_account = *new_owner_fee_info.key;
+        stake_pool.serialize(&mut stake_pool_info.data.borrow_mut())?;
+        Ok(())
+    }
+
+    /// Processes [AcceptOwner](enum.Instruction.html).
+    pub fn process_accept_owner(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        let new_owner_info = next_account_info(account_info_iter)?;
+
+        if stake_pool_info.owner != program_id {
+            msg!(
+                "Wrong owner {} for the stake pool {}. Expected {}",
+                stake_pool_info.owner,
+                stake_pool_info.key,
+                program_id
+            );
+            return Err(StakePoolError::WrongOwner.into());
+        }
+        let mut stake_pool = StakePool::deserialize(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            return Err(StakePoolError::InvalidState.into());
+        }
+
+        if stake_pool.pending_owner == Pubkey::default() {
+            msg!("No owner proposal to accept");
+            return Err(StakePoolError::InvalidState.into());
+        }
+        if *new_owner_info.key != stake_pool.pending_owner {
+            msg!(
+                "Expected proposed owner {} but got {}",
+                &stake_pool.pending_owner,
+                new_owner_info.key
+            );
+            return Err(StakePoolError::WrongOwner.into());
+        }
+        if !new_owner_info.is_signer {
+            return Err(StakePoolError::SignatureMissing.into());
+        }
+
+        stake_pool.owner = stake_pool.pending_owner;
+        stake_pool.owner_fee_account = stake_pool.pending_owner_fee_account;
+        stake_pool.pending_owner = Pubkey::default();
+        stake_pool.pending_owner_fee_account = Pubkey::default();
+        stake_pool.serialize(&mut stake_pool_info.data.borrow_mut())?;
+        Ok(())
+    }
+
+    /// Processes [CancelOwnerProposal](enum.Instruction.html).
+    pub fn process_cancel_owner_proposal(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        let owner_info = next_account_info(account_info_iter)?;
+
+        if stake_pool_info.owner != program_id {
+            msg!(
+                "Wrong owner {} for the stake pool {}. Expected {}",
+                stake_pool_info.owner,
+                stake_pool_info.key,
+                program_id
+            );
+            return Err(StakePoolError::WrongOwner.into());
+        }
+        let mut stake_pool = StakePool::deserialize(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            return Err(StakePoolError::InvalidState.into());
+        }
+
+        // Check owner validity and signature
+        stake_pool.check_owner(owner_info)?;
+
+        stake_pool.pending_owner = Pubkey::default();
+        stake_pool.pending_owner_fee_account = Pubkey::default();
+        stake_pool.serialize(&mut stake_pool_info.data.borrow_mut())?;
+        Ok(())
+    }
+
+    /// Processes [MigrateState](enum.Instruction.html).
+    pub fn process_migrate_state(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let stake_pool_info = next_account_info(account_info_iter)?;
+
+        if stake_pool_info.owner != program_id {
+            msg!(
+                "Wrong owner {} for the stake pool {}. Expected {}",
+                stake_pool_info.owner,
+                stake_pool_info.key,
+                program_id
+            );
+            return Err(StakePoolError::WrongOwner.into());
+        }
+
+        let mut data = stake_pool_info.data.borrow_mut();
+        if data.first() == Some(&PROGRAM_VERSION) {
+            msg!("Stake pool already has version {}", PROGRAM_VERSION);
+            return Ok(());
+        }
+        let mut stake_pool = StakePool::deserialize_v1(&data)?;
+        if data.len() < StakePool::PACKED_LEN {
+            msg!(
+                "Stake pool account has {} bytes but version {} needs {}",
+                data.len(),
+                PROGRAM_VERSION,
+                StakePool::PACKED_LEN
+            );
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+
+        stake_pool.version = PROGRAM_VERSION;
+        for byte in data.iter_mut() {
+            *byte = 0;
+        }
+        stake_pool.serialize(&mut data)
+    }
+
+    /// Processes [SetDepositFee, SetWithdrawalFee and SetPerformanceFee](enum.Instruction.html).
+    pub fn process_set_fee(
+        program_id: &Pubkey,
+        fee_type: FeeType,
+        fee: Fee,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        let owner_info = next_account_info(account_info_iter)?;
+        // Clock sysvar account
+        let clock_info = next_account_info(account_info_iter)?;
+        let clock = &Clock::from_account_info(clock_info)?;
+
+        if stake_pool_info.owner != program_id {
+            msg!(
+                "Wrong owner {} for the stake pool {}. Expected {}",
+                stake_pool_info.owner,
+                stake_pool_info.key,
+                program_id
+            );
+            return Err(StakePoolError::WrongOwner.into());
+        }
+        let mut stake_pool = StakePool::deserialize(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            return Err(StakePoolError::InvalidState.into());
+        }
+
+        // Check owner validity and signature
+        stake_pool.check_owner(owner_info)?;
+
+        if !fee.is_within(&fee_type.max()) {
+            msg!(
+                "{:?} fee {}/{} is above the maximum {}/{}",
+                fee_type,
+                fee.numerator,
+                fee.denominator,
+                fee_type.max().numerator,
+                fee_type.max().denominator
+            );
+            return Err(StakePoolError::FeeTooHigh.into());
+        }
+
+        stake_pool.set_fee(fee_type, fee, clock.epoch);
+        let pending = *stake_pool.pending_fee_mut(fee_type);
+        if pending.is_pending() {
+            msg!(
+                "{:?} fee increase is applied in epoch {}",
+                fee_type,
+                pending.activation_epoch
+            );
+        }
+        stake_pool.serialize(&mut stake_pool_info.data.borrow_mut())?;
+        Ok(())
+    }
+
+    /// Processes [SetFreshnessPolicy](enum.Instruction.html).
+    pub fn process_set_freshness_policy(
+        program_id: &Pubkey,
+        policy: FreshnessPolicy,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        let owner_info = next_account_info(account_info_iter)?;
+
+        if stake_pool_info.owner != program_id {
+            msg!(
+                "Wrong owner {} for the stake pool {}. Expected {}",
+                stake_pool_info.owner,
+                stake_pool_info.key,
+                program_id
+            );
+            return Err(StakePoolError::WrongOwner.into());
+        }
+        let mut stake_pool = StakePool::deserialize(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            return Err(StakePoolError::InvalidState.into());
+        }
+
+        // Check owner validity and signature
+        stake_pool.check_owner(owner_info)?;
+
+        stake_pool.freshness_policy = policy;
+        stake_pool.serialize(&mut stake_pool_info.data.borrow_mut())?;
+        Ok(())
+    }
+
+    /// Processes [SetLiquidityTarget](enum.Instruction.html).
+    pub fn process_set_liquidity_target(
+        program_id: &Pubkey,
+        liquidity_target_bps: u16,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        let owner_info = next_account_info(account_info_iter)?;
+
+        if stake_pool_info.owner != program_id {
+            msg!(
+                "Wrong owner {} for the stake pool {}. Expected {}",
+                stake_pool_info.owner,
+                stake_pool_info.key,
+                program_id
+            );
+            return Err(StakePoolError::WrongOwner.into());
+        }
+        let mut stake_pool = StakePool::deserialize(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            return Err(StakePoolError::InvalidState.into());
+        }
+
+        // Check owner validity and signature
+        stake_pool.check_owner(owner_info)?;
+
+        if liquidity_target_bps > StakePool::MAX_LIQUIDITY_TARGET_BPS {
+            msg!(
+                "Liquidity target {} bps is above {}",
+                liquidity_target_bps,
+                StakePool::MAX_LIQUIDITY_TARGET_BPS
+            );
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        stake_pool.liquidity_target_bps = liquidity_target_bps;
+        stake_pool.serialize(&mut stake_pool_info.data.borrow_mut())?;
+        Ok(())
+    }
+
+    /// Processes [SetCreditOrdering](enum.Instruction.html).
+    pub fn process_set_credit_ordering(
+        program_id: &Pubkey,
+        credit_ordering: CreditOrdering,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        let owner_info = next_account_info(account_info_iter)?;
+
+        if stake_pool_info.owner != program_id {
+            msg!(
+                "Wrong owner {} for the stake pool {}. Expected {}",
+                stake_pool_info.owner,
+                stake_pool_info.key,
+                program_id
+            );
+            return Err(StakePoolError::WrongOwner.into());
+        }
+        let mut stake_pool = StakePool::deserialize(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            return Err(StakePoolError::InvalidState.into());
+        }
+
+        // Check owner validity and signature
+        stake_pool.check_owner(owner_info)?;
+
+        stake_pool.credit_ordering = credit_ordering;
+        stake_pool.serialize(&mut stake_pool_info.data.borrow_mut())?;
+        Ok(())
+    }
+
+    /// Processes [ReallocList](enum.Instruction.html).
+    pub fn process_realloc_list(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let stake_pool_info = next_account_info(account_info_iter)?;
+        let owner_info = next_account_info(account_info_iter)?;
+        let list_info = next_account_info(account_info_iter)?;
+        let new_list_info = next_account_info(account_info_iter)?;
+        // Receives the lamports of the old list account
+        let lamports_to_info = next_account_info(account_info_iter)?;
+        // Rent sysvar account
+        let rent_info = next_account_info(account_info_iter)?;
+        let rent = &Rent::from_account_info(rent_info)?;
+
+        if stake_pool_info.owner != program_id {
+            msg!(
+                "Wrong owner {} for the stake pool {}. Expected {}",
+                stake_pool_info.owner,
+                stake_pool_info.key,
+                program_id
+            );
+            return Err(StakePoolError::WrongOwner.into());
+        }
+        let mut stake_pool = StakePool::deserialize(&stake_pool_info.data.borrow())?;
+        if !stake_pool.is_initialized() {
+            return Err(StakePoolError::InvalidState.into());
+        }
+
+        // Check owner validity and signature
+        stake_pool.check_owner(owner_info)?;
+
+        if new_list_info.owner != program_id {
+            msg!(
+                "Wrong owner {} for the new list {}. Expected {}",
+                new_list_info.owner,
+                new_list_info.key,
+                program_id
+            );
+            return Err(StakePoolError::WrongOwner.into());
+        }
+        if new_list_info.key == list_info.key || new_list_info.key == lamports_to_info.key {
+            return Err(ProgramError::InvalidArgument);
+        }
+        if !rent.is_exempt(new_list_info.lamports(), new_list_info.data_len()) {
+            return Err(StakePoolError::AccountNotRentExempt.into());
+        }
+
+        if *list_info.key == stake_pool.validator_stake_list {
+            let validator_stake_list = ValidatorStakeList::deserialize(&list_info.data.borrow())?;
+            if !validator_stake_list.is_initialized() {
+                return Err(StakePoolError::InvalidState.into());
+            }
+            if ValidatorStakeList::deserialize(&new_list_info.data.borrow())?.is_initialized() {
+                return Err(StakePoolError::AlreadyInUse.into());
+            }
+            let capacity = ValidatorStakeList::capacity(new_list_info.data_len());
+            if validator_stake_list.validators.len() > capacity {
+                msg!(
+                    "New list fits {} of {} validators",
+                    capacity,
+                    validator_stake_list.validators.len()
+                );
+                return Err(StakePoolError::ValidatorListOverflow.into());
+            }
+            validator_stake_list.serialize(&mut new_list_info.data.borrow_mut())?;
+            stake_pool.validator_stake_list = *new_list_info.key;
+        } else {
+            msg!(
+                "Account {} is not the validator stake list of the pool",
+                list_info.key
+            );
+            return Err(ProgramError::InvalidArgument);
+        }
+        msg!("List {} moved to {}", list_info.key, new_list_info.key);
+
+        Self::close_account(list_info, lamports_to_info)?;
+
+        stake_pool.serialize(&mut stake_pool_info.data.borrow_mut())?;
+        Ok(())
+    }
+
+    /// Processes [SetValidatorTargetWeight](enum.Instruction.html).
+    pub fn process_set_validator_target_weight(
+        program_id: &Pubkey,
+        target_weight: u32,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
         let stake_pool_info = next_account_info(account_info_iter)?;
         let owner_info = next_account_info(account_info_iter)?;
-        let new_owner_info = next_account_info(account_info_iter)?;
-        let new_owner_fee_info = next_account_info(account_info_iter)?;
+        let validator_stake_list_info = next_account_info(account_info_iter)?;
+        let validator_info = next_account_info(account_info_iter)?;
 
         if stake_pool_info.owner != program_id {
             msg!(
@@ -1438,7 +3375,7 @@ impl Processor {
             );
             return Err(StakePoolError::WrongOwner.into());
         }
-        let mut stake_pool = StakePool::deserialize(&stake_pool_info.data.borrow())?;
+        let stake_pool = StakePool::deserialize(&stake_pool_info.data.borrow())?;
         if !stake_pool.is_initialized() {
             return Err(StakePoolError::InvalidState.into());
         }
@@ -1446,19 +3383,26 @@ impl Processor {
         // Check owner validity and signature
         stake_pool.check_owner(owner_info)?;
 
-        // Check for owner fee account to have proper mint assigned
-        if stake_pool.pool_mint
-            != spl_token::state::Account::unpack_from_slice(&new_owner_fee_info.data.borrow())?.mint
-        {
-            return Err(StakePoolError::WrongAccountMint.into());
+        // Check validator stake account list storage
+        if *validator_stake_list_info.key != stake_pool.validator_stake_list {
+            return Err(StakePoolError::InvalidValidatorStakeList.into());
         }
 
-        stake_pool.owner = *new_owner_info.key;
-        stake_pool.owner_fee_account = *new_owner_fee_info.key;
-        stake_pool.serialize(&mut stake_pool_info.data.borrow_mut())?;
+        let mut validator_stake_list =
+            ValidatorStakeList::deserialize(&validator_stake_list_info.data.borrow())?;
+        if !validator_stake_list.is_initialized() {
+            return Err(StakePoolError::InvalidState.into());
+        }
+
+        let validator = validator_stake_list
+            .find_mut(validator_info.key)
+            .ok_or(StakePoolError::ValidatorNotFound)?;
+        validator.target_weight = target_weight;
+        validator_stake_list.serialize(&mut validator_stake_list_info.data.borrow_mut())?;
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn init_stake<'a>(
         validator_stake_info: &mut ValidatorStakeInfo,
         validator_vote_info: &AccountInfo<'a>,
@@ -1470,6 +3414,7 @@ impl Processor {
         reserve_info: &AccountInfo<'a>,
         deposit_info: &AccountInfo<'a>,
         withdraw_info: &AccountInfo<'a>,
+        system_program_info: &AccountInfo<'a>,
         stake_program_info: &AccountInfo<'a>,
         clock_info: &AccountInfo<'a>,
         stake_history_info: &AccountInfo<'a>,
@@ -1486,7 +3431,7 @@ impl Processor {
         let stake_signer_seeds = &[
             &validator_vote_info.key.to_bytes()[..32],
             &stake_pool.to_bytes()[..32],
-            &unsafe { std::mem::transmute::<u32, [u8; 4]>(index) },
+            &index.to_le_bytes(),
             &[stake_bump_seed],
         ];
 
@@ -1506,7 +3451,11 @@ impl Processor {
                 std::mem::size_of::<stake::StakeState>() as u64,
                 &stake::id(),
             ),
-            &[reserve_info.clone(), stake_account_info.clone()],
+            &[
+                reserve_info.clone(),
+                stake_account_info.clone(),
+                system_program_info.clone(),
+            ],
             &[stake_signer_seeds, reserve_signer_seeds],
         )?;
 
@@ -1549,6 +3498,7 @@ impl Processor {
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn redelegate_stake<'a>(
         validator_stake_info: &mut ValidatorStakeInfo,
         validator_vote_info: &AccountInfo<'a>,
@@ -1606,15 +3556,21 @@ impl Processor {
         Ok(())
     }
 
-    /// Process DelegateReserve
+    /// Process DelegateReserve and DelegateReserveToTargets.
+    /// Without the owner allocations are limited by the validator target weights
     pub fn process_delegate_reserve(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
         instructions: &[DelegateReserveInstruction],
+        permissionless: bool,
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
         let stake_pool_info = next_account_info(account_info_iter)?;
-        let owner_info = next_account_info(account_info_iter)?;
+        let owner_info = if permissionless {
+            None
+        } else {
+            Some(next_account_info(account_info_iter)?)
+        };
         let validator_stake_list_info = next_account_info(account_info_iter)?;
         let withdraw_info = next_account_info(account_info_iter)?;
         let deposit_info = next_account_info(account_info_iter)?;
@@ -1624,10 +3580,10 @@ impl Processor {
         let stake_program_info = next_account_info(account_info_iter)?;
         // Clock sysvar account
         let clock_info = next_account_info(account_info_iter)?;
-        // let clock = &Clock::from_account_info(clock_info)?;
+        let clock = &Clock::from_account_info(clock_info)?;
         // Stake history sysvar account
         let stake_history_info = next_account_info(account_info_iter)?;
-        // let stake_history = &StakeHistory::from_account_info(stake_history_info)?;
+        let stake_history = &StakeHistory::from_account_info(stake_history_info)?;
         // Stake config sysvar account
         let stake_config_info = next_account_info(account_info_iter)?;
         // Rent sysvar account
@@ -1648,7 +3604,9 @@ impl Processor {
             return Err(StakePoolError::InvalidState.into());
         }
 
-        stake_pool.check_owner(owner_info)?;
+        if let Some(owner_info) = owner_info {
+            stake_pool.check_owner(owner_info)?;
+        }
 
         // Check validator stake account list storage
         if *validator_stake_list_info.key != stake_pool.validator_stake_list {
@@ -1662,6 +3620,21 @@ impl Processor {
             return Err(StakePoolError::InvalidState.into());
         }
 
+        if permissionless {
+            if let Some(validator) = validator_stake_list
+                .validators
+                .iter()
+                .find(|validator| validator.last_update_epoch < clock.epoch)
+            {
+                msg!(
+                    "Validator {} balance is from epoch {}. Run UpdateListBalance first",
+                    validator.validator_account,
+                    validator.last_update_epoch
+                );
+                return Err(StakePoolError::StakeListOutOfDate.into());
+            }
+        }
+
         stake_pool.check_authority_deposit(deposit_info.key, program_id, stake_pool_info.key)?;
         stake_pool.check_authority_withdraw(withdraw_info.key, program_id, stake_pool_info.key)?;
 
@@ -1688,25 +3661,68 @@ impl Processor {
             &[reserve_bump],
         ];
 
-        let lamports_available = reserve_account_info
-            .lamports
-            .borrow()
-            .saturating_sub(Self::min_reserve_balance(&rent));
+        // Keep the liquidity target for instant withdrawals
+        let reserve_floor = Self::min_reserve_balance(&rent).max(
+            stake_pool
+                .calc_liquidity_target()
+                .ok_or(StakePoolError::CalculationFailure)?,
+        );
+        // Lamports owed to credit tickets are never delegated
+        let reserve_lamports = reserve_account_info.lamports();
+        let lamports_available = reserve_lamports
+            .checked_sub(stake_pool.credit_lamports_total)
+            .ok_or_else(|| {
+                msg!(
+                    "Reserve holds {} lamports but credit tickets are owed {}",
+                    reserve_lamports,
+                    stake_pool.credit_lamports_total
+                );
+                ProgramError::InsufficientFunds
+            })?
+            .saturating_sub(reserve_floor);
+        let mut deficits = if permissionless {
+            Some(
+                validator_stake_list
+                    .target_deficits(lamports_available)
+                    .ok_or(StakePoolError::CalculationFailure)?,
+            )
+        } else {
+            None
+        };
         let mut total_amount = 0;
         let mut changed = false;
         for instruction in instructions {
             let validator_vote_info = next_account_info(account_info_iter)?;
             let stake_account_info = next_account_info(account_info_iter)?;
 
-            if let Some(validator) = validator_stake_list
+            if let Some((validator_index, validator)) = validator_stake_list
                 .validators
                 .iter_mut()
-                .find(|validator| *validator_vote_info.key == validator.validator_account)
+                .enumerate()
+                .find(|(_, validator)| *validator_vote_info.key == validator.validator_account)
             {
                 if total_amount + instruction.amount > lamports_available {
                     return Err(ProgramError::InsufficientFunds);
                 }
 
+                if let Some(deficits) = &mut deficits {
+                    if instruction.amount == 0 {
+                        msg!("Amount must not be zero");
+                        return Err(ProgramError::InvalidArgument);
+                    }
+                    let deficit = &mut deficits[validator_index];
+                    if instruction.amount > *deficit {
+                        msg!(
+                            "Validator {} can get {} lamports from the reserve but {} requested",
+                            validator.validator_account,
+                            deficit,
+                            instruction.amount
+                        );
+                        return Err(StakePoolError::DelegationAboveTarget.into());
+                    }
+                    *deficit -= instruction.amount;
+                }
+
                 if instruction.stake_index > validator.stake_count {
                     return Err(StakePoolError::InvalidStakeIndex.into());
                 }
@@ -1732,6 +3748,7 @@ impl Processor {
                         reserve_account_info,
                         deposit_info,
                         withdraw_info,
+                        system_program_info,
                         stake_program_info,
                         clock_info,
                         stake_history_info,
@@ -1741,6 +3758,30 @@ impl Processor {
                         reserve_signer_seeds,
                     )?;
                 } else {
+                    // Redelegating reactivates the stake, so without the owner
+                    // it must not undo a deactivation
+                    if permissionless {
+                        let stake_state: stake::StakeState =
+                            deserialize(&stake_account_info.data.borrow()).map_err(|_| {
+                                msg!("Error reading stake {} state", stake_account_info.key);
+                                ProgramError::InvalidAccountData
+                            })?;
+                        if let Some(delegation) = stake_state.delegation() {
+                            let (effective, activating, deactivating) = delegation
+                                .stake_activating_and_deactivating(
+                                    clock.epoch,
+                                    Some(stake_history),
+                                    true,
+                                );
+                            if effective > 0 || activating > 0 || deactivating > 0 {
+                                msg!(
+                                    "Stake {} is not inactive. Only the owner can redelegate it",
+                                    stake_account_info.key
+                                );
+                                return Err(StakePoolError::WrongStakeState.into());
+                            }
+                        }
+                    }
                     // must be stake account
                     msg!("Redelegate stake {}", stake_account_info.key);
                     Self::redelegate_stake(
@@ -1763,8 +3804,8 @@ impl Processor {
 
                 if instruction.stake_index >= validator.stake_count {
                     validator.stake_count = instruction.stake_index + 1;
-                    changed = true;
                 }
+                changed = true;
 
                 total_amount += instruction.amount;
             } else {
@@ -1794,10 +3835,8 @@ impl Processor {
         let stake_program_info = next_account_info(account_info_iter)?;
         // Clock sysvar account
         let clock_info = next_account_info(account_info_iter)?;
-        // let clock = &Clock::from_account_info(clock_info)?;
         // Stake history sysvar account
         let stake_history_info = next_account_info(account_info_iter)?;
-        // let stake_history = &StakeHistory::from_account_info(stake_history_info)?;
 
         if stake_pool_info.owner != program_id {
             msg!(
@@ -1871,18 +3910,18 @@ impl Processor {
                 );
 
                 let main_stake_state: stake::StakeState =
-                    deserialize(&main_stake_account_info.data.borrow()).or_else(|_| {
+                    deserialize(&main_stake_account_info.data.borrow()).map_err(|_| {
                         msg!("Error reading stake {} state", main_stake_account_info.key);
-                        Err(ProgramError::InvalidAccountData)
+                        ProgramError::InvalidAccountData
                     })?;
 
                 let additional_stake_state: stake::StakeState =
-                    deserialize(&additional_stake_account_info.data.borrow()).or_else(|_| {
+                    deserialize(&additional_stake_account_info.data.borrow()).map_err(|_| {
                         msg!(
                             "Error reading stake {} state",
                             additional_stake_account_info.key
                         );
-                        Err(ProgramError::InvalidAccountData)
+                        ProgramError::InvalidAccountData
                     })?;
 
                 match (main_stake_state, additional_stake_state) {
@@ -1917,12 +3956,12 @@ impl Processor {
                         deposit_info.key,
                     ),
                     &[
-                        stake_program_info.clone(),
                         main_stake_account_info.clone(),
                         additional_stake_account_info.clone(),
                         clock_info.clone(),
                         stake_history_info.clone(),
                         deposit_info.clone(),
+                        stake_program_info.clone(),
                     ],
                     &[deposit_signer_seeds],
                 )?;
@@ -1947,17 +3986,27 @@ impl Processor {
         Ok(())
     }
 
-    /// Process Unstake
+    /// Process Unstake and UnstakeForCredits
     pub fn process_unstake(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
         instructions: &[UnstakeInstruction],
+        permissionless: bool,
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
         let stake_pool_info = next_account_info(account_info_iter)?;
-        let owner_info = next_account_info(account_info_iter)?;
+        let owner_info = if permissionless {
+            None
+        } else {
+            Some(next_account_info(account_info_iter)?)
+        };
         let validator_stake_list_info = next_account_info(account_info_iter)?;
         let deposit_info = next_account_info(account_info_iter)?;
+        let reserve_account_info = if permissionless {
+            Some(next_account_info(account_info_iter)?)
+        } else {
+            None
+        };
         let system_program_info = next_account_info(account_info_iter)?;
         // Staking program id
         let stake_program_info = next_account_info(account_info_iter)?;
@@ -1966,8 +4015,7 @@ impl Processor {
         let rent = &Rent::from_account_info(rent_info)?;
         // Clock sysvar account
         let clock_info = next_account_info(account_info_iter)?;
-        // let clock = &Clock::from_account_info(clock_info)?;
-        let stake_history_info = next_account_info(account_info_iter)?;
+        let clock = &Clock::from_account_info(clock_info)?;
 
         if stake_pool_info.owner != program_id {
             msg!(
@@ -1978,12 +4026,14 @@ impl Processor {
             );
             return Err(StakePoolError::WrongOwner.into());
         }
-        let stake_pool = StakePool::deserialize(&stake_pool_info.data.borrow())?;
+        let mut stake_pool = StakePool::deserialize(&stake_pool_info.data.borrow())?;
         if !stake_pool.is_initialized() {
             return Err(StakePoolError::InvalidState.into());
         }
 
-        stake_pool.check_owner(owner_info)?;
+        if let Some(owner_info) = owner_info {
+            stake_pool.check_owner(owner_info)?;
+        }
 
         // Check validator stake account list storage
         if *validator_stake_list_info.key != stake_pool.validator_stake_list {
@@ -2005,12 +4055,91 @@ impl Processor {
             &[stake_pool.deposit_bump_seed],
         ];
 
+        let (reserve_address, reserve_bump) =
+            Self::get_reserve_adderess(program_id, stake_pool_info.key);
+        let reserve_signer_seeds: &[&[u8]] = &[
+            &stake_pool_info.key.to_bytes()[..32],
+            Self::AUTHORITY_RESERVE,
+            &[reserve_bump],
+        ];
+
+        // Lamports left to unstake and the stake each validator can give
+        let mut limits = None;
+        if let Some(reserve_account_info) = reserve_account_info {
+            if *reserve_account_info.key != reserve_address {
+                msg!(
+                    "Expected reserve to be {} but got {}",
+                    &reserve_address,
+                    reserve_account_info.key
+                );
+                return Err(ProgramError::InvalidArgument);
+            }
+
+            // Credits are priced at the pool ratio
+            if stake_pool.last_update_epoch < clock.epoch {
+                return Err(StakePoolError::StakeListAndPoolOutOfDate.into());
+            }
+            if let Some(validator) = validator_stake_list
+                .validators
+                .iter()
+                .find(|validator| validator.last_update_epoch < clock.epoch)
+            {
+                msg!(
+                    "Validator {} balance is from epoch {}. Run UpdateListBalance first",
+                    validator.validator_account,
+                    validator.last_update_epoch
+                );
+                return Err(StakePoolError::StakeListOutOfDate.into());
+            }
+
+            let reserve_surplus = reserve_account_info
+                .lamports
+                .borrow()
+                .saturating_sub(Self::min_reserve_balance(&rent));
+            let required = stake_pool.required_unstake(
+                reserve_surplus,
+                stake_pool.deactivating_lamports_at(clock.epoch),
+            );
+            let excesses = validator_stake_list
+                .target_excesses(required)
+                .ok_or(StakePoolError::CalculationFailure)?;
+
+            // No validator left out of the batch may be more over its target
+            let is_selected = |validator: &ValidatorStakeInfo| {
+                instructions
+                    .iter()
+                    .any(|instruction| instruction.validator_address == validator.validator_account)
+            };
+            let max_skipped_excess = validator_stake_list
+                .validators
+                .iter()
+                .zip(&excesses)
+                .filter(|(validator, _)| !is_selected(validator))
+                .map(|(_, excess)| *excess)
+                .max()
+                .unwrap_or(0);
+            for (validator, excess) in validator_stake_list.validators.iter().zip(&excesses) {
+                if is_selected(validator) && *excess < max_skipped_excess {
+                    msg!(
+                        "Validator {} is {} lamports over its target but another one is {} over",
+                        validator.validator_account,
+                        excess,
+                        max_skipped_excess
+                    );
+                    return Err(StakePoolError::ValidatorNotOverTarget.into());
+                }
+            }
+
+            limits = Some((required, excesses));
+        }
+
         let mut changed = false;
         for instruction in instructions {
-            if let Some(validator) = validator_stake_list
+            if let Some((validator_index, validator)) = validator_stake_list
                 .validators
                 .iter_mut()
-                .find(|validator| instruction.validator_address == validator.validator_account)
+                .enumerate()
+                .find(|(_, validator)| instruction.validator_address == validator.validator_account)
             {
                 if instruction.source_index >= validator.stake_count {
                     return Err(StakePoolError::InvalidStakeIndex.into());
@@ -2027,6 +4156,39 @@ impl Processor {
                     instruction.source_index,
                     source_stake_info.key,
                 )?;
+
+                let amount = if instruction.split_index == instruction.source_index {
+                    **source_stake_info.lamports.borrow()
+                } else {
+                    instruction.amount
+                };
+                if let Some((required, excesses)) = &mut limits {
+                    if amount > *required {
+                        msg!(
+                            "Credits need {} more lamports but {} requested",
+                            required,
+                            amount
+                        );
+                        return Err(StakePoolError::UnstakeAboveRequired.into());
+                    }
+                    *required -= amount;
+
+                    let excess = &mut excesses[validator_index];
+                    if amount > *excess {
+                        msg!(
+                            "Validator {} is {} lamports over its target but {} requested",
+                            validator.validator_account,
+                            excess,
+                            amount
+                        );
+                        return Err(StakePoolError::ValidatorNotOverTarget.into());
+                    }
+                    *excess -= amount;
+                }
+                stake_pool
+                    .record_deactivation(clock.epoch, amount)
+                    .ok_or(StakePoolError::CalculationFailure)?;
+
                 if instruction.split_index == instruction.source_index {
                     // Deactivate main stake
                     msg!("Unstake {}", source_stake_info.key);
@@ -2034,11 +4196,10 @@ impl Processor {
                     invoke_signed(
                         &stake::deactivate_stake(source_stake_info.key, deposit_info.key),
                         &[
-                            stake_program_info.clone(),
                             source_stake_info.clone(),
                             deposit_info.clone(),
                             clock_info.clone(),
-                            stake_history_info.clone(),
+                            stake_program_info.clone(),
                         ],
                         &[deposit_signer_seeds],
                     )?;
@@ -2063,28 +4224,47 @@ impl Processor {
                         split_stake_info.key
                     );
 
-                    let split_stake_signer_seeds = &[
+                    let split_stake_signer_seeds: &[&[u8]] = &[
                         &instruction.validator_address.to_bytes()[..32],
                         &stake_pool_info.key.to_bytes()[..32],
-                        &unsafe { std::mem::transmute::<u32, [u8; 4]>(instruction.split_index) },
+                        &instruction.split_index.to_le_bytes(),
                         &[split_stake_bump_seed],
                     ];
 
-                    invoke_signed(
-                        &system_instruction::create_account(
-                            owner_info.key, // Sending 0, so any signer will suffice
-                            split_stake_info.key,
-                            rent.minimum_balance(std::mem::size_of::<StakeState>()),
-                            std::mem::size_of::<StakeState>() as u64,
-                            &stake::id(),
-                        ),
-                        &[
-                            system_program_info.clone(),
-                            owner_info.clone(),
-                            split_stake_info.clone(),
-                        ],
-                        &[split_stake_signer_seeds],
-                    )?;
+                    if let Some(owner_info) = owner_info {
+                        invoke_signed(
+                            &system_instruction::create_account(
+                                owner_info.key, // Sending 0, so any signer will suffice
+                                split_stake_info.key,
+                                rent.minimum_balance(std::mem::size_of::<StakeState>()),
+                                std::mem::size_of::<StakeState>() as u64,
+                                &stake::id(),
+                            ),
+                            &[
+                                owner_info.clone(),
+                                split_stake_info.clone(),
+                                system_program_info.clone(),
+                            ],
+                            &[split_stake_signer_seeds],
+                        )?;
+                    } else if let Some(reserve_account_info) = reserve_account_info {
+                        // Rent comes back to the reserve when the split stake is swept
+                        invoke_signed(
+                            &system_instruction::create_account(
+                                reserve_account_info.key,
+                                split_stake_info.key,
+                                rent.minimum_balance(std::mem::size_of::<StakeState>()),
+                                std::mem::size_of::<StakeState>() as u64,
+                                &stake::id(),
+                            ),
+                            &[
+                                reserve_account_info.clone(),
+                                split_stake_info.clone(),
+                                system_program_info.clone(),
+                            ],
+                            &[split_stake_signer_seeds, reserve_signer_seeds],
+                        )?;
+                    }
 
                     invoke_signed(
                         &stake::split_only(
@@ -2094,10 +4274,10 @@ impl Processor {
                             split_stake_info.key,
                         ),
                         &[
-                            stake_program_info.clone(),
                             source_stake_info.clone(),
                             deposit_info.clone(),
                             split_stake_info.clone(),
+                            stake_program_info.clone(),
                         ],
                         &[deposit_signer_seeds],
                     )?;
@@ -2105,11 +4285,10 @@ impl Processor {
                     invoke_signed(
                         &stake::deactivate_stake(split_stake_info.key, deposit_info.key),
                         &[
-                            stake_program_info.clone(),
                             split_stake_info.clone(),
                             deposit_info.clone(),
                             clock_info.clone(),
-                            stake_history_info.clone(),
+                            stake_program_info.clone(),
                         ],
                         &[deposit_signer_seeds],
                     )?;
@@ -2126,6 +4305,7 @@ impl Processor {
         if changed {
             validator_stake_list.serialize(&mut validator_stake_list_info.data.borrow_mut())?;
         }
+        stake_pool.serialize(&mut stake_pool_info.data.borrow_mut())?;
 
         Ok(())
     }
@@ -2135,13 +4315,12 @@ impl Processor {
         let account_info_iter = &mut accounts.iter();
         // Stake pool
         let stake_pool_info = next_account_info(account_info_iter)?;
-        let credit_list_info = next_account_info(account_info_iter)?;
-        // Stake pool deposit authority
-        let withdraw_info = next_account_info(account_info_iter)?;
         // Reserve account
         let reserve_account_info = next_account_info(account_info_iter)?;
-        // User account with pool tokens to burn from
-        let credit_reserve_info = next_account_info(account_info_iter)?;
+        // Stake pool withdraw authority
+        let withdraw_info = next_account_info(account_info_iter)?;
+        // Account to receive pool fee tokens
+        let owner_fee_info = next_account_info(account_info_iter)?;
         // Pool token mint account
         let pool_mint_info = next_account_info(account_info_iter)?;
         // Rent sysvar account
@@ -2172,27 +4351,11 @@ impl Processor {
         if stake_pool.token_program_id != *token_program_info.key {
             return Err(ProgramError::IncorrectProgramId);
         }
-
-        if *credit_list_info.key != stake_pool.credit_list {
-            msg!(
-                "Expected credit list to be {} but got {}",
-                &stake_pool.credit_list,
-                credit_list_info.key
-            );
-            return Err(ProgramError::InvalidArgument.into());
-        }
-        let mut credit_list = CreditList::deserialize(&credit_list_info.data.borrow())?;
-        if !credit_list.is_initialized() {
-            return Err(StakePoolError::InvalidState.into());
+        if stake_pool.owner_fee_account != *owner_fee_info.key {
+            return Err(StakePoolError::InvalidFeeAccount.into());
         }
-
-        if *credit_reserve_info.key != stake_pool.credit_reserve {
-            msg!(
-                "Expected credit reserve to be {} but got {}",
-                &stake_pool.credit_reserve,
-                credit_reserve_info.key
-            );
-            return Err(ProgramError::InvalidArgument.into());
+        if stake_pool.pool_mint != *pool_mint_info.key {
+            return Err(StakePoolError::WrongPoolMint.into());
         }
 
         stake_pool.check_authority_withdraw(withdraw_info.key, program_id, stake_pool_info.key)?;
@@ -2217,87 +4380,108 @@ impl Processor {
             .lamports
             .borrow()
             .saturating_sub(Self::min_reserve_balance(&rent));
-        let mut total_amount = 0;
-        let mut invalid_credits = Vec::new();
-        // take all creditors out of the list
-        let mut credit_list_iter = {
-            let size = credit_list.credits.len();
-            std::mem::replace(&mut credit_list.credits, Vec::with_capacity(size)).into_iter()
-        };
-        while let (Some(user_info), Some(credit)) =
-            (account_info_iter.next(), credit_list_iter.next())
-        {
-            if *user_info.key != credit.sol_target {
+        let mut accounts = Vec::new();
+        let mut items = Vec::new();
+        while let Some(ticket_info) = account_info_iter.next() {
+            let user_info = next_account_info(account_info_iter)?;
+            let payer_info = next_account_info(account_info_iter)?;
+            if ticket_info.owner != program_id {
+                // Uncredit or ExpireCredit closed it
+                items.push(CreditQueueItem::Closed(*ticket_info.key));
+                accounts.push((ticket_info, user_info, payer_info));
+                continue;
+            }
+            let ticket = Self::read_credit_ticket(program_id, stake_pool_info.key, ticket_info)?;
+            if *user_info.key != ticket.sol_target {
                 msg!(
                     "Expecting user {} but got {}",
-                    &credit.sol_target,
+                    &ticket.sol_target,
                     *user_info.key
                 );
                 return Err(ProgramError::InvalidArgument);
             }
-            if *user_info.owner != system_program::id() {
-                msg!(
-                    "Invalid user {} account owner {}. Ignoring...",
-                    user_info.key,
-                    user_info.owner,
-                );
-                invalid_credits.push(credit);
-                continue;
-            }
+            Self::check_ticket_payer(&ticket, payer_info)?;
+            items.push(CreditQueueItem::Open {
+                ticket,
+                is_payable: *user_info.owner == system_program::id(),
+            });
+            accounts.push((ticket_info, user_info, payer_info));
+        }
 
-            let pool_amount = credit.token_amount;
-            let stake_amount = stake_pool
-                .calc_lamports_amount(pool_amount)
-                .ok_or(StakePoolError::CalculationFailure)?;
+        let payments = stake_pool.pay_credits(&mut items, lamports_available, |sequence| {
+            Self::get_credit_ticket_address(program_id, stake_pool_info.key, sequence).0
+        })?;
 
-            if total_amount + stake_amount > lamports_available {
-                // return creditor into the list
-                credit_list.credits.push(credit);
-                break;
+        let reserve_signer_seeds: &[&[u8]] = &[
+            &stake_pool_info.key.to_bytes()[..32],
+            Self::AUTHORITY_RESERVE,
+            &[reserve_bump],
+        ];
+        let mut total_amount = 0;
+        let mut total_fee_amount: u64 = 0;
+        let mut paid_count = 0;
+        for ((item, payment), (ticket_info, user_info, payer_info)) in
+            items.iter().zip(payments).zip(accounts)
+        {
+            let ticket = match item {
+                CreditQueueItem::Open { ticket, .. } => ticket,
+                CreditQueueItem::Closed(_) => continue,
+            };
+            if payment.lamports > 0 {
+                // The withdrawal fee is taken from every payment, partial ones included
+                let (fee_lamports, fee_amount) = stake_pool
+                    .book_credit_fee(payment.lamports)
+                    .ok_or(StakePoolError::CalculationFailure)?;
+                total_fee_amount = total_fee_amount
+                    .checked_add(fee_amount)
+                    .ok_or(StakePoolError::CalculationFailure)?;
+                invoke_signed(
+                    &system_instruction::transfer(
+                        reserve_account_info.key,
+                        &ticket.sol_target,
+                        payment.lamports - fee_lamports,
+                    ),
+                    &[
+                        reserve_account_info.clone(),
+                        user_info.clone(),
+                        system_program_info.clone(),
+                    ],
+                    &[reserve_signer_seeds],
+                )?;
+                total_amount += payment.lamports;
+                paid_count += 1;
             }
+            if payment.is_full {
+                Self::close_account(ticket_info, payer_info)?;
+            } else if payment.lamports > 0 {
+                msg!(
+                    "Credit ticket #{} is paid partially with {} lamports",
+                    ticket.sequence,
+                    payment.lamports
+                );
+                ticket.pack_into_slice(&mut ticket_info.data.borrow_mut());
+            }
+        }
+        msg!(
+            "Paid {} credit tickets with {} lamports, queue tail at #{}",
+            paid_count,
+            total_amount,
+            stake_pool.credit_queue_tail
+        );
 
-            Self::token_burn(
+        if total_fee_amount > 0 {
+            Self::token_mint_to(
                 stake_pool_info.key,
                 token_program_info.clone(),
-                credit_reserve_info.clone(),
                 pool_mint_info.clone(),
+                owner_fee_info.clone(),
                 withdraw_info.clone(),
                 Self::AUTHORITY_WITHDRAW,
                 stake_pool.withdraw_bump_seed,
-                pool_amount,
-            )?;
-
-            let reserve_signer_seeds: &[&[u8]] = &[
-                &stake_pool_info.key.to_bytes()[..32],
-                Self::AUTHORITY_RESERVE,
-                &[reserve_bump],
-            ];
-
-            invoke_signed(
-                &system_instruction::transfer(
-                    reserve_account_info.key,
-                    &credit.sol_target,
-                    stake_amount,
-                ),
-                &[
-                    system_program_info.clone(),
-                    reserve_account_info.clone(),
-                    user_info.clone(),
-                ],
-                &[reserve_signer_seeds],
+                total_fee_amount,
             )?;
-
-            stake_pool.pool_total -= pool_amount;
-            total_amount += stake_amount;
         }
 
-        // Return creditors left into the list
-        credit_list.credits.extend(credit_list_iter);
-        credit_list.credits.extend(invalid_credits.into_iter());
-
-        credit_list.serialize(&mut credit_list_info.data.borrow_mut())?;
-
-        stake_pool.stake_total -= total_amount;
         stake_pool.serialize(&mut stake_pool_info.data.borrow_mut())?;
 
         Ok(())
@@ -2321,7 +4505,11 @@ impl Processor {
             }
             StakePoolInstruction::UpdateListBalance => {
                 msg!("Instruction: UpdateListBalance");
-                Self::process_update_list_balance(program_id, accounts)
+                Self::process_update_list_balance(program_id, accounts, false)
+            }
+            StakePoolInstruction::UpdateListBalancePage => {
+                msg!("Instruction: UpdateListBalancePage");
+                Self::process_update_list_balance(program_id, accounts, true)
             }
             StakePoolInstruction::UpdatePoolBalance => {
                 msg!("Instruction: UpdatePoolBalance");
@@ -2329,19 +4517,35 @@ impl Processor {
             }
             StakePoolInstruction::Deposit(amount) => {
                 msg!("Instruction: Deposit {}", amount);
-                Self::process_deposit(program_id, amount, accounts)
+                Self::process_deposit(program_id, amount, 0, accounts)
+            }
+            StakePoolInstruction::DepositWithSlippage(args) => {
+                msg!(
+                    "Instruction: DepositWithSlippage {} min {}",
+                    args.amount,
+                    args.minimum_out
+                );
+                Self::process_deposit(program_id, args.amount, args.minimum_out, accounts)
             }
             StakePoolInstruction::Withdraw(amount) => {
                 msg!("Instruction: Withdraw {}", amount);
-                Self::process_withdraw(program_id, amount, accounts)
+                Self::process_withdraw(program_id, amount, 0, accounts)
+            }
+            StakePoolInstruction::WithdrawWithSlippage(args) => {
+                msg!(
+                    "Instruction: WithdrawWithSlippage {} min {}",
+                    args.amount,
+                    args.minimum_out
+                );
+                Self::process_withdraw(program_id, args.amount, args.minimum_out, accounts)
             }
             StakePoolInstruction::SetStakingAuthority => {
-                panic!("Instruction: SetStakingAuthority");
-                // Self::process_set_staking_auth(program_id, accounts)
+                msg!("Instruction: SetStakingAuthority");
+                Self::process_set_staking_authority(program_id, accounts)
             }
-            StakePoolInstruction::SetOwner => {
-                msg!("Instruction: SetOwner");
-                Self::process_set_owner(program_id, accounts)
+            StakePoolInstruction::ProposeOwner => {
+                msg!("Instruction: ProposeOwner");
+                Self::process_propose_owner(program_id, accounts)
             }
             StakePoolInstruction::Credit(amount) => {
                 msg!("Instruction: Credit {}", amount);
@@ -2356,7 +4560,7 @@ impl Processor {
                     "Instruction: DelegateReserve with {} instructions",
                     instructions.len()
                 );
-                Self::process_delegate_reserve(program_id, accounts, &instructions)
+                Self::process_delegate_reserve(program_id, accounts, &instructions, false)
             }
             StakePoolInstruction::MergeStakes(instructions) => {
                 msg!(
@@ -2370,12 +4574,89 @@ impl Processor {
                     "Instruction: Unstake with {} instructions",
                     instructions.len()
                 );
-                Self::process_unstake(program_id, accounts, &instructions)
+                Self::process_unstake(program_id, accounts, &instructions, false)
             }
             StakePoolInstruction::PayCreditors => {
                 msg!("Instruction: PayCreditors");
                 Self::process_pay_creditors(program_id, accounts)
             }
+            StakePoolInstruction::SetFreshnessPolicy(policy) => {
+                msg!(
+                    "Instruction: SetFreshnessPolicy with {} grace slots",
+                    policy.grace_slots
+                );
+                Self::process_set_freshness_policy(program_id, policy, accounts)
+            }
+            StakePoolInstruction::SetDepositFee(fee) => {
+                msg!("Instruction: SetDepositFee");
+                Self::process_set_fee(program_id, FeeType::Deposit, fee, accounts)
+            }
+            StakePoolInstruction::SetWithdrawalFee(fee) => {
+                msg!("Instruction: SetWithdrawalFee");
+                Self::process_set_fee(program_id, FeeType::Withdrawal, fee, accounts)
+            }
+            StakePoolInstruction::SetPerformanceFee(fee) => {
+                msg!("Instruction: SetPerformanceFee");
+                Self::process_set_fee(program_id, FeeType::Performance, fee, accounts)
+            }
+            StakePoolInstruction::AcceptOwner => {
+                msg!("Instruction: AcceptOwner");
+                Self::process_accept_owner(program_id, accounts)
+            }
+            StakePoolInstruction::CancelOwnerProposal => {
+                msg!("Instruction: CancelOwnerProposal");
+                Self::process_cancel_owner_proposal(program_id, accounts)
+            }
+            StakePoolInstruction::MigrateState => {
+                msg!("Instruction: MigrateState");
+                Self::process_migrate_state(program_id, accounts)
+            }
+            StakePoolInstruction::SetValidatorTargetWeight(target_weight) => {
+                msg!("Instruction: SetValidatorTargetWeight");
+                Self::process_set_validator_target_weight(program_id, target_weight, accounts)
+            }
+            StakePoolInstruction::DelegateReserveToTargets(instructions) => {
+                msg!(
+                    "Instruction: DelegateReserveToTargets with {} instructions",
+                    instructions.len()
+                );
+                Self::process_delegate_reserve(program_id, accounts, &instructions, true)
+            }
+            StakePoolInstruction::SetLiquidityTarget(liquidity_target_bps) => {
+                msg!("Instruction: SetLiquidityTarget");
+                Self::process_set_liquidity_target(program_id, liquidity_target_bps, accounts)
+            }
+            StakePoolInstruction::ReallocList => {
+                msg!("Instruction: ReallocList");
+                Self::process_realloc_list(program_id, accounts)
+            }
+            StakePoolInstruction::SetCreditOrdering(credit_ordering) => {
+                msg!("Instruction: SetCreditOrdering {:?}", credit_ordering);
+                Self::process_set_credit_ordering(program_id, credit_ordering, accounts)
+            }
+            StakePoolInstruction::DepositStake(stake_index) => {
+                msg!("Instruction: DepositStake {}", stake_index);
+                Self::process_deposit_stake(program_id, stake_index, accounts)
+            }
+            StakePoolInstruction::WithdrawStake(args) => {
+                msg!("Instruction: WithdrawStake");
+                Self::process_withdraw_stake(program_id, &args, accounts)
+            }
+            StakePoolInstruction::ExpireCredit => {
+                msg!("Instruction: ExpireCredit");
+                Self::process_expire_credit(program_id, accounts)
+            }
+            StakePoolInstruction::RefundCreditList => {
+                msg!("Instruction: RefundCreditList");
+                Self::process_refund_credit_list(program_id, accounts)
+            }
+            StakePoolInstruction::UnstakeForCredits(instructions) => {
+                msg!(
+                    "Instruction: UnstakeForCredits with {} instructions",
+                    instructions.len()
+                );
+                Self::process_unstake(program_id, accounts, &instructions, true)
+            }
         }
     }
 }
@@ -2390,7 +4671,7 @@ impl PrintProgramError for StakePoolError {
             StakePoolError::InvalidProgramAddress => msg!("Error: The program address provided doesn't match the value generated by the program"),
             StakePoolError::InvalidState => msg!("Error: The stake pool state is invalid"),
             StakePoolError::CalculationFailure => msg!("Error: The calculation failed"),
-            StakePoolError::FeeTooHigh => msg!("Error: Stake pool fee > 1"),
+            StakePoolError::FeeTooHigh => msg!("Error: Stake pool fee is above its maximum"),
             StakePoolError::WrongAccountMint => msg!("Error: Token account is associated with the wrong mint"),
             StakePoolError::NonZeroBalance => msg!("Error: Account balance should be zero"),
             StakePoolError::WrongOwner => msg!("Error: Wrong pool owner account"),
@@ -2403,8 +4684,8 @@ impl PrintProgramError for StakePoolError {
             StakePoolError::ValidatorAlreadyAdded => msg!("Error: Stake account voting for this validator already exists in the pool"),
             StakePoolError::ValidatorNotFound => msg!("Error: Stake account for this validator not found in the pool"),
             StakePoolError::InvalidStakeAccountAddress => msg!("Error: Stake account address not properly derived from the validator address"),
-            StakePoolError::StakeListOutOfDate => msg!("Error: Identify validator stake accounts with old balances and update them"),
-            StakePoolError::StakeListAndPoolOutOfDate => msg!("Error: First update old validator stake account balances and then pool stake balance"),
+            StakePoolError::StakeListOutOfDate => msg!("Error: Run UpdateListBalance for validator stake accounts with old balances"),
+            StakePoolError::StakeListAndPoolOutOfDate => msg!("Error: Run UpdateListBalance for old validator stake account balances and then UpdatePoolBalance"),
             StakePoolError::UnknownValidatorStakeAccount => {
                 msg!("Error: Validator stake account is not found in the list storage")
             }
@@ -2419,6 +4700,19 @@ impl PrintProgramError for StakePoolError {
             StakePoolError::InvalidStakeIndex => msg!("Error: Invalid stake index"),
             StakePoolError::CreditListOverfow => msg!("Error: Credit list overflow"),
             StakePoolError::UnknownCreditor => msg!("Error: Invalid unknown creditor"),
+            StakePoolError::PoolBalanceOutOfDate => msg!("Error: Pool balance is out of date. Run UpdatePoolBalance (after UpdateListBalance for old validator balances)"),
+            StakePoolError::OutdatedStateVersion => msg!("Error: Stake pool account must be upgraded by MigrateState"),
+            StakePoolError::DelegationAboveTarget => msg!("Error: Delegation moves the validator stake above its target"),
+            StakePoolError::UnstakeAboveRequired => msg!("Error: Unstake is larger than queued credits need"),
+            StakePoolError::ValidatorNotOverTarget => msg!("Error: Unstake must take from the validators most over their target weights"),
+            StakePoolError::ListUpdateInProgress => msg!("Error: Paged validator stake list update is in progress"),
+            StakePoolError::CreditNotExpired => {
+                msg!("Error: Credit ticket is payable or can't expire yet")
+            }
+            StakePoolError::SlippageExceeded => msg!("Error: Output is below the requested minimum"),
+            StakePoolError::ValidatorListNeedsRealloc => {
+                msg!("Error: Validator stake list must be moved to a larger account with ReallocList")
+            }
         }
     }
 }
@@ -1,21 +1,112 @@
 //! State transition types
 
 use crate::error::StakePoolError;
-use crate::instruction::Fee;
+use crate::instruction::{CreditOrdering, Fee, FreshnessPolicy};
 use crate::processor::Processor;
+use crate::PROGRAM_VERSION;
+use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
 use core::convert::TryInto;
 use solana_program::{
-    account_info::{next_account_info, AccountInfo},
+    account_info::AccountInfo,
+    clock::Clock,
     entrypoint::ProgramResult,
+    epoch_schedule::EpochSchedule,
     msg,
     program_error::ProgramError,
+    program_pack::{IsInitialized, Pack, Sealed},
     pubkey::Pubkey,
 };
 use std::convert::TryFrom;
 use std::mem::size_of;
 
+/// Fees kept in [StakePool](struct.StakePool.html)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FeeType {
+    /// Minted on deposits
+    Deposit,
+    /// Taken on withdrawals and credit payouts
+    Withdrawal,
+    /// Minted from the epoch rewards
+    Performance,
+}
+
+impl FeeType {
+    /// Maximum value allowed for the fee
+    pub fn max(&self) -> Fee {
+        match self {
+            Self::Deposit => StakePool::MAX_DEPOSIT_FEE,
+            Self::Withdrawal => StakePool::MAX_WITHDRAWAL_FEE,
+            Self::Performance => StakePool::MAX_PERFORMANCE_FEE,
+        }
+    }
+}
+
+/// Fee increase waiting for its activation epoch
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PendingFee {
+    /// Fee to apply
+    pub fee: Fee,
+    /// Epoch when the fee is applied by UpdatePoolBalance. 0 if nothing is pending
+    pub activation_epoch: u64,
+}
+
+impl PendingFee {
+    /// Check if there is a fee waiting for activation
+    pub fn is_pending(&self) -> bool {
+        self.activation_epoch > 0
+    }
+}
+
+/// Position of the paged UpdateListBalance round in the validator stake list
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ListUpdateCursor {
+    /// Epoch of the round
+    pub epoch: u64,
+    /// Validator to continue with
+    pub validator_index: u32,
+    /// Stake account of the validator to continue with
+    pub stake_index: u32,
+    /// Stake count the validator keeps after the accounts processed so far
+    pub stake_count: u32,
+}
+
+impl ListUpdateCursor {
+    /// Position the next page starts at. A round starts over from the first
+    /// validator in a new epoch and after the previous round finished
+    pub fn start(&self, epoch: u64, validator_count: usize) -> ListUpdateCursor {
+        if self.epoch == epoch && (self.validator_index as usize) < validator_count {
+            *self
+        } else {
+            ListUpdateCursor {
+                epoch,
+                ..ListUpdateCursor::default()
+            }
+        }
+    }
+
+    /// Position after the validator the cursor is at
+    pub fn next_validator(&self) -> ListUpdateCursor {
+        ListUpdateCursor {
+            epoch: self.epoch,
+            validator_index: self.validator_index + 1,
+            ..ListUpdateCursor::default()
+        }
+    }
+
+    /// Check if the round stopped in the middle of a validator's stake accounts
+    /// during `epoch`
+    pub fn in_progress(&self, epoch: u64) -> bool {
+        self.epoch == epoch && self.stake_index > 0
+    }
+
+    /// Check if the round is in the middle of the validator stake accounts
+    pub fn is_inside(&self, epoch: u64, validator_index: usize) -> bool {
+        self.in_progress(epoch) && self.validator_index as usize == validator_index
+    }
+}
+
 /// Initialized program details.
-#[repr(C)]
+/// Serialized in a packed little-endian layout, see `StakePool::pack_into_slice`
 #[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct StakePool {
     /// Pool version
@@ -31,7 +122,8 @@ pub struct StakePool {
     pub withdraw_bump_seed: u8,
     /// Validator stake list storage account
     pub validator_stake_list: Pubkey,
-    /// Credit list storage account
+    /// Credit list storage account of pools created before credit tickets.
+    /// Default once RefundCreditList returned all of its credits
     pub credit_list: Pubkey,
     /// Pool Mint
     pub pool_mint: Pubkey,
@@ -48,12 +140,88 @@ pub struct StakePool {
     /// Last epoch stake_total field was updated
     pub last_update_epoch: u64,
     /// Fee applied to deposits
-    pub fee: Fee,
+    pub deposit_fee: Fee,
+    /// Last slot stake_total field was updated
+    pub last_update_slot: u64,
+    /// Freshness required from stake_total on deposits and withdrawals
+    pub freshness_policy: FreshnessPolicy,
+    /// Fee applied to withdrawals and credit payouts
+    pub withdrawal_fee: Fee,
+    /// Fee applied to the stake_total increase between pool updates above `performance_high_water`
+    pub performance_fee: Fee,
+    /// Deposit fee increase waiting for activation
+    pub pending_deposit_fee: PendingFee,
+    /// Withdrawal fee increase waiting for activation
+    pub pending_withdrawal_fee: PendingFee,
+    /// Performance fee increase waiting for activation
+    pub pending_performance_fee: PendingFee,
+    /// Owner proposed by ProposeOwner. Default pubkey if there is no proposal
+    pub pending_owner: Pubkey,
+    /// Owner fee account to use when pending_owner accepts the ownership
+    pub pending_owner_fee_account: Pubkey,
+    /// Reserve balance kept for instant withdrawals, in basis points of stake_total.
+    /// DelegateReserve doesn't stake below it
+    pub liquidity_target_bps: u16,
+    /// Stake cooling down in `deactivating_epoch`: the validator totals at
+    /// UpdatePoolBalance plus later Unstake deactivations.
+    /// It stays in the stake accounts until UpdateListBalance sweeps it
+    pub deactivating_lamports: u64,
+    /// Epoch of `deactivating_lamports`
+    pub deactivating_epoch: u64,
+    /// Effective stake that is not cooling down, summed by UpdatePoolBalance
+    pub active_lamports: u64,
+    /// Stake warming up, summed by UpdatePoolBalance
+    pub activating_lamports: u64,
+    /// Progress of the paged UpdateListBalance
+    pub list_update_cursor: ListUpdateCursor,
+    /// Sequence number of the next credit ticket. Only grows
+    pub credit_queue_head: u64,
+    /// Sequence number of the first ticket FIFO PayCreditors hasn't paid, skipped
+    /// or found closed. FIFO payments start there
+    pub credit_queue_tail: u64,
+    /// Lamports owed to the open credit tickets.
+    /// They are still in the reserve or the stakes but not in `stake_total`
+    pub credit_lamports_total: u64,
+    /// How PayCreditors handles a ticket the reserve can't pay in full
+    pub credit_ordering: CreditOrdering,
+    /// Highest value in lamports of `HIGH_WATER_TOKENS` pool tokens seen by UpdatePoolBalance.
+    /// The performance fee applies only to the rewards above it, so the value lost
+    /// to a slash is not charged again when it recovers. Zero before the first update
+    pub performance_high_water: u64,
 }
 
 impl StakePool {
-    /// Length of state data when serialized
-    pub const LEN: usize = size_of::<StakePool>();
+    /// Length of the fields when serialized. The layout has no padding
+    pub const PACKED_LEN: usize = 538;
+    /// Version of the `repr(C)` layout used before the packed one
+    pub const VERSION_V1: u8 = 1;
+    /// Size of the v1 fields: the `repr(C)` struct ending with the deposit fee
+    pub const V1_MIN_LEN: usize = 272;
+    /// Size of the v1 accounts created by the pool client: the struct plus
+    /// reserved space. MigrateState rewrites them in place
+    pub const V1_CLIENT_LEN: usize = 1180;
+    /// Pool tokens `performance_high_water` is the value of
+    pub const HIGH_WATER_TOKENS: u64 = 1_000_000_000;
+    /// Maximum deposit fee (10%)
+    pub const MAX_DEPOSIT_FEE: Fee = Fee {
+        denominator: 10,
+        numerator: 1,
+    };
+    /// Maximum withdrawal fee (10%)
+    pub const MAX_WITHDRAWAL_FEE: Fee = Fee {
+        denominator: 10,
+        numerator: 1,
+    };
+    /// Maximum performance fee (25% of rewards)
+    pub const MAX_PERFORMANCE_FEE: Fee = Fee {
+        denominator: 4,
+        numerator: 1,
+    };
+    /// Epochs between a fee increase and its activation.
+    /// Gives depositors at least one full epoch to exit
+    pub const FEE_INCREASE_DELAY_EPOCHS: u64 = 2;
+    /// Maximum liquidity target (the whole pool)
+    pub const MAX_LIQUIDITY_TARGET_BPS: u16 = 10_000;
     /// calculate the pool tokens that should be minted
     pub fn calc_pool_deposit_amount(&self, stake_lamports: u64) -> Option<u64> {
         if self.stake_total == 0 {
@@ -70,6 +238,18 @@ impl StakePool {
         )
         .ok()
     }
+    /// calculate the pool tokens that back `stake_lamports` leaving the pool,
+    /// rounded up so the remaining holders don't pay for the rounding
+    pub fn calc_pool_burn_amount(&self, stake_lamports: u64) -> Option<u64> {
+        let stake_total = self.stake_total as u128;
+        u64::try_from(
+            (stake_lamports as u128)
+                .checked_mul(self.pool_total as u128)?
+                .checked_add(stake_total.checked_sub(1)?)?
+                .checked_div(stake_total)?,
+        )
+        .ok()
+    }
     /// calculate lamports amount
     pub fn calc_lamports_amount(&self, pool_tokens: u64) -> Option<u64> {
         u64::try_from(
@@ -79,19 +259,235 @@ impl StakePool {
         )
         .ok()
     }
-    /// calculate the fee in pool tokens that goes to the owner
-    pub fn calc_fee_amount(&self, pool_amount: u64) -> Option<u64> {
-        if self.fee.denominator == 0 {
+    /// calculate the deposit fee in pool tokens that goes to the owner
+    pub fn calc_deposit_fee_amount(&self, pool_amount: u64) -> Option<u64> {
+        self.deposit_fee.apply(pool_amount)
+    }
+    /// calculate the withdrawal fee in pool tokens that goes to the owner
+    pub fn calc_withdrawal_fee_amount(&self, pool_amount: u64) -> Option<u64> {
+        self.withdrawal_fee.apply(pool_amount)
+    }
+    /// calculate the performance fee in pool tokens to mint when stake_total
+    /// grows to `new_stake_total`. The fee share of the increase is priced
+    /// at the new ratio, so the owner gets exactly the fee lamports worth of tokens
+    pub fn calc_performance_fee_amount(&self, new_stake_total: u64) -> Option<u64> {
+        // Rewards that only win back the value under the high-water mark are free
+        let high_water_stake = u64::try_from(
+            (self.performance_high_water as u128)
+                .checked_mul(self.pool_total as u128)?
+                .checked_add(Self::HIGH_WATER_TOKENS as u128 - 1)?
+                .checked_div(Self::HIGH_WATER_TOKENS as u128)?,
+        )
+        .ok()?;
+        let reward = new_stake_total.saturating_sub(self.stake_total.max(high_water_stake));
+        let fee_lamports = self.performance_fee.apply(reward)?;
+        if fee_lamports == 0 || self.pool_total == 0 {
             return Some(0);
         }
         u64::try_from(
-            (pool_amount as u128)
-                .checked_mul(self.fee.numerator as u128)?
-                .checked_div(self.fee.denominator as u128)?,
+            (fee_lamports as u128)
+                .checked_mul(self.pool_total as u128)?
+                .checked_div(new_stake_total.checked_sub(fee_lamports)? as u128)?,
         )
         .ok()
     }
 
+    /// Raises `performance_high_water` to the current value of the pool tokens
+    pub fn update_performance_high_water(&mut self) -> Option<()> {
+        if self.pool_total == 0 {
+            return Some(());
+        }
+        let value = self.calc_lamports_amount(Self::HIGH_WATER_TOKENS)?;
+        self.performance_high_water = self.performance_high_water.max(value);
+        Some(())
+    }
+
+    /// calculate the reserve balance to keep for instant withdrawals
+    pub fn calc_liquidity_target(&self) -> Option<u64> {
+        u64::try_from(
+            (self.stake_total as u128)
+                .checked_mul(self.liquidity_target_bps as u128)?
+                .checked_div(Self::MAX_LIQUIDITY_TARGET_BPS as u128)?,
+        )
+        .ok()
+    }
+
+    /// calculate the lamports to unstake so PayCreditors can pay every open
+    /// credit ticket: `credit_lamports_total` minus the reserve surplus and the stake
+    /// that is already deactivating
+    pub fn required_unstake(&self, reserve_surplus: u64, deactivating_lamports: u64) -> u64 {
+        self.credit_lamports_total
+            .saturating_sub(reserve_surplus)
+            .saturating_sub(deactivating_lamports)
+    }
+
+    /// books the withdrawal fee of a credit payment of `lamports`. The fee lamports
+    /// stay in the reserve for the holders and the owner gets the pool tokens they are worth.
+    /// Returns the fee in lamports and the pool tokens to mint
+    pub fn book_credit_fee(&mut self, lamports: u64) -> Option<(u64, u64)> {
+        let fee_lamports = self.withdrawal_fee.apply(lamports)?;
+        let fee_amount = self.calc_pool_deposit_amount(fee_lamports)?;
+        self.stake_total = self.stake_total.checked_add(fee_lamports)?;
+        self.pool_total = self.pool_total.checked_add(fee_amount)?;
+        Some((fee_lamports, fee_amount))
+    }
+
+    /// calculate what PayCreditors pays for `ticket` with `lamports_available`
+    /// left in the reserve. A ticket that doesn't fit gets the rest of the reserve
+    /// with FIFO ordering and nothing with skip-ahead
+    pub fn calc_credit_payment(
+        &self,
+        ticket: &CreditRecord,
+        lamports_available: u64,
+    ) -> CreditPayment {
+        if ticket.lamports_owed <= lamports_available {
+            CreditPayment {
+                lamports: ticket.lamports_owed,
+                is_full: true,
+            }
+        } else if self.credit_ordering == CreditOrdering::SkipAhead {
+            CreditPayment::default()
+        } else {
+            CreditPayment {
+                lamports: lamports_available,
+                is_full: false,
+            }
+        }
+    }
+
+    /// Pays the credit tickets passed to PayCreditors in the given order from
+    /// `lamports_available` and books the payments. With FIFO ordering the items walk
+    /// the queue one sequence at a time from `credit_queue_tail`, closed tickets included,
+    /// and the tail moves past the tickets that are paid in full, skipped or closed.
+    /// `ticket_address` gives the account of a ticket sequence.
+    /// Returns the payment of every item: nothing for the closed and skipped tickets
+    /// and for the items after the payments stopped
+    pub fn pay_credits(
+        &mut self,
+        items: &mut [CreditQueueItem],
+        lamports_available: u64,
+        ticket_address: impl Fn(u64) -> Pubkey,
+    ) -> Result<Vec<CreditPayment>, ProgramError> {
+        let is_fifo = self.credit_ordering == CreditOrdering::Fifo;
+        let mut payments = vec![CreditPayment::default(); items.len()];
+        let mut lamports_left = lamports_available;
+        let mut sequences = Vec::with_capacity(items.len());
+        for (item, payment) in items.iter_mut().zip(payments.iter_mut()) {
+            let (ticket, is_payable) = match item {
+                CreditQueueItem::Closed(address) => {
+                    if !is_fifo
+                        || self.credit_queue_tail >= self.credit_queue_head
+                        || *address != ticket_address(self.credit_queue_tail)
+                    {
+                        msg!(
+                            "Account {} is not an open credit ticket of the pool",
+                            address
+                        );
+                        return Err(StakePoolError::UnknownCreditor.into());
+                    }
+                    self.credit_queue_tail += 1;
+                    continue;
+                }
+                CreditQueueItem::Open { ticket, is_payable } => (ticket, *is_payable),
+            };
+            if is_fifo && ticket.sequence != self.credit_queue_tail {
+                msg!(
+                    "Expected credit ticket #{} but got #{}",
+                    self.credit_queue_tail,
+                    ticket.sequence
+                );
+                return Err(ProgramError::InvalidArgument);
+            }
+            if sequences.contains(&ticket.sequence) {
+                msg!("Credit ticket #{} is passed twice", ticket.sequence);
+                return Err(ProgramError::InvalidArgument);
+            }
+            sequences.push(ticket.sequence);
+            if !is_payable {
+                msg!(
+                    "Skipping credit ticket #{}. It expires at epoch {}",
+                    ticket.sequence,
+                    ticket.expiry_epoch()
+                );
+                if is_fifo {
+                    self.credit_queue_tail += 1;
+                }
+                continue;
+            }
+
+            *payment = self.calc_credit_payment(ticket, lamports_left);
+            if !payment.is_full && payment.lamports == 0 {
+                msg!(
+                    "Reserve can't pay credit ticket #{} with {} lamports left",
+                    ticket.sequence,
+                    lamports_left
+                );
+                if is_fifo {
+                    break;
+                }
+                continue;
+            }
+            self.book_credit_payment(ticket, payment.lamports)
+                .ok_or(StakePoolError::CalculationFailure)?;
+            lamports_left -= payment.lamports;
+            if !payment.is_full {
+                // The reserve is used up, the rest of the ticket waits in the queue
+                break;
+            }
+            if is_fifo {
+                self.credit_queue_tail += 1;
+            }
+        }
+        Ok(payments)
+    }
+
+    /// Books the Credit of `ticket`: its burned pool tokens leave `pool_total`
+    /// and the lamports it is owed move from `stake_total` to `credit_lamports_total`
+    pub fn book_credit(&mut self, ticket: &CreditRecord) -> Option<()> {
+        self.pool_total = self.pool_total.checked_sub(ticket.token_amount)?;
+        self.stake_total = self.stake_total.checked_sub(ticket.lamports_owed)?;
+        self.credit_lamports_total = self
+            .credit_lamports_total
+            .checked_add(ticket.lamports_owed)?;
+        Some(())
+    }
+
+    /// Books `lamports` paid to `ticket` from the reserve
+    pub fn book_credit_payment(&mut self, ticket: &mut CreditRecord, lamports: u64) -> Option<()> {
+        ticket.release(lamports)?;
+        self.credit_lamports_total = self.credit_lamports_total.checked_sub(lamports)?;
+        Some(())
+    }
+
+    /// Books `lamports` of `ticket` returned to the pool. Returns the pool tokens
+    /// to mint for them: their value at the current ratio, but no more than
+    /// the share of the tokens burned at Credit
+    pub fn book_uncredit(&mut self, ticket: &mut CreditRecord, lamports: u64) -> Option<u64> {
+        let deposit_amount = self.calc_pool_deposit_amount(lamports)?;
+        let pool_amount = deposit_amount.min(ticket.release(lamports)?);
+        self.pool_total = self.pool_total.checked_add(pool_amount)?;
+        self.stake_total = self.stake_total.checked_add(lamports)?;
+        self.credit_lamports_total = self.credit_lamports_total.checked_sub(lamports)?;
+        Some(pool_amount)
+    }
+
+    /// Stake deactivated during `epoch` that is still cooling down
+    pub fn deactivating_lamports_at(&self, epoch: u64) -> u64 {
+        if self.deactivating_epoch == epoch {
+            self.deactivating_lamports
+        } else {
+            0
+        }
+    }
+
+    /// Records `lamports` of stake deactivated during `epoch`.
+    /// Deactivations from earlier epochs are assumed to have cooled down
+    pub fn record_deactivation(&mut self, epoch: u64, lamports: u64) -> Option<()> {
+        self.deactivating_lamports = self.deactivating_lamports_at(epoch).checked_add(lamports)?;
+        self.deactivating_epoch = epoch;
+        Some(())
+    }
+
     /// Checks withdraw authority
     pub fn check_authority_withdraw(
         &self,
@@ -134,42 +530,376 @@ impl StakePool {
         Ok(())
     }
 
-    /// Check if StakePool is initialized
-    pub fn is_initialized(&self) -> bool {
-        self.version > 0
+    /// Fee field by its type
+    pub fn fee_mut(&mut self, fee_type: FeeType) -> &mut Fee {
+        match fee_type {
+            FeeType::Deposit => &mut self.deposit_fee,
+            FeeType::Withdrawal => &mut self.withdrawal_fee,
+            FeeType::Performance => &mut self.performance_fee,
+        }
+    }
+
+    /// Pending fee field by its type
+    pub fn pending_fee_mut(&mut self, fee_type: FeeType) -> &mut PendingFee {
+        match fee_type {
+            FeeType::Deposit => &mut self.pending_deposit_fee,
+            FeeType::Withdrawal => &mut self.pending_withdrawal_fee,
+            FeeType::Performance => &mut self.pending_performance_fee,
+        }
+    }
+
+    /// Applies decreases immediately and queues increases
+    /// for `FEE_INCREASE_DELAY_EPOCHS` after `epoch`
+    pub fn set_fee(&mut self, fee_type: FeeType, fee: Fee, epoch: u64) {
+        if fee.is_within(self.fee_mut(fee_type)) {
+            *self.fee_mut(fee_type) = fee;
+            *self.pending_fee_mut(fee_type) = PendingFee::default();
+        } else {
+            *self.pending_fee_mut(fee_type) = PendingFee {
+                fee,
+                activation_epoch: epoch + Self::FEE_INCREASE_DELAY_EPOCHS,
+            };
+        }
+    }
+
+    /// Applies pending fees whose activation epoch has arrived
+    pub fn promote_pending_fees(&mut self, epoch: u64) {
+        for fee_type in &[FeeType::Deposit, FeeType::Withdrawal, FeeType::Performance] {
+            let pending = *self.pending_fee_mut(*fee_type);
+            if pending.is_pending() && pending.activation_epoch <= epoch {
+                msg!(
+                    "{:?} fee is now {}/{}",
+                    fee_type,
+                    pending.fee.numerator,
+                    pending.fee.denominator
+                );
+                *self.fee_mut(*fee_type) = pending.fee;
+                *self.pending_fee_mut(*fee_type) = PendingFee::default();
+            }
+        }
+    }
+
+    /// Checks that stake_total is recent enough to price deposits and withdrawals:
+    /// updated in the current epoch, or the epoch is less than `grace_slots` slots old
+    pub fn check_freshness(&self, clock: &Clock, epoch_schedule: &EpochSchedule) -> ProgramResult {
+        let epoch_start = epoch_schedule.get_first_slot_in_epoch(clock.epoch);
+        if self.last_update_epoch >= clock.epoch
+            || clock.slot.saturating_sub(epoch_start) < self.freshness_policy.grace_slots
+        {
+            return Ok(());
+        }
+        msg!(
+            "Pool balance was updated at slot {} of epoch {} but current epoch is {}. Run UpdatePoolBalance first",
+            self.last_update_slot,
+            self.last_update_epoch,
+            clock.epoch
+        );
+        Err(StakePoolError::PoolBalanceOutOfDate.into())
     }
 
     /// Deserializes a byte buffer into a [StakePool](struct.StakePool.html).
+    /// Accounts in the v1 layout must be upgraded by MigrateState first
     pub fn deserialize(input: &[u8]) -> Result<StakePool, ProgramError> {
-        if input.len() < size_of::<StakePool>() {
+        Self::unpack_from_slice(input)
+    }
+
+    /// Serializes [StakePool](struct.StakePool.html) into a byte buffer.
+    /// Bytes after `PACKED_LEN` are left untouched
+    pub fn serialize(&self, output: &mut [u8]) -> ProgramResult {
+        if output.len() < Self::PACKED_LEN {
             return Err(ProgramError::InvalidAccountData);
         }
+        self.pack_into_slice(output);
+        Ok(())
+    }
 
-        let stake_pool: &StakePool = unsafe { &*(&input[0] as *const u8 as *const StakePool) };
+    /// Deserializes the v1 layout: the `repr(C)` memory image of the struct.
+    /// Bytes after `V1_MIN_LEN` are reserved space and ignored, fields added
+    /// later start from their defaults
+    pub fn deserialize_v1(input: &[u8]) -> Result<StakePool, ProgramError> {
+        if input.len() < Self::V1_MIN_LEN || input[0] != Self::VERSION_V1 {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let input = array_ref![input, 0, StakePool::V1_MIN_LEN];
+        #[rustfmt::skip]
+        let (
+            version,
+            owner,
+            deposit_bump_seed,
+            withdraw_bump_seed,
+            validator_stake_list,
+            credit_list,
+            pool_mint,
+            owner_fee_account,
+            credit_reserve,
+            token_program_id,
+            _padding,
+            stake_total,
+            pool_total,
+            last_update_epoch,
+            deposit_fee,
+        ) = array_refs![input, 1, 32, 1, 1, 32, 32, 32, 32, 32, 32, 5, 8, 8, 8, 16];
+        Ok(StakePool {
+            version: version[0],
+            owner: Pubkey::new(owner),
+            deposit_bump_seed: deposit_bump_seed[0],
+            withdraw_bump_seed: withdraw_bump_seed[0],
+            validator_stake_list: Pubkey::new(validator_stake_list),
+            credit_list: Pubkey::new(credit_list),
+            pool_mint: Pubkey::new(pool_mint),
+            owner_fee_account: Pubkey::new(owner_fee_account),
+            credit_reserve: Pubkey::new(credit_reserve),
+            token_program_id: Pubkey::new(token_program_id),
+            stake_total: u64::from_le_bytes(*stake_total),
+            pool_total: u64::from_le_bytes(*pool_total),
+            last_update_epoch: u64::from_le_bytes(*last_update_epoch),
+            deposit_fee: unpack_fee(deposit_fee),
+            ..StakePool::default()
+        })
+    }
+}
+
+impl Sealed for StakePool {}
 
-        Ok(*stake_pool)
+impl IsInitialized for StakePool {
+    fn is_initialized(&self) -> bool {
+        self.version > 0
     }
+}
 
-    /// Serializes [StakePool](struct.StakePool.html) into a byte buffer.
-    pub fn serialize(&self, output: &mut [u8]) -> ProgramResult {
-        if output.len() < size_of::<StakePool>() {
+impl Pack for StakePool {
+    /// Size of new stake pool accounts.
+    /// Bytes after `PACKED_LEN` are reserved for new fields
+    const LEN: usize = 1024;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, StakePool::PACKED_LEN];
+        #[rustfmt::skip]
+        let (
+            version,
+            owner,
+            deposit_bump_seed,
+            withdraw_bump_seed,
+            validator_stake_list,
+            credit_list,
+            pool_mint,
+            owner_fee_account,
+            credit_reserve,
+            token_program_id,
+            stake_total,
+            pool_total,
+            last_update_epoch,
+            deposit_fee,
+            last_update_slot,
+            freshness_grace_slots,
+            withdrawal_fee,
+            performance_fee,
+            pending_deposit_fee,
+            pending_withdrawal_fee,
+            pending_performance_fee,
+            pending_owner,
+            pending_owner_fee_account,
+            liquidity_target_bps,
+            deactivating_lamports,
+            deactivating_epoch,
+            active_lamports,
+            activating_lamports,
+            list_update_cursor,
+            credit_queue_head,
+            credit_queue_tail,
+            credit_lamports_total,
+            credit_ordering,
+            performance_high_water,
+        ) = mut_array_refs![dst, 1, 32, 1, 1, 32, 32, 32, 32, 32, 32, 8, 8, 8, 16, 8, 8, 16, 16, 24, 24, 24, 32, 32, 2, 8, 8, 8, 8, 20, 8, 8, 8, 1, 8];
+        version[0] = self.version;
+        owner.copy_from_slice(self.owner.as_ref());
+        deposit_bump_seed[0] = self.deposit_bump_seed;
+        withdraw_bump_seed[0] = self.withdraw_bump_seed;
+        validator_stake_list.copy_from_slice(self.validator_stake_list.as_ref());
+        credit_list.copy_from_slice(self.credit_list.as_ref());
+        pool_mint.copy_from_slice(self.pool_mint.as_ref());
+        owner_fee_account.copy_from_slice(self.owner_fee_account.as_ref());
+        credit_reserve.copy_from_slice(self.credit_reserve.as_ref());
+        token_program_id.copy_from_slice(self.token_program_id.as_ref());
+        *stake_total = self.stake_total.to_le_bytes();
+        *pool_total = self.pool_total.to_le_bytes();
+        *last_update_epoch = self.last_update_epoch.to_le_bytes();
+        pack_fee(&self.deposit_fee, deposit_fee);
+        *last_update_slot = self.last_update_slot.to_le_bytes();
+        *freshness_grace_slots = self.freshness_policy.grace_slots.to_le_bytes();
+        pack_fee(&self.withdrawal_fee, withdrawal_fee);
+        pack_fee(&self.performance_fee, performance_fee);
+        pack_pending_fee(&self.pending_deposit_fee, pending_deposit_fee);
+        pack_pending_fee(&self.pending_withdrawal_fee, pending_withdrawal_fee);
+        pack_pending_fee(&self.pending_performance_fee, pending_performance_fee);
+        pending_owner.copy_from_slice(self.pending_owner.as_ref());
+        pending_owner_fee_account.copy_from_slice(self.pending_owner_fee_account.as_ref());
+        *liquidity_target_bps = self.liquidity_target_bps.to_le_bytes();
+        *deactivating_lamports = self.deactivating_lamports.to_le_bytes();
+        *deactivating_epoch = self.deactivating_epoch.to_le_bytes();
+        *active_lamports = self.active_lamports.to_le_bytes();
+        *activating_lamports = self.activating_lamports.to_le_bytes();
+        pack_list_update_cursor(&self.list_update_cursor, list_update_cursor);
+        *credit_queue_head = self.credit_queue_head.to_le_bytes();
+        *credit_queue_tail = self.credit_queue_tail.to_le_bytes();
+        *credit_lamports_total = self.credit_lamports_total.to_le_bytes();
+        credit_ordering[0] = self.credit_ordering as u8;
+        *performance_high_water = self.performance_high_water.to_le_bytes();
+    }
+
+    /// Accepts any buffer of at least `PACKED_LEN` bytes.
+    /// Uninitialized buffers unpack into the default value
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() < Self::PACKED_LEN {
+            if src.first() == Some(&Self::VERSION_V1) {
+                msg!("Stake pool has layout v1. Run MigrateState");
+                return Err(StakePoolError::OutdatedStateVersion.into());
+            }
             return Err(ProgramError::InvalidAccountData);
         }
-        #[allow(clippy::cast_ptr_alignment)]
-        let value = unsafe { &mut *(&mut output[0] as *mut u8 as *mut StakePool) };
-        *value = *self;
+        match src[0] {
+            0 => return Ok(StakePool::default()),
+            PROGRAM_VERSION => {}
+            Self::VERSION_V1 => {
+                msg!("Stake pool has layout v1. Run MigrateState");
+                return Err(StakePoolError::OutdatedStateVersion.into());
+            }
+            version => {
+                msg!("Unknown stake pool version {}", version);
+                return Err(ProgramError::InvalidAccountData);
+            }
+        }
+        let src = array_ref![src, 0, StakePool::PACKED_LEN];
+        #[rustfmt::skip]
+        let (
+            version,
+            owner,
+            deposit_bump_seed,
+            withdraw_bump_seed,
+            validator_stake_list,
+            credit_list,
+            pool_mint,
+            owner_fee_account,
+            credit_reserve,
+            token_program_id,
+            stake_total,
+            pool_total,
+            last_update_epoch,
+            deposit_fee,
+            last_update_slot,
+            freshness_grace_slots,
+            withdrawal_fee,
+            performance_fee,
+            pending_deposit_fee,
+            pending_withdrawal_fee,
+            pending_performance_fee,
+            pending_owner,
+            pending_owner_fee_account,
+            liquidity_target_bps,
+            deactivating_lamports,
+            deactivating_epoch,
+            active_lamports,
+            activating_lamports,
+            list_update_cursor,
+            credit_queue_head,
+            credit_queue_tail,
+            credit_lamports_total,
+            credit_ordering,
+            performance_high_water,
+        ) = array_refs![src, 1, 32, 1, 1, 32, 32, 32, 32, 32, 32, 8, 8, 8, 16, 8, 8, 16, 16, 24, 24, 24, 32, 32, 2, 8, 8, 8, 8, 20, 8, 8, 8, 1, 8];
+        Ok(StakePool {
+            version: version[0],
+            owner: Pubkey::new(owner),
+            deposit_bump_seed: deposit_bump_seed[0],
+            withdraw_bump_seed: withdraw_bump_seed[0],
+            validator_stake_list: Pubkey::new(validator_stake_list),
+            credit_list: Pubkey::new(credit_list),
+            pool_mint: Pubkey::new(pool_mint),
+            owner_fee_account: Pubkey::new(owner_fee_account),
+            credit_reserve: Pubkey::new(credit_reserve),
+            token_program_id: Pubkey::new(token_program_id),
+            stake_total: u64::from_le_bytes(*stake_total),
+            pool_total: u64::from_le_bytes(*pool_total),
+            last_update_epoch: u64::from_le_bytes(*last_update_epoch),
+            deposit_fee: unpack_fee(deposit_fee),
+            last_update_slot: u64::from_le_bytes(*last_update_slot),
+            freshness_policy: FreshnessPolicy::grace(u64::from_le_bytes(*freshness_grace_slots)),
+            withdrawal_fee: unpack_fee(withdrawal_fee),
+            performance_fee: unpack_fee(performance_fee),
+            pending_deposit_fee: unpack_pending_fee(pending_deposit_fee),
+            pending_withdrawal_fee: unpack_pending_fee(pending_withdrawal_fee),
+            pending_performance_fee: unpack_pending_fee(pending_performance_fee),
+            pending_owner: Pubkey::new(pending_owner),
+            pending_owner_fee_account: Pubkey::new(pending_owner_fee_account),
+            liquidity_target_bps: u16::from_le_bytes(*liquidity_target_bps),
+            deactivating_lamports: u64::from_le_bytes(*deactivating_lamports),
+            deactivating_epoch: u64::from_le_bytes(*deactivating_epoch),
+            active_lamports: u64::from_le_bytes(*active_lamports),
+            activating_lamports: u64::from_le_bytes(*activating_lamports),
+            list_update_cursor: unpack_list_update_cursor(list_update_cursor),
+            credit_queue_head: u64::from_le_bytes(*credit_queue_head),
+            credit_queue_tail: u64::from_le_bytes(*credit_queue_tail),
+            credit_lamports_total: u64::from_le_bytes(*credit_lamports_total),
+            credit_ordering: CreditOrdering::try_from(credit_ordering[0])
+                .map_err(|_| ProgramError::InvalidAccountData)?,
+            performance_high_water: u64::from_le_bytes(*performance_high_water),
+        })
+    }
+}
 
-        Ok(())
+fn pack_fee(fee: &Fee, output: &mut [u8; 16]) {
+    let (denominator, numerator) = mut_array_refs![output, 8, 8];
+    *denominator = fee.denominator.to_le_bytes();
+    *numerator = fee.numerator.to_le_bytes();
+}
+
+fn unpack_fee(input: &[u8; 16]) -> Fee {
+    let (denominator, numerator) = array_refs![input, 8, 8];
+    Fee {
+        denominator: u64::from_le_bytes(*denominator),
+        numerator: u64::from_le_bytes(*numerator),
+    }
+}
+
+fn pack_pending_fee(pending_fee: &PendingFee, output: &mut [u8; 24]) {
+    let (fee, activation_epoch) = mut_array_refs![output, 16, 8];
+    pack_fee(&pending_fee.fee, fee);
+    *activation_epoch = pending_fee.activation_epoch.to_le_bytes();
+}
+
+fn unpack_pending_fee(input: &[u8; 24]) -> PendingFee {
+    let (fee, activation_epoch) = array_refs![input, 16, 8];
+    PendingFee {
+        fee: unpack_fee(fee),
+        activation_epoch: u64::from_le_bytes(*activation_epoch),
     }
 }
 
-/// Max validator count
+fn pack_list_update_cursor(cursor: &ListUpdateCursor, output: &mut [u8; 20]) {
+    let (epoch, validator_index, stake_index, stake_count) = mut_array_refs![output, 8, 4, 4, 4];
+    *epoch = cursor.epoch.to_le_bytes();
+    *validator_index = cursor.validator_index.to_le_bytes();
+    *stake_index = cursor.stake_index.to_le_bytes();
+    *stake_count = cursor.stake_count.to_le_bytes();
+}
+
+fn unpack_list_update_cursor(input: &[u8; 20]) -> ListUpdateCursor {
+    let (epoch, validator_index, stake_index, stake_count) = array_refs![input, 8, 4, 4, 4];
+    ListUpdateCursor {
+        epoch: u64::from_le_bytes(*epoch),
+        validator_index: u32::from_le_bytes(*validator_index),
+        stake_index: u32::from_le_bytes(*stake_index),
+        stake_count: u32::from_le_bytes(*stake_count),
+    }
+}
+
+/// Validator capacity of a list account created with `ValidatorStakeList::LEN`.
+/// The capacity of a pool comes from its list account size
 pub const MAX_VALIDATORS: usize = 100;
 /// Minimum stake account balance
 pub const MIN_STAKE_ACCOUNT_BALANCE: u64 = 100000000; // 0.1 SOL
 
 /// Storage list for all validator stake accounts in the pool.
-#[repr(C)]
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct ValidatorStakeList {
     /// Validator stake list version
@@ -179,7 +909,6 @@ pub struct ValidatorStakeList {
 }
 
 /// Information about the singe validator stake account
-#[repr(C)]
 #[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct ValidatorStakeInfo {
     /// Validator account pubkey
@@ -193,17 +922,47 @@ pub struct ValidatorStakeInfo {
 
     /// Stake account count
     pub stake_count: u32,
+
+    /// Share of the delegated stake relative to the other validators' weights.
+    /// Set by SetValidatorTargetWeight
+    pub target_weight: u32,
+
+    /// Stake still warming up at `last_update_epoch`
+    pub activating_lamports: u64,
+
+    /// Stake still cooling down at `last_update_epoch`
+    pub deactivating_lamports: u64,
+
+    /// Effective stake that is not cooling down at `last_update_epoch`
+    pub active_lamports: u64,
 }
 
 impl ValidatorStakeList {
-    /// Length of ValidatorStakeList data when serialized
+    /// Account size for the default `MAX_VALIDATORS` capacity
     pub const LEN: usize = Self::HEADER_LEN + ValidatorStakeInfo::LEN * MAX_VALIDATORS;
 
     /// Header length
     pub const HEADER_LEN: usize = size_of::<u8>() + size_of::<u16>();
 
     /// Version of validator stake list
-    pub const VALIDATOR_STAKE_LIST_VERSION: u8 = 1;
+    pub const VALIDATOR_STAKE_LIST_VERSION: u8 = 2;
+
+    /// Version of the lists written before the target weights and the stake activation fields
+    pub const VERSION_V1: u8 = 1;
+
+    /// ValidatorStakeInfo length in v1 lists: the `repr(C)` struct
+    /// with 4 bytes of padding after `stake_count`
+    pub const V1_RECORD_LEN: usize = 56;
+
+    /// Account size that fits `capacity` validators
+    pub fn space(capacity: usize) -> usize {
+        Self::HEADER_LEN + ValidatorStakeInfo::LEN * capacity
+    }
+
+    /// Number of validators an account of `data_len` bytes fits
+    pub fn capacity(data_len: usize) -> usize {
+        (data_len.saturating_sub(Self::HEADER_LEN) / ValidatorStakeInfo::LEN).min(u16::MAX as usize)
+    }
 
     /// Check if contains validator with particular pubkey
     pub fn contains(&self, validator: &Pubkey) -> bool {
@@ -230,48 +989,140 @@ impl ValidatorStakeList {
         self.version > 0
     }
 
+    /// Lamports each validator is missing to reach its `target_weight` share
+    /// of the validator balances plus `reserve_lamports`. In list order
+    pub fn target_deficits(&self, reserve_lamports: u64) -> Option<Vec<u64>> {
+        let total_lamports = self.total_balance() + reserve_lamports as u128;
+        Some(
+            self.weight_targets(total_lamports)?
+                .into_iter()
+                .zip(&self.validators)
+                .map(|(target, validator)| target.saturating_sub(validator.balance))
+                .collect(),
+        )
+    }
+
+    /// Lamports each validator holds above its `target_weight` share of the
+    /// validator balances less `withdrawn_lamports`. In list order
+    pub fn target_excesses(&self, withdrawn_lamports: u64) -> Option<Vec<u64>> {
+        let total_lamports = self
+            .total_balance()
+            .saturating_sub(withdrawn_lamports as u128);
+        Some(
+            self.weight_targets(total_lamports)?
+                .into_iter()
+                .zip(&self.validators)
+                .map(|(target, validator)| validator.balance.saturating_sub(target))
+                .collect(),
+        )
+    }
+
+    fn total_balance(&self) -> u128 {
+        self.validators
+            .iter()
+            .map(|validator| validator.balance as u128)
+            .sum()
+    }
+
+    /// Splits `total_lamports` by `target_weight`. All targets are zero
+    /// when no validator has a weight
+    fn weight_targets(&self, total_lamports: u128) -> Option<Vec<u64>> {
+        let total_weight: u128 = self
+            .validators
+            .iter()
+            .map(|validator| validator.target_weight as u128)
+            .sum();
+        self.validators
+            .iter()
+            .map(|validator| {
+                if total_weight == 0 {
+                    return Some(0);
+                }
+                u64::try_from(
+                    total_lamports
+                        .checked_mul(validator.target_weight as u128)?
+                        .checked_div(total_weight)?,
+                )
+                .ok()
+            })
+            .collect()
+    }
+
+    /// Sums of the validators' active, activating and deactivating stake
+    pub fn activation_totals(&self) -> Option<(u64, u64, u64)> {
+        let mut totals = (0u64, 0u64, 0u64);
+        for validator in &self.validators {
+            totals.0 = totals.0.checked_add(validator.active_lamports)?;
+            totals.1 = totals.1.checked_add(validator.activating_lamports)?;
+            totals.2 = totals.2.checked_add(validator.deactivating_lamports)?;
+        }
+        Some(totals)
+    }
+
     /// Deserializes a byte buffer into a ValidatorStakeList.
+    /// Fields missing from older versions read as zero
     pub fn deserialize(input: &[u8]) -> Result<Self, ProgramError> {
-        if input.len() < Self::LEN {
+        if input.len() < Self::HEADER_LEN {
             return Err(ProgramError::InvalidAccountData);
         }
 
-        if input[0] == 0 {
-            return Ok(ValidatorStakeList {
-                version: 0,
-                validators: vec![],
-            });
-        }
+        let record_len = match input[0] {
+            0 => {
+                return Ok(ValidatorStakeList {
+                    version: 0,
+                    validators: vec![],
+                })
+            }
+            Self::VERSION_V1 => Self::V1_RECORD_LEN,
+            Self::VALIDATOR_STAKE_LIST_VERSION => ValidatorStakeInfo::LEN,
+            version => {
+                msg!("Unknown validator stake list version {}", version);
+                return Err(ProgramError::InvalidAccountData);
+            }
+        };
 
         let number_of_validators: usize = u16::from_le_bytes(
             input[1..3]
                 .try_into()
                 .or(Err(ProgramError::InvalidAccountData))?,
         ) as usize;
-        if number_of_validators > MAX_VALIDATORS {
+        if input.len() < Self::HEADER_LEN + record_len * number_of_validators {
             return Err(ProgramError::InvalidAccountData);
         }
         let mut validators: Vec<ValidatorStakeInfo> = Vec::with_capacity(number_of_validators + 1);
 
+        let mut record = [0; ValidatorStakeInfo::LEN];
         let mut from = Self::HEADER_LEN;
-        let mut to = from + ValidatorStakeInfo::LEN;
         for _ in 0..number_of_validators {
-            validators.push(ValidatorStakeInfo::deserialize(&input[from..to])?);
-            from += ValidatorStakeInfo::LEN;
-            to += ValidatorStakeInfo::LEN;
+            record[..record_len].copy_from_slice(&input[from..from + record_len]);
+            let mut validator = ValidatorStakeInfo::unpack_from_slice(&record)?;
+            if input[0] == Self::VERSION_V1 {
+                // The bytes `target_weight` reads are padding in v1 records
+                validator.target_weight = 0;
+            }
+            validators.push(validator);
+            from += record_len;
         }
         Ok(ValidatorStakeList {
-            version: input[0],
+            version: Self::VALIDATOR_STAKE_LIST_VERSION,
             validators,
         })
     }
 
     /// Serializes ValidatorStakeList into a byte buffer.
+    /// Lists created with the v1 `LEN` keep fitting up to 70 validators,
+    /// longer v1 lists must be moved by ReallocList first
     pub fn serialize(&self, output: &mut [u8]) -> ProgramResult {
-        if output.len() < Self::LEN {
-            return Err(ProgramError::InvalidAccountData);
-        }
-        if self.validators.len() > MAX_VALIDATORS {
+        let capacity = Self::capacity(output.len());
+        if self.validators.len() > capacity {
+            if output.first() == Some(&Self::VERSION_V1) {
+                msg!(
+                    "v1 list holds {} validators but fits {} in the current layout",
+                    self.validators.len(),
+                    capacity
+                );
+                return Err(StakePoolError::ValidatorListNeedsRealloc.into());
+            }
             return Err(ProgramError::InvalidAccountData);
         }
         output[0] = self.version;
@@ -279,7 +1130,7 @@ impl ValidatorStakeList {
         let mut from = Self::HEADER_LEN;
         let mut to = from + ValidatorStakeInfo::LEN;
         for validator in &self.validators {
-            validator.serialize(&mut output[from..to])?;
+            validator.pack_into_slice(&mut output[from..to]);
             from += ValidatorStakeInfo::LEN;
             to += ValidatorStakeInfo::LEN;
         }
@@ -287,45 +1138,85 @@ impl ValidatorStakeList {
     }
 }
 
-impl ValidatorStakeInfo {
+impl Sealed for ValidatorStakeInfo {}
+
+impl Pack for ValidatorStakeInfo {
     /// Length of ValidatorStakeInfo data when serialized
-    pub const LEN: usize = size_of::<ValidatorStakeInfo>();
+    const LEN: usize = 80;
 
-    /// Deserializes a byte buffer into a ValidatorStakeInfo.
-    pub fn deserialize(input: &[u8]) -> Result<Self, ProgramError> {
-        if input.len() < Self::LEN {
-            return Err(ProgramError::InvalidAccountData);
-        }
-        #[allow(clippy::cast_ptr_alignment)]
-        let stake_info: &ValidatorStakeInfo =
-            unsafe { &*(&input[0] as *const u8 as *const ValidatorStakeInfo) };
-        Ok(*stake_info)
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, ValidatorStakeInfo::LEN];
+        let (
+            validator_account,
+            balance,
+            last_update_epoch,
+            stake_count,
+            target_weight,
+            activating_lamports,
+            deactivating_lamports,
+            active_lamports,
+        ) = mut_array_refs![dst, 32, 8, 8, 4, 4, 8, 8, 8];
+        validator_account.copy_from_slice(self.validator_account.as_ref());
+        *balance = self.balance.to_le_bytes();
+        *last_update_epoch = self.last_update_epoch.to_le_bytes();
+        *stake_count = self.stake_count.to_le_bytes();
+        *target_weight = self.target_weight.to_le_bytes();
+        *activating_lamports = self.activating_lamports.to_le_bytes();
+        *deactivating_lamports = self.deactivating_lamports.to_le_bytes();
+        *active_lamports = self.active_lamports.to_le_bytes();
     }
 
-    /// Serializes ValidatorStakeInfo into a byte buffer.
-    pub fn serialize(&self, output: &mut [u8]) -> ProgramResult {
-        if output.len() < Self::LEN {
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() < Self::LEN {
             return Err(ProgramError::InvalidAccountData);
         }
-
-        #[allow(clippy::cast_ptr_alignment)]
-        let value = unsafe { &mut *(&mut output[0] as *mut u8 as *mut ValidatorStakeInfo) };
-        *value = *self;
-        Ok(())
+        let src = array_ref![src, 0, ValidatorStakeInfo::LEN];
+        let (
+            validator_account,
+            balance,
+            last_update_epoch,
+            stake_count,
+            target_weight,
+            activating_lamports,
+            deactivating_lamports,
+            active_lamports,
+        ) = array_refs![src, 32, 8, 8, 4, 4, 8, 8, 8];
+        Ok(ValidatorStakeInfo {
+            validator_account: Pubkey::new(validator_account),
+            balance: u64::from_le_bytes(*balance),
+            last_update_epoch: u64::from_le_bytes(*last_update_epoch),
+            stake_count: u32::from_le_bytes(*stake_count),
+            target_weight: u32::from_le_bytes(*target_weight),
+            activating_lamports: u64::from_le_bytes(*activating_lamports),
+            deactivating_lamports: u64::from_le_bytes(*deactivating_lamports),
+            active_lamports: u64::from_le_bytes(*active_lamports),
+        })
     }
+}
 
+impl ValidatorStakeInfo {
     /// Stake account address for validator
     pub fn stake_address(
         &self,
         program_id: &Pubkey,
         stake_pool: &Pubkey,
         index: u32,
+    ) -> (Pubkey, u8) {
+        Self::find_stake_address(program_id, &self.validator_account, stake_pool, index)
+    }
+
+    /// Stake account address for any validator vote account
+    pub fn find_stake_address(
+        program_id: &Pubkey,
+        validator_account: &Pubkey,
+        stake_pool: &Pubkey,
+        index: u32,
     ) -> (Pubkey, u8) {
         Pubkey::find_program_address(
             &[
-                &self.validator_account.to_bytes()[..32],
+                &validator_account.to_bytes()[..32],
                 &stake_pool.to_bytes()[..32],
-                &unsafe { std::mem::transmute::<u32, [u8; 4]>(index) },
+                &index.to_le_bytes(),
             ],
             program_id,
         )
@@ -356,147 +1247,955 @@ impl ValidatorStakeInfo {
     }
 }
 
-/// Maximum credit records
-pub const MAX_CREDIT_RECORDS: usize = 1000;
-/// Credit list
-#[repr(C)]
-#[derive(Clone, Debug, Default, PartialEq)]
-pub struct CreditList {
-    /// Validator stake list version
+/// Credit ticket. Every Credit opens one in its own account at the program address
+/// for `[stake pool, "credit", sequence]`. PayCreditors and Uncredit close it.
+/// Credit burns the pool tokens and locks their SOL value in `lamports_owed`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CreditRecord {
+    /// Ticket version, 0 for a closed ticket
     pub version: u8,
-    /// List of all credit users with amount of credit tokens
-    pub credits: Vec<CreditRecord>,
+    /// Stake pool of the ticket
+    pub stake_pool: Pubkey,
+    /// Position in the credit queue, `StakePool::credit_queue_head` at Credit
+    pub sequence: u64,
+    /// User account for receiving sols
+    pub sol_target: Pubkey,
+    /// Signature to cancel request
+    pub cancel_authority: Pubkey,
+    /// Pool tokens burned at Credit for `lamports_owed`
+    pub token_amount: u64,
+    /// Epoch of the Credit
+    pub created_epoch: u64,
+    /// Lamports to pay
+    pub lamports_owed: u64,
+    /// Token account the pool tokens came from. Uncredit returns them there only
+    pub refund_account: Pubkey,
+    /// Payer of the ticket rent. Gets it back when the ticket is closed
+    pub payer: Pubkey,
 }
 
-impl CreditList {
-    /// Length of ValidatorStakeList data when serialized
-    pub const LEN: usize = Self::HEADER_LEN + CreditRecord::LEN * MAX_CREDIT_RECORDS;
-
-    /// Header length
-    pub const HEADER_LEN: usize = size_of::<u8>() + size_of::<u16>();
-
-    /// Version of validator stake list
-    pub const VERSION: u8 = 1;
+impl CreditRecord {
+    /// Version of credit tickets
+    pub const VERSION: u8 = 2;
+    /// Length of the fields when serialized. The rest of `LEN` is reserved
+    pub const PACKED_LEN: usize = 193;
+    /// Epochs after Credit when anyone may expire the ticket
+    pub const EXPIRY_EPOCHS: u64 = 10;
 
-    /// Check if contains validator with particular pubkey
-    pub fn contains(&self, user: &Pubkey) -> bool {
-        self.credits.iter().any(|x| x.sol_target == *user)
+    /// Check if the ticket is open
+    pub fn is_initialized(&self) -> bool {
+        self.version > 0
     }
 
-    /// Check if contains validator with particular pubkey (mutable)
-    pub fn find_mut(&mut self, user: &Pubkey) -> Option<&mut CreditRecord> {
-        self.credits.iter_mut().find(|x| x.sol_target == *user)
+    /// Takes `lamports` off the ticket together with the same share of its burned
+    /// pool tokens. Returns that share
+    pub fn release(&mut self, lamports: u64) -> Option<u64> {
+        let token_share = if lamports == self.lamports_owed {
+            self.token_amount
+        } else {
+            u64::try_from(
+                (self.token_amount as u128)
+                    .checked_mul(lamports as u128)?
+                    .checked_div(self.lamports_owed as u128)?,
+            )
+            .ok()?
+        };
+        self.lamports_owed = self.lamports_owed.checked_sub(lamports)?;
+        self.token_amount -= token_share;
+        Some(token_share)
     }
-    /// Check if contains validator with particular pubkey (immutable)
-    pub fn find(&self, user: &Pubkey) -> Option<&CreditRecord> {
-        self.credits.iter().find(|x| x.sol_target == *user)
+
+    /// First epoch when ExpireCredit may return the ticket to its refund account
+    pub fn expiry_epoch(&self) -> u64 {
+        self.created_epoch.saturating_add(Self::EXPIRY_EPOCHS)
     }
+}
 
-    /// Check if validator stake list is initialized
-    pub fn is_initialized(&self) -> bool {
-        self.version > 0
+impl Sealed for CreditRecord {}
+
+impl Pack for CreditRecord {
+    /// Size of credit ticket accounts
+    const LEN: usize = 200;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, CreditRecord::PACKED_LEN];
+        let (
+            version,
+            stake_pool,
+            sequence,
+            sol_target,
+            cancel_authority,
+            token_amount,
+            created_epoch,
+            lamports_owed,
+            refund_account,
+            payer,
+        ) = mut_array_refs![dst, 1, 32, 8, 32, 32, 8, 8, 8, 32, 32];
+        version[0] = self.version;
+        stake_pool.copy_from_slice(self.stake_pool.as_ref());
+        *sequence = self.sequence.to_le_bytes();
+        sol_target.copy_from_slice(self.sol_target.as_ref());
+        cancel_authority.copy_from_slice(self.cancel_authority.as_ref());
+        *token_amount = self.token_amount.to_le_bytes();
+        *created_epoch = self.created_epoch.to_le_bytes();
+        *lamports_owed = self.lamports_owed.to_le_bytes();
+        refund_account.copy_from_slice(self.refund_account.as_ref());
+        payer.copy_from_slice(self.payer.as_ref());
     }
 
-    /// Deserializes a byte buffer into a ValidatorStakeList.
-    pub fn deserialize(input: &[u8]) -> Result<Self, ProgramError> {
-        if input.len() < Self::LEN {
-            msg!("Too short credit list account");
+    /// Accepts any buffer of at least `PACKED_LEN` bytes.
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() < Self::PACKED_LEN {
             return Err(ProgramError::InvalidAccountData);
         }
+        let src = array_ref![src, 0, CreditRecord::PACKED_LEN];
+        let (
+            version,
+            stake_pool,
+            sequence,
+            sol_target,
+            cancel_authority,
+            token_amount,
+            created_epoch,
+            lamports_owed,
+            refund_account,
+            payer,
+        ) = array_refs![src, 1, 32, 8, 32, 32, 8, 8, 8, 32, 32];
+        Ok(CreditRecord {
+            version: version[0],
+            stake_pool: Pubkey::new(stake_pool),
+            sequence: u64::from_le_bytes(*sequence),
+            sol_target: Pubkey::new(sol_target),
+            cancel_authority: Pubkey::new(cancel_authority),
+            token_amount: u64::from_le_bytes(*token_amount),
+            created_epoch: u64::from_le_bytes(*created_epoch),
+            lamports_owed: u64::from_le_bytes(*lamports_owed),
+            refund_account: Pubkey::new(refund_account),
+            payer: Pubkey::new(payer),
+        })
+    }
+}
 
-        if input[0] == 0 {
-            return Ok(CreditList {
-                version: 0,
-                credits: vec![],
-            });
-        }
+/// Credit list of the pools created before credit tickets.
+/// Its pool tokens wait in the credit reserve until RefundCreditList returns them
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CreditList {
+    /// List version, 0 for an account that is not a list
+    pub version: u8,
+    /// Credits in the request order
+    pub credits: Vec<CreditListEntry>,
+}
 
-        let number_of_records: usize = u16::from_le_bytes(
-            input[1..3]
-                .try_into()
-                .or(Err(ProgramError::InvalidAccountData))?,
-        ) as usize;
-        if number_of_records > MAX_CREDIT_RECORDS {
+impl CreditList {
+    /// Header length: version and entry count
+    pub const HEADER_LEN: usize = size_of::<u8>() + size_of::<u16>();
+
+    /// Deserializes a byte buffer into a [CreditList](struct.CreditList.html)
+    pub fn deserialize(input: &[u8]) -> Result<Self, ProgramError> {
+        if input.len() < Self::HEADER_LEN {
             return Err(ProgramError::InvalidAccountData);
         }
-        let mut credits: Vec<CreditRecord> = Vec::with_capacity(number_of_records + 1);
-
-        let mut from = Self::HEADER_LEN;
-        let mut to = from + CreditRecord::LEN;
-        for _ in 0..number_of_records {
-            credits.push(CreditRecord::deserialize(&input[from..to])?);
-            from += CreditRecord::LEN;
-            to += CreditRecord::LEN;
+        if input[0] == 0 {
+            return Ok(CreditList::default());
         }
-        Ok(Self {
+        let count = u16::from_le_bytes(*array_ref![input, 1, 2]) as usize;
+        let end = count
+            .checked_mul(CreditListEntry::LEN)
+            .and_then(|len| len.checked_add(Self::HEADER_LEN))
+            .ok_or(ProgramError::InvalidAccountData)?;
+        let credits = input
+            .get(Self::HEADER_LEN..end)
+            .ok_or(ProgramError::InvalidAccountData)?
+            .chunks_exact(CreditListEntry::LEN)
+            .map(CreditListEntry::unpack_from_slice)
+            .collect::<Result<_, _>>()?;
+        Ok(CreditList {
             version: input[0],
             credits,
         })
     }
 
-    /// Serializes ValidatorStakeList into a byte buffer.
+    /// Serializes [CreditList](struct.CreditList.html) into a byte buffer.
+    /// Bytes after the last entry are left untouched
     pub fn serialize(&self, output: &mut [u8]) -> ProgramResult {
-        if output.len() < Self::LEN {
-            return Err(ProgramError::InvalidAccountData);
-        }
-        if self.credits.len() > MAX_CREDIT_RECORDS {
+        let end = self.credits.len() * CreditListEntry::LEN + Self::HEADER_LEN;
+        if output.len() < end || self.credits.len() > u16::MAX as usize {
             return Err(ProgramError::InvalidAccountData);
         }
         output[0] = self.version;
-        output[1..3].copy_from_slice(&u16::to_le_bytes(self.credits.len() as u16));
-        let mut from = Self::HEADER_LEN;
-        let mut to = from + CreditRecord::LEN;
-        for record in &self.credits {
-            record.serialize(&mut output[from..to])?;
-            from += CreditRecord::LEN;
-            to += CreditRecord::LEN;
+        output[1..3].copy_from_slice(&(self.credits.len() as u16).to_le_bytes());
+        for (entry, dst) in self
+            .credits
+            .iter()
+            .zip(output[Self::HEADER_LEN..end].chunks_exact_mut(CreditListEntry::LEN))
+        {
+            entry.pack_into_slice(dst);
         }
         Ok(())
     }
 }
-/// Credit record
-#[repr(C)]
+
+/// Credit request of a [CreditList](struct.CreditList.html)
 #[derive(Clone, Copy, Debug, Default, PartialEq)]
-pub struct CreditRecord {
+pub struct CreditListEntry {
     /// User account for receiving sols
     pub sol_target: Pubkey,
     /// Signature to cancel request
     pub cancel_authority: Pubkey,
-    /// Amount from reserve
+    /// Pool tokens in the credit reserve
     pub token_amount: u64,
 }
 
-impl CreditRecord {
-    /// Length of CreditRecord
-    pub const LEN: usize = size_of::<CreditRecord>();
+impl Sealed for CreditListEntry {}
 
-    /// Deserializes a byte buffer into a CreditRecord.
-    pub fn deserialize(input: &[u8]) -> Result<Self, ProgramError> {
-        if input.len() < Self::LEN {
-            return Err(ProgramError::InvalidAccountData);
-        }
-        #[allow(clippy::cast_ptr_alignment)]
-        let credit_record: &CreditRecord =
-            unsafe { &*(&input[0] as *const u8 as *const CreditRecord) };
-        Ok(*credit_record)
+impl Pack for CreditListEntry {
+    /// Size of the `repr(C)` record
+    const LEN: usize = 72;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, CreditListEntry::LEN];
+        let (sol_target, cancel_authority, token_amount) = mut_array_refs![dst, 32, 32, 8];
+        sol_target.copy_from_slice(self.sol_target.as_ref());
+        cancel_authority.copy_from_slice(self.cancel_authority.as_ref());
+        *token_amount = self.token_amount.to_le_bytes();
     }
 
-    /// Serializes CreditRecord into a byte buffer.
-    pub fn serialize(&self, output: &mut [u8]) -> ProgramResult {
-        if output.len() < Self::LEN {
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() < Self::LEN {
             return Err(ProgramError::InvalidAccountData);
         }
-
-        #[allow(clippy::cast_ptr_alignment)]
-        let value = unsafe { &mut *(&mut output[0] as *mut u8 as *mut CreditRecord) };
-        *value = *self;
-        Ok(())
+        let src = array_ref![src, 0, CreditListEntry::LEN];
+        let (sol_target, cancel_authority, token_amount) = array_refs![src, 32, 32, 8];
+        Ok(CreditListEntry {
+            sol_target: Pubkey::new(sol_target),
+            cancel_authority: Pubkey::new(cancel_authority),
+            token_amount: u64::from_le_bytes(*token_amount),
+        })
     }
 }
 
+/// Credit ticket account passed to PayCreditors
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CreditQueueItem {
+    /// Open ticket. It is not payable when its target is not a system account
+    Open {
+        /// The ticket
+        ticket: CreditRecord,
+        /// PayCreditors can pay the target
+        is_payable: bool,
+    },
+    /// Account of a ticket closed before its turn
+    Closed(Pubkey),
+}
+
+/// Share of a credit ticket paid by PayCreditors
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CreditPayment {
+    /// Lamports for the ticket target
+    pub lamports: u64,
+    /// The payment closes the ticket
+    pub is_full: bool,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use proptest::{collection::vec, prelude::*};
+
+    const STAKE_POOL_V1: &[u8] = include_bytes!("../tests/fixtures/stake_pool_v1.bin");
+
+    #[test]
+    fn test_stake_pool_v1_fixture() {
+        let expected = StakePool {
+            version: StakePool::VERSION_V1,
+            owner: Pubkey::new(&[1; 32]),
+            deposit_bump_seed: 254,
+            withdraw_bump_seed: 253,
+            validator_stake_list: Pubkey::new(&[2; 32]),
+            credit_list: Pubkey::new(&[3; 32]),
+            pool_mint: Pubkey::new(&[4; 32]),
+            owner_fee_account: Pubkey::new(&[5; 32]),
+            credit_reserve: Pubkey::new(&[6; 32]),
+            token_program_id: Pubkey::new(&[7; 32]),
+            stake_total: 10_000_000_000,
+            pool_total: 9_000_000_000,
+            last_update_epoch: 42,
+            deposit_fee: Fee {
+                denominator: 100,
+                numerator: 3,
+            },
+            ..StakePool::default()
+        };
+        assert_eq!(STAKE_POOL_V1.len(), StakePool::V1_MIN_LEN);
+        assert_eq!(StakePool::deserialize_v1(STAKE_POOL_V1), Ok(expected));
+        assert_eq!(
+            StakePool::deserialize(STAKE_POOL_V1),
+            Err(StakePoolError::OutdatedStateVersion.into())
+        );
+
+        // Accounts created by the pool client carry reserved space after the fields
+        let mut data = STAKE_POOL_V1.to_vec();
+        data.resize(StakePool::V1_CLIENT_LEN, 0xff);
+        let stake_pool = StakePool::deserialize_v1(&data).unwrap();
+        assert_eq!(stake_pool, expected);
+        assert_eq!(
+            StakePool::deserialize(&data),
+            Err(StakePoolError::OutdatedStateVersion.into())
+        );
+
+        // Migrated in place
+        let migrated = StakePool {
+            version: PROGRAM_VERSION,
+            ..stake_pool
+        };
+        migrated.serialize(&mut data).unwrap();
+        assert_eq!(StakePool::deserialize(&data), Ok(migrated));
+    }
+
+    #[test]
+    fn test_stake_pool_packed_layout() {
+        let stake_pool = StakePool {
+            version: PROGRAM_VERSION,
+            owner: Pubkey::new_unique(),
+            deposit_bump_seed: 1,
+            withdraw_bump_seed: 2,
+            validator_stake_list: Pubkey::new_unique(),
+            credit_list: Pubkey::new_unique(),
+            pool_mint: Pubkey::new_unique(),
+            owner_fee_account: Pubkey::new_unique(),
+            credit_reserve: Pubkey::new_unique(),
+            token_program_id: Pubkey::new_unique(),
+            stake_total: 3,
+            pool_total: 4,
+            last_update_epoch: 5,
+            deposit_fee: Fee {
+                denominator: 6,
+                numerator: 7,
+            },
+            last_update_slot: 8,
+            freshness_policy: FreshnessPolicy::grace(9),
+            withdrawal_fee: Fee {
+                denominator: 10,
+                numerator: 11,
+            },
+            performance_fee: Fee {
+                denominator: 12,
+                numerator: 13,
+            },
+            pending_deposit_fee: PendingFee {
+                fee: Fee {
+                    denominator: 14,
+                    numerator: 15,
+                },
+                activation_epoch: 16,
+            },
+            pending_withdrawal_fee: PendingFee::default(),
+            pending_performance_fee: PendingFee {
+                fee: Fee {
+                    denominator: 17,
+                    numerator: 18,
+                },
+                activation_epoch: 19,
+            },
+            pending_owner: Pubkey::new_unique(),
+            pending_owner_fee_account: Pubkey::new_unique(),
+            liquidity_target_bps: 20,
+            deactivating_lamports: 21,
+            deactivating_epoch: 22,
+            active_lamports: 23,
+            activating_lamports: 24,
+            list_update_cursor: ListUpdateCursor {
+                epoch: 25,
+                validator_index: 26,
+                stake_index: 27,
+                stake_count: 28,
+            },
+            credit_queue_head: 29,
+            credit_queue_tail: 30,
+            credit_lamports_total: 31,
+            credit_ordering: CreditOrdering::SkipAhead,
+            performance_high_water: 32,
+        };
+        let mut data = vec![0xff; StakePool::LEN];
+        stake_pool.serialize(&mut data).unwrap();
+        assert_eq!(StakePool::deserialize(&data), Ok(stake_pool));
+        // No padding between the fields, reserved space is untouched
+        assert_eq!(data[35..67], stake_pool.validator_stake_list.to_bytes());
+        assert_eq!(data[227..235], 3u64.to_le_bytes());
+        assert!(data[StakePool::PACKED_LEN..]
+            .iter()
+            .all(|byte| *byte == 0xff));
+
+        assert_eq!(
+            StakePool::deserialize(&data[..StakePool::PACKED_LEN - 1]),
+            Err(ProgramError::InvalidAccountData)
+        );
+        assert_eq!(
+            StakePool::deserialize(&[0; StakePool::PACKED_LEN]),
+            Ok(StakePool::default())
+        );
+    }
+
+    #[test]
+    fn test_fee_increase_timelock() {
+        let fee = |numerator| Fee {
+            denominator: 100,
+            numerator,
+        };
+        let mut stake_pool = StakePool {
+            withdrawal_fee: fee(2),
+            ..StakePool::default()
+        };
+
+        // Decrease is applied immediately
+        stake_pool.set_fee(FeeType::Withdrawal, fee(1), 10);
+        assert_eq!(stake_pool.withdrawal_fee, fee(1));
+        assert!(!stake_pool.pending_withdrawal_fee.is_pending());
+
+        // Increase waits for a full epoch
+        stake_pool.set_fee(FeeType::Withdrawal, fee(3), 10);
+        assert_eq!(stake_pool.withdrawal_fee, fee(1));
+        assert_eq!(
+            stake_pool.pending_withdrawal_fee,
+            PendingFee {
+                fee: fee(3),
+                activation_epoch: 12
+            }
+        );
+        stake_pool.promote_pending_fees(11);
+        assert_eq!(stake_pool.withdrawal_fee, fee(1));
+        stake_pool.promote_pending_fees(12);
+        assert_eq!(stake_pool.withdrawal_fee, fee(3));
+        assert!(!stake_pool.pending_withdrawal_fee.is_pending());
+
+        // Decrease cancels a pending increase
+        stake_pool.set_fee(FeeType::Withdrawal, fee(5), 20);
+        stake_pool.set_fee(FeeType::Withdrawal, fee(3), 20);
+        assert_eq!(stake_pool.withdrawal_fee, fee(3));
+        assert!(!stake_pool.pending_withdrawal_fee.is_pending());
+        assert_eq!(stake_pool.deposit_fee, Fee::default());
+    }
+
+    #[test]
+    fn test_pool_burn_amount() {
+        let stake_pool = StakePool {
+            stake_total: 3_000,
+            pool_total: 1_000,
+            ..StakePool::default()
+        };
+        assert_eq!(stake_pool.calc_pool_burn_amount(3_000), Some(1_000));
+        // Rounded up where the withdrawal rounds down
+        assert_eq!(stake_pool.calc_pool_withdraw_amount(1_000), Some(333));
+        assert_eq!(stake_pool.calc_pool_burn_amount(1_000), Some(334));
+        assert_eq!(stake_pool.calc_pool_burn_amount(0), Some(0));
+
+        // Nothing is counted, nothing can leave
+        assert_eq!(StakePool::default().calc_pool_burn_amount(1), None);
+    }
+
+    #[test]
+    fn test_performance_fee() {
+        let mut stake_pool = StakePool {
+            stake_total: 990,
+            pool_total: 990,
+            performance_fee: Fee {
+                denominator: 10,
+                numerator: 1,
+            },
+            ..StakePool::default()
+        };
+        // No rewards, no fee
+        assert_eq!(stake_pool.calc_performance_fee_amount(990), Some(0));
+        assert_eq!(stake_pool.calc_performance_fee_amount(900), Some(0));
+
+        // 110 lamports of rewards: 11 lamports go to the owner
+        let fee_amount = stake_pool.calc_performance_fee_amount(1_100).unwrap();
+        assert_eq!(fee_amount, 10);
+        stake_pool.stake_total = 1_100;
+        stake_pool.pool_total += fee_amount;
+        assert_eq!(stake_pool.calc_lamports_amount(fee_amount), Some(11));
+
+        stake_pool.performance_fee = Fee::default();
+        assert_eq!(stake_pool.calc_performance_fee_amount(2_000), Some(0));
+    }
+
+    #[test]
+    fn test_performance_high_water() {
+        let mut stake_pool = StakePool {
+            stake_total: 1_000,
+            pool_total: 1_000,
+            performance_fee: Fee {
+                denominator: 10,
+                numerator: 1,
+            },
+            ..StakePool::default()
+        };
+        stake_pool.update_performance_high_water().unwrap();
+        assert_eq!(
+            stake_pool.performance_high_water,
+            StakePool::HIGH_WATER_TOKENS
+        );
+
+        // A slash takes 100 lamports. Winning them back is free
+        stake_pool.stake_total = 900;
+        stake_pool.update_performance_high_water().unwrap();
+        assert_eq!(
+            stake_pool.performance_high_water,
+            StakePool::HIGH_WATER_TOKENS
+        );
+        assert_eq!(stake_pool.calc_performance_fee_amount(1_000), Some(0));
+
+        // Only the 100 lamports above the mark are charged
+        let fee_amount = stake_pool.calc_performance_fee_amount(1_100).unwrap();
+        assert_eq!(
+            fee_amount,
+            StakePool {
+                stake_total: 1_000,
+                ..stake_pool
+            }
+            .calc_performance_fee_amount(1_100)
+            .unwrap()
+        );
+        assert!(fee_amount > 0);
+        stake_pool.stake_total = 1_100;
+        stake_pool.pool_total += fee_amount;
+        stake_pool.update_performance_high_water().unwrap();
+        assert_eq!(
+            stake_pool.performance_high_water,
+            stake_pool
+                .calc_lamports_amount(StakePool::HIGH_WATER_TOKENS)
+                .unwrap()
+        );
+        assert!(stake_pool.performance_high_water > StakePool::HIGH_WATER_TOKENS);
+    }
+
+    #[test]
+    fn test_liquidity_target() {
+        let mut stake_pool = StakePool {
+            stake_total: 1_000_000,
+            ..StakePool::default()
+        };
+        assert_eq!(stake_pool.calc_liquidity_target(), Some(0));
+        stake_pool.liquidity_target_bps = 250;
+        assert_eq!(stake_pool.calc_liquidity_target(), Some(25_000));
+        stake_pool.liquidity_target_bps = StakePool::MAX_LIQUIDITY_TARGET_BPS;
+        assert_eq!(stake_pool.calc_liquidity_target(), Some(1_000_000));
+        stake_pool.stake_total = u64::MAX;
+        assert_eq!(stake_pool.calc_liquidity_target(), Some(u64::MAX));
+    }
+
+    #[test]
+    fn test_required_unstake() {
+        let mut stake_pool = StakePool {
+            stake_total: 2_000,
+            pool_total: 1_000,
+            ..StakePool::default()
+        };
+        assert_eq!(stake_pool.required_unstake(0, 0), 0);
+        stake_pool.credit_lamports_total = 800;
+        assert_eq!(stake_pool.required_unstake(0, 0), 800);
+        assert_eq!(stake_pool.required_unstake(300, 200), 300);
+        assert_eq!(stake_pool.required_unstake(700, 200), 0);
+        assert_eq!(stake_pool.required_unstake(u64::MAX, u64::MAX), 0);
+    }
+
+    #[test]
+    fn test_credit_accounting() {
+        // 1000 pool tokens at 2 lamports each. A creditor asks for 100 of them
+        let mut stake_pool = StakePool {
+            stake_total: 2_000,
+            pool_total: 1_000,
+            ..StakePool::default()
+        };
+        let lamports_owed = stake_pool.calc_lamports_amount(100).unwrap();
+        assert_eq!(lamports_owed, 200);
+        let ticket = CreditRecord {
+            version: CreditRecord::VERSION,
+            token_amount: 100,
+            lamports_owed,
+            ..CreditRecord::default()
+        };
+        // The tokens are burned and their value leaves stake_total at Credit
+        stake_pool.book_credit(&ticket).unwrap();
+        assert_eq!(stake_pool.pool_total, 900);
+        assert_eq!(stake_pool.stake_total, 1_800);
+        assert_eq!(stake_pool.credit_lamports_total, 200);
+        let holder_value = |stake_pool: &StakePool| stake_pool.calc_lamports_amount(900).unwrap();
+        assert_eq!(holder_value(&stake_pool), 1_800);
+
+        // Rewards: UpdatePoolBalance sees 2300 lamports in the reserve and the stakes.
+        // The holders get all of them, the creditor gets the quote
+        let mut rewarded = stake_pool;
+        rewarded.stake_total = 2_300 - rewarded.credit_lamports_total;
+        assert_eq!(holder_value(&rewarded), 2_100);
+        let mut paid_ticket = ticket;
+        rewarded.book_credit_payment(&mut paid_ticket, 150).unwrap();
+        assert_eq!(paid_ticket.lamports_owed, 50);
+        assert_eq!(paid_ticket.token_amount, 25);
+        assert_eq!(rewarded.credit_lamports_total, 50);
+        assert_eq!(holder_value(&rewarded), 2_100);
+        assert_eq!(rewarded.book_credit_payment(&mut paid_ticket, 51), None);
+        rewarded.book_credit_payment(&mut paid_ticket, 50).unwrap();
+        assert_eq!(paid_ticket.token_amount, 0);
+        assert_eq!(rewarded.credit_lamports_total, 0);
+
+        // Uncredit after the rewards mints fewer tokens than were burned
+        let mut rewarded = stake_pool;
+        rewarded.stake_total = 2_300 - rewarded.credit_lamports_total;
+        let mut returned_ticket = ticket;
+        assert_eq!(rewarded.book_uncredit(&mut returned_ticket, 200), Some(85));
+        assert_eq!(rewarded.pool_total, 985);
+        assert_eq!(rewarded.stake_total, 2_300);
+        assert_eq!(rewarded.credit_lamports_total, 0);
+        assert_eq!(returned_ticket.lamports_owed, 0);
+
+        // Slashing: 1800 lamports are left. The holders take the whole loss
+        let mut slashed = stake_pool;
+        slashed.stake_total = 1_800 - slashed.credit_lamports_total;
+        assert_eq!(holder_value(&slashed), 1_600);
+        // Uncredit gets no more than the burned tokens back, half of them for half of the lamports
+        let mut returned_ticket = ticket;
+        assert_eq!(slashed.book_uncredit(&mut returned_ticket, 100), Some(50));
+        assert_eq!(returned_ticket.token_amount, 50);
+        assert_eq!(returned_ticket.lamports_owed, 100);
+        assert_eq!(slashed.book_uncredit(&mut returned_ticket, 100), Some(50));
+        assert_eq!(slashed.pool_total, 1_000);
+        assert_eq!(slashed.stake_total, 1_800);
+        assert_eq!(slashed.credit_lamports_total, 0);
+        assert_eq!(slashed.book_uncredit(&mut returned_ticket, 1), None);
+
+        // The withdrawal fee is taken from the payment and stays with the holders
+        let mut stake_pool = StakePool {
+            withdrawal_fee: Fee {
+                denominator: 100,
+                numerator: 10,
+            },
+            ..stake_pool
+        };
+        assert_eq!(stake_pool.book_credit_fee(200), Some((20, 10)));
+        assert_eq!(stake_pool.stake_total, 1_820);
+        assert_eq!(stake_pool.pool_total, 910);
+        assert_eq!(holder_value(&stake_pool), 1_800);
+        assert_eq!(stake_pool.book_credit_fee(0), Some((0, 0)));
+    }
+
+    #[test]
+    fn test_credit_payment() {
+        let stake_pool = StakePool {
+            stake_total: 2_000,
+            pool_total: 1_000,
+            ..StakePool::default()
+        };
+        let ticket = CreditRecord {
+            version: CreditRecord::VERSION,
+            token_amount: 500,
+            lamports_owed: 1_000,
+            ..CreditRecord::default()
+        };
+        assert_eq!(
+            stake_pool.calc_credit_payment(&ticket, 1_000),
+            CreditPayment {
+                lamports: 1_000,
+                is_full: true,
+            }
+        );
+
+        // FIFO pays what is left in the reserve
+        assert_eq!(
+            stake_pool.calc_credit_payment(&ticket, 300),
+            CreditPayment {
+                lamports: 300,
+                is_full: false,
+            }
+        );
+
+        // Skip-ahead pays nothing
+        let stake_pool = StakePool {
+            credit_ordering: CreditOrdering::SkipAhead,
+            ..stake_pool
+        };
+        assert_eq!(
+            stake_pool.calc_credit_payment(&ticket, 999),
+            CreditPayment::default()
+        );
+    }
+
+    fn test_ticket_address(sequence: u64) -> Pubkey {
+        let mut bytes = [0; 32];
+        bytes[..8].copy_from_slice(&sequence.to_le_bytes());
+        Pubkey::new(&bytes)
+    }
+
+    fn open_ticket(sequence: u64, lamports_owed: u64) -> CreditQueueItem {
+        CreditQueueItem::Open {
+            ticket: CreditRecord {
+                version: CreditRecord::VERSION,
+                sequence,
+                token_amount: lamports_owed,
+                lamports_owed,
+                ..CreditRecord::default()
+            },
+            is_payable: true,
+        }
+    }
+
+    #[test]
+    fn test_pay_credits() {
+        let stake_pool = StakePool {
+            credit_queue_head: 4,
+            credit_queue_tail: 1,
+            credit_lamports_total: 300,
+            ..StakePool::default()
+        };
+
+        // FIFO walks the queue from the tail: the closed ticket #1 is passed over,
+        // #2 is paid in full and #3 partially
+        let mut fifo = stake_pool;
+        let mut items = [
+            CreditQueueItem::Closed(test_ticket_address(1)),
+            open_ticket(2, 100),
+            open_ticket(3, 200),
+        ];
+        let payments = fifo
+            .pay_credits(&mut items, 150, test_ticket_address)
+            .unwrap();
+        assert_eq!(
+            payments,
+            vec![
+                CreditPayment::default(),
+                CreditPayment {
+                    lamports: 100,
+                    is_full: true,
+                },
+                CreditPayment {
+                    lamports: 50,
+                    is_full: false,
+                },
+            ]
+        );
+        assert_eq!(fifo.credit_queue_tail, 3);
+        assert_eq!(fifo.credit_lamports_total, 150);
+        match &items[2] {
+            CreditQueueItem::Open { ticket, .. } => assert_eq!(ticket.lamports_owed, 150),
+            CreditQueueItem::Closed(_) => panic!("ticket #3 is open"),
+        }
+
+        // FIFO can't start after the tail
+        let mut items = [open_ticket(2, 100)];
+        assert_eq!(
+            { stake_pool }.pay_credits(&mut items, 150, test_ticket_address),
+            Err(ProgramError::InvalidArgument)
+        );
+
+        // A closed ticket must be the one at the tail
+        let mut items = [CreditQueueItem::Closed(test_ticket_address(2))];
+        assert_eq!(
+            { stake_pool }.pay_credits(&mut items, 150, test_ticket_address),
+            Err(StakePoolError::UnknownCreditor.into())
+        );
+
+        // Skip-ahead pays whatever fits, takes no closed tickets
+        // and doesn't move the tail
+        let mut skip_ahead = StakePool {
+            credit_ordering: CreditOrdering::SkipAhead,
+            ..stake_pool
+        };
+        let mut items = [open_ticket(3, 200), open_ticket(2, 100)];
+        let payments = { skip_ahead }
+            .pay_credits(&mut items, 150, test_ticket_address)
+            .unwrap();
+        assert_eq!(payments[0], CreditPayment::default());
+        assert!(payments[1].is_full);
+        let mut items = [CreditQueueItem::Closed(test_ticket_address(1))];
+        assert_eq!(
+            { skip_ahead }.pay_credits(&mut items, 150, test_ticket_address),
+            Err(StakePoolError::UnknownCreditor.into())
+        );
+
+        // The same ticket can't be paid twice
+        let mut items = [open_ticket(2, 100), open_ticket(2, 100)];
+        assert_eq!(
+            skip_ahead.pay_credits(&mut items, 300, test_ticket_address),
+            Err(ProgramError::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn test_record_deactivation() {
+        let mut stake_pool = StakePool::default();
+        stake_pool.record_deactivation(5, 100).unwrap();
+        stake_pool.record_deactivation(5, 50).unwrap();
+        assert_eq!(stake_pool.deactivating_lamports_at(5), 150);
+        assert_eq!(stake_pool.deactivating_lamports_at(6), 0);
+
+        // A new epoch starts from zero
+        stake_pool.record_deactivation(6, 10).unwrap();
+        assert_eq!(stake_pool.deactivating_lamports_at(6), 10);
+        assert_eq!(stake_pool.record_deactivation(6, u64::MAX), None);
+    }
+
+    #[test]
+    fn test_list_update_cursor() {
+        let cursor = ListUpdateCursor {
+            epoch: 5,
+            validator_index: 2,
+            stake_index: 3,
+            stake_count: 1,
+        };
+        assert_eq!(cursor.start(5, 10), cursor);
+        assert!(cursor.is_inside(5, 2));
+        assert!(!cursor.is_inside(5, 1));
+        assert!(!cursor.is_inside(6, 2));
+        assert!(cursor.in_progress(5));
+        assert!(!cursor.in_progress(6));
+
+        // Finishing a validator moves to the first stake of the next one
+        let next = cursor.next_validator();
+        assert_eq!(
+            next,
+            ListUpdateCursor {
+                epoch: 5,
+                validator_index: 3,
+                stake_index: 0,
+                stake_count: 0,
+            }
+        );
+        assert!(!next.in_progress(5));
+        assert!(!next.is_inside(5, 3));
+        assert_eq!(next.start(5, 10), next);
+        assert_eq!(
+            next.start(5, 3),
+            ListUpdateCursor {
+                epoch: 5,
+                ..ListUpdateCursor::default()
+            }
+        );
+
+        // New epoch or finished round
+        let first = ListUpdateCursor {
+            epoch: 6,
+            ..ListUpdateCursor::default()
+        };
+        assert_eq!(cursor.start(6, 10), first);
+        assert!(!first.is_inside(6, 0));
+        assert_eq!(
+            cursor.start(5, 2),
+            ListUpdateCursor {
+                epoch: 5,
+                ..ListUpdateCursor::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_freshness_policy() {
+        // Epoch 5 starts at slot 500
+        let epoch_schedule = EpochSchedule::custom(100, 100, false);
+        let clock = Clock {
+            slot: 560,
+            epoch: 5,
+            ..Clock::default()
+        };
+        let mut stake_pool = StakePool {
+            last_update_epoch: 5,
+            last_update_slot: 510,
+            freshness_policy: FreshnessPolicy::STRICT,
+            ..StakePool::default()
+        };
+        // Updated in the current epoch
+        assert_eq!(stake_pool.check_freshness(&clock, &epoch_schedule), Ok(()));
+
+        // Updated in the previous epoch
+        stake_pool.last_update_epoch = 4;
+        stake_pool.last_update_slot = 499;
+        assert_eq!(
+            stake_pool.check_freshness(&clock, &epoch_schedule),
+            Err(StakePoolError::PoolBalanceOutOfDate.into())
+        );
+
+        // Even in the first slot of the epoch
+        let epoch_start_clock = Clock {
+            slot: 500,
+            epoch: 5,
+            ..Clock::default()
+        };
+        assert_eq!(
+            stake_pool.check_freshness(&epoch_start_clock, &epoch_schedule),
+            Err(StakePoolError::PoolBalanceOutOfDate.into())
+        );
+
+        // The grace counts from the epoch start, not from the last update.
+        // Slot 560 is the 61st slot of the epoch
+        stake_pool.freshness_policy = FreshnessPolicy::grace(61);
+        assert_eq!(stake_pool.check_freshness(&clock, &epoch_schedule), Ok(()));
+        stake_pool.last_update_slot = 420;
+        assert_eq!(stake_pool.check_freshness(&clock, &epoch_schedule), Ok(()));
+        stake_pool.freshness_policy = FreshnessPolicy::grace(60);
+        assert_eq!(
+            stake_pool.check_freshness(&clock, &epoch_schedule),
+            Err(StakePoolError::PoolBalanceOutOfDate.into())
+        );
+
+        stake_pool.freshness_policy = FreshnessPolicy::PERMISSIVE;
+        stake_pool.last_update_epoch = 0;
+        stake_pool.last_update_slot = 0;
+        assert_eq!(stake_pool.check_freshness(&clock, &epoch_schedule), Ok(()));
+    }
+
+    #[test]
+    fn test_target_deficits() {
+        let validator = |balance, target_weight| ValidatorStakeInfo {
+            balance,
+            target_weight,
+            ..ValidatorStakeInfo::default()
+        };
+        let mut stake_list = ValidatorStakeList {
+            version: ValidatorStakeList::VALIDATOR_STAKE_LIST_VERSION,
+            validators: vec![validator(100, 1), validator(500, 1), validator(0, 2)],
+        };
+        // 1000 lamports in total: targets are 250, 250 and 500
+        assert_eq!(stake_list.target_deficits(400), Some(vec![150, 0, 500]));
+        assert_eq!(stake_list.target_deficits(0), Some(vec![50, 0, 300]));
+
+        // Zero weight gets nothing
+        stake_list.validators[2].target_weight = 0;
+        assert_eq!(stake_list.target_deficits(400), Some(vec![400, 0, 0]));
+
+        for validator in &mut stake_list.validators {
+            validator.target_weight = 0;
+        }
+        assert_eq!(stake_list.target_deficits(400), Some(vec![0, 0, 0]));
+
+        // Rounded down, the remainder stays in the reserve
+        stake_list.validators = vec![validator(0, 1), validator(0, 1), validator(0, 1)];
+        assert_eq!(stake_list.target_deficits(100), Some(vec![33, 33, 33]));
+    }
+
+    #[test]
+    fn test_target_excesses() {
+        let validator = |balance, target_weight| ValidatorStakeInfo {
+            balance,
+            target_weight,
+            ..ValidatorStakeInfo::default()
+        };
+        let mut stake_list = ValidatorStakeList {
+            version: ValidatorStakeList::VALIDATOR_STAKE_LIST_VERSION,
+            validators: vec![validator(100, 1), validator(500, 1), validator(400, 2)],
+        };
+        // 800 lamports stay staked: targets are 200, 200 and 400
+        assert_eq!(stake_list.target_excesses(200), Some(vec![0, 300, 0]));
+        assert_eq!(stake_list.target_excesses(0), Some(vec![0, 250, 0]));
+        assert_eq!(stake_list.target_excesses(2_000), Some(vec![100, 500, 400]));
+
+        // Zero weight keeps nothing
+        stake_list.validators[2].target_weight = 0;
+        assert_eq!(stake_list.target_excesses(200), Some(vec![0, 100, 400]));
+
+        for validator in &mut stake_list.validators {
+            validator.target_weight = 0;
+        }
+        assert_eq!(stake_list.target_excesses(200), Some(vec![100, 500, 400]));
+    }
 
     #[test]
     fn test_state_packing() {
@@ -529,18 +2228,30 @@ mod test {
                     balance: 123456789,
                     last_update_epoch: 987654321,
                     stake_count: 0,
+                    target_weight: 0,
+                    activating_lamports: 1000,
+                    deactivating_lamports: 0,
+                    active_lamports: 3000,
                 },
                 ValidatorStakeInfo {
                     validator_account: Pubkey::new_from_array([2; 32]),
                     balance: 998877665544,
                     last_update_epoch: 11223445566,
                     stake_count: 0,
+                    target_weight: 0,
+                    activating_lamports: 0,
+                    deactivating_lamports: 2000,
+                    active_lamports: 0,
                 },
                 ValidatorStakeInfo {
                     validator_account: Pubkey::new_from_array([3; 32]),
                     balance: 0,
                     last_update_epoch: 999999999999999,
                     stake_count: 0,
+                    target_weight: 0,
+                    activating_lamports: 0,
+                    deactivating_lamports: 0,
+                    active_lamports: 0,
                 },
             ],
         };
@@ -549,4 +2260,573 @@ mod test {
         let stake_list_unpacked = ValidatorStakeList::deserialize(&bytes).unwrap();
         assert_eq!(stake_list_unpacked, stake_list);
     }
+
+    #[test]
+    fn test_credit_list() {
+        // Memory image of the list the pools kept before credit tickets
+        let mut data = vec![0; CreditList::HEADER_LEN + 3 * CreditListEntry::LEN + 10];
+        data[0] = 1;
+        data[1..3].copy_from_slice(&2u16.to_le_bytes());
+        for (index, fill) in [1u8, 2].iter().enumerate() {
+            let offset = CreditList::HEADER_LEN + index * CreditListEntry::LEN;
+            data[offset..offset + 64].copy_from_slice(&[*fill; 64]);
+            data[offset + 64..offset + 72].copy_from_slice(&(*fill as u64 * 100).to_le_bytes());
+        }
+        let mut credit_list = CreditList::deserialize(&data).unwrap();
+        assert_eq!(
+            credit_list.credits,
+            vec![
+                CreditListEntry {
+                    sol_target: Pubkey::new(&[1; 32]),
+                    cancel_authority: Pubkey::new(&[1; 32]),
+                    token_amount: 100,
+                },
+                CreditListEntry {
+                    sol_target: Pubkey::new(&[2; 32]),
+                    cancel_authority: Pubkey::new(&[2; 32]),
+                    token_amount: 200,
+                },
+            ]
+        );
+
+        credit_list.credits.pop();
+        credit_list.serialize(&mut data).unwrap();
+        assert_eq!(CreditList::deserialize(&data), Ok(credit_list));
+
+        // The entry count must fit the account
+        data[1..3].copy_from_slice(&4u16.to_le_bytes());
+        assert_eq!(
+            CreditList::deserialize(&data),
+            Err(ProgramError::InvalidAccountData)
+        );
+        assert_eq!(
+            CreditList::deserialize(&[0; CreditList::HEADER_LEN]),
+            Ok(CreditList::default())
+        );
+    }
+
+    #[test]
+    fn test_validator_stake_list_old_versions() {
+        let validator = ValidatorStakeInfo {
+            validator_account: Pubkey::new_from_array([1; 32]),
+            balance: 10,
+            last_update_epoch: 11,
+            stake_count: 12,
+            target_weight: 0,
+            activating_lamports: 0,
+            deactivating_lamports: 0,
+            active_lamports: 0,
+        };
+        let mut record = [0; ValidatorStakeInfo::LEN];
+        validator.pack_into_slice(&mut record);
+        // Padding after `stake_count` may hold anything
+        record[52..56].copy_from_slice(&[0xab; 4]);
+        // v1 accounts were sized for 100 records of 56 bytes
+        let record_len = ValidatorStakeList::V1_RECORD_LEN;
+        let mut data = vec![0; ValidatorStakeList::HEADER_LEN + 100 * record_len];
+        data[0] = ValidatorStakeList::VERSION_V1;
+        data[1..3].copy_from_slice(&2u16.to_le_bytes());
+        for index in 0..2 {
+            let from = ValidatorStakeList::HEADER_LEN + index * record_len;
+            data[from..from + record_len].copy_from_slice(&record[..record_len]);
+        }
+        assert_eq!(
+            ValidatorStakeList::deserialize(&data),
+            Ok(ValidatorStakeList {
+                version: ValidatorStakeList::VALIDATOR_STAKE_LIST_VERSION,
+                validators: vec![validator; 2],
+            })
+        );
+
+        // Stored back in the current version while the records fit
+        let mut stake_list = ValidatorStakeList::deserialize(&data).unwrap();
+        stake_list.validators[1].active_lamports = 5;
+        stake_list.serialize(&mut data).unwrap();
+        assert_eq!(data[0], ValidatorStakeList::VALIDATOR_STAKE_LIST_VERSION);
+        assert_eq!(
+            ValidatorStakeList::deserialize(&data),
+            Ok(stake_list.clone())
+        );
+        stake_list.validators = vec![validator; 71];
+        assert_eq!(
+            stake_list.serialize(&mut data),
+            Err(ProgramError::InvalidAccountData)
+        );
+
+        data[0] = ValidatorStakeList::VALIDATOR_STAKE_LIST_VERSION + 1;
+        assert_eq!(
+            ValidatorStakeList::deserialize(&data),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn test_full_v1_validator_stake_list() {
+        let record_len = ValidatorStakeList::V1_RECORD_LEN;
+        let mut data = vec![0; ValidatorStakeList::HEADER_LEN + 100 * record_len];
+        data[0] = ValidatorStakeList::VERSION_V1;
+        data[1..3].copy_from_slice(&100u16.to_le_bytes());
+        let validators: Vec<ValidatorStakeInfo> = (0..100u8)
+            .map(|index| ValidatorStakeInfo {
+                validator_account: Pubkey::new_from_array([index; 32]),
+                balance: index as u64 * 1_000,
+                last_update_epoch: 7,
+                stake_count: index as u32 % 3,
+                ..ValidatorStakeInfo::default()
+            })
+            .collect();
+        for (index, validator) in validators.iter().enumerate() {
+            let mut record = [0; ValidatorStakeInfo::LEN];
+            validator.pack_into_slice(&mut record);
+            let from = ValidatorStakeList::HEADER_LEN + index * record_len;
+            data[from..from + record_len].copy_from_slice(&record[..record_len]);
+        }
+        let stake_list = ValidatorStakeList::deserialize(&data).unwrap();
+        assert_eq!(stake_list.validators, validators);
+
+        // The records don't fit in place and the account is left untouched
+        let original = data.clone();
+        assert_eq!(
+            stake_list.serialize(&mut data),
+            Err(StakePoolError::ValidatorListNeedsRealloc.into())
+        );
+        assert_eq!(data, original);
+
+        // ReallocList writes them into an account sized for the current layout
+        let mut new_data = vec![0; ValidatorStakeList::space(100)];
+        stake_list.serialize(&mut new_data).unwrap();
+        assert_eq!(ValidatorStakeList::deserialize(&new_data), Ok(stake_list));
+    }
+
+    #[test]
+    fn test_list_capacity() {
+        assert_eq!(
+            ValidatorStakeList::capacity(ValidatorStakeList::LEN),
+            MAX_VALIDATORS
+        );
+        assert_eq!(
+            ValidatorStakeList::capacity(ValidatorStakeList::LEN - 1),
+            MAX_VALIDATORS - 1
+        );
+        assert_eq!(ValidatorStakeList::capacity(0), 0);
+        assert_eq!(ValidatorStakeList::capacity(usize::MAX), u16::MAX as usize);
+
+        // A small pool serializes into its own account size
+        let validator = ValidatorStakeInfo {
+            validator_account: Pubkey::new_from_array([1; 32]),
+            ..ValidatorStakeInfo::default()
+        };
+        let mut stake_list = ValidatorStakeList {
+            version: ValidatorStakeList::VALIDATOR_STAKE_LIST_VERSION,
+            validators: vec![validator; 2],
+        };
+        let mut data = vec![0; ValidatorStakeList::space(2)];
+        stake_list.serialize(&mut data).unwrap();
+        assert_eq!(
+            ValidatorStakeList::deserialize(&data),
+            Ok(stake_list.clone())
+        );
+        stake_list.validators.push(validator);
+        assert_eq!(
+            stake_list.serialize(&mut data),
+            Err(ProgramError::InvalidAccountData)
+        );
+
+        // A big pool goes over the default capacity
+        let mut data = vec![0; ValidatorStakeList::space(MAX_VALIDATORS + 1)];
+        stake_list.validators = vec![validator; MAX_VALIDATORS + 1];
+        stake_list.serialize(&mut data).unwrap();
+        assert_eq!(ValidatorStakeList::deserialize(&data), Ok(stake_list));
+    }
+
+    #[test]
+    fn test_activation_totals() {
+        let validator =
+            |active_lamports, activating_lamports, deactivating_lamports| ValidatorStakeInfo {
+                activating_lamports,
+                deactivating_lamports,
+                active_lamports,
+                ..ValidatorStakeInfo::default()
+            };
+        let mut stake_list = ValidatorStakeList {
+            version: ValidatorStakeList::VALIDATOR_STAKE_LIST_VERSION,
+            validators: vec![],
+        };
+        assert_eq!(stake_list.activation_totals(), Some((0, 0, 0)));
+        stake_list.validators = vec![validator(100, 20, 3), validator(200, 0, 5)];
+        assert_eq!(stake_list.activation_totals(), Some((300, 20, 8)));
+        stake_list.validators.push(validator(u64::MAX, 0, 0));
+        assert_eq!(stake_list.activation_totals(), None);
+    }
+
+    prop_compose! {
+        fn pubkey()(bytes in any::<[u8; 32]>()) -> Pubkey {
+            Pubkey::new(&bytes)
+        }
+    }
+
+    prop_compose! {
+        fn fee()(denominator in any::<u64>(), numerator in any::<u64>()) -> Fee {
+            Fee { denominator, numerator }
+        }
+    }
+
+    prop_compose! {
+        fn pending_fee()(fee in fee(), activation_epoch in any::<u64>()) -> PendingFee {
+            PendingFee { fee, activation_epoch }
+        }
+    }
+
+    prop_compose! {
+        fn list_update_cursor()(
+            epoch in any::<u64>(),
+            (validator_index, stake_index, stake_count) in (any::<u32>(), any::<u32>(), any::<u32>()),
+        ) -> ListUpdateCursor {
+            ListUpdateCursor { epoch, validator_index, stake_index, stake_count }
+        }
+    }
+
+    prop_compose! {
+        fn stake_pool()(
+            (owner, deposit_bump_seed, withdraw_bump_seed, validator_stake_list, credit_list, pool_mint)
+                in (pubkey(), any::<u8>(), any::<u8>(), pubkey(), pubkey(), pubkey()),
+            (owner_fee_account, credit_reserve, token_program_id, stake_total, pool_total, last_update_epoch)
+                in (pubkey(), pubkey(), pubkey(), any::<u64>(), any::<u64>(), any::<u64>()),
+            (deposit_fee, last_update_slot, grace_slots, withdrawal_fee, performance_fee)
+                in (fee(), any::<u64>(), any::<u64>(), fee(), fee()),
+            (pending_deposit_fee, pending_withdrawal_fee, pending_performance_fee, pending_owner, pending_owner_fee_account)
+                in (pending_fee(), pending_fee(), pending_fee(), pubkey(), pubkey()),
+            liquidity_target_bps in any::<u16>(),
+            (deactivating_lamports, deactivating_epoch, active_lamports, activating_lamports)
+                in (any::<u64>(), any::<u64>(), any::<u64>(), any::<u64>()),
+            list_update_cursor in list_update_cursor(),
+            (credit_queue_head, credit_queue_tail, credit_lamports_total)
+                in (any::<u64>(), any::<u64>(), any::<u64>()),
+            (credit_ordering, performance_high_water) in (credit_ordering(), any::<u64>()),
+        ) -> StakePool {
+            StakePool {
+                version: PROGRAM_VERSION,
+                owner,
+                deposit_bump_seed,
+                withdraw_bump_seed,
+                validator_stake_list,
+                credit_list,
+                pool_mint,
+                owner_fee_account,
+                credit_reserve,
+                token_program_id,
+                stake_total,
+                pool_total,
+                last_update_epoch,
+                deposit_fee,
+                last_update_slot,
+                freshness_policy: FreshnessPolicy::grace(grace_slots),
+                withdrawal_fee,
+                performance_fee,
+                pending_deposit_fee,
+                pending_withdrawal_fee,
+                pending_performance_fee,
+                pending_owner,
+                pending_owner_fee_account,
+                liquidity_target_bps,
+                deactivating_lamports,
+                deactivating_epoch,
+                active_lamports,
+                activating_lamports,
+                list_update_cursor,
+                credit_queue_head,
+                credit_queue_tail,
+                credit_lamports_total,
+                credit_ordering,
+                performance_high_water,
+            }
+        }
+    }
+
+    fn credit_ordering() -> impl Strategy<Value = CreditOrdering> {
+        prop_oneof![Just(CreditOrdering::Fifo), Just(CreditOrdering::SkipAhead)]
+    }
+
+    prop_compose! {
+        fn validator_stake_info()(
+            validator_account in pubkey(),
+            balance in any::<u64>(),
+            last_update_epoch in any::<u64>(),
+            stake_count in any::<u32>(),
+            target_weight in any::<u32>(),
+            (activating_lamports, deactivating_lamports, active_lamports)
+                in (any::<u64>(), any::<u64>(), any::<u64>()),
+        ) -> ValidatorStakeInfo {
+            ValidatorStakeInfo {
+                validator_account,
+                balance,
+                last_update_epoch,
+                stake_count,
+                target_weight,
+                activating_lamports,
+                deactivating_lamports,
+                active_lamports,
+            }
+        }
+    }
+
+    prop_compose! {
+        fn credit_record()(
+            (stake_pool, sequence) in (pubkey(), any::<u64>()),
+            (sol_target, cancel_authority) in (pubkey(), pubkey()),
+            (token_amount, created_epoch, lamports_owed) in (any::<u64>(), any::<u64>(), any::<u64>()),
+            (refund_account, payer) in (pubkey(), pubkey()),
+        ) -> CreditRecord {
+            CreditRecord {
+                version: CreditRecord::VERSION,
+                stake_pool,
+                sequence,
+                sol_target,
+                cancel_authority,
+                token_amount,
+                created_epoch,
+                lamports_owed,
+                refund_account,
+                payer,
+            }
+        }
+    }
+
+    prop_compose! {
+        fn credit_list_entry()(
+            (sol_target, cancel_authority) in (pubkey(), pubkey()),
+            token_amount in any::<u64>(),
+        ) -> CreditListEntry {
+            CreditListEntry { sol_target, cancel_authority, token_amount }
+        }
+    }
+
+    /// Buffers of any length, with the first byte biased towards known versions
+    fn account_data(max_len: usize) -> impl Strategy<Value = Vec<u8>> {
+        (
+            prop_oneof![Just(0), Just(1), Just(PROGRAM_VERSION), any::<u8>()],
+            vec(any::<u8>(), 0..max_len),
+        )
+            .prop_map(|(version, mut data)| {
+                if let Some(first) = data.first_mut() {
+                    *first = version;
+                }
+                data
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn test_stake_pool_pack_round_trip(stake_pool in stake_pool()) {
+            let mut data = vec![0; StakePool::LEN];
+            StakePool::pack(stake_pool, &mut data).unwrap();
+            prop_assert_eq!(StakePool::unpack(&data), Ok(stake_pool));
+            prop_assert_eq!(StakePool::deserialize(&data[..StakePool::PACKED_LEN]), Ok(stake_pool));
+        }
+
+        #[test]
+        fn test_validator_stake_info_pack_round_trip(info in validator_stake_info()) {
+            let mut data = [0xff; ValidatorStakeInfo::LEN];
+            ValidatorStakeInfo::pack(info, &mut data).unwrap();
+            prop_assert_eq!(ValidatorStakeInfo::unpack_unchecked(&data), Ok(info));
+            prop_assert_eq!(&data[52..56], &info.target_weight.to_le_bytes()[..]);
+        }
+
+        #[test]
+        fn test_credit_record_pack_round_trip(record in credit_record()) {
+            let mut data = [0; CreditRecord::LEN];
+            CreditRecord::pack(record, &mut data).unwrap();
+            prop_assert_eq!(CreditRecord::unpack_unchecked(&data), Ok(record));
+        }
+
+        #[test]
+        fn test_credit_list_entry_pack_round_trip(entry in credit_list_entry()) {
+            let mut data = [0; CreditListEntry::LEN];
+            CreditListEntry::pack(entry, &mut data).unwrap();
+            prop_assert_eq!(CreditListEntry::unpack_unchecked(&data), Ok(entry));
+        }
+
+        #[test]
+        fn test_credit_list_round_trip(
+            version in 1..=u8::MAX,
+            credits in vec(credit_list_entry(), 0..64),
+            spare in 0..CreditListEntry::LEN * 2,
+        ) {
+            let credit_list = CreditList { version, credits };
+            let len = CreditList::HEADER_LEN + credit_list.credits.len() * CreditListEntry::LEN;
+            let mut data = vec![0xff; len + spare];
+            credit_list.serialize(&mut data).unwrap();
+            prop_assert!(data[len..].iter().all(|byte| *byte == 0xff));
+            prop_assert_eq!(CreditList::deserialize(&data), Ok(credit_list.clone()));
+            prop_assert_eq!(
+                credit_list.serialize(&mut data[..len - 1]),
+                Err(ProgramError::InvalidAccountData)
+            );
+        }
+
+        #[test]
+        fn test_validator_stake_list_round_trip(
+            validators in vec(validator_stake_info(), 0..=MAX_VALIDATORS)
+        ) {
+            let stake_list = ValidatorStakeList {
+                version: ValidatorStakeList::VALIDATOR_STAKE_LIST_VERSION,
+                validators,
+            };
+            let mut data = vec![0; ValidatorStakeList::LEN];
+            stake_list.serialize(&mut data).unwrap();
+            prop_assert_eq!(ValidatorStakeList::deserialize(&data), Ok(stake_list));
+        }
+
+        #[test]
+        fn test_pay_credits_within_reserve_surplus(
+            credit_ordering in credit_ordering(),
+            tickets in vec((any::<u32>(), any::<bool>(), any::<bool>()), 0..16),
+            credit_queue_tail in 0u64..1_000,
+            reserve_surplus in any::<u32>(),
+        ) {
+            let is_fifo = credit_ordering == CreditOrdering::Fifo;
+            let lamports_available = u64::from(reserve_surplus);
+            let credit_lamports_total = tickets
+                .iter()
+                .map(|(lamports_owed, _, _)| u64::from(*lamports_owed))
+                .sum();
+            let mut stake_pool = StakePool {
+                credit_ordering,
+                credit_queue_head: credit_queue_tail + tickets.len() as u64,
+                credit_queue_tail,
+                credit_lamports_total,
+                ..StakePool::default()
+            };
+            let mut items: Vec<_> = tickets
+                .iter()
+                .zip(credit_queue_tail..)
+                .map(|(&(lamports_owed, is_payable, is_closed), sequence)| {
+                    if is_fifo && is_closed {
+                        CreditQueueItem::Closed(test_ticket_address(sequence))
+                    } else {
+                        CreditQueueItem::Open {
+                            ticket: CreditRecord {
+                                version: CreditRecord::VERSION,
+                                sequence,
+                                token_amount: lamports_owed.into(),
+                                lamports_owed: lamports_owed.into(),
+                                ..CreditRecord::default()
+                            },
+                            is_payable,
+                        }
+                    }
+                })
+                .collect();
+            if !is_fifo {
+                // Skip-ahead takes the tickets in any order
+                items.reverse();
+            }
+            let original = items.clone();
+
+            let payments = stake_pool
+                .pay_credits(&mut items, lamports_available, test_ticket_address)
+                .unwrap();
+            prop_assert_eq!(payments.len(), items.len());
+            let total_paid: u64 = payments.iter().map(|payment| payment.lamports).sum();
+            prop_assert!(total_paid <= lamports_available);
+            prop_assert_eq!(
+                stake_pool.credit_lamports_total,
+                credit_lamports_total - total_paid
+            );
+
+            let mut expected_tail = credit_queue_tail;
+            let mut is_stopped = false;
+            for ((item, before), payment) in items.iter().zip(&original).zip(&payments) {
+                let (ticket, owed, is_payable) = match (item, before) {
+                    (
+                        CreditQueueItem::Open { ticket, .. },
+                        CreditQueueItem::Open { ticket: before, is_payable },
+                    ) => (ticket, before.lamports_owed, *is_payable),
+                    _ => {
+                        prop_assert_eq!(payment, &CreditPayment::default());
+                        if !is_stopped {
+                            expected_tail += 1;
+                        }
+                        continue;
+                    }
+                };
+                prop_assert!(payment.lamports <= owed);
+                prop_assert_eq!(ticket.lamports_owed, owed - payment.lamports);
+                if is_stopped || !is_payable {
+                    prop_assert_eq!(payment, &CreditPayment::default());
+                    if !is_stopped && is_fifo {
+                        expected_tail += 1;
+                    }
+                } else if payment.is_full {
+                    prop_assert_eq!(payment.lamports, owed);
+                    if is_fifo {
+                        expected_tail += 1;
+                    }
+                } else if is_fifo {
+                    // The reserve is used up here: a partial payment takes the rest
+                    // and nothing after it is paid
+                    prop_assert!(payment.lamports < owed);
+                    is_stopped = true;
+                } else {
+                    // Skip-ahead never pays partially
+                    prop_assert_eq!(payment, &CreditPayment::default());
+                }
+            }
+            if is_stopped {
+                prop_assert_eq!(total_paid, lamports_available);
+            }
+            prop_assert_eq!(stake_pool.credit_queue_tail, expected_tail);
+            prop_assert!(stake_pool.credit_queue_tail <= stake_pool.credit_queue_head);
+        }
+
+        #[test]
+        fn test_stake_pool_deserialize_arbitrary(data in account_data(StakePool::LEN + 1)) {
+            if let Ok(stake_pool) = StakePool::deserialize(&data) {
+                let mut output = data.clone();
+                stake_pool.serialize(&mut output).unwrap();
+                if stake_pool.is_initialized() {
+                    prop_assert_eq!(&output, &data);
+                }
+            }
+            let _ = StakePool::deserialize_v1(&data);
+        }
+
+        #[test]
+        fn test_record_unpack_arbitrary(data in vec(any::<u8>(), 0..CreditRecord::LEN + 1)) {
+            let _ = ValidatorStakeInfo::unpack_from_slice(&data);
+            let _ = CreditRecord::unpack_from_slice(&data);
+            let _ = CreditListEntry::unpack_from_slice(&data);
+        }
+
+        #[test]
+        fn test_credit_list_deserialize_arbitrary(
+            data in account_data(CreditList::HEADER_LEN + 8 * CreditListEntry::LEN),
+            count in prop_oneof![0..=8u16, any::<u16>()],
+        ) {
+            let mut data = data;
+            if data.len() >= CreditList::HEADER_LEN {
+                data[1..3].copy_from_slice(&count.to_le_bytes());
+            }
+            if let Ok(credit_list) = CreditList::deserialize(&data) {
+                // What is read back is written back the same way
+                if credit_list.version != 0 {
+                    let mut output = data.clone();
+                    credit_list.serialize(&mut output).unwrap();
+                    prop_assert_eq!(&output, &data);
+                }
+            }
+        }
+
+        #[test]
+        fn test_validator_stake_list_deserialize_arbitrary(
+            data in account_data(ValidatorStakeList::LEN + 1),
+            count in prop_oneof![0..=MAX_VALIDATORS as u16, any::<u16>()],
+        ) {
+            let mut data = data;
+            if data.len() >= ValidatorStakeList::HEADER_LEN {
+                data[1..3].copy_from_slice(&count.to_le_bytes());
+            }
+            let _ = ValidatorStakeList::deserialize(&data);
+        }
+    }
 }
@@ -0,0 +1,1209 @@
+#![allow(dead_code)]
+
+mod native;
+
+use bincode::{deserialize, serialize};
+use solana_bpf_tenderize::{
+    id,
+    instruction::{
+        self, CreditOrdering, DelegateReserveInstruction, Fee, FreshnessPolicy, InitArgs,
+        MergeStakesInstruction, UnstakeInstruction,
+    },
+    processor::Processor,
+    stake,
+    state::{CreditRecord, StakePool, ValidatorStakeList, MAX_VALIDATORS},
+};
+use solana_program::{
+    clock::Clock, instruction::Instruction, message::Message, native_token::sol_to_lamports,
+    program_pack::Pack, pubkey::Pubkey, rent::Rent, system_instruction, sysvar,
+};
+use solana_program_test::*;
+use solana_sdk::{
+    account::Account,
+    commitment_config::CommitmentLevel,
+    signature::{Keypair, Signer},
+    transaction::Transaction,
+    transport::TransportError,
+};
+use solana_vote_program::{
+    vote_instruction,
+    vote_state::{VoteInit, VoteState},
+};
+use std::{cell::RefCell, collections::HashSet};
+use tarpc::context;
+
+/// spl-memo, which program-test loads along with spl-token
+mod spl_memo {
+    solana_program::declare_id!("Memo1UhkJRfHyvLMcVucJwxXeuD728EqVDDwQDxFMNo");
+}
+
+pub fn program_test() -> ProgramTest {
+    let mut program_test = ProgramTest::new(
+        "solana_bpf_tenderize",
+        id(),
+        Some(native::process_instruction),
+    );
+    // Stake warms up and cools down by a quarter of the cluster's effective
+    // stake per epoch. A large bootstrap delegation lets pool stake move within
+    // one epoch, as it does on mainnet
+    program_test.add_account(Pubkey::new_unique(), cluster_stake_account());
+    program_test
+}
+
+fn cluster_stake_account() -> Account {
+    let size = std::mem::size_of::<stake::StakeState>();
+    let rent_exempt_reserve = Rent::default().minimum_balance(size);
+    let lamports = sol_to_lamports(100_000.0);
+    let state = stake::StakeState::Stake(
+        stake::Meta {
+            rent_exempt_reserve,
+            ..stake::Meta::default()
+        },
+        stake::Stake {
+            delegation: stake::Delegation {
+                voter_pubkey: Pubkey::new_unique(),
+                stake: lamports,
+                activation_epoch: u64::MAX,
+                deactivation_epoch: u64::MAX,
+                warmup_cooldown_rate: 0.25,
+            },
+            credits_observed: 0,
+        },
+    );
+    let mut data = serialize(&state).unwrap();
+    data.resize(size, 0);
+    Account {
+        lamports: rent_exempt_reserve + lamports,
+        data,
+        owner: stake::id(),
+        ..Account::default()
+    }
+}
+
+thread_local! {
+    static SENT_MESSAGES: RefCell<HashSet<Vec<u8>>> = RefCell::new(HashSet::new());
+}
+
+pub async fn process(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    instructions: &[Instruction],
+    signers: &[&Keypair],
+) -> Result<(), TransportError> {
+    // After a warp program-test roots the frozen bank behind the working one,
+    // and the banks server only accepts blockhashes that root knows. `Processed`
+    // resolves to that root, so until the next warp every transaction shares a
+    // blockhash
+    let (_, recent_blockhash, _) = banks_client
+        .get_fees_with_commitment_and_context(context::current(), CommitmentLevel::Processed)
+        .await
+        .unwrap();
+    // A repeated message would be dropped as a duplicate of the first one,
+    // so it gets a memo to tell them apart
+    let mut message = Message::new(instructions, Some(&payer.pubkey()));
+    message.recent_blockhash = recent_blockhash;
+    let mut repeat = 0;
+    while !SENT_MESSAGES.with(|sent| sent.borrow_mut().insert(message.serialize())) {
+        repeat += 1;
+        let mut tagged = instructions.to_vec();
+        tagged.push(Instruction {
+            program_id: spl_memo::id(),
+            accounts: vec![],
+            data: format!("repeat {}", repeat).into_bytes(),
+        });
+        message = Message::new(&tagged, Some(&payer.pubkey()));
+        message.recent_blockhash = recent_blockhash;
+    }
+    let mut transaction = Transaction::new_unsigned(message);
+    let mut all_signers = vec![payer];
+    all_signers.extend_from_slice(signers);
+    transaction.sign(&all_signers, recent_blockhash);
+    banks_client.process_transaction(transaction).await
+}
+
+pub async fn get_account(banks_client: &mut BanksClient, pubkey: &Pubkey) -> Account {
+    banks_client
+        .get_account(*pubkey)
+        .await
+        .expect("account not found")
+        .expect("account empty")
+}
+
+pub async fn get_clock(banks_client: &mut BanksClient) -> Clock {
+    let clock_account = get_account(banks_client, &sysvar::clock::id()).await;
+    deserialize(&clock_account.data).unwrap()
+}
+
+/// Moves the bank to the first slot of the next epoch
+pub async fn warp_to_next_epoch(context: &mut ProgramTestContext) -> u64 {
+    let clock = get_clock(&mut context.banks_client).await;
+    let next_epoch = clock.epoch + 1;
+    let slot = context
+        .genesis_config()
+        .epoch_schedule
+        .get_first_slot_in_epoch(next_epoch);
+    context.warp_to_slot(slot).unwrap();
+    next_epoch
+}
+
+pub async fn transfer(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    recipient: &Pubkey,
+    amount: u64,
+) {
+    process(
+        banks_client,
+        payer,
+        &[system_instruction::transfer(
+            &payer.pubkey(),
+            recipient,
+            amount,
+        )],
+        &[],
+    )
+    .await
+    .unwrap();
+}
+
+pub async fn create_mint(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    pool_mint: &Keypair,
+    owner: &Pubkey,
+) -> Result<(), TransportError> {
+    let rent = banks_client.get_rent().await.unwrap();
+    let mint_rent = rent.minimum_balance(spl_token::state::Mint::LEN);
+
+    process(
+        banks_client,
+        payer,
+        &[
+            system_instruction::create_account(
+                &payer.pubkey(),
+                &pool_mint.pubkey(),
+                mint_rent,
+                spl_token::state::Mint::LEN as u64,
+                &spl_token::id(),
+            ),
+            spl_token::instruction::initialize_mint(
+                &spl_token::id(),
+                &pool_mint.pubkey(),
+                &owner,
+                None,
+                0,
+            )
+            .unwrap(),
+        ],
+        &[pool_mint],
+    )
+    .await
+}
+
+pub async fn create_token_account(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    account: &Keypair,
+    pool_mint: &Pubkey,
+    owner: &Pubkey,
+) -> Result<(), TransportError> {
+    let rent = banks_client.get_rent().await.unwrap();
+    let account_rent = rent.minimum_balance(spl_token::state::Account::LEN);
+
+    process(
+        banks_client,
+        payer,
+        &[
+            system_instruction::create_account(
+                &payer.pubkey(),
+                &account.pubkey(),
+                account_rent,
+                spl_token::state::Account::LEN as u64,
+                &spl_token::id(),
+            ),
+            spl_token::instruction::initialize_account(
+                &spl_token::id(),
+                &account.pubkey(),
+                pool_mint,
+                owner,
+            )
+            .unwrap(),
+        ],
+        &[account],
+    )
+    .await
+}
+
+pub async fn get_token_balance(banks_client: &mut BanksClient, token: &Pubkey) -> u64 {
+    let token_account = get_account(banks_client, token).await;
+    spl_token::state::Account::unpack_from_slice(&token_account.data)
+        .unwrap()
+        .amount
+}
+
+pub async fn get_token_supply(banks_client: &mut BanksClient, mint: &Pubkey) -> u64 {
+    let mint_account = get_account(banks_client, mint).await;
+    spl_token::state::Mint::unpack_from_slice(&mint_account.data)
+        .unwrap()
+        .supply
+}
+
+pub async fn approve(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    account: &Pubkey,
+    delegate: &Pubkey,
+    owner: &Keypair,
+    amount: u64,
+) {
+    process(
+        banks_client,
+        payer,
+        &[spl_token::instruction::approve(
+            &spl_token::id(),
+            account,
+            delegate,
+            &owner.pubkey(),
+            &[],
+            amount,
+        )
+        .unwrap()],
+        &[owner],
+    )
+    .await
+    .unwrap();
+}
+
+pub async fn create_vote(banks_client: &mut BanksClient, payer: &Keypair, vote: &Keypair) {
+    let rent = banks_client.get_rent().await.unwrap();
+    let rent_voter = rent.minimum_balance(VoteState::size_of());
+    let node = Keypair::new();
+
+    process(
+        banks_client,
+        payer,
+        &vote_instruction::create_account(
+            &payer.pubkey(),
+            &vote.pubkey(),
+            &VoteInit {
+                node_pubkey: node.pubkey(),
+                authorized_voter: vote.pubkey(),
+                authorized_withdrawer: vote.pubkey(),
+                commission: 0,
+            },
+            rent_voter,
+        ),
+        &[vote, &node],
+    )
+    .await
+    .unwrap();
+}
+
+pub async fn get_stake_state(banks_client: &mut BanksClient, stake: &Pubkey) -> stake::StakeState {
+    let stake_account = get_account(banks_client, stake).await;
+    deserialize(&stake_account.data).unwrap()
+}
+
+/// Creates a stake account with both authorities set to `authority` and delegates it
+pub async fn create_delegated_stake(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    stake_account: &Keypair,
+    authority: &Keypair,
+    vote: &Pubkey,
+    lamports: u64,
+) {
+    let mut instructions = stake::create_account(
+        &payer.pubkey(),
+        &stake_account.pubkey(),
+        &stake::Authorized {
+            staker: authority.pubkey(),
+            withdrawer: authority.pubkey(),
+        },
+        &stake::Lockup::default(),
+        lamports,
+    );
+    instructions.push(stake::delegate_stake(
+        &stake_account.pubkey(),
+        &authority.pubkey(),
+        vote,
+    ));
+    process(
+        banks_client,
+        payer,
+        &instructions,
+        &[stake_account, authority],
+    )
+    .await
+    .unwrap();
+}
+
+/// Allocates a rent exempt stake account without initializing it
+pub async fn create_uninitialized_stake(
+    banks_client: &mut BanksClient,
+    payer: &Keypair,
+    stake_account: &Keypair,
+) {
+    let rent = banks_client.get_rent().await.unwrap();
+    let size = std::mem::size_of::<stake::StakeState>();
+    process(
+        banks_client,
+        payer,
+        &[system_instruction::create_account(
+            &payer.pubkey(),
+            &stake_account.pubkey(),
+            rent.minimum_balance(size),
+            size as u64,
+            &stake::id(),
+        )],
+        &[stake_account],
+    )
+    .await
+    .unwrap();
+}
+
+pub struct StakePoolAccounts {
+    pub stake_pool: Keypair,
+    pub validator_stake_list: Keypair,
+    pub pool_mint: Keypair,
+    pub pool_fee_account: Keypair,
+    pub credit_reserve: Keypair,
+    pub owner: Keypair,
+    pub withdraw_authority: Pubkey,
+    pub deposit_authority: Pubkey,
+    pub reserve: Pubkey,
+    pub deposit_fee: Fee,
+    pub withdrawal_fee: Fee,
+    pub performance_fee: Fee,
+    pub freshness_policy: FreshnessPolicy,
+    pub validator_capacity: usize,
+}
+
+impl StakePoolAccounts {
+    pub fn new() -> Self {
+        let stake_pool = Keypair::new();
+        let (withdraw_authority, _) = Processor::find_authority_bump_seed(
+            &id(),
+            &stake_pool.pubkey(),
+            Processor::AUTHORITY_WITHDRAW,
+        );
+        let (deposit_authority, _) = Processor::find_authority_bump_seed(
+            &id(),
+            &stake_pool.pubkey(),
+            Processor::AUTHORITY_DEPOSIT,
+        );
+        let (reserve, _) = Processor::get_reserve_adderess(&id(), &stake_pool.pubkey());
+
+        Self {
+            stake_pool,
+            validator_stake_list: Keypair::new(),
+            pool_mint: Keypair::new(),
+            pool_fee_account: Keypair::new(),
+            credit_reserve: Keypair::new(),
+            owner: Keypair::new(),
+            withdraw_authority,
+            deposit_authority,
+            reserve,
+            deposit_fee: Fee {
+                numerator: 1,
+                denominator: 100,
+            },
+            withdrawal_fee: Fee::default(),
+            performance_fee: Fee::default(),
+            freshness_policy: FreshnessPolicy::STRICT,
+            validator_capacity: MAX_VALIDATORS,
+        }
+    }
+
+    pub fn calculate_deposit_fee(&self, amount: u64) -> u64 {
+        self.deposit_fee.apply(amount).unwrap()
+    }
+
+    pub fn calculate_withdrawal_fee(&self, amount: u64) -> u64 {
+        self.withdrawal_fee.apply(amount).unwrap()
+    }
+
+    pub async fn initialize(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+    ) -> Result<(), TransportError> {
+        create_mint(
+            banks_client,
+            payer,
+            &self.pool_mint,
+            &self.withdraw_authority,
+        )
+        .await?;
+        create_token_account(
+            banks_client,
+            payer,
+            &self.pool_fee_account,
+            &self.pool_mint.pubkey(),
+            &self.owner.pubkey(),
+        )
+        .await?;
+        create_token_account(
+            banks_client,
+            payer,
+            &self.credit_reserve,
+            &self.pool_mint.pubkey(),
+            &self.owner.pubkey(),
+        )
+        .await?;
+
+        let rent = banks_client.get_rent().await.unwrap();
+        let validator_stake_list_len = ValidatorStakeList::space(self.validator_capacity);
+        process(
+            banks_client,
+            payer,
+            &[
+                system_instruction::create_account(
+                    &payer.pubkey(),
+                    &self.stake_pool.pubkey(),
+                    rent.minimum_balance(StakePool::LEN),
+                    StakePool::LEN as u64,
+                    &id(),
+                ),
+                system_instruction::create_account(
+                    &payer.pubkey(),
+                    &self.validator_stake_list.pubkey(),
+                    rent.minimum_balance(validator_stake_list_len),
+                    validator_stake_list_len as u64,
+                    &id(),
+                ),
+                instruction::initialize(
+                    &id(),
+                    &self.stake_pool.pubkey(),
+                    &self.owner.pubkey(),
+                    &self.validator_stake_list.pubkey(),
+                    &self.pool_mint.pubkey(),
+                    &self.pool_fee_account.pubkey(),
+                    &self.credit_reserve.pubkey(),
+                    &spl_token::id(),
+                    InitArgs {
+                        deposit_fee: self.deposit_fee,
+                        withdrawal_fee: self.withdrawal_fee,
+                        performance_fee: self.performance_fee,
+                        freshness_policy: self.freshness_policy,
+                    },
+                )
+                .unwrap(),
+            ],
+            &[&self.stake_pool, &self.validator_stake_list, &self.owner],
+        )
+        .await
+    }
+
+    pub async fn add_validator(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        validator: &Pubkey,
+    ) -> Result<(), TransportError> {
+        process(
+            banks_client,
+            payer,
+            &[instruction::add_validator(
+                &id(),
+                &self.stake_pool.pubkey(),
+                &self.owner.pubkey(),
+                &self.validator_stake_list.pubkey(),
+                validator,
+            )
+            .unwrap()],
+            &[&self.owner],
+        )
+        .await
+    }
+
+    pub async fn deposit(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        user: &Keypair,
+        pool_tokens_to: &Pubkey,
+        amount: u64,
+    ) -> Result<(), TransportError> {
+        process(
+            banks_client,
+            payer,
+            &[instruction::deposit(
+                &id(),
+                &self.stake_pool.pubkey(),
+                &user.pubkey(),
+                pool_tokens_to,
+                &self.pool_fee_account.pubkey(),
+                &self.pool_mint.pubkey(),
+                &spl_token::id(),
+                amount,
+            )
+            .unwrap()],
+            &[user],
+        )
+        .await
+    }
+
+    pub async fn deposit_with_slippage(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        user: &Keypair,
+        pool_tokens_to: &Pubkey,
+        amount: u64,
+        min_pool_tokens_out: u64,
+    ) -> Result<(), TransportError> {
+        process(
+            banks_client,
+            payer,
+            &[instruction::deposit_with_slippage(
+                &id(),
+                &self.stake_pool.pubkey(),
+                &user.pubkey(),
+                pool_tokens_to,
+                &self.pool_fee_account.pubkey(),
+                &self.pool_mint.pubkey(),
+                &spl_token::id(),
+                amount,
+                min_pool_tokens_out,
+            )
+            .unwrap()],
+            &[user],
+        )
+        .await
+    }
+
+    pub async fn deposit_wrapped(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        user_wrapped_sol_account: &Pubkey,
+        pool_tokens_to: &Pubkey,
+        amount: u64,
+    ) -> Result<(), TransportError> {
+        process(
+            banks_client,
+            payer,
+            &[instruction::deposit_wrapped(
+                &id(),
+                &self.stake_pool.pubkey(),
+                user_wrapped_sol_account,
+                pool_tokens_to,
+                &self.pool_fee_account.pubkey(),
+                &self.pool_mint.pubkey(),
+                &spl_token::id(),
+                amount,
+            )
+            .unwrap()],
+            &[],
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn deposit_stake(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        user_stake: &Pubkey,
+        stake_authority: &Keypair,
+        validator: &Pubkey,
+        stake_index: u32,
+        pool_tokens_to: &Pubkey,
+    ) -> Result<(), TransportError> {
+        process(
+            banks_client,
+            payer,
+            &[instruction::deposit_stake(
+                &id(),
+                &self.stake_pool.pubkey(),
+                &self.validator_stake_list.pubkey(),
+                user_stake,
+                &stake_authority.pubkey(),
+                validator,
+                stake_index,
+                pool_tokens_to,
+                &self.pool_fee_account.pubkey(),
+                &self.pool_mint.pubkey(),
+                &spl_token::id(),
+            )
+            .unwrap()],
+            &[stake_authority],
+        )
+        .await
+    }
+
+    pub async fn delegate_reserve(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        delegations: &[(Pubkey, DelegateReserveInstruction)],
+    ) -> Result<(), TransportError> {
+        process(
+            banks_client,
+            payer,
+            &[instruction::delegate_reserve(
+                &id(),
+                &self.stake_pool.pubkey(),
+                &self.owner.pubkey(),
+                &self.validator_stake_list.pubkey(),
+                delegations,
+            )
+            .unwrap()],
+            &[&self.owner],
+        )
+        .await
+    }
+
+    pub async fn delegate_reserve_to_targets(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        delegations: &[(Pubkey, DelegateReserveInstruction)],
+    ) -> Result<(), TransportError> {
+        process(
+            banks_client,
+            payer,
+            &[instruction::delegate_reserve_to_targets(
+                &id(),
+                &self.stake_pool.pubkey(),
+                &self.validator_stake_list.pubkey(),
+                delegations,
+            )
+            .unwrap()],
+            &[],
+        )
+        .await
+    }
+
+    pub async fn set_validator_target_weight(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        validator: &Pubkey,
+        target_weight: u32,
+    ) -> Result<(), TransportError> {
+        process(
+            banks_client,
+            payer,
+            &[instruction::set_validator_target_weight(
+                &id(),
+                &self.stake_pool.pubkey(),
+                &self.owner.pubkey(),
+                &self.validator_stake_list.pubkey(),
+                validator,
+                target_weight,
+            )
+            .unwrap()],
+            &[&self.owner],
+        )
+        .await
+    }
+
+    pub async fn set_liquidity_target(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        liquidity_target_bps: u16,
+    ) -> Result<(), TransportError> {
+        process(
+            banks_client,
+            payer,
+            &[instruction::set_liquidity_target(
+                &id(),
+                &self.stake_pool.pubkey(),
+                &self.owner.pubkey(),
+                liquidity_target_bps,
+            )
+            .unwrap()],
+            &[&self.owner],
+        )
+        .await
+    }
+
+    pub async fn set_credit_ordering(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        credit_ordering: CreditOrdering,
+    ) -> Result<(), TransportError> {
+        process(
+            banks_client,
+            payer,
+            &[instruction::set_credit_ordering(
+                &id(),
+                &self.stake_pool.pubkey(),
+                &self.owner.pubkey(),
+                credit_ordering,
+            )
+            .unwrap()],
+            &[&self.owner],
+        )
+        .await
+    }
+
+    pub async fn merge_stakes(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        merges: &[MergeStakesInstruction],
+    ) -> Result<(), TransportError> {
+        process(
+            banks_client,
+            payer,
+            &[instruction::merge_stakes(
+                &id(),
+                &self.stake_pool.pubkey(),
+                &self.validator_stake_list.pubkey(),
+                merges,
+            )
+            .unwrap()],
+            &[],
+        )
+        .await
+    }
+
+    pub async fn unstake(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        unstakes: &[UnstakeInstruction],
+    ) -> Result<(), TransportError> {
+        process(
+            banks_client,
+            payer,
+            &[instruction::unstake(
+                &id(),
+                &self.stake_pool.pubkey(),
+                &self.owner.pubkey(),
+                &self.validator_stake_list.pubkey(),
+                unstakes,
+            )
+            .unwrap()],
+            &[&self.owner],
+        )
+        .await
+    }
+
+    pub async fn unstake_for_credits(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        unstakes: &[UnstakeInstruction],
+    ) -> Result<(), TransportError> {
+        process(
+            banks_client,
+            payer,
+            &[instruction::unstake_for_credits(
+                &id(),
+                &self.stake_pool.pubkey(),
+                &self.validator_stake_list.pubkey(),
+                unstakes,
+            )
+            .unwrap()],
+            &[],
+        )
+        .await
+    }
+
+    pub async fn update_list_balance(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+    ) -> Result<(), TransportError> {
+        let validator_stake_list = self.get_validator_stake_list(banks_client).await;
+        process(
+            banks_client,
+            payer,
+            &[instruction::update_list_balance(
+                &id(),
+                &self.stake_pool.pubkey(),
+                &self.validator_stake_list.pubkey(),
+                &validator_stake_list.validators,
+            )
+            .unwrap()],
+            &[],
+        )
+        .await
+    }
+
+    pub async fn update_list_balance_page(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        max_accounts: usize,
+    ) -> Result<(), TransportError> {
+        let stake_pool = self.get_stake_pool(banks_client).await;
+        let validator_stake_list = self.get_validator_stake_list(banks_client).await;
+        let clock = get_clock(banks_client).await;
+        let start = stake_pool
+            .list_update_cursor
+            .start(clock.epoch, validator_stake_list.validators.len());
+        process(
+            banks_client,
+            payer,
+            &[instruction::update_list_balance_page(
+                &id(),
+                &self.stake_pool.pubkey(),
+                &self.validator_stake_list.pubkey(),
+                &validator_stake_list.validators,
+                &start,
+                max_accounts,
+            )
+            .unwrap()],
+            &[],
+        )
+        .await
+    }
+
+    /// Sends pages until every validator is updated. Returns the number of pages
+    pub async fn update_list_balance_paged(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        max_accounts: usize,
+    ) -> Result<usize, TransportError> {
+        let validator_count = self
+            .get_validator_stake_list(banks_client)
+            .await
+            .validators
+            .len();
+        let epoch = get_clock(banks_client).await.epoch;
+        let mut pages = 0;
+        loop {
+            let cursor = self.get_stake_pool(banks_client).await.list_update_cursor;
+            if cursor.epoch == epoch && cursor.validator_index as usize >= validator_count {
+                return Ok(pages);
+            }
+            self.update_list_balance_page(banks_client, payer, max_accounts)
+                .await?;
+            pages += 1;
+        }
+    }
+
+    pub async fn update_pool_balance(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+    ) -> Result<(), TransportError> {
+        process(
+            banks_client,
+            payer,
+            &[instruction::update_pool_balance(
+                &id(),
+                &self.stake_pool.pubkey(),
+                &self.validator_stake_list.pubkey(),
+                &self.pool_fee_account.pubkey(),
+                &self.pool_mint.pubkey(),
+                &spl_token::id(),
+            )
+            .unwrap()],
+            &[],
+        )
+        .await
+    }
+
+    pub async fn withdraw(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        burn_from: &Pubkey,
+        sol_target: &Pubkey,
+        amount: u64,
+    ) -> Result<(), TransportError> {
+        process(
+            banks_client,
+            payer,
+            &[instruction::withdraw(
+                &id(),
+                &self.stake_pool.pubkey(),
+                burn_from,
+                &self.pool_fee_account.pubkey(),
+                &self.pool_mint.pubkey(),
+                sol_target,
+                &spl_token::id(),
+                amount,
+            )
+            .unwrap()],
+            &[],
+        )
+        .await
+    }
+
+    pub async fn withdraw_with_slippage(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        burn_from: &Pubkey,
+        sol_target: &Pubkey,
+        amount: u64,
+        min_lamports_out: u64,
+    ) -> Result<(), TransportError> {
+        process(
+            banks_client,
+            payer,
+            &[instruction::withdraw_with_slippage(
+                &id(),
+                &self.stake_pool.pubkey(),
+                burn_from,
+                &self.pool_fee_account.pubkey(),
+                &self.pool_mint.pubkey(),
+                sol_target,
+                &spl_token::id(),
+                amount,
+                min_lamports_out,
+            )
+            .unwrap()],
+            &[],
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn withdraw_stake(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        validator: &Pubkey,
+        stake_index: u32,
+        stake_to: &Pubkey,
+        user_stake_authority: &Pubkey,
+        burn_from: &Pubkey,
+        user_transfer_authority: &Keypair,
+        amount: u64,
+    ) -> Result<(), TransportError> {
+        process(
+            banks_client,
+            payer,
+            &[instruction::withdraw_stake(
+                &id(),
+                &self.stake_pool.pubkey(),
+                &self.validator_stake_list.pubkey(),
+                validator,
+                stake_index,
+                stake_to,
+                user_stake_authority,
+                burn_from,
+                &user_transfer_authority.pubkey(),
+                &self.pool_fee_account.pubkey(),
+                &self.pool_mint.pubkey(),
+                &spl_token::id(),
+                amount,
+            )
+            .unwrap()],
+            &[user_transfer_authority],
+        )
+        .await
+    }
+
+    pub async fn set_staking_authority(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        stake: &Pubkey,
+        new_authority: &Pubkey,
+    ) -> Result<(), TransportError> {
+        process(
+            banks_client,
+            payer,
+            &[instruction::set_staking_authority(
+                &id(),
+                &self.stake_pool.pubkey(),
+                &self.owner.pubkey(),
+                &self.validator_stake_list.pubkey(),
+                stake,
+                new_authority,
+            )
+            .unwrap()],
+            &[&self.owner],
+        )
+        .await
+    }
+
+    pub fn credit_ticket(&self, sequence: u64) -> Pubkey {
+        Processor::get_credit_ticket_address(&id(), &self.stake_pool.pubkey(), sequence).0
+    }
+
+    /// Opens a credit ticket at the queue head and returns its address
+    #[allow(clippy::too_many_arguments)]
+    pub async fn credit(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        burn_from: &Pubkey,
+        user_transfer_authority: &Keypair,
+        sol_target: &Pubkey,
+        cancel_authority: &Pubkey,
+        amount: u64,
+    ) -> Result<Pubkey, TransportError> {
+        let sequence = self.get_stake_pool(banks_client).await.credit_queue_head;
+        process(
+            banks_client,
+            payer,
+            &[instruction::credit(
+                &id(),
+                &self.stake_pool.pubkey(),
+                sequence,
+                burn_from,
+                &user_transfer_authority.pubkey(),
+                &self.pool_mint.pubkey(),
+                sol_target,
+                cancel_authority,
+                &payer.pubkey(),
+                &spl_token::id(),
+                amount,
+            )
+            .unwrap()],
+            &[user_transfer_authority],
+        )
+        .await?;
+        Ok(self.credit_ticket(sequence))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn uncredit(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        ticket: &Pubkey,
+        return_to: &Pubkey,
+        sol_target: &Pubkey,
+        rent_payer: &Pubkey,
+        cancel_authority: &Keypair,
+        amount: u64,
+    ) -> Result<(), TransportError> {
+        process(
+            banks_client,
+            payer,
+            &[instruction::uncredit(
+                &id(),
+                &self.stake_pool.pubkey(),
+                ticket,
+                return_to,
+                sol_target,
+                rent_payer,
+                &cancel_authority.pubkey(),
+                &self.pool_mint.pubkey(),
+                &spl_token::id(),
+                amount,
+            )
+            .unwrap()],
+            &[cancel_authority],
+        )
+        .await
+    }
+
+    pub async fn expire_credit(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        ticket: &Pubkey,
+        refund_account: &Pubkey,
+        sol_target: &Pubkey,
+        rent_payer: &Pubkey,
+    ) -> Result<(), TransportError> {
+        process(
+            banks_client,
+            payer,
+            &[instruction::expire_credit(
+                &id(),
+                &self.stake_pool.pubkey(),
+                ticket,
+                refund_account,
+                sol_target,
+                rent_payer,
+                &self.pool_mint.pubkey(),
+                &spl_token::id(),
+            )
+            .unwrap()],
+            &[],
+        )
+        .await
+    }
+
+    /// `tickets` are every credit ticket with its SOL target and rent payer
+    pub async fn pay_creditors(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        tickets: &[(Pubkey, Pubkey, Pubkey)],
+    ) -> Result<(), TransportError> {
+        process(
+            banks_client,
+            payer,
+            &[instruction::pay_creditors(
+                &id(),
+                &self.stake_pool.pubkey(),
+                &self.pool_fee_account.pubkey(),
+                &self.pool_mint.pubkey(),
+                &spl_token::id(),
+                tickets,
+            )
+            .unwrap()],
+            &[],
+        )
+        .await
+    }
+
+    /// Moves a list of the pool into a new account of `len` bytes
+    pub async fn realloc_list(
+        &self,
+        banks_client: &mut BanksClient,
+        payer: &Keypair,
+        list: &Pubkey,
+        new_list: &Keypair,
+        len: usize,
+    ) -> Result<(), TransportError> {
+        let rent = banks_client.get_rent().await.unwrap();
+        process(
+            banks_client,
+            payer,
+            &[
+                system_instruction::create_account(
+                    &payer.pubkey(),
+                    &new_list.pubkey(),
+                    rent.minimum_balance(len),
+                    len as u64,
+                    &id(),
+                ),
+                instruction::realloc_list(
+                    &id(),
+                    &self.stake_pool.pubkey(),
+                    &self.owner.pubkey(),
+                    list,
+                    &new_list.pubkey(),
+                    &payer.pubkey(),
+                )
+                .unwrap(),
+            ],
+            &[new_list, &self.owner],
+        )
+        .await
+    }
+
+    pub async fn get_stake_pool(&self, banks_client: &mut BanksClient) -> StakePool {
+        let stake_pool_account = get_account(banks_client, &self.stake_pool.pubkey()).await;
+        StakePool::deserialize(&stake_pool_account.data).unwrap()
+    }
+
+    pub async fn get_validator_stake_list(
+        &self,
+        banks_client: &mut BanksClient,
+    ) -> ValidatorStakeList {
+        let list_account = get_account(banks_client, &self.validator_stake_list.pubkey()).await;
+        ValidatorStakeList::deserialize(&list_account.data).unwrap()
+    }
+
+    pub async fn get_credit_ticket(
+        &self,
+        banks_client: &mut BanksClient,
+        ticket: &Pubkey,
+    ) -> CreditRecord {
+        let ticket_account = get_account(banks_client, ticket).await;
+        CreditRecord::unpack_from_slice(&ticket_account.data).unwrap()
+    }
+
+    pub async fn get_reserve_balance(&self, banks_client: &mut BanksClient) -> u64 {
+        banks_client.get_balance(self.reserve).await.unwrap()
+    }
+}
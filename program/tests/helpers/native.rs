@@ -0,0 +1,234 @@
+//! Native runner for the pool program.
+//!
+//! solana-program-test 1.5.8 copies CPI results back into the caller by
+//! instruction position rather than by account key, and it can't resize
+//! account data. Every spl-token `SetAuthority` and every `CreateAccount` the
+//! pool invokes trips one of the two. This runner mirrors the program-test
+//! entrypoint and `sol_invoke_signed`, matching accounts by key and letting
+//! the invoked program size a new account or change its owner, within the
+//! limits the BPF loader applies. It is only used when no BPF build of the
+//! program is found.
+
+use solana_bpf_tenderize::processor::Processor;
+use solana_program::{
+    account_info::AccountInfo,
+    entrypoint::{ProgramResult, MAX_PERMITTED_DATA_INCREASE},
+    instruction::Instruction,
+    message::Message,
+    program_error::ProgramError,
+    program_stubs,
+    pubkey::Pubkey,
+};
+use solana_program_test::to_instruction_error;
+use solana_runtime::message_processor::MessageProcessor;
+use solana_sdk::{
+    account::Account,
+    instruction::InstructionError,
+    keyed_account::KeyedAccount,
+    process_instruction::{stable_log, InvokeContext},
+};
+use std::{
+    cell::RefCell, collections::HashMap, convert::TryFrom, mem::transmute, rc::Rc, sync::Once,
+};
+
+thread_local! {
+    static INVOKE_CONTEXT: RefCell<Option<(usize, usize)>> = RefCell::new(None);
+}
+
+fn set_invoke_context(new: &mut dyn InvokeContext) {
+    INVOKE_CONTEXT.with(|invoke_context| unsafe {
+        invoke_context.replace(Some(transmute::<_, (usize, usize)>(new)))
+    });
+}
+
+fn get_invoke_context<'a>() -> &'a mut dyn InvokeContext {
+    let fat = INVOKE_CONTEXT.with(|invoke_context| match *invoke_context.borrow() {
+        Some(val) => val,
+        None => panic!("Invoke context not set!"),
+    });
+    unsafe { transmute::<(usize, usize), &mut dyn InvokeContext>(fat) }
+}
+
+/// Native entrypoint for `ProgramTest::add_program`
+pub fn process_instruction(
+    program_id: &Pubkey,
+    keyed_accounts: &[KeyedAccount],
+    input: &[u8],
+    invoke_context: &mut dyn InvokeContext,
+) -> Result<(), InstructionError> {
+    // program-test installs its own stubs while it sets up the bank, which
+    // always happens before the first instruction gets here
+    static STUBS: Once = Once::new();
+    STUBS.call_once(|| {
+        program_stubs::set_syscall_stubs(Box::new(SyscallStubs {}));
+    });
+    set_invoke_context(invoke_context);
+
+    let mut accounts: HashMap<Pubkey, Account> = keyed_accounts
+        .iter()
+        .map(|ka| (*ka.unsigned_key(), ka.account.borrow().clone()))
+        .collect();
+    let account_refs: HashMap<_, _> = accounts
+        .iter_mut()
+        .map(|(key, account)| {
+            (
+                *key,
+                (
+                    Rc::new(RefCell::new(&mut account.lamports)),
+                    Rc::new(RefCell::new(&mut account.data[..])),
+                    &account.owner,
+                ),
+            )
+        })
+        .collect();
+    let account_infos: Vec<AccountInfo> = keyed_accounts
+        .iter()
+        .map(|keyed_account| {
+            let key = keyed_account.unsigned_key();
+            let (lamports, data, owner) = &account_refs[key];
+            AccountInfo {
+                key,
+                is_signer: keyed_account.signer_key().is_some(),
+                is_writable: keyed_account.is_writable(),
+                lamports: lamports.clone(),
+                data: data.clone(),
+                owner,
+                executable: keyed_account.executable().unwrap(),
+                rent_epoch: keyed_account.rent_epoch().unwrap(),
+            }
+        })
+        .collect();
+
+    let result =
+        Processor::process(program_id, &account_infos, input).map_err(to_instruction_error);
+
+    if result.is_ok() {
+        // Owners are committed too: the program may have assigned an account
+        // through the system program
+        for (keyed_account, account_info) in keyed_accounts.iter().zip(account_infos.iter()) {
+            let mut account = keyed_account.account.borrow_mut();
+            account.lamports = account_info.lamports();
+            account.data = account_info.data.borrow().to_vec();
+            account.owner = *account_info.owner;
+        }
+    }
+
+    result
+}
+
+struct SyscallStubs {}
+
+impl program_stubs::SyscallStubs for SyscallStubs {
+    fn sol_log(&self, message: &str) {
+        let invoke_context = get_invoke_context();
+        let logger = invoke_context.get_logger();
+        let logger = logger.borrow_mut();
+        if logger.log_enabled() {
+            logger.log(&format!("Program log: {}", message));
+        }
+    }
+
+    fn sol_invoke_signed(
+        &self,
+        instruction: &Instruction,
+        account_infos: &[AccountInfo],
+        signers_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        let invoke_context = get_invoke_context();
+        let logger = invoke_context.get_logger();
+        let caller = *invoke_context.get_caller().expect("get_caller");
+
+        let message = Message::new(&[instruction.clone()], None);
+        let find_account_info = |key: &Pubkey| {
+            account_infos
+                .iter()
+                .find(|account_info| account_info.unsigned_key() == key)
+                .unwrap_or_else(|| panic!("Missing account {} for invoke", key))
+        };
+        let caller_privileges = (0..message.account_keys.len())
+            .map(|index| message.is_writable(index))
+            .collect::<Vec<bool>>();
+
+        stable_log::program_invoke(
+            &logger,
+            &instruction.program_id,
+            invoke_context.invoke_depth(),
+        );
+
+        fn to_account(account_info: &AccountInfo) -> Account {
+            Account {
+                lamports: account_info.lamports(),
+                data: account_info.try_borrow_data().unwrap().to_vec(),
+                owner: *account_info.owner,
+                executable: account_info.executable,
+                rent_epoch: account_info.rent_epoch,
+            }
+        }
+        let executables = vec![(
+            instruction.program_id,
+            RefCell::new(to_account(find_account_info(&instruction.program_id))),
+        )];
+        let accounts = message
+            .account_keys
+            .iter()
+            .map(|key| Rc::new(RefCell::new(to_account(find_account_info(key)))))
+            .collect::<Vec<_>>();
+
+        for meta in instruction.accounts.iter() {
+            if meta.is_signer && !find_account_info(&meta.pubkey).is_signer {
+                let program_signer = signers_seeds.iter().any(|seeds| {
+                    Pubkey::create_program_address(seeds, &caller).ok() == Some(meta.pubkey)
+                });
+                if !program_signer {
+                    panic!("Missing signer for {}", meta.pubkey);
+                }
+            }
+        }
+
+        invoke_context.record_instruction(instruction);
+
+        MessageProcessor::process_cross_program_instruction(
+            &message,
+            &executables,
+            &accounts,
+            &caller_privileges,
+            invoke_context,
+        )
+        .map_err(|err| ProgramError::try_from(err).unwrap_or_else(|err| panic!("{}", err)))?;
+
+        // Copy the writable accounts back by key
+        for (index, key) in message.account_keys.iter().enumerate() {
+            let account = accounts[index].borrow();
+            if !message.is_writable(index) || account.executable {
+                continue;
+            }
+            let account_info = find_account_info(key);
+            **account_info.try_borrow_mut_lamports()? = account.lamports;
+            if *account_info.owner != account.owner {
+                #[allow(clippy::transmute_ptr_to_ptr)]
+                #[allow(mutable_transmutes)]
+                let owner = unsafe { transmute::<&Pubkey, &mut Pubkey>(account_info.owner) };
+                *owner = account.owner;
+            }
+            let mut data = account_info.try_borrow_mut_data()?;
+            if data.len() == account.data.len() {
+                data.copy_from_slice(&account.data);
+            } else {
+                // Same limits as the BPF loader: only `CreateAccount` may size
+                // an account, and only an empty one
+                if !data.is_empty() || account.data.len() > MAX_PERMITTED_DATA_INCREASE {
+                    panic!(
+                        "Inner instructions do not support realloc: {} -> {}",
+                        data.len(),
+                        account.data.len()
+                    );
+                }
+                // The caller's slice can't grow, so hand it a new one
+                *data = Box::leak(account.data.clone().into_boxed_slice());
+            }
+        }
+
+        stable_log::program_success(&logger, &instruction.program_id);
+        Ok(())
+    }
+}
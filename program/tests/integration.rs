@@ -1,33 +1,4207 @@
 #![cfg(feature = "test-bpf")]
 
-use {
-    assert_matches::*,
-    solana_program::{
-        instruction::{AccountMeta, Instruction},
-        pubkey::Pubkey,
+mod helpers;
+
+use helpers::*;
+use solana_bpf_tenderize::{
+    error::StakePoolError,
+    id,
+    instruction::{
+        self, CreditOrdering, DelegateReserveInstruction, Fee, FreshnessPolicy,
+        MergeStakesInstruction, UnstakeInstruction,
+    },
+    processor::Processor,
+    stake,
+    state::{
+        CreditList, CreditListEntry, CreditRecord, StakePool, ValidatorStakeInfo,
+        ValidatorStakeList, MAX_VALIDATORS,
     },
-    solana_sdk::{signature::Signer, transaction::Transaction},
-    solana_validator::test_validator::*,
+    PROGRAM_VERSION,
+};
+use solana_program::{
+    instruction::InstructionError,
+    native_token::sol_to_lamports,
+    program_option::COption,
+    program_pack::{IsInitialized, Pack},
+    pubkey::Pubkey,
+    system_instruction,
 };
+use solana_sdk::{
+    account::Account,
+    signature::{Keypair, Signer},
+    transaction::TransactionError,
+    transport::TransportError,
+};
+
+#[tokio::test]
+async fn test_stake_pool_lifecycle() {
+    let stake_pool_accounts = StakePoolAccounts::new();
+    let validator = Keypair::new();
+
+    let (main_stake, _) = ValidatorStakeInfo::find_stake_address(
+        &id(),
+        &validator.pubkey(),
+        &stake_pool_accounts.stake_pool.pubkey(),
+        0,
+    );
+    let (additional_stake, _) = ValidatorStakeInfo::find_stake_address(
+        &id(),
+        &validator.pubkey(),
+        &stake_pool_accounts.stake_pool.pubkey(),
+        1,
+    );
+    let mut context = program_test().start_with_context().await;
+
+    // Initialize
+    stake_pool_accounts
+        .initialize(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert!(stake_pool.is_initialized());
+    assert_eq!(stake_pool.owner, stake_pool_accounts.owner.pubkey());
+    assert_eq!(stake_pool.pool_mint, stake_pool_accounts.pool_mint.pubkey());
+    assert_eq!(
+        stake_pool.credit_reserve,
+        stake_pool_accounts.credit_reserve.pubkey()
+    );
+    assert_eq!(stake_pool.stake_total, 0);
+    assert_eq!(stake_pool.pool_total, 0);
+    assert_eq!(stake_pool.credit_queue_head, 0);
+
+    // Add validator
+    create_vote(&mut context.banks_client, &context.payer, &validator).await;
+    stake_pool_accounts
+        .add_validator(
+            &mut context.banks_client,
+            &context.payer,
+            &validator.pubkey(),
+        )
+        .await
+        .unwrap();
+    let validator_stake_list = stake_pool_accounts
+        .get_validator_stake_list(&mut context.banks_client)
+        .await;
+    assert_eq!(validator_stake_list.validators.len(), 1);
+    assert_eq!(
+        validator_stake_list.validators[0].validator_account,
+        validator.pubkey()
+    );
+    assert_eq!(validator_stake_list.validators[0].stake_count, 0);
+
+    // Deposit native SOLs
+    let user = Keypair::new();
+    let user_pool_account = Keypair::new();
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &user.pubkey(),
+        sol_to_lamports(20.0),
+    )
+    .await;
+    create_token_account(
+        &mut context.banks_client,
+        &context.payer,
+        &user_pool_account,
+        &stake_pool_accounts.pool_mint.pubkey(),
+        &user.pubkey(),
+    )
+    .await
+    .unwrap();
+    let deposit_amount = sol_to_lamports(10.0);
+    stake_pool_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &user,
+            &user_pool_account.pubkey(),
+            deposit_amount,
+        )
+        .await
+        .unwrap();
+    let fee = stake_pool_accounts.calculate_deposit_fee(deposit_amount);
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(stake_pool.stake_total, deposit_amount);
+    assert_eq!(stake_pool.pool_total, deposit_amount);
+    assert_eq!(
+        get_token_balance(&mut context.banks_client, &user_pool_account.pubkey()).await,
+        deposit_amount - fee
+    );
+    assert_eq!(
+        get_token_balance(
+            &mut context.banks_client,
+            &stake_pool_accounts.pool_fee_account.pubkey()
+        )
+        .await,
+        fee
+    );
+    assert_eq!(
+        stake_pool_accounts
+            .get_reserve_balance(&mut context.banks_client)
+            .await,
+        deposit_amount
+    );
+
+    // Delegate reserve into two stake accounts
+    let main_amount = sol_to_lamports(2.0);
+    let additional_amount = sol_to_lamports(1.0);
+    stake_pool_accounts
+        .delegate_reserve(
+            &mut context.banks_client,
+            &context.payer,
+            &[
+                (
+                    validator.pubkey(),
+                    DelegateReserveInstruction {
+                        amount: main_amount,
+                        stake_index: 0,
+                    },
+                ),
+                (
+                    validator.pubkey(),
+                    DelegateReserveInstruction {
+                        amount: additional_amount,
+                        stake_index: 1,
+                    },
+                ),
+            ],
+        )
+        .await
+        .unwrap();
+    let validator_stake_list = stake_pool_accounts
+        .get_validator_stake_list(&mut context.banks_client)
+        .await;
+    assert_eq!(validator_stake_list.validators[0].stake_count, 2);
+    assert_eq!(
+        validator_stake_list.validators[0].balance,
+        main_amount + additional_amount
+    );
+    assert_eq!(
+        stake_pool_accounts
+            .get_reserve_balance(&mut context.banks_client)
+            .await,
+        deposit_amount - main_amount - additional_amount
+    );
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(stake_pool.stake_total, deposit_amount);
+    assert_eq!(stake_pool.pool_total, deposit_amount);
 
-#[test]
-fn test_validator_transaction() {
-    let program_id = Pubkey::new_unique();
+    // Merge while both stakes are in their activation epoch
+    stake_pool_accounts
+        .merge_stakes(
+            &mut context.banks_client,
+            &context.payer,
+            &[MergeStakesInstruction {
+                validator_address: validator.pubkey(),
+                main_index: 0,
+                additional_index: 1,
+            }],
+        )
+        .await
+        .unwrap();
+    let validator_stake_list = stake_pool_accounts
+        .get_validator_stake_list(&mut context.banks_client)
+        .await;
+    assert_eq!(validator_stake_list.validators[0].stake_count, 1);
+    let merged_lamports = main_amount + additional_amount;
+    assert_eq!(
+        context.banks_client.get_balance(main_stake).await.unwrap(),
+        merged_lamports
+    );
+    assert_eq!(
+        context
+            .banks_client
+            .get_balance(additional_stake)
+            .await
+            .unwrap(),
+        0
+    );
+
+    // Next epoch: refresh the list and the pool
+    let epoch = warp_to_next_epoch(&mut context).await;
+    stake_pool_accounts
+        .update_list_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    stake_pool_accounts
+        .update_pool_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    let validator_stake_list = stake_pool_accounts
+        .get_validator_stake_list(&mut context.banks_client)
+        .await;
+    assert_eq!(validator_stake_list.validators[0].balance, merged_lamports);
+    assert_eq!(validator_stake_list.validators[0].last_update_epoch, epoch);
+    let reserve_balance = stake_pool_accounts
+        .get_reserve_balance(&mut context.banks_client)
+        .await;
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(stake_pool.last_update_epoch, epoch);
+    assert_eq!(stake_pool.stake_total, reserve_balance + merged_lamports);
+    assert_eq!(stake_pool.pool_total, deposit_amount);
 
-    let (test_validator, payer) = TestValidatorGenesis::default()
-        .add_program("bpf_program_template", program_id)
-        .start();
-    let (rpc_client, recent_blockhash, _fee_calculator) = test_validator.rpc_client();
+    // Unstake the merged account completely
+    stake_pool_accounts
+        .unstake(
+            &mut context.banks_client,
+            &context.payer,
+            &[UnstakeInstruction {
+                validator_address: validator.pubkey(),
+                source_index: 0,
+                split_index: 0,
+                amount: 0,
+            }],
+        )
+        .await
+        .unwrap();
+    match get_stake_state(&mut context.banks_client, &main_stake).await {
+        stake::StakeState::Stake(_, stake) => {
+            assert_eq!(stake.delegation.deactivation_epoch, epoch);
+        }
+        _ => panic!("stake account must stay delegated"),
+    }
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(stake_pool.stake_total, reserve_balance + merged_lamports);
+    assert_eq!(stake_pool.pool_total, deposit_amount);
+
+    // Credit
+    let sol_target = Pubkey::new_unique();
+    let credit_amount = sol_to_lamports(1.0);
+    let credit_lamports = stake_pool.calc_lamports_amount(credit_amount).unwrap();
+    let ticket = stake_pool_accounts
+        .credit(
+            &mut context.banks_client,
+            &context.payer,
+            &user_pool_account.pubkey(),
+            &user,
+            &sol_target,
+            &user.pubkey(),
+            credit_amount,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        stake_pool_accounts
+            .get_credit_ticket(&mut context.banks_client, &ticket)
+            .await,
+        CreditRecord {
+            version: CreditRecord::VERSION,
+            stake_pool: stake_pool_accounts.stake_pool.pubkey(),
+            sequence: 0,
+            sol_target,
+            cancel_authority: user.pubkey(),
+            token_amount: credit_amount,
+            created_epoch: get_clock(&mut context.banks_client).await.epoch,
+            lamports_owed: credit_lamports,
+            refund_account: user_pool_account.pubkey(),
+            payer: context.payer.pubkey(),
+        }
+    );
+    let ticket_rent = context.banks_client.get_balance(ticket).await.unwrap();
+    // The tokens are burned right away
+    assert_eq!(
+        get_token_balance(&mut context.banks_client, &user_pool_account.pubkey()).await,
+        deposit_amount - fee - credit_amount
+    );
+    assert_eq!(
+        get_token_supply(
+            &mut context.banks_client,
+            &stake_pool_accounts.pool_mint.pubkey()
+        )
+        .await,
+        deposit_amount - credit_amount
+    );
+    let credited_stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(
+        credited_stake_pool.pool_total,
+        deposit_amount - credit_amount
+    );
+    assert_eq!(
+        credited_stake_pool.stake_total,
+        stake_pool.stake_total - credit_lamports
+    );
+    assert_eq!(credited_stake_pool.credit_queue_head, 1);
+    assert_eq!(credited_stake_pool.credit_lamports_total, credit_lamports);
 
-    let mut transaction = Transaction::new_with_payer(
-        &[Instruction {
-            program_id,
-            accounts: vec![AccountMeta::new(payer.pubkey(), false)],
-            data: vec![1, 2, 3],
-        }],
-        Some(&payer.pubkey()),
+    // Next epoch: refresh and pay creditors from the reserve
+    let epoch = warp_to_next_epoch(&mut context).await;
+    stake_pool_accounts
+        .update_list_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    stake_pool_accounts
+        .update_pool_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    // The unstaked account cooled down and went back to the reserve
+    let validator_stake_list = stake_pool_accounts
+        .get_validator_stake_list(&mut context.banks_client)
+        .await;
+    assert_eq!(validator_stake_list.validators[0].balance, 0);
+    assert_eq!(validator_stake_list.validators[0].stake_count, 0);
+    assert_eq!(validator_stake_list.validators[0].deactivating_lamports, 0);
+    assert_eq!(
+        context.banks_client.get_balance(main_stake).await.unwrap(),
+        0
+    );
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(stake_pool.last_update_epoch, epoch);
+    // The owed lamports are still in the reserve but out of stake_total
+    assert_eq!(
+        stake_pool.stake_total,
+        stake_pool_accounts
+            .get_reserve_balance(&mut context.banks_client)
+            .await
+            - credit_lamports
     );
-    transaction.sign(&[&payer], recent_blockhash);
 
-    assert_matches!(rpc_client.send_and_confirm_transaction(&transaction), Ok(_));
+    // Anybody may pay the creditors. The closed ticket returns its rent to its payer
+    let cranker = Keypair::new();
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &cranker.pubkey(),
+        sol_to_lamports(1.0),
+    )
+    .await;
+    let payer_lamports = context
+        .banks_client
+        .get_balance(context.payer.pubkey())
+        .await
+        .unwrap();
+    stake_pool_accounts
+        .pay_creditors(
+            &mut context.banks_client,
+            &cranker,
+            &[(ticket, sol_target, context.payer.pubkey())],
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        context.banks_client.get_balance(sol_target).await.unwrap(),
+        credit_lamports
+    );
+    assert_eq!(
+        context
+            .banks_client
+            .get_balance(context.payer.pubkey())
+            .await
+            .unwrap(),
+        payer_lamports + ticket_rent
+    );
+    assert!(context
+        .banks_client
+        .get_account(ticket)
+        .await
+        .unwrap()
+        .is_none());
+    let paid_stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(paid_stake_pool.stake_total, stake_pool.stake_total);
+    assert_eq!(paid_stake_pool.pool_total, deposit_amount - credit_amount);
+    assert_eq!(paid_stake_pool.credit_lamports_total, 0);
+    assert_eq!(
+        get_token_supply(
+            &mut context.banks_client,
+            &stake_pool_accounts.pool_mint.pubkey()
+        )
+        .await,
+        paid_stake_pool.pool_total
+    );
+}
+
+#[tokio::test]
+async fn test_deposit_wrapped_sol() {
+    let stake_pool_accounts = StakePoolAccounts::new();
+    let mut context = program_test().start_with_context().await;
+    stake_pool_accounts
+        .initialize(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+
+    let user = Keypair::new();
+    let user_pool_account = Keypair::new();
+    create_token_account(
+        &mut context.banks_client,
+        &context.payer,
+        &user_pool_account,
+        &stake_pool_accounts.pool_mint.pubkey(),
+        &user.pubkey(),
+    )
+    .await
+    .unwrap();
+
+    // The reserve pays for the temporary account, so it must exist first
+    let native_amount = sol_to_lamports(1.0);
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &user.pubkey(),
+        native_amount,
+    )
+    .await;
+    stake_pool_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &user,
+            &user_pool_account.pubkey(),
+            native_amount,
+        )
+        .await
+        .unwrap();
+
+    let wrapped_amount = sol_to_lamports(3.0);
+    let user_wrapped_account = Keypair::new();
+    let rent = context.banks_client.get_rent().await.unwrap();
+    process(
+        &mut context.banks_client,
+        &context.payer,
+        &[
+            system_instruction::create_account(
+                &context.payer.pubkey(),
+                &user_wrapped_account.pubkey(),
+                rent.minimum_balance(spl_token::state::Account::LEN) + wrapped_amount,
+                spl_token::state::Account::LEN as u64,
+                &spl_token::id(),
+            ),
+            spl_token::instruction::initialize_account(
+                &spl_token::id(),
+                &user_wrapped_account.pubkey(),
+                &spl_token::native_mint::id(),
+                &user.pubkey(),
+            )
+            .unwrap(),
+        ],
+        &[&user_wrapped_account],
+    )
+    .await
+    .unwrap();
+    approve(
+        &mut context.banks_client,
+        &context.payer,
+        &user_wrapped_account.pubkey(),
+        &stake_pool_accounts.withdraw_authority,
+        &user,
+        wrapped_amount,
+    )
+    .await;
+
+    stake_pool_accounts
+        .deposit_wrapped(
+            &mut context.banks_client,
+            &context.payer,
+            &user_wrapped_account.pubkey(),
+            &user_pool_account.pubkey(),
+            wrapped_amount,
+        )
+        .await
+        .unwrap();
+
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(stake_pool.stake_total, native_amount + wrapped_amount);
+    assert_eq!(stake_pool.pool_total, native_amount + wrapped_amount);
+    assert_eq!(
+        stake_pool_accounts
+            .get_reserve_balance(&mut context.banks_client)
+            .await,
+        native_amount + wrapped_amount
+    );
+    assert_eq!(
+        get_token_balance(&mut context.banks_client, &user_wrapped_account.pubkey()).await,
+        0
+    );
+    assert_eq!(
+        get_token_balance(&mut context.banks_client, &user_pool_account.pubkey()).await,
+        native_amount + wrapped_amount
+            - stake_pool_accounts.calculate_deposit_fee(native_amount)
+            - stake_pool_accounts.calculate_deposit_fee(wrapped_amount)
+    );
+}
+
+#[tokio::test]
+async fn test_deposit_requires_fresh_pool_balance() {
+    let stake_pool_accounts = StakePoolAccounts::new();
+    let mut context = program_test().start_with_context().await;
+    stake_pool_accounts
+        .initialize(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+
+    let user = Keypair::new();
+    let user_pool_account = Keypair::new();
+    create_token_account(
+        &mut context.banks_client,
+        &context.payer,
+        &user_pool_account,
+        &stake_pool_accounts.pool_mint.pubkey(),
+        &user.pubkey(),
+    )
+    .await
+    .unwrap();
+    let deposit_amount = sol_to_lamports(1.0);
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &user.pubkey(),
+        deposit_amount * 3,
+    )
+    .await;
+    stake_pool_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &user,
+            &user_pool_account.pubkey(),
+            deposit_amount,
+        )
+        .await
+        .unwrap();
+
+    // Strict policy rejects deposits once the epoch changes
+    warp_to_next_epoch(&mut context).await;
+    let error = stake_pool_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &user,
+            &user_pool_account.pubkey(),
+            deposit_amount / 4,
+        )
+        .await
+        .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(error_index),
+        )) => assert_eq!(error_index, StakePoolError::PoolBalanceOutOfDate as u32),
+        _ => panic!("Wrong error occurs while depositing into an outdated pool"),
+    }
+
+    // Permissive policy lets the same deposit through
+    process(
+        &mut context.banks_client,
+        &context.payer,
+        &[instruction::set_freshness_policy(
+            &id(),
+            &stake_pool_accounts.stake_pool.pubkey(),
+            &stake_pool_accounts.owner.pubkey(),
+            FreshnessPolicy::PERMISSIVE,
+        )
+        .unwrap()],
+        &[&stake_pool_accounts.owner],
+    )
+    .await
+    .unwrap();
+    stake_pool_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &user,
+            &user_pool_account.pubkey(),
+            deposit_amount,
+        )
+        .await
+        .unwrap();
+
+    // Back to strict: the update steps make the pool fresh again
+    process(
+        &mut context.banks_client,
+        &context.payer,
+        &[instruction::set_freshness_policy(
+            &id(),
+            &stake_pool_accounts.stake_pool.pubkey(),
+            &stake_pool_accounts.owner.pubkey(),
+            FreshnessPolicy::STRICT,
+        )
+        .unwrap()],
+        &[&stake_pool_accounts.owner],
+    )
+    .await
+    .unwrap();
+    stake_pool_accounts
+        .update_list_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    stake_pool_accounts
+        .update_pool_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    stake_pool_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &user,
+            &user_pool_account.pubkey(),
+            deposit_amount / 2,
+        )
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_withdrawal_fee() {
+    let mut stake_pool_accounts = StakePoolAccounts::new();
+    stake_pool_accounts.withdrawal_fee = Fee {
+        numerator: 2,
+        denominator: 100,
+    };
+    let mut context = program_test().start_with_context().await;
+    stake_pool_accounts
+        .initialize(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+
+    let user = Keypair::new();
+    let user_pool_account = Keypair::new();
+    create_token_account(
+        &mut context.banks_client,
+        &context.payer,
+        &user_pool_account,
+        &stake_pool_accounts.pool_mint.pubkey(),
+        &user.pubkey(),
+    )
+    .await
+    .unwrap();
+    let deposit_amount = sol_to_lamports(10.0);
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &user.pubkey(),
+        deposit_amount,
+    )
+    .await;
+    stake_pool_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &user,
+            &user_pool_account.pubkey(),
+            deposit_amount,
+        )
+        .await
+        .unwrap();
+    let fee_before = get_token_balance(
+        &mut context.banks_client,
+        &stake_pool_accounts.pool_fee_account.pubkey(),
+    )
+    .await;
+
+    let withdraw_amount = sol_to_lamports(1.0);
+    let sol_target = Pubkey::new_unique();
+    approve(
+        &mut context.banks_client,
+        &context.payer,
+        &user_pool_account.pubkey(),
+        &stake_pool_accounts.withdraw_authority,
+        &user,
+        withdraw_amount,
+    )
+    .await;
+    stake_pool_accounts
+        .withdraw(
+            &mut context.banks_client,
+            &context.payer,
+            &user_pool_account.pubkey(),
+            &sol_target,
+            withdraw_amount,
+        )
+        .await
+        .unwrap();
+
+    let fee = stake_pool_accounts.calculate_withdrawal_fee(withdraw_amount);
+    assert_eq!(
+        get_token_balance(
+            &mut context.banks_client,
+            &stake_pool_accounts.pool_fee_account.pubkey(),
+        )
+        .await,
+        fee_before + fee
+    );
+    assert_eq!(
+        context.banks_client.get_balance(sol_target).await.unwrap(),
+        withdraw_amount - fee
+    );
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(
+        stake_pool.pool_total,
+        deposit_amount - withdraw_amount + fee
+    );
+
+    // A credit ticket burns all of its tokens and pays the fee when it is paid.
+    // The pool ratio is still 1:1
+    let credit_amount = sol_to_lamports(1.0);
+    let credit_target = Pubkey::new_unique();
+    let ticket = stake_pool_accounts
+        .credit(
+            &mut context.banks_client,
+            &context.payer,
+            &user_pool_account.pubkey(),
+            &user,
+            &credit_target,
+            &user.pubkey(),
+            credit_amount,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        get_token_balance(
+            &mut context.banks_client,
+            &stake_pool_accounts.pool_fee_account.pubkey(),
+        )
+        .await,
+        fee_before + fee
+    );
+    stake_pool_accounts
+        .pay_creditors(
+            &mut context.banks_client,
+            &context.payer,
+            &[(ticket, credit_target, context.payer.pubkey())],
+        )
+        .await
+        .unwrap();
+    let credit_fee = stake_pool_accounts.calculate_withdrawal_fee(credit_amount);
+    assert_eq!(
+        context
+            .banks_client
+            .get_balance(credit_target)
+            .await
+            .unwrap(),
+        credit_amount - credit_fee
+    );
+    assert_eq!(
+        get_token_balance(
+            &mut context.banks_client,
+            &stake_pool_accounts.pool_fee_account.pubkey(),
+        )
+        .await,
+        fee_before + fee + credit_fee
+    );
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(stake_pool.credit_lamports_total, 0);
+    assert_eq!(
+        stake_pool.pool_total,
+        deposit_amount - withdraw_amount + fee - credit_amount + credit_fee
+    );
+    assert_eq!(stake_pool.stake_total, stake_pool.pool_total);
+    assert_eq!(
+        get_token_supply(
+            &mut context.banks_client,
+            &stake_pool_accounts.pool_mint.pubkey()
+        )
+        .await,
+        stake_pool.pool_total
+    );
+
+    // Owner can lower the fee but not raise it above the cap
+    process(
+        &mut context.banks_client,
+        &context.payer,
+        &[instruction::set_withdrawal_fee(
+            &id(),
+            &stake_pool_accounts.stake_pool.pubkey(),
+            &stake_pool_accounts.owner.pubkey(),
+            Fee::default(),
+        )
+        .unwrap()],
+        &[&stake_pool_accounts.owner],
+    )
+    .await
+    .unwrap();
+    let error = process(
+        &mut context.banks_client,
+        &context.payer,
+        &[instruction::set_withdrawal_fee(
+            &id(),
+            &stake_pool_accounts.stake_pool.pubkey(),
+            &stake_pool_accounts.owner.pubkey(),
+            Fee {
+                numerator: 1,
+                denominator: 2,
+            },
+        )
+        .unwrap()],
+        &[&stake_pool_accounts.owner],
+    )
+    .await
+    .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(error_index),
+        )) => assert_eq!(error_index, StakePoolError::FeeTooHigh as u32),
+        _ => panic!("Wrong error occurs while setting a fee above the cap"),
+    }
+    assert_eq!(
+        stake_pool_accounts
+            .get_stake_pool(&mut context.banks_client)
+            .await
+            .withdrawal_fee,
+        Fee::default()
+    );
+
+    // Increase is applied by the pool update a full epoch later
+    let increased_fee = Fee {
+        numerator: 1,
+        denominator: 100,
+    };
+    process(
+        &mut context.banks_client,
+        &context.payer,
+        &[instruction::set_withdrawal_fee(
+            &id(),
+            &stake_pool_accounts.stake_pool.pubkey(),
+            &stake_pool_accounts.owner.pubkey(),
+            increased_fee,
+        )
+        .unwrap()],
+        &[&stake_pool_accounts.owner],
+    )
+    .await
+    .unwrap();
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(stake_pool.withdrawal_fee, Fee::default());
+    assert_eq!(stake_pool.pending_withdrawal_fee.fee, increased_fee);
+
+    for _ in 0..StakePool::FEE_INCREASE_DELAY_EPOCHS {
+        assert_eq!(
+            stake_pool_accounts
+                .get_stake_pool(&mut context.banks_client)
+                .await
+                .withdrawal_fee,
+            Fee::default()
+        );
+        warp_to_next_epoch(&mut context).await;
+        stake_pool_accounts
+            .update_list_balance(&mut context.banks_client, &context.payer)
+            .await
+            .unwrap();
+        stake_pool_accounts
+            .update_pool_balance(&mut context.banks_client, &context.payer)
+            .await
+            .unwrap();
+    }
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(stake_pool.withdrawal_fee, increased_fee);
+    assert!(!stake_pool.pending_withdrawal_fee.is_pending());
+}
+
+#[tokio::test]
+async fn test_slippage_bounds() {
+    let stake_pool_accounts = StakePoolAccounts::new();
+    let mut context = program_test().start_with_context().await;
+    stake_pool_accounts
+        .initialize(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+
+    let user = Keypair::new();
+    let user_pool_account = Keypair::new();
+    create_token_account(
+        &mut context.banks_client,
+        &context.payer,
+        &user_pool_account,
+        &stake_pool_accounts.pool_mint.pubkey(),
+        &user.pubkey(),
+    )
+    .await
+    .unwrap();
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &user.pubkey(),
+        sol_to_lamports(20.0),
+    )
+    .await;
+    stake_pool_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &user,
+            &user_pool_account.pubkey(),
+            sol_to_lamports(10.0),
+        )
+        .await
+        .unwrap();
+
+    let expect_slippage = |error: TransportError| match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(error_index),
+        )) => assert_eq!(error_index, StakePoolError::SlippageExceeded as u32),
+        _ => panic!("Wrong error occurs while breaking the minimum: {:?}", error),
+    };
+
+    let deposit_amount = sol_to_lamports(1.0);
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    let pool_amount = stake_pool.calc_pool_deposit_amount(deposit_amount).unwrap();
+    let user_amount = pool_amount - stake_pool_accounts.calculate_deposit_fee(pool_amount);
+    let error = stake_pool_accounts
+        .deposit_with_slippage(
+            &mut context.banks_client,
+            &context.payer,
+            &user,
+            &user_pool_account.pubkey(),
+            deposit_amount,
+            user_amount + 1,
+        )
+        .await
+        .unwrap_err();
+    expect_slippage(error);
+
+    let tokens_before =
+        get_token_balance(&mut context.banks_client, &user_pool_account.pubkey()).await;
+    stake_pool_accounts
+        .deposit_with_slippage(
+            &mut context.banks_client,
+            &context.payer,
+            &user,
+            &user_pool_account.pubkey(),
+            deposit_amount,
+            user_amount,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        get_token_balance(&mut context.banks_client, &user_pool_account.pubkey()).await,
+        tokens_before + user_amount
+    );
+
+    let withdraw_amount = sol_to_lamports(2.0);
+    let sol_target = Pubkey::new_unique();
+    approve(
+        &mut context.banks_client,
+        &context.payer,
+        &user_pool_account.pubkey(),
+        &stake_pool_accounts.withdraw_authority,
+        &user,
+        withdraw_amount,
+    )
+    .await;
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    let burn_amount =
+        withdraw_amount - stake_pool_accounts.calculate_withdrawal_fee(withdraw_amount);
+    let lamports = stake_pool.calc_lamports_amount(burn_amount).unwrap();
+    let error = stake_pool_accounts
+        .withdraw_with_slippage(
+            &mut context.banks_client,
+            &context.payer,
+            &user_pool_account.pubkey(),
+            &sol_target,
+            withdraw_amount,
+            lamports + 1,
+        )
+        .await
+        .unwrap_err();
+    expect_slippage(error);
+
+    stake_pool_accounts
+        .withdraw_with_slippage(
+            &mut context.banks_client,
+            &context.payer,
+            &user_pool_account.pubkey(),
+            &sol_target,
+            withdraw_amount,
+            lamports,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        get_account(&mut context.banks_client, &sol_target)
+            .await
+            .lamports,
+        lamports
+    );
+}
+
+#[tokio::test]
+async fn test_owner_transfer() {
+    let stake_pool_accounts = StakePoolAccounts::new();
+    let mut context = program_test().start_with_context().await;
+    stake_pool_accounts
+        .initialize(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+
+    let new_owner = Keypair::new();
+    let new_owner_fee_account = Keypair::new();
+    create_token_account(
+        &mut context.banks_client,
+        &context.payer,
+        &new_owner_fee_account,
+        &stake_pool_accounts.pool_mint.pubkey(),
+        &new_owner.pubkey(),
+    )
+    .await
+    .unwrap();
+
+    // Fee account must be a token account
+    let error = process(
+        &mut context.banks_client,
+        &context.payer,
+        &[instruction::propose_owner(
+            &id(),
+            &stake_pool_accounts.stake_pool.pubkey(),
+            &stake_pool_accounts.owner.pubkey(),
+            &new_owner.pubkey(),
+            &new_owner.pubkey(),
+        )
+        .unwrap()],
+        &[&stake_pool_accounts.owner],
+    )
+    .await
+    .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(error_index),
+        )) => assert_eq!(error_index, StakePoolError::InvalidFeeAccount as u32),
+        _ => panic!("Wrong error occurs while proposing a non-token fee account"),
+    }
+
+    process(
+        &mut context.banks_client,
+        &context.payer,
+        &[instruction::propose_owner(
+            &id(),
+            &stake_pool_accounts.stake_pool.pubkey(),
+            &stake_pool_accounts.owner.pubkey(),
+            &new_owner.pubkey(),
+            &new_owner_fee_account.pubkey(),
+        )
+        .unwrap()],
+        &[&stake_pool_accounts.owner],
+    )
+    .await
+    .unwrap();
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(stake_pool.owner, stake_pool_accounts.owner.pubkey());
+    assert_eq!(stake_pool.pending_owner, new_owner.pubkey());
+
+    // Only the proposed owner can accept
+    let stranger = Keypair::new();
+    let error = process(
+        &mut context.banks_client,
+        &context.payer,
+        &[instruction::accept_owner(
+            &id(),
+            &stake_pool_accounts.stake_pool.pubkey(),
+            &stranger.pubkey(),
+        )
+        .unwrap()],
+        &[&stranger],
+    )
+    .await
+    .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(error_index),
+        )) => assert_eq!(error_index, StakePoolError::WrongOwner as u32),
+        _ => panic!("Wrong error occurs while accepting by a stranger"),
+    }
+
+    process(
+        &mut context.banks_client,
+        &context.payer,
+        &[instruction::accept_owner(
+            &id(),
+            &stake_pool_accounts.stake_pool.pubkey(),
+            &new_owner.pubkey(),
+        )
+        .unwrap()],
+        &[&new_owner],
+    )
+    .await
+    .unwrap();
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(stake_pool.owner, new_owner.pubkey());
+    assert_eq!(stake_pool.owner_fee_account, new_owner_fee_account.pubkey());
+    assert_eq!(stake_pool.pending_owner, Pubkey::default());
+
+    // Cancelled proposal can't be accepted
+    process(
+        &mut context.banks_client,
+        &context.payer,
+        &[
+            instruction::propose_owner(
+                &id(),
+                &stake_pool_accounts.stake_pool.pubkey(),
+                &new_owner.pubkey(),
+                &stranger.pubkey(),
+                &new_owner_fee_account.pubkey(),
+            )
+            .unwrap(),
+            instruction::cancel_owner_proposal(
+                &id(),
+                &stake_pool_accounts.stake_pool.pubkey(),
+                &new_owner.pubkey(),
+            )
+            .unwrap(),
+        ],
+        &[&new_owner],
+    )
+    .await
+    .unwrap();
+    assert!(process(
+        &mut context.banks_client,
+        &context.payer,
+        &[instruction::accept_owner(
+            &id(),
+            &stake_pool_accounts.stake_pool.pubkey(),
+            &stranger.pubkey(),
+        )
+        .unwrap()],
+        &[&stranger],
+    )
+    .await
+    .is_err());
+    assert_eq!(
+        stake_pool_accounts
+            .get_stake_pool(&mut context.banks_client)
+            .await
+            .owner,
+        new_owner.pubkey()
+    );
+}
+
+#[tokio::test]
+async fn test_delegate_reserve_to_targets() {
+    let stake_pool_accounts = StakePoolAccounts::new();
+    let mut context = program_test().start_with_context().await;
+    stake_pool_accounts
+        .initialize(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+
+    let validator_a = Keypair::new();
+    let validator_b = Keypair::new();
+    for (validator, target_weight) in &[(&validator_a, 1), (&validator_b, 3)] {
+        create_vote(&mut context.banks_client, &context.payer, validator).await;
+        stake_pool_accounts
+            .add_validator(
+                &mut context.banks_client,
+                &context.payer,
+                &validator.pubkey(),
+            )
+            .await
+            .unwrap();
+        stake_pool_accounts
+            .set_validator_target_weight(
+                &mut context.banks_client,
+                &context.payer,
+                &validator.pubkey(),
+                *target_weight,
+            )
+            .await
+            .unwrap();
+    }
+    let validator_stake_list = stake_pool_accounts
+        .get_validator_stake_list(&mut context.banks_client)
+        .await;
+    assert_eq!(validator_stake_list.validators[0].target_weight, 1);
+    assert_eq!(validator_stake_list.validators[1].target_weight, 3);
+
+    let user = Keypair::new();
+    let user_pool_account = Keypair::new();
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &user.pubkey(),
+        sol_to_lamports(20.0),
+    )
+    .await;
+    create_token_account(
+        &mut context.banks_client,
+        &context.payer,
+        &user_pool_account,
+        &stake_pool_accounts.pool_mint.pubkey(),
+        &user.pubkey(),
+    )
+    .await
+    .unwrap();
+    stake_pool_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &user,
+            &user_pool_account.pubkey(),
+            sol_to_lamports(10.0),
+        )
+        .await
+        .unwrap();
+
+    // A quarter of the reserve belongs to validator A
+    let delegate = |amount| {
+        [(
+            validator_a.pubkey(),
+            DelegateReserveInstruction {
+                amount,
+                stake_index: 0,
+            },
+        )]
+    };
+    let error = stake_pool_accounts
+        .delegate_reserve_to_targets(
+            &mut context.banks_client,
+            &context.payer,
+            &delegate(sol_to_lamports(3.0)),
+        )
+        .await
+        .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(error_index),
+        )) => assert_eq!(error_index, StakePoolError::DelegationAboveTarget as u32),
+        _ => panic!("Wrong error occurs while delegating above the target"),
+    }
+
+    stake_pool_accounts
+        .delegate_reserve_to_targets(
+            &mut context.banks_client,
+            &context.payer,
+            &delegate(sol_to_lamports(2.0)),
+        )
+        .await
+        .unwrap();
+    let validator_stake_list = stake_pool_accounts
+        .get_validator_stake_list(&mut context.banks_client)
+        .await;
+    assert_eq!(
+        validator_stake_list.validators[0].balance,
+        sol_to_lamports(2.0)
+    );
+
+    // Validator A reached its target
+    assert!(stake_pool_accounts
+        .delegate_reserve_to_targets(
+            &mut context.banks_client,
+            &context.payer,
+            &[(
+                validator_a.pubkey(),
+                DelegateReserveInstruction {
+                    amount: sol_to_lamports(1.0),
+                    stake_index: 1,
+                },
+            )],
+        )
+        .await
+        .is_err());
+
+    // Empty delegations and redelegations of live stake need the owner
+    let delegate_b = |amount, stake_index| {
+        [(
+            validator_b.pubkey(),
+            DelegateReserveInstruction {
+                amount,
+                stake_index,
+            },
+        )]
+    };
+    let error = stake_pool_accounts
+        .delegate_reserve_to_targets(&mut context.banks_client, &context.payer, &delegate_b(0, 0))
+        .await
+        .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::InvalidArgument,
+        )) => {}
+        _ => panic!("Wrong error occurs while delegating nothing"),
+    }
+    stake_pool_accounts
+        .delegate_reserve_to_targets(
+            &mut context.banks_client,
+            &context.payer,
+            &delegate_b(sol_to_lamports(1.0), 0),
+        )
+        .await
+        .unwrap();
+    let error = stake_pool_accounts
+        .delegate_reserve_to_targets(
+            &mut context.banks_client,
+            &context.payer,
+            &delegate_b(sol_to_lamports(1.0), 0),
+        )
+        .await
+        .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(error_index),
+        )) => assert_eq!(error_index, StakePoolError::WrongStakeState as u32),
+        _ => panic!("Wrong error occurs while redelegating an activating stake"),
+    }
+
+    // The owner is not limited by the targets
+    stake_pool_accounts
+        .delegate_reserve(
+            &mut context.banks_client,
+            &context.payer,
+            &[(
+                validator_a.pubkey(),
+                DelegateReserveInstruction {
+                    amount: sol_to_lamports(1.0),
+                    stake_index: 1,
+                },
+            )],
+        )
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_delegate_reserve_keeps_credit_lamports() {
+    let stake_pool_accounts = StakePoolAccounts::new();
+    let mut context = program_test().start_with_context().await;
+    stake_pool_accounts
+        .initialize(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+
+    let validator = Keypair::new();
+    create_vote(&mut context.banks_client, &context.payer, &validator).await;
+    stake_pool_accounts
+        .add_validator(
+            &mut context.banks_client,
+            &context.payer,
+            &validator.pubkey(),
+        )
+        .await
+        .unwrap();
+
+    let user = Keypair::new();
+    let user_pool_account = Keypair::new();
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &user.pubkey(),
+        sol_to_lamports(5.0),
+    )
+    .await;
+    create_token_account(
+        &mut context.banks_client,
+        &context.payer,
+        &user_pool_account,
+        &stake_pool_accounts.pool_mint.pubkey(),
+        &user.pubkey(),
+    )
+    .await
+    .unwrap();
+    stake_pool_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &user,
+            &user_pool_account.pubkey(),
+            sol_to_lamports(4.0),
+        )
+        .await
+        .unwrap();
+
+    // Every deposited lamport is owed to the credit ticket
+    let user_balance =
+        get_token_balance(&mut context.banks_client, &user_pool_account.pubkey()).await;
+    let sol_target = Pubkey::new_unique();
+    let ticket = stake_pool_accounts
+        .credit(
+            &mut context.banks_client,
+            &context.payer,
+            &user_pool_account.pubkey(),
+            &user,
+            &sol_target,
+            &user.pubkey(),
+            user_balance,
+        )
+        .await
+        .unwrap();
+
+    let delegate = [(
+        validator.pubkey(),
+        DelegateReserveInstruction {
+            amount: sol_to_lamports(1.0),
+            stake_index: 0,
+        },
+    )];
+    let error = stake_pool_accounts
+        .delegate_reserve(&mut context.banks_client, &context.payer, &delegate)
+        .await
+        .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::InsufficientFunds,
+        )) => {}
+        _ => panic!("Wrong error occurs while delegating credit lamports"),
+    }
+
+    // Lamports returned to the pool can be delegated again
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    stake_pool_accounts
+        .uncredit(
+            &mut context.banks_client,
+            &context.payer,
+            &ticket,
+            &user_pool_account.pubkey(),
+            &sol_target,
+            &context.payer.pubkey(),
+            &user,
+            stake_pool.credit_lamports_total / 2,
+        )
+        .await
+        .unwrap();
+    stake_pool_accounts
+        .delegate_reserve(&mut context.banks_client, &context.payer, &delegate)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_deposit_stake() {
+    let stake_pool_accounts = StakePoolAccounts::new();
+    let mut context = program_test().start_with_context().await;
+    stake_pool_accounts
+        .initialize(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+
+    let validator = Keypair::new();
+    create_vote(&mut context.banks_client, &context.payer, &validator).await;
+    stake_pool_accounts
+        .add_validator(
+            &mut context.banks_client,
+            &context.payer,
+            &validator.pubkey(),
+        )
+        .await
+        .unwrap();
+    let unlisted_validator = Keypair::new();
+    create_vote(
+        &mut context.banks_client,
+        &context.payer,
+        &unlisted_validator,
+    )
+    .await;
+
+    let user = Keypair::new();
+    let user_pool_account = Keypair::new();
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &user.pubkey(),
+        sol_to_lamports(20.0),
+    )
+    .await;
+    create_token_account(
+        &mut context.banks_client,
+        &context.payer,
+        &user_pool_account,
+        &stake_pool_accounts.pool_mint.pubkey(),
+        &user.pubkey(),
+    )
+    .await
+    .unwrap();
+    stake_pool_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &user,
+            &user_pool_account.pubkey(),
+            sol_to_lamports(10.0),
+        )
+        .await
+        .unwrap();
+    stake_pool_accounts
+        .delegate_reserve(
+            &mut context.banks_client,
+            &context.payer,
+            &[(
+                validator.pubkey(),
+                DelegateReserveInstruction {
+                    amount: sol_to_lamports(2.0),
+                    stake_index: 0,
+                },
+            )],
+        )
+        .await
+        .unwrap();
+
+    let first_stake = Keypair::new();
+    let second_stake = Keypair::new();
+    let topped_up_stake = Keypair::new();
+    let late_stake = Keypair::new();
+    let unlisted_stake = Keypair::new();
+    for (stake_account, vote) in &[
+        (&first_stake, &validator),
+        (&second_stake, &validator),
+        (&topped_up_stake, &validator),
+        (&late_stake, &validator),
+        (&unlisted_stake, &unlisted_validator),
+    ] {
+        create_delegated_stake(
+            &mut context.banks_client,
+            &context.payer,
+            stake_account,
+            &user,
+            &vote.pubkey(),
+            sol_to_lamports(3.0),
+        )
+        .await;
+    }
+
+    let expect_error = |error: TransportError, expected: StakePoolError| match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(error_index),
+        )) => assert_eq!(error_index, expected as u32),
+        _ => panic!("Wrong error occurs while depositing stake: {:?}", error),
+    };
+
+    // Stake still warming up is not priced yet
+    let error = stake_pool_accounts
+        .deposit_stake(
+            &mut context.banks_client,
+            &context.payer,
+            &first_stake.pubkey(),
+            &user,
+            &validator.pubkey(),
+            1,
+            &user_pool_account.pubkey(),
+        )
+        .await
+        .unwrap_err();
+    expect_error(error, StakePoolError::WrongStakeState);
+
+    warp_to_next_epoch(&mut context).await;
+    stake_pool_accounts
+        .update_list_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    stake_pool_accounts
+        .update_pool_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+
+    let error = stake_pool_accounts
+        .deposit_stake(
+            &mut context.banks_client,
+            &context.payer,
+            &unlisted_stake.pubkey(),
+            &user,
+            &unlisted_validator.pubkey(),
+            0,
+            &user_pool_account.pubkey(),
+        )
+        .await
+        .unwrap_err();
+    expect_error(error, StakePoolError::ValidatorNotFound);
+
+    // Indexes past the stake count are not allowed
+    let error = stake_pool_accounts
+        .deposit_stake(
+            &mut context.banks_client,
+            &context.payer,
+            &first_stake.pubkey(),
+            &user,
+            &validator.pubkey(),
+            2,
+            &user_pool_account.pubkey(),
+        )
+        .await
+        .unwrap_err();
+    expect_error(error, StakePoolError::InvalidStakeIndex);
+
+    // The first stake becomes the validator's next stake account
+    let stake_amount = match get_stake_state(&mut context.banks_client, &first_stake.pubkey()).await
+    {
+        stake::StakeState::Stake(_, stake) => stake.delegation.stake,
+        _ => panic!("user stake account must be delegated"),
+    };
+    // The tokens are minted for the stake, the rent exempt reserve goes to the holders
+    let first_lamports = get_account(&mut context.banks_client, &first_stake.pubkey())
+        .await
+        .lamports;
+    assert!(first_lamports > stake_amount);
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    let pool_amount = stake_pool.calc_pool_deposit_amount(stake_amount).unwrap();
+    let fee_amount = stake_pool_accounts.calculate_deposit_fee(pool_amount);
+    let tokens_before =
+        get_token_balance(&mut context.banks_client, &user_pool_account.pubkey()).await;
+    stake_pool_accounts
+        .deposit_stake(
+            &mut context.banks_client,
+            &context.payer,
+            &first_stake.pubkey(),
+            &user,
+            &validator.pubkey(),
+            1,
+            &user_pool_account.pubkey(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        get_token_balance(&mut context.banks_client, &user_pool_account.pubkey()).await,
+        tokens_before + pool_amount - fee_amount
+    );
+    let (registered_stake, _) = ValidatorStakeInfo::find_stake_address(
+        &id(),
+        &validator.pubkey(),
+        &stake_pool_accounts.stake_pool.pubkey(),
+        1,
+    );
+    match get_stake_state(&mut context.banks_client, &registered_stake).await {
+        stake::StakeState::Stake(meta, stake) => {
+            assert_eq!(
+                meta.authorized.staker,
+                stake_pool_accounts.deposit_authority
+            );
+            assert_eq!(
+                meta.authorized.withdrawer,
+                stake_pool_accounts.withdraw_authority
+            );
+            assert_eq!(stake.delegation.stake, stake_amount);
+        }
+        _ => panic!("registered stake account must be delegated"),
+    }
+    assert!(context
+        .banks_client
+        .get_account(first_stake.pubkey())
+        .await
+        .unwrap()
+        .is_none());
+    let validator_stake_list = stake_pool_accounts
+        .get_validator_stake_list(&mut context.banks_client)
+        .await;
+    assert_eq!(validator_stake_list.validators[0].stake_count, 2);
+    let stake_pool_after = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(
+        stake_pool_after.stake_total,
+        stake_pool.stake_total + first_lamports
+    );
+    assert_eq!(
+        stake_pool_after.pool_total,
+        stake_pool.pool_total + pool_amount
+    );
+
+    // Lamports beyond the stake and its rent exempt reserve are not taken
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &topped_up_stake.pubkey(),
+        sol_to_lamports(1.0),
+    )
+    .await;
+    let error = stake_pool_accounts
+        .deposit_stake(
+            &mut context.banks_client,
+            &context.payer,
+            &topped_up_stake.pubkey(),
+            &user,
+            &validator.pubkey(),
+            0,
+            &user_pool_account.pubkey(),
+        )
+        .await
+        .unwrap_err();
+    expect_error(error, StakePoolError::WrongStakeState);
+
+    // The second stake merges into the reserve delegation
+    let balance_before = validator_stake_list.validators[0].balance;
+    let lamports = get_account(&mut context.banks_client, &second_stake.pubkey())
+        .await
+        .lamports;
+    stake_pool_accounts
+        .deposit_stake(
+            &mut context.banks_client,
+            &context.payer,
+            &second_stake.pubkey(),
+            &user,
+            &validator.pubkey(),
+            0,
+            &user_pool_account.pubkey(),
+        )
+        .await
+        .unwrap();
+    let validator_stake_list = stake_pool_accounts
+        .get_validator_stake_list(&mut context.banks_client)
+        .await;
+    assert_eq!(validator_stake_list.validators[0].stake_count, 2);
+    assert_eq!(
+        validator_stake_list.validators[0].balance,
+        balance_before + lamports
+    );
+    let (main_stake, _) = ValidatorStakeInfo::find_stake_address(
+        &id(),
+        &validator.pubkey(),
+        &stake_pool_accounts.stake_pool.pubkey(),
+        0,
+    );
+    match get_stake_state(&mut context.banks_client, &main_stake).await {
+        stake::StakeState::Stake(_, stake) => {
+            assert!(stake.delegation.stake > sol_to_lamports(4.0));
+        }
+        _ => panic!("main stake account must stay delegated"),
+    }
+
+    // A paged update that stopped inside the validator zeroed its record.
+    // The permissive policy lets the stale pool balance through
+    process(
+        &mut context.banks_client,
+        &context.payer,
+        &[instruction::set_freshness_policy(
+            &id(),
+            &stake_pool_accounts.stake_pool.pubkey(),
+            &stake_pool_accounts.owner.pubkey(),
+            FreshnessPolicy::PERMISSIVE,
+        )
+        .unwrap()],
+        &[&stake_pool_accounts.owner],
+    )
+    .await
+    .unwrap();
+    warp_to_next_epoch(&mut context).await;
+    stake_pool_accounts
+        .update_list_balance_page(&mut context.banks_client, &context.payer, 2)
+        .await
+        .unwrap();
+    let error = stake_pool_accounts
+        .deposit_stake(
+            &mut context.banks_client,
+            &context.payer,
+            &late_stake.pubkey(),
+            &user,
+            &validator.pubkey(),
+            2,
+            &user_pool_account.pubkey(),
+        )
+        .await
+        .unwrap_err();
+    expect_error(error, StakePoolError::ListUpdateInProgress);
+}
+
+#[tokio::test]
+async fn test_set_staking_authority() {
+    let stake_pool_accounts = StakePoolAccounts::new();
+    let mut context = program_test().start_with_context().await;
+    stake_pool_accounts
+        .initialize(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+
+    let validator = Keypair::new();
+    create_vote(&mut context.banks_client, &context.payer, &validator).await;
+    stake_pool_accounts
+        .add_validator(
+            &mut context.banks_client,
+            &context.payer,
+            &validator.pubkey(),
+        )
+        .await
+        .unwrap();
+
+    let user = Keypair::new();
+    let user_pool_account = Keypair::new();
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &user.pubkey(),
+        sol_to_lamports(20.0),
+    )
+    .await;
+    create_token_account(
+        &mut context.banks_client,
+        &context.payer,
+        &user_pool_account,
+        &stake_pool_accounts.pool_mint.pubkey(),
+        &user.pubkey(),
+    )
+    .await
+    .unwrap();
+    stake_pool_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &user,
+            &user_pool_account.pubkey(),
+            sol_to_lamports(10.0),
+        )
+        .await
+        .unwrap();
+    stake_pool_accounts
+        .delegate_reserve(
+            &mut context.banks_client,
+            &context.payer,
+            &[(
+                validator.pubkey(),
+                DelegateReserveInstruction {
+                    amount: sol_to_lamports(5.0),
+                    stake_index: 0,
+                },
+            )],
+        )
+        .await
+        .unwrap();
+
+    warp_to_next_epoch(&mut context).await;
+    stake_pool_accounts
+        .update_list_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    stake_pool_accounts
+        .update_pool_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+
+    let (validator_stake, _) = ValidatorStakeInfo::find_stake_address(
+        &id(),
+        &validator.pubkey(),
+        &stake_pool_accounts.stake_pool.pubkey(),
+        0,
+    );
+    let lamports = context
+        .banks_client
+        .get_balance(validator_stake)
+        .await
+        .unwrap();
+    let stake_amount = match get_stake_state(&mut context.banks_client, &validator_stake).await {
+        stake::StakeState::Stake(_, stake) => stake.delegation.stake,
+        _ => panic!("validator stake account must be delegated"),
+    };
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    let validator_stake_list = stake_pool_accounts
+        .get_validator_stake_list(&mut context.banks_client)
+        .await;
+    let pool_tokens =
+        get_token_balance(&mut context.banks_client, &user_pool_account.pubkey()).await;
+    let new_authority = Keypair::new();
+
+    // Only the owner can hand the stake over
+    let stranger = Keypair::new();
+    let error = process(
+        &mut context.banks_client,
+        &context.payer,
+        &[instruction::set_staking_authority(
+            &id(),
+            &stake_pool_accounts.stake_pool.pubkey(),
+            &stranger.pubkey(),
+            &stake_pool_accounts.validator_stake_list.pubkey(),
+            &validator_stake,
+            &stranger.pubkey(),
+        )
+        .unwrap()],
+        &[&stranger],
+    )
+    .await
+    .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(error_index),
+        )) => assert_eq!(error_index, StakePoolError::WrongOwner as u32),
+        _ => panic!("Wrong error occurs while handing a stake over without the owner"),
+    }
+
+    stake_pool_accounts
+        .set_staking_authority(
+            &mut context.banks_client,
+            &context.payer,
+            &validator_stake,
+            &new_authority.pubkey(),
+        )
+        .await
+        .unwrap();
+
+    // The stake leaves with both authorities and the holders keep their tokens
+    match get_stake_state(&mut context.banks_client, &validator_stake).await {
+        stake::StakeState::Stake(meta, _) => {
+            assert_eq!(meta.authorized.staker, new_authority.pubkey());
+            assert_eq!(meta.authorized.withdrawer, new_authority.pubkey());
+        }
+        _ => panic!("validator stake account must stay delegated"),
+    }
+    assert_eq!(
+        get_token_balance(&mut context.banks_client, &user_pool_account.pubkey()).await,
+        pool_tokens
+    );
+    let stake_pool_after = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(stake_pool_after.pool_total, stake_pool.pool_total);
+    assert_eq!(
+        stake_pool_after.stake_total,
+        stake_pool.stake_total - lamports
+    );
+    assert_eq!(
+        stake_pool_after.active_lamports,
+        stake_pool.active_lamports - stake_amount
+    );
+    assert_eq!(
+        stake_pool_after.activating_lamports,
+        stake_pool.activating_lamports
+    );
+    let validator_stake_list_after = stake_pool_accounts
+        .get_validator_stake_list(&mut context.banks_client)
+        .await;
+    let (validator_before, validator_after) = (
+        &validator_stake_list.validators[0],
+        &validator_stake_list_after.validators[0],
+    );
+    assert_eq!(validator_after.stake_count, 0);
+    assert_eq!(validator_after.balance, validator_before.balance - lamports);
+    assert_eq!(
+        validator_after.active_lamports,
+        validator_before.active_lamports - stake_amount
+    );
+
+    // The pool balance agrees with the accounts left in the pool
+    stake_pool_accounts
+        .update_list_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    stake_pool_accounts
+        .update_pool_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    assert_eq!(
+        stake_pool_accounts
+            .get_stake_pool(&mut context.banks_client)
+            .await
+            .stake_total,
+        stake_pool_after.stake_total
+    );
+}
+
+#[tokio::test]
+async fn test_withdraw_stake() {
+    let stake_pool_accounts = StakePoolAccounts::new();
+    let mut context = program_test().start_with_context().await;
+    stake_pool_accounts
+        .initialize(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+
+    let validator = Keypair::new();
+    create_vote(&mut context.banks_client, &context.payer, &validator).await;
+    stake_pool_accounts
+        .add_validator(
+            &mut context.banks_client,
+            &context.payer,
+            &validator.pubkey(),
+        )
+        .await
+        .unwrap();
+
+    let user = Keypair::new();
+    let user_pool_account = Keypair::new();
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &user.pubkey(),
+        sol_to_lamports(20.0),
+    )
+    .await;
+    create_token_account(
+        &mut context.banks_client,
+        &context.payer,
+        &user_pool_account,
+        &stake_pool_accounts.pool_mint.pubkey(),
+        &user.pubkey(),
+    )
+    .await
+    .unwrap();
+    stake_pool_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &user,
+            &user_pool_account.pubkey(),
+            sol_to_lamports(10.0),
+        )
+        .await
+        .unwrap();
+    stake_pool_accounts
+        .delegate_reserve(
+            &mut context.banks_client,
+            &context.payer,
+            &[(
+                validator.pubkey(),
+                DelegateReserveInstruction {
+                    amount: sol_to_lamports(5.0),
+                    stake_index: 0,
+                },
+            )],
+        )
+        .await
+        .unwrap();
+
+    warp_to_next_epoch(&mut context).await;
+    stake_pool_accounts
+        .update_list_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    stake_pool_accounts
+        .update_pool_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+
+    let pool_tokens =
+        get_token_balance(&mut context.banks_client, &user_pool_account.pubkey()).await;
+    // The withdraw authority approval doesn't let anyone else burn the tokens
+    approve(
+        &mut context.banks_client,
+        &context.payer,
+        &user_pool_account.pubkey(),
+        &stake_pool_accounts.withdraw_authority,
+        &user,
+        pool_tokens,
+    )
+    .await;
+
+    let split_stake = Keypair::new();
+    create_uninitialized_stake(&mut context.banks_client, &context.payer, &split_stake).await;
+    let stake_owner = Keypair::new();
+
+    // Only the owner or a delegate of the pool tokens can withdraw them
+    let stranger = Keypair::new();
+    let error = stake_pool_accounts
+        .withdraw_stake(
+            &mut context.banks_client,
+            &context.payer,
+            &validator.pubkey(),
+            0,
+            &split_stake.pubkey(),
+            &stranger.pubkey(),
+            &user_pool_account.pubkey(),
+            &stranger,
+            sol_to_lamports(1.0),
+        )
+        .await
+        .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(error_index),
+        )) => assert_eq!(
+            error_index,
+            spl_token::error::TokenError::OwnerMismatch as u32
+        ),
+        _ => panic!("Wrong error occurs while withdrawing someone else's tokens"),
+    }
+    assert_eq!(
+        get_token_balance(&mut context.banks_client, &user_pool_account.pubkey()).await,
+        pool_tokens
+    );
+
+    // The validator stake must keep its minimum balance
+    let error = stake_pool_accounts
+        .withdraw_stake(
+            &mut context.banks_client,
+            &context.payer,
+            &validator.pubkey(),
+            0,
+            &split_stake.pubkey(),
+            &stake_owner.pubkey(),
+            &user_pool_account.pubkey(),
+            &user,
+            sol_to_lamports(5.0),
+        )
+        .await
+        .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::InsufficientFunds,
+        )) => {}
+        _ => panic!("Wrong error occurs while emptying the validator stake"),
+    }
+
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    let withdraw_amount = sol_to_lamports(2.0);
+    let fee_amount = stake_pool_accounts.calculate_withdrawal_fee(withdraw_amount);
+    let stake_amount = stake_pool
+        .calc_lamports_amount(withdraw_amount - fee_amount)
+        .unwrap();
+    let reserve_balance = stake_pool_accounts
+        .get_reserve_balance(&mut context.banks_client)
+        .await;
+    let validator_balance = stake_pool_accounts
+        .get_validator_stake_list(&mut context.banks_client)
+        .await
+        .validators[0]
+        .balance;
+    stake_pool_accounts
+        .withdraw_stake(
+            &mut context.banks_client,
+            &context.payer,
+            &validator.pubkey(),
+            0,
+            &split_stake.pubkey(),
+            &stake_owner.pubkey(),
+            &user_pool_account.pubkey(),
+            &user,
+            withdraw_amount,
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(
+        get_token_balance(&mut context.banks_client, &user_pool_account.pubkey()).await,
+        pool_tokens - withdraw_amount
+    );
+    // The reserve is not touched
+    assert_eq!(
+        stake_pool_accounts
+            .get_reserve_balance(&mut context.banks_client)
+            .await,
+        reserve_balance
+    );
+    match get_stake_state(&mut context.banks_client, &split_stake.pubkey()).await {
+        stake::StakeState::Stake(meta, stake) => {
+            assert_eq!(meta.authorized.staker, stake_owner.pubkey());
+            assert_eq!(meta.authorized.withdrawer, stake_owner.pubkey());
+            assert_eq!(stake.delegation.voter_pubkey, validator.pubkey());
+            assert_eq!(stake.delegation.stake, stake_amount);
+        }
+        _ => panic!("split stake account must be delegated"),
+    }
+    let validator_stake_list = stake_pool_accounts
+        .get_validator_stake_list(&mut context.banks_client)
+        .await;
+    assert_eq!(
+        validator_stake_list.validators[0].balance,
+        validator_balance - stake_amount
+    );
+    let stake_pool_after = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(
+        stake_pool_after.stake_total,
+        stake_pool.stake_total - stake_amount
+    );
+    assert_eq!(
+        stake_pool_after.pool_total,
+        stake_pool.pool_total - (withdraw_amount - fee_amount)
+    );
+
+    // The target must be an uninitialized stake account
+    let error = stake_pool_accounts
+        .withdraw_stake(
+            &mut context.banks_client,
+            &context.payer,
+            &validator.pubkey(),
+            0,
+            &stake_owner.pubkey(),
+            &stake_owner.pubkey(),
+            &user_pool_account.pubkey(),
+            &user,
+            sol_to_lamports(1.0),
+        )
+        .await
+        .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(error_index),
+        )) => assert_eq!(error_index, StakePoolError::WrongStakeState as u32),
+        _ => panic!("Wrong error occurs while splitting into a system account"),
+    }
+
+    // A paged update that stopped inside the validator zeroed its record.
+    // The permissive policy lets the stale pool balance through
+    process(
+        &mut context.banks_client,
+        &context.payer,
+        &[instruction::set_freshness_policy(
+            &id(),
+            &stake_pool_accounts.stake_pool.pubkey(),
+            &stake_pool_accounts.owner.pubkey(),
+            FreshnessPolicy::PERMISSIVE,
+        )
+        .unwrap()],
+        &[&stake_pool_accounts.owner],
+    )
+    .await
+    .unwrap();
+    stake_pool_accounts
+        .delegate_reserve(
+            &mut context.banks_client,
+            &context.payer,
+            &[(
+                validator.pubkey(),
+                DelegateReserveInstruction {
+                    amount: sol_to_lamports(2.0),
+                    stake_index: 1,
+                },
+            )],
+        )
+        .await
+        .unwrap();
+    warp_to_next_epoch(&mut context).await;
+    stake_pool_accounts
+        .update_list_balance_page(&mut context.banks_client, &context.payer, 2)
+        .await
+        .unwrap();
+    let split_stake = Keypair::new();
+    create_uninitialized_stake(&mut context.banks_client, &context.payer, &split_stake).await;
+    let error = stake_pool_accounts
+        .withdraw_stake(
+            &mut context.banks_client,
+            &context.payer,
+            &validator.pubkey(),
+            0,
+            &split_stake.pubkey(),
+            &stake_owner.pubkey(),
+            &user_pool_account.pubkey(),
+            &user,
+            sol_to_lamports(1.0),
+        )
+        .await
+        .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(error_index),
+        )) => assert_eq!(error_index, StakePoolError::ListUpdateInProgress as u32),
+        _ => panic!("Wrong error occurs while withdrawing during a paged update"),
+    }
+}
+
+#[tokio::test]
+async fn test_update_list_balance_paged() {
+    let stake_pool_accounts = StakePoolAccounts::new();
+    let mut context = program_test().start_with_context().await;
+    stake_pool_accounts
+        .initialize(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+
+    let mut validators = vec![];
+    for _ in 0..MAX_VALIDATORS {
+        let validator = Keypair::new();
+        create_vote(&mut context.banks_client, &context.payer, &validator).await;
+        stake_pool_accounts
+            .add_validator(
+                &mut context.banks_client,
+                &context.payer,
+                &validator.pubkey(),
+            )
+            .await
+            .unwrap();
+        validators.push(validator.pubkey());
+    }
+
+    let user = Keypair::new();
+    let user_pool_account = Keypair::new();
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &user.pubkey(),
+        sol_to_lamports(20.0),
+    )
+    .await;
+    create_token_account(
+        &mut context.banks_client,
+        &context.payer,
+        &user_pool_account,
+        &stake_pool_accounts.pool_mint.pubkey(),
+        &user.pubkey(),
+    )
+    .await
+    .unwrap();
+    stake_pool_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &user,
+            &user_pool_account.pubkey(),
+            sol_to_lamports(10.0),
+        )
+        .await
+        .unwrap();
+
+    // The first validator has more stake accounts than fit into a page
+    let mut delegations: Vec<_> = (0..5)
+        .map(|stake_index| {
+            (
+                validators[0],
+                DelegateReserveInstruction {
+                    amount: sol_to_lamports(1.0),
+                    stake_index,
+                },
+            )
+        })
+        .collect();
+    delegations.push((
+        validators[MAX_VALIDATORS - 1],
+        DelegateReserveInstruction {
+            amount: sol_to_lamports(1.0),
+            stake_index: 0,
+        },
+    ));
+    stake_pool_accounts
+        .delegate_reserve(&mut context.banks_client, &context.payer, &delegations)
+        .await
+        .unwrap();
+
+    let epoch = warp_to_next_epoch(&mut context).await;
+
+    let assert_error = |error: TransportError, expected: StakePoolError| match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(error_index),
+        )) => assert_eq!(error_index, expected as u32),
+        _ => panic!("Wrong error {:?}, expected {:?}", error, expected),
+    };
+
+    // The first page stops inside the stake accounts of the first validator
+    stake_pool_accounts
+        .update_list_balance_page(&mut context.banks_client, &context.payer, 4)
+        .await
+        .unwrap();
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(stake_pool.list_update_cursor.epoch, epoch);
+    assert_eq!(stake_pool.list_update_cursor.validator_index, 0);
+    assert_eq!(stake_pool.list_update_cursor.stake_index, 3);
+    let validator_stake_list = stake_pool_accounts
+        .get_validator_stake_list(&mut context.banks_client)
+        .await;
+    assert!(validator_stake_list.validators[0].last_update_epoch < epoch);
+
+    assert_error(
+        stake_pool_accounts
+            .update_pool_balance(&mut context.banks_client, &context.payer)
+            .await
+            .unwrap_err(),
+        StakePoolError::StakeListOutOfDate,
+    );
+    // The unpaged update can't interleave with the unfinished validator
+    assert_error(
+        stake_pool_accounts
+            .update_list_balance(&mut context.banks_client, &context.payer)
+            .await
+            .unwrap_err(),
+        StakePoolError::ListUpdateInProgress,
+    );
+
+    let pages = stake_pool_accounts
+        .update_list_balance_paged(&mut context.banks_client, &context.payer, 10)
+        .await
+        .unwrap();
+    assert!(pages > 1);
+    let validator_stake_list = stake_pool_accounts
+        .get_validator_stake_list(&mut context.banks_client)
+        .await;
+    assert!(validator_stake_list
+        .validators
+        .iter()
+        .all(|validator| validator.last_update_epoch == epoch));
+    assert_eq!(validator_stake_list.validators[0].stake_count, 5);
+    for validator in &validator_stake_list.validators {
+        let mut balance = 0;
+        for index in 0..validator.stake_count {
+            let (stake_account, _) =
+                validator.stake_address(&id(), &stake_pool_accounts.stake_pool.pubkey(), index);
+            balance += get_account(&mut context.banks_client, &stake_account)
+                .await
+                .lamports;
+        }
+        assert_eq!(validator.balance, balance);
+    }
+    assert!(validator_stake_list.validators[MAX_VALIDATORS - 1].balance > 0);
+
+    stake_pool_accounts
+        .update_pool_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(stake_pool.last_update_epoch, epoch);
+
+    // A second round in the same epoch doesn't expose the half counted validator
+    stake_pool_accounts
+        .update_list_balance_page(&mut context.banks_client, &context.payer, 4)
+        .await
+        .unwrap();
+    let validator_stake_list = stake_pool_accounts
+        .get_validator_stake_list(&mut context.banks_client)
+        .await;
+    assert!(validator_stake_list.validators[0].last_update_epoch < epoch);
+    assert_error(
+        stake_pool_accounts
+            .update_pool_balance(&mut context.banks_client, &context.payer)
+            .await
+            .unwrap_err(),
+        StakePoolError::StakeListOutOfDate,
+    );
+
+    stake_pool_accounts
+        .update_list_balance_paged(&mut context.banks_client, &context.payer, 10)
+        .await
+        .unwrap();
+    stake_pool_accounts
+        .update_pool_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    let stake_pool_after = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(stake_pool_after.stake_total, stake_pool.stake_total);
+    assert_eq!(stake_pool_after.pool_total, stake_pool.pool_total);
+}
+
+#[tokio::test]
+async fn test_realloc_list() {
+    let mut stake_pool_accounts = StakePoolAccounts::new();
+    stake_pool_accounts.validator_capacity = 2;
+    let mut context = program_test().start_with_context().await;
+    stake_pool_accounts
+        .initialize(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+
+    let assert_error = |error: TransportError, expected: StakePoolError| match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(error_index),
+        )) => assert_eq!(error_index, expected as u32),
+        _ => panic!("Wrong error {:?}, expected {:?}", error, expected),
+    };
+
+    let validators: Vec<_> = (0..3).map(|_| Keypair::new()).collect();
+    for validator in &validators {
+        create_vote(&mut context.banks_client, &context.payer, validator).await;
+    }
+    for validator in &validators[..2] {
+        stake_pool_accounts
+            .add_validator(
+                &mut context.banks_client,
+                &context.payer,
+                &validator.pubkey(),
+            )
+            .await
+            .unwrap();
+    }
+    assert_error(
+        stake_pool_accounts
+            .add_validator(
+                &mut context.banks_client,
+                &context.payer,
+                &validators[2].pubkey(),
+            )
+            .await
+            .unwrap_err(),
+        StakePoolError::ValidatorListOverflow,
+    );
+
+    // The new account must fit every validator
+    let new_validator_stake_list = Keypair::new();
+    assert_error(
+        stake_pool_accounts
+            .realloc_list(
+                &mut context.banks_client,
+                &context.payer,
+                &stake_pool_accounts.validator_stake_list.pubkey(),
+                &new_validator_stake_list,
+                ValidatorStakeList::space(1),
+            )
+            .await
+            .unwrap_err(),
+        StakePoolError::ValidatorListOverflow,
+    );
+
+    // Only the owner moves lists
+    let old_validator_stake_list = stake_pool_accounts.validator_stake_list.pubkey();
+    assert!(process(
+        &mut context.banks_client,
+        &context.payer,
+        &[instruction::realloc_list(
+            &id(),
+            &stake_pool_accounts.stake_pool.pubkey(),
+            &context.payer.pubkey(),
+            &old_validator_stake_list,
+            &new_validator_stake_list.pubkey(),
+            &context.payer.pubkey(),
+        )
+        .unwrap()],
+        &[],
+    )
+    .await
+    .is_err());
+
+    stake_pool_accounts
+        .realloc_list(
+            &mut context.banks_client,
+            &context.payer,
+            &old_validator_stake_list,
+            &new_validator_stake_list,
+            ValidatorStakeList::space(4),
+        )
+        .await
+        .unwrap();
+    // The old list account is closed
+    assert!(context
+        .banks_client
+        .get_account(old_validator_stake_list)
+        .await
+        .unwrap()
+        .is_none());
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(
+        stake_pool.validator_stake_list,
+        new_validator_stake_list.pubkey()
+    );
+
+    stake_pool_accounts.validator_stake_list = new_validator_stake_list;
+    let validator_stake_list = stake_pool_accounts
+        .get_validator_stake_list(&mut context.banks_client)
+        .await;
+    assert_eq!(
+        validator_stake_list
+            .validators
+            .iter()
+            .map(|validator| validator.validator_account)
+            .collect::<Vec<_>>(),
+        vec![validators[0].pubkey(), validators[1].pubkey()]
+    );
+    stake_pool_accounts
+        .add_validator(
+            &mut context.banks_client,
+            &context.payer,
+            &validators[2].pubkey(),
+        )
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_credit_tickets() {
+    let stake_pool_accounts = StakePoolAccounts::new();
+    let mut context = program_test().start_with_context().await;
+    stake_pool_accounts
+        .initialize(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+
+    let user = Keypair::new();
+    let user_pool_account = Keypair::new();
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &user.pubkey(),
+        sol_to_lamports(5.0),
+    )
+    .await;
+    create_token_account(
+        &mut context.banks_client,
+        &context.payer,
+        &user_pool_account,
+        &stake_pool_accounts.pool_mint.pubkey(),
+        &user.pubkey(),
+    )
+    .await
+    .unwrap();
+    let deposit_amount = sol_to_lamports(4.0);
+    stake_pool_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &user,
+            &user_pool_account.pubkey(),
+            deposit_amount,
+        )
+        .await
+        .unwrap();
+    let credit_amount = sol_to_lamports(1.0);
+
+    // The second target leaves the system program after Credit and can't get SOL
+    let moved_target = Keypair::new();
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &moved_target.pubkey(),
+        sol_to_lamports(1.0),
+    )
+    .await;
+    let sol_targets = [
+        Pubkey::new_unique(),
+        moved_target.pubkey(),
+        Pubkey::new_unique(),
+    ];
+    let mut tickets = vec![];
+    for sol_target in sol_targets.iter() {
+        tickets.push(
+            stake_pool_accounts
+                .credit(
+                    &mut context.banks_client,
+                    &context.payer,
+                    &user_pool_account.pubkey(),
+                    &user,
+                    sol_target,
+                    &user.pubkey(),
+                    credit_amount,
+                )
+                .await
+                .unwrap(),
+        );
+    }
+    for (sequence, ticket) in tickets.iter().enumerate() {
+        assert_eq!(ticket, &stake_pool_accounts.credit_ticket(sequence as u64));
+        assert_eq!(
+            stake_pool_accounts
+                .get_credit_ticket(&mut context.banks_client, ticket)
+                .await
+                .sol_target,
+            sol_targets[sequence]
+        );
+    }
+    process(
+        &mut context.banks_client,
+        &context.payer,
+        &[system_instruction::assign(
+            &moved_target.pubkey(),
+            &spl_token::id(),
+        )],
+        &[&moved_target],
+    )
+    .await
+    .unwrap();
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(stake_pool.credit_queue_head, 3);
+    assert_eq!(stake_pool.credit_lamports_total, 3 * credit_amount);
+    assert_eq!(stake_pool.pool_total, deposit_amount - 3 * credit_amount);
+
+    // Half of the last ticket is returned as new pool tokens, the ticket stays open
+    let user_balance =
+        get_token_balance(&mut context.banks_client, &user_pool_account.pubkey()).await;
+    stake_pool_accounts
+        .uncredit(
+            &mut context.banks_client,
+            &context.payer,
+            &tickets[2],
+            &user_pool_account.pubkey(),
+            &sol_targets[2],
+            &context.payer.pubkey(),
+            &user,
+            credit_amount / 2,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        stake_pool_accounts
+            .get_credit_ticket(&mut context.banks_client, &tickets[2])
+            .await
+            .lamports_owed,
+        credit_amount - credit_amount / 2
+    );
+    assert_eq!(
+        get_token_balance(&mut context.banks_client, &user_pool_account.pubkey()).await,
+        user_balance + credit_amount / 2
+    );
+
+    // Rewards arrive before the payment. The tickets keep the value quoted at Credit
+    let rewards = sol_to_lamports(1.0);
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &stake_pool_accounts.reserve,
+        rewards,
+    )
+    .await;
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    warp_to_next_epoch(&mut context).await;
+    stake_pool_accounts
+        .update_list_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    stake_pool_accounts
+        .update_pool_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    let rewarded_stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(
+        rewarded_stake_pool.credit_lamports_total,
+        stake_pool.credit_lamports_total
+    );
+    // The holders get all the rewards
+    assert_eq!(
+        rewarded_stake_pool.stake_total,
+        stake_pool.stake_total + rewards
+    );
+
+    // FIFO ordering takes the tickets in the queue order only
+    let payer = context.payer.pubkey();
+    let out_of_order = [
+        (tickets[2], sol_targets[2], payer),
+        (tickets[1], sol_targets[1], payer),
+        (tickets[0], sol_targets[0], payer),
+    ];
+    let error = stake_pool_accounts
+        .pay_creditors(&mut context.banks_client, &context.payer, &out_of_order)
+        .await
+        .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::InvalidArgument,
+        )) => {}
+        _ => panic!("Wrong error occurs while paying out of the queue order"),
+    }
+    stake_pool_accounts
+        .set_credit_ordering(
+            &mut context.banks_client,
+            &context.payer,
+            CreditOrdering::SkipAhead,
+        )
+        .await
+        .unwrap();
+
+    // Pay the tickets out of order. The token target is skipped without blocking the rest
+    stake_pool_accounts
+        .pay_creditors(&mut context.banks_client, &context.payer, &out_of_order)
+        .await
+        .unwrap();
+    assert_eq!(
+        context
+            .banks_client
+            .get_balance(sol_targets[0])
+            .await
+            .unwrap(),
+        credit_amount
+    );
+    assert_eq!(
+        context
+            .banks_client
+            .get_balance(sol_targets[2])
+            .await
+            .unwrap(),
+        credit_amount - credit_amount / 2
+    );
+    for ticket in [tickets[0], tickets[2]].iter() {
+        assert!(context
+            .banks_client
+            .get_account(*ticket)
+            .await
+            .unwrap()
+            .is_none());
+    }
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(stake_pool.credit_lamports_total, credit_amount);
+    assert_eq!(stake_pool.stake_total, rewarded_stake_pool.stake_total);
+
+    // The skipped ticket is cancelled by its owner and closed.
+    // The rewards raised the ratio, so fewer tokens than were burned come back
+    let user_balance =
+        get_token_balance(&mut context.banks_client, &user_pool_account.pubkey()).await;
+    let pool_amount = stake_pool.calc_pool_deposit_amount(credit_amount).unwrap();
+    assert!(pool_amount < credit_amount);
+    stake_pool_accounts
+        .uncredit(
+            &mut context.banks_client,
+            &context.payer,
+            &tickets[1],
+            &user_pool_account.pubkey(),
+            &sol_targets[1],
+            &context.payer.pubkey(),
+            &user,
+            credit_amount,
+        )
+        .await
+        .unwrap();
+    assert!(context
+        .banks_client
+        .get_account(tickets[1])
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        get_token_balance(&mut context.banks_client, &user_pool_account.pubkey()).await,
+        user_balance + pool_amount
+    );
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(stake_pool.credit_lamports_total, 0);
+    assert_eq!(stake_pool.credit_queue_head, 3);
+    assert_eq!(
+        get_token_supply(
+            &mut context.banks_client,
+            &stake_pool_accounts.pool_mint.pubkey()
+        )
+        .await,
+        stake_pool.pool_total
+    );
+}
+
+#[tokio::test]
+async fn test_credit_on_behalf_of_others() {
+    let stake_pool_accounts = StakePoolAccounts::new();
+    let mut context = program_test().start_with_context().await;
+    stake_pool_accounts
+        .initialize(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+
+    let user = Keypair::new();
+    let user_pool_account = Keypair::new();
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &user.pubkey(),
+        sol_to_lamports(5.0),
+    )
+    .await;
+    create_token_account(
+        &mut context.banks_client,
+        &context.payer,
+        &user_pool_account,
+        &stake_pool_accounts.pool_mint.pubkey(),
+        &user.pubkey(),
+    )
+    .await
+    .unwrap();
+    stake_pool_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &user,
+            &user_pool_account.pubkey(),
+            sol_to_lamports(2.0),
+        )
+        .await
+        .unwrap();
+    let user_balance =
+        get_token_balance(&mut context.banks_client, &user_pool_account.pubkey()).await;
+    let credit_amount = sol_to_lamports(1.0);
+
+    // A delegation to the pool authority doesn't let anybody else credit the tokens
+    approve(
+        &mut context.banks_client,
+        &context.payer,
+        &user_pool_account.pubkey(),
+        &stake_pool_accounts.withdraw_authority,
+        &user,
+        credit_amount,
+    )
+    .await;
+    let attacker = Keypair::new();
+    let error = stake_pool_accounts
+        .credit(
+            &mut context.banks_client,
+            &context.payer,
+            &user_pool_account.pubkey(),
+            &attacker,
+            &attacker.pubkey(),
+            &attacker.pubkey(),
+            credit_amount,
+        )
+        .await
+        .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(error_index),
+        )) => assert_eq!(
+            error_index,
+            spl_token::error::TokenError::OwnerMismatch as u32
+        ),
+        _ => panic!("Wrong error occurs while crediting someone else's tokens"),
+    }
+
+    // The transfer authority must sign
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    let mut instruction = instruction::credit(
+        &id(),
+        &stake_pool_accounts.stake_pool.pubkey(),
+        stake_pool.credit_queue_head,
+        &user_pool_account.pubkey(),
+        &user.pubkey(),
+        &stake_pool_accounts.pool_mint.pubkey(),
+        &attacker.pubkey(),
+        &attacker.pubkey(),
+        &context.payer.pubkey(),
+        &spl_token::id(),
+        credit_amount,
+    )
+    .unwrap();
+    instruction.accounts[3].is_signer = false;
+    let error = process(
+        &mut context.banks_client,
+        &context.payer,
+        &[instruction],
+        &[],
+    )
+    .await
+    .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(error_index),
+        )) => assert_eq!(error_index, StakePoolError::SignatureMissing as u32),
+        _ => panic!("Wrong error occurs while crediting without the user signature"),
+    }
+    assert_eq!(
+        get_token_balance(&mut context.banks_client, &user_pool_account.pubkey()).await,
+        user_balance
+    );
+    assert_eq!(
+        stake_pool_accounts
+            .get_stake_pool(&mut context.banks_client)
+            .await
+            .credit_queue_head,
+        0
+    );
+
+    // A cancel authority of somebody else can't redirect the refund
+    let sol_target = Pubkey::new_unique();
+    let ticket = stake_pool_accounts
+        .credit(
+            &mut context.banks_client,
+            &context.payer,
+            &user_pool_account.pubkey(),
+            &user,
+            &sol_target,
+            &attacker.pubkey(),
+            credit_amount,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        stake_pool_accounts
+            .get_credit_ticket(&mut context.banks_client, &ticket)
+            .await
+            .refund_account,
+        user_pool_account.pubkey()
+    );
+    let attacker_pool_account = Keypair::new();
+    create_token_account(
+        &mut context.banks_client,
+        &context.payer,
+        &attacker_pool_account,
+        &stake_pool_accounts.pool_mint.pubkey(),
+        &attacker.pubkey(),
+    )
+    .await
+    .unwrap();
+    let error = stake_pool_accounts
+        .uncredit(
+            &mut context.banks_client,
+            &context.payer,
+            &ticket,
+            &attacker_pool_account.pubkey(),
+            &sol_target,
+            &context.payer.pubkey(),
+            &attacker,
+            credit_amount,
+        )
+        .await
+        .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::InvalidArgument,
+        )) => {}
+        _ => panic!("Wrong error occurs while refunding to another account"),
+    }
+
+    stake_pool_accounts
+        .uncredit(
+            &mut context.banks_client,
+            &context.payer,
+            &ticket,
+            &user_pool_account.pubkey(),
+            &sol_target,
+            &context.payer.pubkey(),
+            &attacker,
+            credit_amount,
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        get_token_balance(&mut context.banks_client, &user_pool_account.pubkey()).await,
+        user_balance
+    );
+    assert_eq!(
+        get_token_balance(&mut context.banks_client, &attacker_pool_account.pubkey()).await,
+        0
+    );
+}
+
+#[tokio::test]
+async fn test_expire_credit() {
+    let stake_pool_accounts = StakePoolAccounts::new();
+    let mut context = program_test().start_with_context().await;
+    stake_pool_accounts
+        .initialize(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+
+    let user = Keypair::new();
+    let user_pool_account = Keypair::new();
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &user.pubkey(),
+        sol_to_lamports(5.0),
+    )
+    .await;
+    create_token_account(
+        &mut context.banks_client,
+        &context.payer,
+        &user_pool_account,
+        &stake_pool_accounts.pool_mint.pubkey(),
+        &user.pubkey(),
+    )
+    .await
+    .unwrap();
+    stake_pool_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &user,
+            &user_pool_account.pubkey(),
+            sol_to_lamports(2.0),
+        )
+        .await
+        .unwrap();
+    let user_balance =
+        get_token_balance(&mut context.banks_client, &user_pool_account.pubkey()).await;
+
+    // Credit only takes a system account as the target
+    let target = Keypair::new();
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &target.pubkey(),
+        sol_to_lamports(1.0),
+    )
+    .await;
+    let credit_amount = sol_to_lamports(1.0);
+    let ticket = stake_pool_accounts
+        .credit(
+            &mut context.banks_client,
+            &context.payer,
+            &user_pool_account.pubkey(),
+            &user,
+            &target.pubkey(),
+            &user.pubkey(),
+            credit_amount,
+        )
+        .await
+        .unwrap();
+    let expiry_epoch = stake_pool_accounts
+        .get_credit_ticket(&mut context.banks_client, &ticket)
+        .await
+        .expiry_epoch();
+    assert_eq!(
+        expiry_epoch,
+        get_clock(&mut context.banks_client).await.epoch + CreditRecord::EXPIRY_EPOCHS
+    );
+    // The target leaves the system program afterwards, so PayCreditors always skips the ticket
+    process(
+        &mut context.banks_client,
+        &context.payer,
+        &[system_instruction::assign(
+            &target.pubkey(),
+            &spl_token::id(),
+        )],
+        &[&target],
+    )
+    .await
+    .unwrap();
+
+    let error = stake_pool_accounts
+        .expire_credit(
+            &mut context.banks_client,
+            &context.payer,
+            &ticket,
+            &user_pool_account.pubkey(),
+            &target.pubkey(),
+            &context.payer.pubkey(),
+        )
+        .await
+        .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(error_index),
+        )) => assert_eq!(error_index, StakePoolError::CreditNotExpired as u32),
+        _ => panic!("Wrong error occurs while expiring a fresh credit ticket"),
+    }
+
+    while warp_to_next_epoch(&mut context).await < expiry_epoch {}
+    stake_pool_accounts
+        .update_list_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    stake_pool_accounts
+        .update_pool_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    stake_pool_accounts
+        .pay_creditors(
+            &mut context.banks_client,
+            &context.payer,
+            &[(ticket, target.pubkey(), context.payer.pubkey())],
+        )
+        .await
+        .unwrap();
+    assert!(context
+        .banks_client
+        .get_account(ticket)
+        .await
+        .unwrap()
+        .is_some());
+    // The FIFO queue moves on past the skipped ticket
+    let skipped = stake_pool_accounts
+        .get_credit_ticket(&mut context.banks_client, &ticket)
+        .await;
+    assert_eq!(
+        stake_pool_accounts
+            .get_stake_pool(&mut context.banks_client)
+            .await
+            .credit_queue_tail,
+        skipped.sequence + 1
+    );
+
+    // Only the refund account recorded at Credit gets the tokens
+    let error = stake_pool_accounts
+        .expire_credit(
+            &mut context.banks_client,
+            &context.payer,
+            &ticket,
+            &target.pubkey(),
+            &target.pubkey(),
+            &context.payer.pubkey(),
+        )
+        .await
+        .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::InvalidArgument,
+        )) => {}
+        _ => panic!("Wrong error occurs while expiring to another account"),
+    }
+
+    // Anybody may expire the ticket, the rent goes to its payer
+    let stranger = Keypair::new();
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &stranger.pubkey(),
+        sol_to_lamports(1.0),
+    )
+    .await;
+    let ticket_rent = context.banks_client.get_balance(ticket).await.unwrap();
+    let payer_lamports = context
+        .banks_client
+        .get_balance(context.payer.pubkey())
+        .await
+        .unwrap();
+    let target_lamports = context
+        .banks_client
+        .get_balance(target.pubkey())
+        .await
+        .unwrap();
+    stake_pool_accounts
+        .expire_credit(
+            &mut context.banks_client,
+            &stranger,
+            &ticket,
+            &user_pool_account.pubkey(),
+            &target.pubkey(),
+            &context.payer.pubkey(),
+        )
+        .await
+        .unwrap();
+    assert!(context
+        .banks_client
+        .get_account(ticket)
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        context
+            .banks_client
+            .get_balance(target.pubkey())
+            .await
+            .unwrap(),
+        target_lamports
+    );
+    assert_eq!(
+        context
+            .banks_client
+            .get_balance(context.payer.pubkey())
+            .await
+            .unwrap(),
+        payer_lamports + ticket_rent
+    );
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(stake_pool.credit_lamports_total, 0);
+    // The pool ratio is still 1:1 and the unpaid ticket owes no withdrawal fee
+    assert_eq!(
+        get_token_balance(&mut context.banks_client, &user_pool_account.pubkey()).await,
+        user_balance
+    );
+    assert_eq!(
+        get_token_supply(
+            &mut context.banks_client,
+            &stake_pool_accounts.pool_mint.pubkey()
+        )
+        .await,
+        stake_pool.pool_total
+    );
+
+    // A ticket PayCreditors can pay never expires, only its cancel authority returns it
+    let sol_target = Pubkey::new_unique();
+    let ticket = stake_pool_accounts
+        .credit(
+            &mut context.banks_client,
+            &context.payer,
+            &user_pool_account.pubkey(),
+            &user,
+            &sol_target,
+            &user.pubkey(),
+            credit_amount / 2,
+        )
+        .await
+        .unwrap();
+    let expiry_epoch = stake_pool_accounts
+        .get_credit_ticket(&mut context.banks_client, &ticket)
+        .await
+        .expiry_epoch();
+    while warp_to_next_epoch(&mut context).await < expiry_epoch {}
+    stake_pool_accounts
+        .update_list_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    stake_pool_accounts
+        .update_pool_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    let error = stake_pool_accounts
+        .expire_credit(
+            &mut context.banks_client,
+            &context.payer,
+            &ticket,
+            &user_pool_account.pubkey(),
+            &sol_target,
+            &context.payer.pubkey(),
+        )
+        .await
+        .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(error_index),
+        )) => assert_eq!(error_index, StakePoolError::CreditNotExpired as u32),
+        _ => panic!("Wrong error occurs while expiring a payable credit ticket"),
+    }
+}
+
+#[tokio::test]
+async fn test_partial_credit_payment() {
+    let stake_pool_accounts = StakePoolAccounts::new();
+    let validator = Keypair::new();
+    let mut context = program_test().start_with_context().await;
+    stake_pool_accounts
+        .initialize(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    create_vote(&mut context.banks_client, &context.payer, &validator).await;
+    stake_pool_accounts
+        .add_validator(
+            &mut context.banks_client,
+            &context.payer,
+            &validator.pubkey(),
+        )
+        .await
+        .unwrap();
+
+    let user = Keypair::new();
+    let user_pool_account = Keypair::new();
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &user.pubkey(),
+        sol_to_lamports(20.0),
+    )
+    .await;
+    create_token_account(
+        &mut context.banks_client,
+        &context.payer,
+        &user_pool_account,
+        &stake_pool_accounts.pool_mint.pubkey(),
+        &user.pubkey(),
+    )
+    .await
+    .unwrap();
+    stake_pool_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &user,
+            &user_pool_account.pubkey(),
+            sol_to_lamports(10.0),
+        )
+        .await
+        .unwrap();
+    // About 3 SOL stay in the reserve
+    stake_pool_accounts
+        .delegate_reserve(
+            &mut context.banks_client,
+            &context.payer,
+            &[(
+                validator.pubkey(),
+                DelegateReserveInstruction {
+                    amount: sol_to_lamports(7.0),
+                    stake_index: 0,
+                },
+            )],
+        )
+        .await
+        .unwrap();
+
+    // A whale at the head of the queue and a small ticket behind it
+    let sol_targets = [Pubkey::new_unique(), Pubkey::new_unique()];
+    let mut tickets = vec![];
+    for (sol_target, amount) in sol_targets.iter().zip(&[5.0, 1.0]) {
+        tickets.push(
+            stake_pool_accounts
+                .credit(
+                    &mut context.banks_client,
+                    &context.payer,
+                    &user_pool_account.pubkey(),
+                    &user,
+                    sol_target,
+                    &user.pubkey(),
+                    sol_to_lamports(*amount),
+                )
+                .await
+                .unwrap(),
+        );
+    }
+    let whale = stake_pool_accounts
+        .get_credit_ticket(&mut context.banks_client, &tickets[0])
+        .await;
+    let small = stake_pool_accounts
+        .get_credit_ticket(&mut context.banks_client, &tickets[1])
+        .await;
+
+    // FIFO: the whale gets the whole reserve surplus and the payment stops there
+    let reserve_balance = stake_pool_accounts
+        .get_reserve_balance(&mut context.banks_client)
+        .await;
+    stake_pool_accounts
+        .pay_creditors(
+            &mut context.banks_client,
+            &context.payer,
+            &[
+                (tickets[0], sol_targets[0], context.payer.pubkey()),
+                (tickets[1], sol_targets[1], context.payer.pubkey()),
+            ],
+        )
+        .await
+        .unwrap();
+    let paid = reserve_balance
+        - stake_pool_accounts
+            .get_reserve_balance(&mut context.banks_client)
+            .await;
+    assert!(paid > 0 && paid < whale.lamports_owed);
+    // The whale stays at the queue tail
+    assert_eq!(
+        stake_pool_accounts
+            .get_stake_pool(&mut context.banks_client)
+            .await
+            .credit_queue_tail,
+        whale.sequence
+    );
+    assert_eq!(
+        context
+            .banks_client
+            .get_balance(sol_targets[0])
+            .await
+            .unwrap(),
+        paid
+    );
+    // The paid share of the burned tokens leaves the ticket with the lamports
+    let mut unpaid_whale = whale;
+    unpaid_whale.release(paid).unwrap();
+    assert_eq!(unpaid_whale.lamports_owed, whale.lamports_owed - paid);
+    assert_eq!(
+        stake_pool_accounts
+            .get_credit_ticket(&mut context.banks_client, &tickets[0])
+            .await,
+        unpaid_whale
+    );
+    assert_eq!(
+        stake_pool_accounts
+            .get_credit_ticket(&mut context.banks_client, &tickets[1])
+            .await,
+        small
+    );
+    assert_eq!(
+        stake_pool_accounts
+            .get_stake_pool(&mut context.banks_client)
+            .await
+            .credit_lamports_total,
+        whale.lamports_owed + small.lamports_owed - paid
+    );
+
+    // Skip-ahead: the rest of the whale still doesn't fit, the small ticket is paid
+    stake_pool_accounts
+        .set_credit_ordering(
+            &mut context.banks_client,
+            &context.payer,
+            CreditOrdering::SkipAhead,
+        )
+        .await
+        .unwrap();
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &stake_pool_accounts.reserve,
+        sol_to_lamports(1.5),
+    )
+    .await;
+    stake_pool_accounts
+        .pay_creditors(
+            &mut context.banks_client,
+            &context.payer,
+            &[
+                (tickets[0], sol_targets[0], context.payer.pubkey()),
+                (tickets[1], sol_targets[1], context.payer.pubkey()),
+            ],
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        context
+            .banks_client
+            .get_balance(sol_targets[1])
+            .await
+            .unwrap(),
+        small.lamports_owed
+    );
+    assert!(context
+        .banks_client
+        .get_account(tickets[1])
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        stake_pool_accounts
+            .get_credit_ticket(&mut context.banks_client, &tickets[0])
+            .await
+            .lamports_owed,
+        whale.lamports_owed - paid
+    );
+    assert_eq!(
+        context
+            .banks_client
+            .get_balance(sol_targets[0])
+            .await
+            .unwrap(),
+        paid
+    );
+}
+
+#[tokio::test]
+async fn test_liquidity_target() {
+    let stake_pool_accounts = StakePoolAccounts::new();
+    let validator = Keypair::new();
+    let mut context = program_test().start_with_context().await;
+    stake_pool_accounts
+        .initialize(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    create_vote(&mut context.banks_client, &context.payer, &validator).await;
+    stake_pool_accounts
+        .add_validator(
+            &mut context.banks_client,
+            &context.payer,
+            &validator.pubkey(),
+        )
+        .await
+        .unwrap();
+
+    assert!(stake_pool_accounts
+        .set_liquidity_target(
+            &mut context.banks_client,
+            &context.payer,
+            StakePool::MAX_LIQUIDITY_TARGET_BPS + 1,
+        )
+        .await
+        .is_err());
+    stake_pool_accounts
+        .set_liquidity_target(&mut context.banks_client, &context.payer, 5_000)
+        .await
+        .unwrap();
+    assert_eq!(
+        stake_pool_accounts
+            .get_stake_pool(&mut context.banks_client)
+            .await
+            .liquidity_target_bps,
+        5_000
+    );
+
+    let user = Keypair::new();
+    let user_pool_account = Keypair::new();
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &user.pubkey(),
+        sol_to_lamports(20.0),
+    )
+    .await;
+    create_token_account(
+        &mut context.banks_client,
+        &context.payer,
+        &user_pool_account,
+        &stake_pool_accounts.pool_mint.pubkey(),
+        &user.pubkey(),
+    )
+    .await
+    .unwrap();
+    stake_pool_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &user,
+            &user_pool_account.pubkey(),
+            sol_to_lamports(10.0),
+        )
+        .await
+        .unwrap();
+
+    // Half of the pool stays in the reserve
+    let delegate = |amount| {
+        [(
+            validator.pubkey(),
+            DelegateReserveInstruction {
+                amount,
+                stake_index: 0,
+            },
+        )]
+    };
+    let error = stake_pool_accounts
+        .delegate_reserve(
+            &mut context.banks_client,
+            &context.payer,
+            &delegate(sol_to_lamports(6.0)),
+        )
+        .await
+        .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::InsufficientFunds,
+        )) => {}
+        _ => panic!("Wrong error occurs while delegating below the liquidity target"),
+    }
+    stake_pool_accounts
+        .delegate_reserve(
+            &mut context.banks_client,
+            &context.payer,
+            &delegate(sol_to_lamports(5.0)),
+        )
+        .await
+        .unwrap();
+    assert!(
+        stake_pool_accounts
+            .get_reserve_balance(&mut context.banks_client)
+            .await
+            >= sol_to_lamports(5.0)
+    );
+}
+
+#[tokio::test]
+async fn test_unstake_for_credits() {
+    let stake_pool_accounts = StakePoolAccounts::new();
+    let mut context = program_test().start_with_context().await;
+    stake_pool_accounts
+        .initialize(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+
+    let validator_a = Keypair::new();
+    let validator_b = Keypair::new();
+    for validator in &[&validator_a, &validator_b] {
+        create_vote(&mut context.banks_client, &context.payer, validator).await;
+        stake_pool_accounts
+            .add_validator(
+                &mut context.banks_client,
+                &context.payer,
+                &validator.pubkey(),
+            )
+            .await
+            .unwrap();
+        stake_pool_accounts
+            .set_validator_target_weight(
+                &mut context.banks_client,
+                &context.payer,
+                &validator.pubkey(),
+                1,
+            )
+            .await
+            .unwrap();
+    }
+
+    let user = Keypair::new();
+    let user_pool_account = Keypair::new();
+    transfer(
+        &mut context.banks_client,
+        &context.payer,
+        &user.pubkey(),
+        sol_to_lamports(20.0),
+    )
+    .await;
+    create_token_account(
+        &mut context.banks_client,
+        &context.payer,
+        &user_pool_account,
+        &stake_pool_accounts.pool_mint.pubkey(),
+        &user.pubkey(),
+    )
+    .await
+    .unwrap();
+    stake_pool_accounts
+        .deposit(
+            &mut context.banks_client,
+            &context.payer,
+            &user,
+            &user_pool_account.pubkey(),
+            sol_to_lamports(10.0),
+        )
+        .await
+        .unwrap();
+
+    // Validator A gets 4 SOL, validator B 1 SOL
+    stake_pool_accounts
+        .delegate_reserve(
+            &mut context.banks_client,
+            &context.payer,
+            &[
+                (
+                    validator_a.pubkey(),
+                    DelegateReserveInstruction {
+                        amount: sol_to_lamports(4.0),
+                        stake_index: 0,
+                    },
+                ),
+                (
+                    validator_b.pubkey(),
+                    DelegateReserveInstruction {
+                        amount: sol_to_lamports(1.0),
+                        stake_index: 0,
+                    },
+                ),
+            ],
+        )
+        .await
+        .unwrap();
+
+    // Credits need more than the reserve holds
+    let credit_amount = sol_to_lamports(8.0);
+    stake_pool_accounts
+        .credit(
+            &mut context.banks_client,
+            &context.payer,
+            &user_pool_account.pubkey(),
+            &user,
+            &user.pubkey(),
+            &user.pubkey(),
+            credit_amount,
+        )
+        .await
+        .unwrap();
+
+    let split = |validator: &Keypair, amount| UnstakeInstruction {
+        validator_address: validator.pubkey(),
+        source_index: 0,
+        split_index: 1,
+        amount,
+    };
+
+    let epoch = warp_to_next_epoch(&mut context).await;
+    stake_pool_accounts
+        .update_list_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    stake_pool_accounts
+        .update_pool_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+
+    // The rent-exempt reserves of the two stake accounts are not staked
+    let stake_rent = context
+        .banks_client
+        .get_rent()
+        .await
+        .unwrap()
+        .minimum_balance(std::mem::size_of::<stake::StakeState>());
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(
+        stake_pool.active_lamports,
+        sol_to_lamports(5.0) - 2 * stake_rent
+    );
+    assert_eq!(stake_pool.activating_lamports, 0);
+    assert_eq!(stake_pool.deactivating_lamports_at(epoch), 0);
+
+    // Validator A is the most over its target
+    let error = stake_pool_accounts
+        .unstake_for_credits(
+            &mut context.banks_client,
+            &context.payer,
+            &[split(&validator_b, sol_to_lamports(0.5))],
+        )
+        .await
+        .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(error_index),
+        )) => assert_eq!(error_index, StakePoolError::ValidatorNotOverTarget as u32),
+        _ => panic!("Wrong error occurs while unstaking from a validator under its target"),
+    }
+
+    // About 3 SOL are missing from the reserve
+    let error = stake_pool_accounts
+        .unstake_for_credits(
+            &mut context.banks_client,
+            &context.payer,
+            &[split(&validator_a, sol_to_lamports(3.5))],
+        )
+        .await
+        .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(error_index),
+        )) => assert_eq!(error_index, StakePoolError::UnstakeAboveRequired as u32),
+        _ => panic!("Wrong error occurs while unstaking more than credits need"),
+    }
+
+    let reserve_balance = stake_pool_accounts
+        .get_reserve_balance(&mut context.banks_client)
+        .await;
+    stake_pool_accounts
+        .unstake_for_credits(
+            &mut context.banks_client,
+            &context.payer,
+            &[split(&validator_a, sol_to_lamports(2.0))],
+        )
+        .await
+        .unwrap();
+    let validator_stake_list = stake_pool_accounts
+        .get_validator_stake_list(&mut context.banks_client)
+        .await;
+    assert_eq!(validator_stake_list.validators[0].stake_count, 2);
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(
+        stake_pool.deactivating_lamports_at(epoch),
+        sol_to_lamports(2.0)
+    );
+    // The reserve paid the split account rent
+    assert!(
+        stake_pool_accounts
+            .get_reserve_balance(&mut context.banks_client)
+            .await
+            < reserve_balance
+    );
+    let (split_stake, _) = ValidatorStakeInfo::find_stake_address(
+        &id(),
+        &validator_a.pubkey(),
+        &stake_pool_accounts.stake_pool.pubkey(),
+        1,
+    );
+    match get_stake_state(&mut context.banks_client, &split_stake).await {
+        stake::StakeState::Stake(_, stake) => {
+            assert_eq!(stake.delegation.deactivation_epoch, epoch);
+            assert_eq!(stake.delegation.stake, sol_to_lamports(2.0));
+        }
+        _ => panic!("split stake account must be delegated"),
+    }
+
+    // UpdateListBalance sees the split stake cooling down
+    stake_pool_accounts
+        .update_list_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    let validator_stake_list = stake_pool_accounts
+        .get_validator_stake_list(&mut context.banks_client)
+        .await;
+    assert_eq!(
+        validator_stake_list.validators[0].deactivating_lamports,
+        sol_to_lamports(2.0)
+    );
+    assert_eq!(validator_stake_list.validators[0].activating_lamports, 0);
+    assert_eq!(
+        validator_stake_list.validators[0].active_lamports,
+        sol_to_lamports(2.0) - stake_rent
+    );
+    stake_pool_accounts
+        .update_pool_balance(&mut context.banks_client, &context.payer)
+        .await
+        .unwrap();
+    let stake_pool = stake_pool_accounts
+        .get_stake_pool(&mut context.banks_client)
+        .await;
+    assert_eq!(
+        stake_pool.active_lamports,
+        sol_to_lamports(3.0) - 2 * stake_rent
+    );
+    assert_eq!(
+        stake_pool.deactivating_lamports_at(epoch),
+        sol_to_lamports(2.0)
+    );
+}
+
+#[tokio::test]
+async fn test_migrate_state_from_v1() {
+    let v1_data = include_bytes!("fixtures/stake_pool_v1.bin");
+    let mut program_test = program_test();
+    // Accounts created by the v1 pool client: the fields plus zeroed reserved space
+    let stake_pool = Pubkey::new_unique();
+    let mut data = v1_data.to_vec();
+    data.resize(StakePool::V1_CLIENT_LEN, 0);
+    program_test.add_account(
+        stake_pool,
+        Account {
+            lamports: sol_to_lamports(1.0),
+            data,
+            owner: id(),
+            ..Account::default()
+        },
+    );
+    // Accounts sized to the bare v1 fields can't hold the packed layout
+    let short_stake_pool = Pubkey::new_unique();
+    program_test.add_account(
+        short_stake_pool,
+        Account {
+            lamports: sol_to_lamports(1.0),
+            data: v1_data.to_vec(),
+            owner: id(),
+            ..Account::default()
+        },
+    );
+    let (mut banks_client, payer, _) = program_test.start().await;
+
+    process(
+        &mut banks_client,
+        &payer,
+        &[instruction::migrate_state(&id(), &stake_pool).unwrap()],
+        &[],
+    )
+    .await
+    .unwrap();
+    let account = get_account(&mut banks_client, &stake_pool).await;
+    let migrated = StakePool::deserialize(&account.data).unwrap();
+    assert_eq!(
+        migrated,
+        StakePool {
+            version: PROGRAM_VERSION,
+            ..StakePool::deserialize_v1(v1_data).unwrap()
+        }
+    );
+
+    let error = process(
+        &mut banks_client,
+        &payer,
+        &[instruction::migrate_state(&id(), &short_stake_pool).unwrap()],
+        &[],
+    )
+    .await
+    .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::AccountDataTooSmall,
+        )) => {}
+        _ => panic!("Wrong error occurs while migrating a short account"),
+    }
+}
+
+#[tokio::test]
+async fn test_refund_credit_list() {
+    let mut program_test = program_test();
+    let stake_pool = Pubkey::new_unique();
+    let owner = Keypair::new();
+    let credit_list = Pubkey::new_unique();
+    let credit_reserve = Pubkey::new_unique();
+    let pool_mint = Pubkey::new_unique();
+    let (withdraw_authority, withdraw_bump_seed) =
+        Processor::find_authority_bump_seed(&id(), &stake_pool, Processor::AUTHORITY_WITHDRAW);
+    let credits = vec![
+        CreditListEntry {
+            sol_target: Pubkey::new_unique(),
+            cancel_authority: Pubkey::new_unique(),
+            token_amount: 100,
+        },
+        CreditListEntry {
+            sol_target: Pubkey::new_unique(),
+            cancel_authority: Pubkey::new_unique(),
+            token_amount: 200,
+        },
+    ];
+
+    let mut data = vec![0; StakePool::LEN];
+    StakePool {
+        version: PROGRAM_VERSION,
+        owner: owner.pubkey(),
+        withdraw_bump_seed,
+        credit_list,
+        pool_mint,
+        credit_reserve,
+        token_program_id: spl_token::id(),
+        ..StakePool::default()
+    }
+    .serialize(&mut data)
+    .unwrap();
+    program_test.add_account(
+        stake_pool,
+        Account {
+            lamports: sol_to_lamports(1.0),
+            data,
+            owner: id(),
+            ..Account::default()
+        },
+    );
+    let mut data = vec![0; CreditList::HEADER_LEN + 4 * CreditListEntry::LEN];
+    CreditList {
+        version: 1,
+        credits: credits.clone(),
+    }
+    .serialize(&mut data)
+    .unwrap();
+    let list_rent = sol_to_lamports(0.1);
+    program_test.add_account(
+        credit_list,
+        Account {
+            lamports: list_rent,
+            data,
+            owner: id(),
+            ..Account::default()
+        },
+    );
+    program_test.add_account(
+        owner.pubkey(),
+        Account {
+            lamports: sol_to_lamports(1.0),
+            ..Account::default()
+        },
+    );
+    let mut data = vec![0; spl_token::state::Mint::LEN];
+    spl_token::state::Mint {
+        mint_authority: COption::Some(withdraw_authority),
+        supply: 300,
+        decimals: 9,
+        is_initialized: true,
+        freeze_authority: COption::None,
+    }
+    .pack_into_slice(&mut data);
+    program_test.add_account(
+        pool_mint,
+        Account {
+            lamports: sol_to_lamports(1.0),
+            data,
+            owner: spl_token::id(),
+            ..Account::default()
+        },
+    );
+    let mut token_accounts = vec![(credit_reserve, withdraw_authority, 300)];
+    let refund_to: Vec<Pubkey> = credits.iter().map(|_| Pubkey::new_unique()).collect();
+    for (entry, refund_to) in credits.iter().zip(refund_to.iter()) {
+        token_accounts.push((*refund_to, entry.sol_target, 0));
+    }
+    // Belongs to somebody else than the credit owners
+    let stranger_account = Pubkey::new_unique();
+    token_accounts.push((stranger_account, Pubkey::new_unique(), 0));
+    for (address, token_owner, amount) in token_accounts {
+        let mut data = vec![0; spl_token::state::Account::LEN];
+        spl_token::state::Account {
+            mint: pool_mint,
+            owner: token_owner,
+            amount,
+            state: spl_token::state::AccountState::Initialized,
+            ..spl_token::state::Account::default()
+        }
+        .pack_into_slice(&mut data);
+        program_test.add_account(
+            address,
+            Account {
+                lamports: sol_to_lamports(1.0),
+                data,
+                owner: spl_token::id(),
+                ..Account::default()
+            },
+        );
+    }
+    let (mut banks_client, payer, _) = program_test.start().await;
+
+    // The tokens go to the accounts of the credit owners only
+    let error = process(
+        &mut banks_client,
+        &payer,
+        &[instruction::refund_credit_list(
+            &id(),
+            &stake_pool,
+            &owner.pubkey(),
+            &credit_list,
+            &credit_reserve,
+            &spl_token::id(),
+            &[stranger_account],
+        )
+        .unwrap()],
+        &[&owner],
+    )
+    .await
+    .unwrap_err();
+    match error {
+        TransportError::TransactionError(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(error_index),
+        )) => {
+            assert_eq!(error_index, StakePoolError::WrongCreditOwner as u32);
+        }
+        _ => panic!("Wrong error occurs while refunding to another account"),
+    }
+
+    // Refund the last credit first
+    process(
+        &mut banks_client,
+        &payer,
+        &[instruction::refund_credit_list(
+            &id(),
+            &stake_pool,
+            &owner.pubkey(),
+            &credit_list,
+            &credit_reserve,
+            &spl_token::id(),
+            &refund_to[1..],
+        )
+        .unwrap()],
+        &[&owner],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        get_token_balance(&mut banks_client, &refund_to[1]).await,
+        200
+    );
+    assert_eq!(
+        get_token_balance(&mut banks_client, &credit_reserve).await,
+        100
+    );
+    let account = get_account(&mut banks_client, &credit_list).await;
+    assert_eq!(
+        CreditList::deserialize(&account.data).unwrap().credits,
+        credits[..1]
+    );
+
+    process(
+        &mut banks_client,
+        &payer,
+        &[instruction::refund_credit_list(
+            &id(),
+            &stake_pool,
+            &owner.pubkey(),
+            &credit_list,
+            &credit_reserve,
+            &spl_token::id(),
+            &refund_to[..1],
+        )
+        .unwrap()],
+        &[&owner],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        get_token_balance(&mut banks_client, &refund_to[0]).await,
+        100
+    );
+    assert_eq!(
+        get_token_balance(&mut banks_client, &credit_reserve).await,
+        0
+    );
+    // The empty list is closed and its rent goes to the owner
+    assert!(banks_client
+        .get_account(credit_list)
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        banks_client.get_balance(owner.pubkey()).await.unwrap(),
+        sol_to_lamports(1.0) + list_rent
+    );
+    let account = get_account(&mut banks_client, &stake_pool).await;
+    assert_eq!(
+        StakePool::deserialize(&account.data).unwrap().credit_list,
+        Pubkey::default()
+    );
 }
@@ -1,10 +1,9 @@
 import { Account, Connection, TransactionInstruction } from "@solana/web3.js";
 import { sendTransaction } from "../contexts/connection";
 import { WalletAdapter } from "../contexts/wallet";
-import { approve, TokenAccount } from "../models";
+import { TokenAccount } from "../models";
 import { Tenderize } from "../models/lending";
-import { creditInstruction } from "../models/lending/credit";
-import { WITHDRAW_AUTHORITY_PDA } from "../utils/ids";
+import { creditInstruction, creditTicketAddress } from "../models/lending/credit";
 import { notify } from "../utils/notifications";
 
 
@@ -31,17 +30,6 @@ export const credit = async (
 
   const fromAccount = from.pubkey;
 
-  // create approval for transfer transactions
-  approve(
-    instructions,
-    cleanupInstructions,
-    fromAccount,
-    wallet.publicKey,
-    amountLamports,
-    true,
-    WITHDRAW_AUTHORITY_PDA
-  );
-
   // get destination account
   const toAccount = wallet.publicKey;
 
@@ -50,7 +38,10 @@ export const credit = async (
   instructions.push(
     creditInstruction(
       {
+        ticket: await creditTicketAddress(tenderize.creditQueueHead),
+        payer: wallet.publicKey,
         userTokenSource: fromAccount,
+        transferAuthority: wallet.publicKey,
         userSolTarget: toAccount,
         amount: amountLamports,
         cancelAuthority: cancelAuthority.publicKey
@@ -1,20 +1,45 @@
 import {
   PublicKey,
+  SystemProgram,
+  SYSVAR_CLOCK_PUBKEY,
+  SYSVAR_RENT_PUBKEY,
   TransactionInstruction,
 } from '@solana/web3.js';
 import BN from 'bn.js';
 import * as BufferLayout from 'buffer-layout';
 import { Tenderize } from '.';
-import { TOKEN_PROGRAM_ID, TENDERIZE_PROGRAM_ID, STAKE_POOL_ID, WITHDRAW_AUTHORITY_PDA } from '../../utils/ids';
+import {
+  STAKE_POOL_ID,
+  TENDERIZE_PROGRAM_ID,
+  TENDERIZED_SOL_MINT_ID,
+  TOKEN_PROGRAM_ID,
+  SYSVAR_EPOCH_SCHEDULE_PUBKEY,
+} from '../../utils/ids';
 import * as Layout from './../../utils/layout';
 
 export interface CreditParams {
+  ticket: PublicKey; // creditTicketAddress of tenderize.creditQueueHead
+  payer: PublicKey;
   userTokenSource: PublicKey;
+  transferAuthority: PublicKey; // owner of userTokenSource
   userSolTarget: PublicKey;
   cancelAuthority: PublicKey;
   amount: number | BN;
 }
 
+export const creditTicketAddress = async (sequence: BN) => {
+  return (
+    await PublicKey.findProgramAddress(
+      [
+        STAKE_POOL_ID.toBuffer(),
+        Buffer.from('credit'),
+        sequence.toArrayLike(Buffer, 'le', 8),
+      ],
+      TENDERIZE_PROGRAM_ID
+    )
+  )[0];
+};
+
 export const creditInstruction = (
   params: CreditParams,
   tenderize: Tenderize
@@ -35,12 +60,17 @@ export const creditInstruction = (
 
   const keys = [
     { pubkey: STAKE_POOL_ID, isSigner: false, isWritable: true },
-    { pubkey: tenderize.creditList, isSigner: false, isWritable: true },
-    { pubkey: tenderize.creditReserve, isSigner: false, isWritable: true },
-    { pubkey: WITHDRAW_AUTHORITY_PDA, isSigner: false, isWritable: false, },
+    { pubkey: params.ticket, isSigner: false, isWritable: true },
     { pubkey: params.userTokenSource, isSigner: false, isWritable: true },
-    { pubkey: params.userSolTarget, isSigner: false, isWritable: true },
+    { pubkey: params.transferAuthority, isSigner: true, isWritable: false },
+    { pubkey: TENDERIZED_SOL_MINT_ID, isSigner: false, isWritable: true },
+    { pubkey: params.userSolTarget, isSigner: false, isWritable: false },
     { pubkey: params.cancelAuthority, isSigner: false, isWritable: false },
+    { pubkey: params.payer, isSigner: true, isWritable: true },
+    { pubkey: SYSVAR_RENT_PUBKEY, isSigner: false, isWritable: false },
+    { pubkey: SYSVAR_CLOCK_PUBKEY, isSigner: false, isWritable: false },
+    { pubkey: SYSVAR_EPOCH_SCHEDULE_PUBKEY, isSigner: false, isWritable: false },
+    { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
     { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
   ];
   return new TransactionInstruction({
@@ -1,6 +1,7 @@
 import {
   PublicKey,
   SystemProgram,
+  SYSVAR_CLOCK_PUBKEY,
   SYSVAR_RENT_PUBKEY,
   TransactionInstruction,
 } from '@solana/web3.js';
@@ -16,6 +17,7 @@ import {
   RESERVE_ADDRESS_PDA,
   WITHDRAW_AUTHORITY_PDA,
   WRAPPED_SOL_MINT,
+  SYSVAR_EPOCH_SCHEDULE_PUBKEY,
 } from '../../utils/ids';
 import * as Layout from './../../utils/layout';
 import { LendingInstruction } from './lending';
@@ -39,6 +41,8 @@ export interface DepositParams {
   userSource: PublicKey;
   amount: number | BN;
   userToken: PublicKey;
+  // Fails the deposit when fewer pool tokens would be minted to the user
+  minPoolTokensOut?: number | BN;
 }
 
 export const depositInstruction = (
@@ -53,16 +57,21 @@ export const depositInstruction = (
   reserveSupply: PublicKey,
   collateralMint: PublicKey*/
 ): TransactionInstruction => {
+  const withSlippage = params.minPoolTokensOut !== undefined;
   const dataLayout = BufferLayout.struct([
     BufferLayout.u8('instruction'),
     Layout.uint64('liquidityAmount'),
+    ...(withSlippage ? [Layout.uint64('minPoolTokensOut')] : []),
   ]);
 
   const data = Buffer.alloc(dataLayout.span);
   dataLayout.encode(
     {
-      instruction: LendingInstruction.DepositReserveLiquidity,
+      instruction: withSlippage
+        ? LendingInstruction.DepositReserveLiquidityWithSlippage
+        : LendingInstruction.DepositReserveLiquidity,
       liquidityAmount: new BN(params.amount),
+      minPoolTokensOut: new BN(params.minPoolTokensOut || 0),
     },
     data
   );
@@ -76,6 +85,8 @@ export const depositInstruction = (
     { pubkey: OWNER_FEE_ACCOUNT, isSigner: false, isWritable: true },
     { pubkey: TENDERIZED_SOL_MINT_ID, isSigner: false, isWritable: true },
     { pubkey: SYSVAR_RENT_PUBKEY, isSigner: false, isWritable: false },
+    { pubkey: SYSVAR_CLOCK_PUBKEY, isSigner: false, isWritable: false },
+    { pubkey: SYSVAR_EPOCH_SCHEDULE_PUBKEY, isSigner: false, isWritable: false },
     { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
     { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
     { pubkey: TEMP_ACCOUNT_PDA, isSigner: false, isWritable: true },
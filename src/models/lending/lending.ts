@@ -8,6 +8,8 @@ export enum LendingInstruction {
   RepayObligationLiquidity = 6,
   LiquidateObligation = 7,
   AccrueReserveInterest = 8,
+  DepositReserveLiquidityWithSlippage = 33,
+  WithdrawReserveLiquidityWithSlippage = 34,
 }
 
 export const TransactionListLookup: { [key: number]: string } = {
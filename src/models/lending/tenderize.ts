@@ -10,7 +10,8 @@ export const TenderizeLayout: typeof BufferLayout.Structure = BufferLayout.struc
   [
     BufferLayout.u8('version'),
     Layout.publicKey('owner'),
-    BufferLayout.blob(2, 'padding'),
+    BufferLayout.u8('depositBumpSeed'),
+    BufferLayout.u8('withdrawBumpSeed'),
 
     Layout.publicKey('validatorStakeList'),
     Layout.publicKey('creditList'),
@@ -18,23 +19,61 @@ export const TenderizeLayout: typeof BufferLayout.Structure = BufferLayout.struc
     Layout.publicKey('ownerFeeAccount'),
     Layout.publicKey('creditReserve'),
     Layout.publicKey('tokenProgram'),
-    BufferLayout.blob(5, 'padding'),
 
     Layout.uint64('stakeTotal'),
     Layout.uint64('poolTotal'),
     Layout.uint64('lastEpochUpdate'),
     Layout.uint64('feeDenominator'),
     Layout.uint64('feeNumerator'),
+    Layout.uint64('lastUpdateSlot'),
+    Layout.uint64('freshnessGraceSlots'),
+    Layout.uint64('withdrawalFeeDenominator'),
+    Layout.uint64('withdrawalFeeNumerator'),
+    Layout.uint64('performanceFeeDenominator'),
+    Layout.uint64('performanceFeeNumerator'),
+    Layout.uint64('pendingDepositFeeDenominator'),
+    Layout.uint64('pendingDepositFeeNumerator'),
+    Layout.uint64('pendingDepositFeeEpoch'),
+    Layout.uint64('pendingWithdrawalFeeDenominator'),
+    Layout.uint64('pendingWithdrawalFeeNumerator'),
+    Layout.uint64('pendingWithdrawalFeeEpoch'),
+    Layout.uint64('pendingPerformanceFeeDenominator'),
+    Layout.uint64('pendingPerformanceFeeNumerator'),
+    Layout.uint64('pendingPerformanceFeeEpoch'),
+    Layout.publicKey('pendingOwner'),
+    Layout.publicKey('pendingOwnerFeeAccount'),
+    BufferLayout.u16('liquidityTargetBps'),
+    Layout.uint64('deactivatingLamports'),
+    Layout.uint64('deactivatingEpoch'),
+    Layout.uint64('activeLamports'),
+    Layout.uint64('activatingLamports'),
+    Layout.uint64('listUpdateEpoch'),
+    BufferLayout.u32('listUpdateValidatorIndex'),
+    BufferLayout.u32('listUpdateStakeIndex'),
+    BufferLayout.u32('listUpdateStakeCount'),
+    Layout.uint64('creditQueueHead'),
+    Layout.uint64('creditQueueTail'),
+    Layout.uint64('creditLamportsTotal'),
+    BufferLayout.u8('creditOrdering'),
+    Layout.uint64('performanceHighWater'),
+    // The rest of the account is reserved for new fields
   ]
 );
 
+export const TENDERIZE_LAYOUT_VERSION = 2;
+
 export const isTenderize = (info: AccountInfo<Buffer>) => {
-  return info.data.length === TenderizeLayout.span;
+  return (
+    info.data.length >= TenderizeLayout.span &&
+    info.data[0] === TENDERIZE_LAYOUT_VERSION
+  );
 };
 
 export interface Tenderize {
   version: number;
   owner: PublicKey;
+  depositBumpSeed: number;
+  withdrawBumpSeed: number;
   validatorStakeList: PublicKey;
   creditList: PublicKey;
   poolMint: PublicKey;
@@ -46,6 +85,37 @@ export interface Tenderize {
   lastEpochUpdate: BN;
   feeDenominator: BN;
   feeNumerator: BN;
+  lastUpdateSlot: BN;
+  freshnessGraceSlots: BN;
+  withdrawalFeeDenominator: BN;
+  withdrawalFeeNumerator: BN;
+  performanceFeeDenominator: BN;
+  performanceFeeNumerator: BN;
+  pendingDepositFeeDenominator: BN;
+  pendingDepositFeeNumerator: BN;
+  pendingDepositFeeEpoch: BN;
+  pendingWithdrawalFeeDenominator: BN;
+  pendingWithdrawalFeeNumerator: BN;
+  pendingWithdrawalFeeEpoch: BN;
+  pendingPerformanceFeeDenominator: BN;
+  pendingPerformanceFeeNumerator: BN;
+  pendingPerformanceFeeEpoch: BN;
+  pendingOwner: PublicKey;
+  pendingOwnerFeeAccount: PublicKey;
+  liquidityTargetBps: number;
+  deactivatingLamports: BN;
+  deactivatingEpoch: BN;
+  activeLamports: BN;
+  activatingLamports: BN;
+  listUpdateEpoch: BN;
+  listUpdateValidatorIndex: number;
+  listUpdateStakeIndex: number;
+  listUpdateStakeCount: number;
+  creditQueueHead: BN; // sequence of the next credit ticket
+  creditQueueTail: BN; // sequence of the first ticket FIFO payments start at
+  creditLamportsTotal: BN; // owed to credit tickets, not in stakeTotal
+  creditOrdering: number; // 0 FIFO with partial payments, 1 skip-ahead
+  performanceHighWater: BN; // highest lamports value of 10^9 pool tokens, the performance fee applies above it
 }
 
 export const TenderizeParser = (
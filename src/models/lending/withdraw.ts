@@ -1,13 +1,14 @@
 import {
   PublicKey,
   SystemProgram,
+  SYSVAR_CLOCK_PUBKEY,
   SYSVAR_RENT_PUBKEY,
   TransactionInstruction,
 } from '@solana/web3.js';
 import BN from 'bn.js';
 import * as BufferLayout from 'buffer-layout';
 import { Tenderize } from '.';
-import { TOKEN_PROGRAM_ID, TENDERIZE_PROGRAM_ID, STAKE_POOL_ID, WITHDRAW_AUTHORITY_PDA, RESERVE_ADDRESS_PDA } from '../../utils/ids';
+import { TOKEN_PROGRAM_ID, TENDERIZE_PROGRAM_ID, STAKE_POOL_ID, WITHDRAW_AUTHORITY_PDA, RESERVE_ADDRESS_PDA, SYSVAR_EPOCH_SCHEDULE_PUBKEY } from '../../utils/ids';
 import * as Layout from './../../utils/layout';
 import { LendingInstruction } from './lending';
 
@@ -15,22 +16,29 @@ export interface WithdrawParams {
   userTokenSource: PublicKey;
   userSolTarget: PublicKey;
   amount: number | BN;
+  // Fails the withdrawal when fewer lamports would be sent to the user
+  minLamportsOut?: number | BN;
 }
 
 export const withdrawInstruction = (
   params: WithdrawParams,
   tenderize: Tenderize
 ): TransactionInstruction => {
+  const withSlippage = params.minLamportsOut !== undefined;
   const dataLayout = BufferLayout.struct([
     BufferLayout.u8('instruction'),
     Layout.uint64('collateralAmount'),
+    ...(withSlippage ? [Layout.uint64('minLamportsOut')] : []),
   ]);
 
   const data = Buffer.alloc(dataLayout.span);
   dataLayout.encode(
     {
-      instruction: LendingInstruction.WithdrawReserveLiquidity,
+      instruction: withSlippage
+        ? LendingInstruction.WithdrawReserveLiquidityWithSlippage
+        : LendingInstruction.WithdrawReserveLiquidity,
       collateralAmount: new BN(params.amount),
+      minLamportsOut: new BN(params.minLamportsOut || 0),
     },
     data
   );
@@ -40,9 +48,12 @@ export const withdrawInstruction = (
     { pubkey: WITHDRAW_AUTHORITY_PDA, isSigner: false, isWritable: false, },
     { pubkey: RESERVE_ADDRESS_PDA, isSigner: false, isWritable: true, },
     { pubkey: params.userTokenSource, isSigner: false, isWritable: true },
+    { pubkey: tenderize.ownerFeeAccount, isSigner: false, isWritable: true },
     { pubkey: tenderize.poolMint, isSigner: false, isWritable: true },
     { pubkey: params.userSolTarget, isSigner: false, isWritable: true },
     { pubkey: SYSVAR_RENT_PUBKEY, isSigner: false, isWritable: false },
+    { pubkey: SYSVAR_CLOCK_PUBKEY, isSigner: false, isWritable: false },
+    { pubkey: SYSVAR_EPOCH_SCHEDULE_PUBKEY, isSigner: false, isWritable: false },
     { pubkey: SystemProgram.programId, isSigner: false, isWritable: false },
     { pubkey: TOKEN_PROGRAM_ID, isSigner: false, isWritable: false },
   ];
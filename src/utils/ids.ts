@@ -20,6 +20,10 @@ export let VALIDATORS_LIST_ID = new PublicKey( // TODO: read from account
   '7qaWgz27453E5CLPvcvGiLR2GobLPbKWsPmGDNQYRm6U'
 );
 
+export const SYSVAR_EPOCH_SCHEDULE_PUBKEY = new PublicKey(
+  'SysvarEpochSchedu1e111111111111111111111111'
+);
+
 export const WRAPPED_SOL_MINT = new PublicKey(
   'So11111111111111111111111111111111111111112'
 );